@@ -0,0 +1,655 @@
+use crate::fuse::{
+    dev_t, flock, fuse, fuse_bufvec, fuse_config, fuse_conn_info, fuse_file_info,
+    fuse_fill_dir_flags, fuse_fill_dir_t, fuse_get_context, fuse_invalidate_path, fuse_operations,
+    fuse_pollhandle, fuse_readdir_flags, gid_t, mode_t, off_t, stat, statvfs, timespec, uid_t,
+    EBUSY,
+};
+use std::{
+    cmp::Reverse,
+    ffi::{c_char, c_int, c_uint, c_void, CStr, CString},
+    mem::MaybeUninit,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+/// Alias directories and the directories they mirror, longest alias first.
+static mut ALIASES: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+/// The mount, known once it is initialized, to invalidate what the kernel caches through.
+static FUSE: AtomicPtr<fuse> = AtomicPtr::new(ptr::null_mut());
+
+/// A guest path with any alias it goes through replaced by the directory the alias mirrors.
+struct Aliased(Option<CString>);
+
+impl Aliased {
+    fn ptr(&self) -> *const c_char {
+        self.0.as_ref().map_or(ptr::null(), |path| path.as_ptr())
+    }
+}
+
+/// Returns the alias `path` is or goes through, and the rest of the path after it.
+unsafe fn find_alias(path: &[u8]) -> Option<(&'static [u8], &[u8])> {
+    ALIASES.iter().find_map(|(alias, target)| {
+        let rest = path.strip_prefix(&alias[..])?;
+        (rest.is_empty() || rest.starts_with(b"/")).then_some((&target[..], rest))
+    })
+}
+
+unsafe fn aliased(path: *const c_char) -> Aliased {
+    if path.is_null() {
+        return Aliased(None);
+    }
+    let path = CStr::from_ptr(path);
+    Aliased(Some(match find_alias(path.to_bytes()) {
+        Some((target, rest)) => CString::new([target, rest].concat()).unwrap(),
+        None => path.to_owned(),
+    }))
+}
+
+/// Guest paths the next layer's `path` shows up at: itself, unless an alias hides it, and its
+/// spelling through every alias of a directory holding it.
+unsafe fn spellings(path: &[u8]) -> Vec<Vec<u8>> {
+    let mut paths = Vec::new();
+    if find_alias(path).is_none() {
+        paths.push(path.to_vec());
+    }
+    for (alias, target) in ALIASES.iter() {
+        if let Some(rest) = path.strip_prefix(&target[..]) {
+            if rest.is_empty() || rest.starts_with(b"/") {
+                paths.push([alias, rest].concat());
+            }
+        }
+    }
+    paths
+}
+
+fn parent(path: &[u8]) -> &[u8] {
+    match path.iter().rposition(|&c| c == b'/') {
+        Some(0) | None => b"/",
+        Some(slash) => &path[..slash],
+    }
+}
+
+/// Drops what the kernel caches for the paths other than `guest` that show the same file,
+/// which it would otherwise keep serving after the change made through `guest`.
+unsafe fn invalidate_others(fuse: *mut fuse, guest: &[u8], path: &[u8]) {
+    for other in spellings(path) {
+        if other != guest {
+            let other = CString::new(other).unwrap();
+            fuse_invalidate_path(fuse, other.as_ptr());
+        }
+    }
+}
+
+/// Invalidates the other paths of the file changed through `guest`, resolved to `path`, along
+/// with those of its directory if `entry` was created or removed there.
+unsafe fn changed(guest: *const c_char, path: &Aliased, entry: bool) {
+    let fuse = FUSE.load(Ordering::Relaxed);
+    let Some(path) = path.0.as_ref() else {
+        return;
+    };
+    if fuse.is_null() || guest.is_null() {
+        return;
+    }
+    let guest = CStr::from_ptr(guest).to_bytes();
+    let path = path.to_bytes();
+    invalidate_others(fuse, guest, path);
+    if entry {
+        invalidate_others(fuse, parent(guest), parent(path));
+    }
+}
+
+/// Whether `path` is an alias itself, which like a bind mountpoint cannot be moved or removed.
+unsafe fn is_alias(path: *const c_char) -> bool {
+    !path.is_null()
+        && ALIASES
+            .iter()
+            .any(|(alias, _)| alias == CStr::from_ptr(path).to_bytes())
+}
+
+unsafe extern "C" fn init(conn: *mut fuse_conn_info, cfg: *mut fuse_config) -> *mut c_void {
+    // the conformance report initializes the layers outside a mount
+    let fuse = fuse_get_context()
+        .as_ref()
+        .map_or(ptr::null_mut(), |context| context.fuse);
+    FUSE.store(fuse, Ordering::Relaxed);
+    let res = match NEXT.assume_init_ref().init {
+        Some(init) => init(conn, cfg),
+        None => ptr::null_mut(),
+    };
+    // report the next layer's inode numbers, so a file seen through an alias and at its real
+    // path is recognizably the same, like a hard link
+    (*cfg).use_ino = 1;
+    res
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    if is_alias(arg1) || is_alias(arg2) {
+        return -(EBUSY as c_int);
+    }
+    let from = aliased(arg1);
+    let to = aliased(arg2);
+    let res = NEXT.assume_init_ref().rename.unwrap()(from.ptr(), to.ptr(), flags);
+    if res == 0 {
+        changed(arg1, &from, true);
+        changed(arg2, &to, true);
+    }
+    res
+}
+
+unsafe extern "C" fn rmdir(arg1: *const c_char) -> c_int {
+    if is_alias(arg1) {
+        return -(EBUSY as c_int);
+    }
+    let path = aliased(arg1);
+    let res = NEXT.assume_init_ref().rmdir.unwrap()(path.ptr());
+    if res == 0 {
+        changed(arg1, &path, true);
+    }
+    res
+}
+
+struct Filler {
+    buf: *mut c_void,
+    filler: fuse_fill_dir_t,
+    /// Names of the aliases in the directory, and whether the next layer listed them.
+    aliases: Vec<(Vec<u8>, bool)>,
+}
+
+unsafe extern "C" fn fill(
+    buf: *mut c_void,
+    name: *const c_char,
+    stbuf: *const stat,
+    off: off_t,
+    flags: fuse_fill_dir_flags,
+) -> c_int {
+    let filler = &mut *buf.cast::<Filler>();
+    let name_bytes = CStr::from_ptr(name).to_bytes();
+    for (alias, listed) in &mut filler.aliases {
+        *listed |= alias == name_bytes;
+    }
+    filler.filler.unwrap()(filler.buf, name, stbuf, off, flags)
+}
+
+unsafe extern "C" fn readdir(
+    arg1: *const c_char,
+    arg2: *mut c_void,
+    arg3: fuse_fill_dir_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+    arg6: fuse_readdir_flags,
+) -> c_int {
+    let path = aliased(arg1);
+    let dir = match arg1.is_null() {
+        true => &[][..],
+        false => CStr::from_ptr(arg1).to_bytes(),
+    };
+    let aliases = ALIASES
+        .iter()
+        .filter_map(|(alias, _)| {
+            let slash = alias.iter().rposition(|&c| c == b'/')?;
+            let parent = match slash {
+                0 => &b"/"[..],
+                _ => &alias[..slash],
+            };
+            (parent == dir).then(|| (alias[slash + 1..].to_vec(), false))
+        })
+        .collect();
+    let mut filler = Filler {
+        buf: arg2,
+        filler: arg3,
+        aliases,
+    };
+    let res = NEXT.assume_init_ref().readdir.unwrap()(
+        path.ptr(),
+        ptr::addr_of_mut!(filler).cast(),
+        Some(fill),
+        arg4,
+        arg5,
+        arg6,
+    );
+    // aliases need not exist in the next layer, but are listed like the bind mountpoints
+    // they stand in for
+    if res == 0 && arg4 == 0 {
+        for (alias, _) in filler.aliases.iter().filter(|(_, listed)| !listed) {
+            let name = CString::new(&alias[..]).unwrap();
+            arg3.unwrap()(arg2, name.as_ptr(), ptr::null(), 0, 0);
+        }
+    }
+    res
+}
+
+unsafe extern "C" fn getattr(
+    arg1: *const c_char,
+    arg2: *mut stat,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = aliased(arg1);
+    NEXT.assume_init_ref().getattr.unwrap()(arg1.ptr(), arg2, fi)
+}
+
+unsafe extern "C" fn readlink(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    let arg1 = aliased(arg1);
+    NEXT.assume_init_ref().readlink.unwrap()(arg1.ptr(), arg2, arg3)
+}
+
+unsafe extern "C" fn mknod(arg1: *const c_char, arg2: mode_t, arg3: dev_t) -> c_int {
+    let path = aliased(arg1);
+    let res = NEXT.assume_init_ref().mknod.unwrap()(path.ptr(), arg2, arg3);
+    if res >= 0 {
+        changed(arg1, &path, true);
+    }
+    res
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    let path = aliased(arg1);
+    let res = NEXT.assume_init_ref().mkdir.unwrap()(path.ptr(), arg2);
+    if res >= 0 {
+        changed(arg1, &path, true);
+    }
+    res
+}
+
+unsafe extern "C" fn unlink(arg1: *const c_char) -> c_int {
+    let path = aliased(arg1);
+    let res = NEXT.assume_init_ref().unlink.unwrap()(path.ptr());
+    if res >= 0 {
+        changed(arg1, &path, true);
+    }
+    res
+}
+
+unsafe extern "C" fn symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let path = aliased(arg2);
+    let res = NEXT.assume_init_ref().symlink.unwrap()(arg1, path.ptr());
+    if res == 0 {
+        changed(arg2, &path, true);
+    }
+    res
+}
+
+unsafe extern "C" fn link(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let from = aliased(arg1);
+    let to = aliased(arg2);
+    let res = NEXT.assume_init_ref().link.unwrap()(from.ptr(), to.ptr());
+    if res == 0 {
+        // the link count of the file changed as well
+        changed(arg1, &from, false);
+        changed(arg2, &to, true);
+    }
+    res
+}
+
+unsafe extern "C" fn chmod(arg1: *const c_char, arg2: mode_t, fi: *mut fuse_file_info) -> c_int {
+    let path = aliased(arg1);
+    let res = NEXT.assume_init_ref().chmod.unwrap()(path.ptr(), arg2, fi);
+    if res >= 0 {
+        changed(arg1, &path, false);
+    }
+    res
+}
+
+unsafe extern "C" fn chown(
+    arg1: *const c_char,
+    arg2: uid_t,
+    arg3: gid_t,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let path = aliased(arg1);
+    let res = NEXT.assume_init_ref().chown.unwrap()(path.ptr(), arg2, arg3, fi);
+    if res >= 0 {
+        changed(arg1, &path, false);
+    }
+    res
+}
+
+unsafe extern "C" fn truncate(arg1: *const c_char, arg2: off_t, fi: *mut fuse_file_info) -> c_int {
+    let path = aliased(arg1);
+    let res = NEXT.assume_init_ref().truncate.unwrap()(path.ptr(), arg2, fi);
+    if res >= 0 {
+        changed(arg1, &path, false);
+    }
+    res
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let arg1 = aliased(arg1);
+    NEXT.assume_init_ref().open.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn read(
+    arg1: *const c_char,
+    arg2: *mut c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = aliased(arg1);
+    NEXT.assume_init_ref().read.unwrap()(arg1.ptr(), arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let path = aliased(arg1);
+    let res = NEXT.assume_init_ref().write.unwrap()(path.ptr(), arg2, arg3, arg4, arg5);
+    if res >= 0 {
+        changed(arg1, &path, false);
+    }
+    res
+}
+
+unsafe extern "C" fn statfs(arg1: *const c_char, arg2: *mut statvfs) -> c_int {
+    let arg1 = aliased(arg1);
+    NEXT.assume_init_ref().statfs.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn flush(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let arg1 = aliased(arg1);
+    NEXT.assume_init_ref().flush.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn release(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let arg1 = aliased(arg1);
+    NEXT.assume_init_ref().release.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn fsync(arg1: *const c_char, arg2: c_int, arg3: *mut fuse_file_info) -> c_int {
+    let arg1 = aliased(arg1);
+    NEXT.assume_init_ref().fsync.unwrap()(arg1.ptr(), arg2, arg3)
+}
+
+unsafe extern "C" fn setxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *const c_char,
+    arg4: usize,
+    arg5: c_int,
+) -> c_int {
+    let path = aliased(arg1);
+    let res = NEXT.assume_init_ref().setxattr.unwrap()(path.ptr(), arg2, arg3, arg4, arg5);
+    if res >= 0 {
+        changed(arg1, &path, false);
+    }
+    res
+}
+
+unsafe extern "C" fn getxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *mut c_char,
+    arg4: usize,
+) -> c_int {
+    let arg1 = aliased(arg1);
+    NEXT.assume_init_ref().getxattr.unwrap()(arg1.ptr(), arg2, arg3, arg4)
+}
+
+unsafe extern "C" fn listxattr(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    let arg1 = aliased(arg1);
+    NEXT.assume_init_ref().listxattr.unwrap()(arg1.ptr(), arg2, arg3)
+}
+
+unsafe extern "C" fn removexattr(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let path = aliased(arg1);
+    let res = NEXT.assume_init_ref().removexattr.unwrap()(path.ptr(), arg2);
+    if res >= 0 {
+        changed(arg1, &path, false);
+    }
+    res
+}
+
+unsafe extern "C" fn opendir(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let arg1 = aliased(arg1);
+    NEXT.assume_init_ref().opendir.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn releasedir(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let arg1 = aliased(arg1);
+    NEXT.assume_init_ref().releasedir.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn fsyncdir(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = aliased(arg1);
+    NEXT.assume_init_ref().fsyncdir.unwrap()(arg1.ptr(), arg2, arg3)
+}
+
+unsafe extern "C" fn access(arg1: *const c_char, arg2: c_int) -> c_int {
+    let arg1 = aliased(arg1);
+    NEXT.assume_init_ref().access.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    let path = aliased(arg1);
+    let res = NEXT.assume_init_ref().create.unwrap()(path.ptr(), arg2, arg3);
+    if res >= 0 {
+        changed(arg1, &path, true);
+    }
+    res
+}
+
+unsafe extern "C" fn lock(
+    arg1: *const c_char,
+    arg2: *mut fuse_file_info,
+    cmd: c_int,
+    arg3: *mut flock,
+) -> c_int {
+    let arg1 = aliased(arg1);
+    NEXT.assume_init_ref().lock.unwrap()(arg1.ptr(), arg2, cmd, arg3)
+}
+
+unsafe extern "C" fn utimens(
+    arg1: *const c_char,
+    tv: *const timespec,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let path = aliased(arg1);
+    let res = NEXT.assume_init_ref().utimens.unwrap()(path.ptr(), tv, fi);
+    if res >= 0 {
+        changed(arg1, &path, false);
+    }
+    res
+}
+
+unsafe extern "C" fn bmap(arg1: *const c_char, blocksize: usize, idx: *mut u64) -> c_int {
+    let arg1 = aliased(arg1);
+    NEXT.assume_init_ref().bmap.unwrap()(arg1.ptr(), blocksize, idx)
+}
+
+unsafe extern "C" fn ioctl(
+    arg1: *const c_char,
+    cmd: c_int,
+    arg: *mut c_void,
+    arg2: *mut fuse_file_info,
+    flags: c_uint,
+    data: *mut c_void,
+) -> c_int {
+    let arg1 = aliased(arg1);
+    NEXT.assume_init_ref().ioctl.unwrap()(arg1.ptr(), cmd, arg, arg2, flags, data)
+}
+
+unsafe extern "C" fn poll(
+    arg1: *const c_char,
+    arg2: *mut fuse_file_info,
+    ph: *mut fuse_pollhandle,
+    reventsp: *mut c_uint,
+) -> c_int {
+    let arg1 = aliased(arg1);
+    NEXT.assume_init_ref().poll.unwrap()(arg1.ptr(), arg2, ph, reventsp)
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let path = aliased(arg1);
+    let res = NEXT.assume_init_ref().write_buf.unwrap()(path.ptr(), buf, off, arg2);
+    if res >= 0 {
+        changed(arg1, &path, false);
+    }
+    res
+}
+
+unsafe extern "C" fn read_buf(
+    arg1: *const c_char,
+    bufp: *mut *mut fuse_bufvec,
+    size: usize,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = aliased(arg1);
+    NEXT.assume_init_ref().read_buf.unwrap()(arg1.ptr(), bufp, size, off, arg2)
+}
+
+unsafe extern "C" fn flock(arg1: *const c_char, arg2: *mut fuse_file_info, op: c_int) -> c_int {
+    let arg1 = aliased(arg1);
+    NEXT.assume_init_ref().flock.unwrap()(arg1.ptr(), arg2, op)
+}
+
+unsafe extern "C" fn fallocate(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: off_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let path = aliased(arg1);
+    let res = NEXT.assume_init_ref().fallocate.unwrap()(path.ptr(), arg2, arg3, arg4, arg5);
+    if res >= 0 {
+        changed(arg1, &path, false);
+    }
+    res
+}
+
+unsafe extern "C" fn copy_file_range(
+    path_in: *const c_char,
+    fi_in: *mut fuse_file_info,
+    offset_in: off_t,
+    path_out: *const c_char,
+    fi_out: *mut fuse_file_info,
+    offset_out: off_t,
+    size: usize,
+    flags: c_int,
+) -> isize {
+    let aliased_in = aliased(path_in);
+    let aliased_out = aliased(path_out);
+    let res = NEXT.assume_init_ref().copy_file_range.unwrap()(
+        aliased_in.ptr(),
+        fi_in,
+        offset_in,
+        aliased_out.ptr(),
+        fi_out,
+        offset_out,
+        size,
+        flags,
+    );
+    if res > 0 {
+        changed(path_out, &aliased_out, false);
+    }
+    res
+}
+
+unsafe extern "C" fn lseek(
+    arg1: *const c_char,
+    off: off_t,
+    whence: c_int,
+    arg2: *mut fuse_file_info,
+) -> off_t {
+    let arg1 = aliased(arg1);
+    NEXT.assume_init_ref().lseek.unwrap()(arg1.ptr(), off, whence, arg2)
+}
+
+/// Makes directories of the share visible at more than one guest path, to emulate bind mounts
+/// the guest cannot perform itself. `aliases` is a comma separated list of `alias=target` pairs
+/// of guest paths, e.g. `/app/config=/shared/config`, after which everything below the alias
+/// is what is below the target. An alias shows up in its parent directory whether or not it
+/// exists in the next layer, and cannot be renamed or removed, like a mountpoint. Inode numbers
+/// come from the next layer, so a file has the same one at both paths. The guest caches each
+/// path separately, so a change made through one of them invalidates the others, which assumes
+/// no layer above changes paths. Changes made to the next layer behind the mount's back still
+/// show up at each path on its own.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer and a valid C string
+#[no_mangle]
+pub unsafe extern "C" fn new_alias_layer(
+    next: *const fuse_operations,
+    aliases: *const c_char,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    let trim = |path: &str| path.trim().trim_end_matches('/').as_bytes().to_vec();
+    let aliases = CStr::from_ptr(aliases).to_string_lossy();
+    for pair in aliases
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+    {
+        match pair
+            .split_once('=')
+            .map(|(alias, target)| (trim(alias), trim(target)))
+        {
+            Some((alias, target)) if alias.starts_with(b"/") && target.starts_with(b"/") => {
+                ALIASES.push((alias, target));
+            }
+            _ => eprintln!("ignoring invalid alias {pair:?}"),
+        }
+    }
+    // nested aliases resolve through the innermost one
+    ALIASES.sort_by_key(|(alias, _)| Reverse(alias.len()));
+    Box::into_raw(Box::new(fuse_operations {
+        getattr: next.getattr.and(Some(getattr)),
+        readlink: next.readlink.and(Some(readlink)),
+        mknod: next.mknod.and(Some(mknod)),
+        mkdir: next.mkdir.and(Some(mkdir)),
+        unlink: next.unlink.and(Some(unlink)),
+        rmdir: next.rmdir.and(Some(rmdir)),
+        symlink: next.symlink.and(Some(symlink)),
+        rename: next.rename.and(Some(rename)),
+        link: next.link.and(Some(link)),
+        chmod: next.chmod.and(Some(chmod)),
+        chown: next.chown.and(Some(chown)),
+        truncate: next.truncate.and(Some(truncate)),
+        open: next.open.and(Some(open)),
+        read: next.read.and(Some(read)),
+        write: next.write.and(Some(write)),
+        statfs: next.statfs.and(Some(statfs)),
+        flush: next.flush.and(Some(flush)),
+        release: next.release.and(Some(release)),
+        fsync: next.fsync.and(Some(fsync)),
+        setxattr: next.setxattr.and(Some(setxattr)),
+        getxattr: next.getxattr.and(Some(getxattr)),
+        listxattr: next.listxattr.and(Some(listxattr)),
+        removexattr: next.removexattr.and(Some(removexattr)),
+        opendir: next.opendir.and(Some(opendir)),
+        readdir: next.readdir.and(Some(readdir)),
+        releasedir: next.releasedir.and(Some(releasedir)),
+        fsyncdir: next.fsyncdir.and(Some(fsyncdir)),
+        init: Some(init),
+        access: next.access.and(Some(access)),
+        create: next.create.and(Some(create)),
+        lock: next.lock.and(Some(lock)),
+        utimens: next.utimens.and(Some(utimens)),
+        bmap: next.bmap.and(Some(bmap)),
+        ioctl: next.ioctl.and(Some(ioctl)),
+        poll: next.poll.and(Some(poll)),
+        write_buf: next.write_buf.and(Some(write_buf)),
+        read_buf: next.read_buf.and(Some(read_buf)),
+        flock: next.flock.and(Some(flock)),
+        fallocate: next.fallocate.and(Some(fallocate)),
+        copy_file_range: next.copy_file_range.and(Some(copy_file_range)),
+        lseek: next.lseek.and(Some(lseek)),
+        ..next
+    }))
+}
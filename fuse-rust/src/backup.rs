@@ -0,0 +1,275 @@
+use crate::{
+    fuse::{
+        fuse_file_info, fuse_operations, mode_t, off_t, stat, EIO, ENOTTY, O_ACCMODE, O_RDONLY,
+        O_TRUNC,
+    },
+    hide::glob,
+    rmtree,
+};
+use std::{
+    collections::BTreeSet,
+    ffi::{c_char, c_int, c_uint, c_void, CStr, OsStr},
+    fs::{self, File, OpenOptions, Permissions},
+    io::{self, Write},
+    mem::MaybeUninit,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{fchown, OpenOptionsExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+/// Size of the chunks files are copied in.
+const CHUNK: usize = 128 * 1024;
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut PATTERNS: Vec<Vec<u8>> = Vec::new();
+static mut BACKUP_DIR: Option<PathBuf> = None;
+static mut KEEP: usize = 0;
+/// Files already backed up since the mount came up.
+static BACKED_UP: Mutex<BTreeSet<Vec<u8>>> = Mutex::new(BTreeSet::new());
+
+/// Whether `path` matches a backup pattern. Patterns containing a `/` are matched against the
+/// whole path, others against the file name.
+unsafe fn is_protected(path: &[u8]) -> bool {
+    let name = &path[path
+        .iter()
+        .rposition(|&c| c == b'/')
+        .map_or(0, |slash| slash + 1)..];
+    PATTERNS
+        .iter()
+        .any(|pattern| match pattern.contains(&b'/') {
+            true => glob(pattern, path),
+            false => glob(pattern, name),
+        })
+}
+
+/// Gives the backup the mode, owner and modification time of the original. Only root may give
+/// files away, so the backup stays with the user the interposer runs as otherwise.
+fn copy_attributes(out: &File, st: &stat) -> io::Result<()> {
+    // the mode the file was created with went through the umask
+    out.set_permissions(Permissions::from_mode(st.st_mode & 0o7777))?;
+    match fchown(out, Some(st.st_uid), Some(st.st_gid)) {
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => (),
+        result => result?,
+    }
+    let mtime = Duration::new(st.st_mtim.tv_sec as u64, st.st_mtim.tv_nsec as u32);
+    out.set_modified(SystemTime::UNIX_EPOCH + mtime)
+}
+
+/// Copies the file at `path` through the next layer to `dest`, along with its mode, owner and
+/// modification time. Returns false if there is no file to copy.
+unsafe fn copy(path: *const c_char, dest: &Path) -> Result<bool, String> {
+    let next = NEXT.assume_init_ref();
+    let mut fi = MaybeUninit::<fuse_file_info>::zeroed().assume_init();
+    fi.flags = O_RDONLY as c_int;
+    if next.open.unwrap()(path, &mut fi) != 0 {
+        return Ok(false);
+    }
+    let result = (|| {
+        let mut st = MaybeUninit::<stat>::zeroed();
+        let res = next.getattr.unwrap()(path, st.as_mut_ptr(), &mut fi);
+        if res != 0 {
+            return Err(format!("getattr failed with errno {}", -res));
+        }
+        let st = st.assume_init();
+        fs::create_dir_all(dest.parent().unwrap()).map_err(|err| err.to_string())?;
+        // only the owner may read the copy until it has the original's mode
+        let mut out = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(dest)
+            .map_err(|err| err.to_string())?;
+        copy_attributes(&out, &st).map_err(|err| err.to_string())?;
+        let mut buf = vec![0u8; CHUNK];
+        let mut off = 0;
+        loop {
+            let res = next.read.unwrap()(path, buf.as_mut_ptr().cast(), CHUNK, off, &mut fi);
+            if res < 0 {
+                return Err(format!("read failed with errno {}", -res));
+            }
+            if res == 0 {
+                // writing moved the modification time again
+                copy_attributes(&out, &st).map_err(|err| err.to_string())?;
+                return out.sync_all().map_err(|err| err.to_string());
+            }
+            out.write_all(&buf[..res as usize])
+                .map_err(|err| err.to_string())?;
+            off += res as off_t;
+        }
+    })();
+    if let Some(release) = next.release {
+        release(path, &mut fi);
+    }
+    result.map(|()| true)
+}
+
+/// Removes the oldest backups of the file backed up to `dest` beyond the number to keep.
+unsafe fn prune(dest: &Path) {
+    let (Some(dir), Some(name)) = (dest.parent(), dest.file_name()) else {
+        return;
+    };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    // backups are named after the file followed by a fixed width timestamp
+    let prefix = [&name.as_bytes()[..name.len() - 20], b"."].concat();
+    let mut backups: Vec<_> = entries
+        .filter_map(|entry| Some(entry.ok()?.file_name()))
+        .filter(|file| file.len() == name.len() && file.as_bytes().starts_with(&prefix))
+        .collect();
+    backups.sort();
+    for old in &backups[..backups.len().saturating_sub(KEEP)] {
+        let _ = fs::remove_file(dir.join(old));
+    }
+}
+
+/// Backs up the file at `path` if it is protected and was not backed up yet since the mount came
+/// up, failing with `EIO` if that is not possible so the original is never lost.
+unsafe fn backup(path: *const c_char) -> Result<(), c_int> {
+    let guest_path = CStr::from_ptr(path).to_bytes();
+    if !is_protected(guest_path) || BACKED_UP.lock().unwrap().contains(guest_path) {
+        return Ok(());
+    }
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let name = [
+        guest_path.strip_prefix(b"/").unwrap_or(guest_path),
+        format!(".{:010}{:09}", time.as_secs(), time.subsec_nanos()).as_bytes(),
+    ]
+    .concat();
+    let dest = BACKUP_DIR.as_ref().unwrap().join(OsStr::from_bytes(&name));
+    match copy(path, &dest) {
+        Ok(copied) => {
+            if copied {
+                prune(&dest);
+            }
+            BACKED_UP.lock().unwrap().insert(guest_path.to_vec());
+            Ok(())
+        }
+        Err(err) => {
+            eprintln!(
+                "failed to back up {} to {}: {err}",
+                String::from_utf8_lossy(guest_path),
+                dest.display()
+            );
+            Err(-(EIO as c_int))
+        }
+    }
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    // editors save by renaming a new version over the original
+    if let Err(res) = backup(arg2) {
+        return res;
+    }
+    NEXT.assume_init_ref().rename.unwrap()(arg1, arg2, flags)
+}
+
+unsafe extern "C" fn unlink(arg1: *const c_char) -> c_int {
+    if let Err(res) = backup(arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().unlink.unwrap()(arg1)
+}
+
+unsafe extern "C" fn truncate(arg1: *const c_char, arg2: off_t, fi: *mut fuse_file_info) -> c_int {
+    if !arg1.is_null() {
+        if let Err(res) = backup(arg1) {
+            return res;
+        }
+    }
+    NEXT.assume_init_ref().truncate.unwrap()(arg1, arg2, fi)
+}
+
+unsafe fn is_modifying(fi: *mut fuse_file_info) -> bool {
+    (*fi).flags & O_ACCMODE as c_int != O_RDONLY as c_int || (*fi).flags & O_TRUNC as c_int != 0
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    if is_modifying(arg2) {
+        if let Err(res) = backup(arg1) {
+            return res;
+        }
+    }
+    NEXT.assume_init_ref().open.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    // without O_EXCL an existing file is opened, and usually truncated
+    if is_modifying(arg3) {
+        if let Err(res) = backup(arg1) {
+            return res;
+        }
+    }
+    NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn ioctl(
+    arg1: *const c_char,
+    cmd: c_int,
+    arg: *mut c_void,
+    arg2: *mut fuse_file_info,
+    flags: c_uint,
+    data: *mut c_void,
+) -> c_int {
+    // the files below would be gone without a chance to back them up, so the guest has to
+    // remove the tree entry by entry
+    if rmtree::is_rmtree(cmd) {
+        return -(ENOTTY as c_int);
+    }
+    NEXT.assume_init_ref().ioctl.unwrap()(arg1, cmd, arg, arg2, flags, data)
+}
+
+/// Before a file matching one of the comma separated glob `patterns` is modified for the first
+/// time since the mount came up, by opening it for writing, truncating it, renaming another file
+/// over it or unlinking it, copies the original into `backup_dir` on the host, protecting
+/// configuration files in mounted volumes from bad edits made in the container. Backups keep the
+/// file's path within the mount, mode, owner and modification time and get a timestamp appended,
+/// and only the `keep` most recent ones of each file are kept. The modification fails with `EIO`
+/// if the backup cannot be made. The passthrough's `FSINTERPOSER_IOC_RMTREE` is refused with
+/// `ENOTTY`, so trees are removed entry by entry.
+///
+/// Patterns containing a `/` are matched against the whole path from the root, others against
+/// the file name. `*` and `?` do not match `/`.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer and valid C strings
+#[no_mangle]
+pub unsafe extern "C" fn new_backup_layer(
+    next: *const fuse_operations,
+    patterns: *const c_char,
+    backup_dir: *const c_char,
+    keep: c_uint,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    PATTERNS = CStr::from_ptr(patterns)
+        .to_bytes()
+        .split(|&c| c == b',')
+        .filter(|pattern| !pattern.is_empty())
+        .map(<[u8]>::to_vec)
+        .collect();
+    BACKUP_DIR = Some(PathBuf::from(OsStr::from_bytes(
+        CStr::from_ptr(backup_dir).to_bytes(),
+    )));
+    KEEP = (keep as usize).max(1);
+    if next.open.is_none() || next.read.is_none() || next.getattr.is_none() {
+        return Box::into_raw(Box::new(next));
+    }
+    Box::into_raw(Box::new(fuse_operations {
+        unlink: next.unlink.and(Some(unlink)),
+        rename: next.rename.and(Some(rename)),
+        truncate: next.truncate.and(Some(truncate)),
+        open: Some(open),
+        create: next.create.and(Some(create)),
+        ioctl: next.ioctl.and(Some(ioctl)),
+        ..next
+    }))
+}
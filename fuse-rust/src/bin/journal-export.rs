@@ -0,0 +1,34 @@
+//! Lists the paths changed according to a journal written by the journal layer, one per line
+//! prefixed with `M ` (modified), `D ` (deleted) or `T ` (whole subtree new).
+//!
+//! Usage: `journal-export <journal> [<output>]`, writing to standard output without an output.
+
+use container_native_fs_interposer_fuse::journal::export_changes;
+use std::{
+    env,
+    fs::File,
+    io::{self, BufWriter},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+fn main() -> ExitCode {
+    let args: Vec<PathBuf> = env::args_os().skip(1).map(PathBuf::from).collect();
+    let result = match &args[..] {
+        [log] => export_changes(log, &mut BufWriter::new(io::stdout().lock())),
+        [log, out] => {
+            File::create(out).and_then(|out| export_changes(log, &mut BufWriter::new(out)))
+        }
+        _ => {
+            eprintln!("usage: journal-export <journal> [<output>]");
+            return ExitCode::from(2);
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("failed to export changes from {}: {err}", args[0].display());
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use std::{
+    ffi::{c_char, CStr},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+static BUDGET: AtomicUsize = AtomicUsize::new(usize::MAX);
+/// Consumers that have charged memory so far.
+static CONSUMERS: Mutex<Vec<&'static Consumer>> = Mutex::new(Vec::new());
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+
+/// A cache whose memory counts against the budget shared by all layers of the process.
+pub struct Consumer {
+    name: &'static CStr,
+    usage: AtomicUsize,
+    /// Frees at least the given number of bytes if the cache holds that many, releasing them.
+    /// State that cannot be dropped without changing behaviour has none and is only counted.
+    evict: Option<fn(usize)>,
+    registered: AtomicBool,
+}
+
+/// How much memory a consumer holds, as reported by [`budget_usage`].
+#[repr(C)]
+pub struct BudgetUsage {
+    pub name: *const c_char,
+    pub bytes: usize,
+}
+
+impl Consumer {
+    pub const fn new(name: &'static CStr, evict: fn(usize)) -> Self {
+        Consumer {
+            name,
+            usage: AtomicUsize::new(0),
+            evict: Some(evict),
+            registered: AtomicBool::new(false),
+        }
+    }
+
+    /// A consumer whose memory counts against the budget but is never evicted, which leaves
+    /// the others to make up for it.
+    pub const fn pinned(name: &'static CStr) -> Self {
+        Consumer {
+            name,
+            usage: AtomicUsize::new(0),
+            evict: None,
+            registered: AtomicBool::new(false),
+        }
+    }
+
+    /// Accounts for `bytes` more held by the cache. Call `enforce` once the cache's locks are
+    /// released to bring the total back under budget.
+    pub fn charge(&'static self, bytes: usize) {
+        if !self.registered.swap(true, Ordering::Relaxed) {
+            CONSUMERS.lock().unwrap().push(self);
+        }
+        self.usage.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn release(&self, bytes: usize) {
+        self.usage.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub fn usage(&self) -> usize {
+        self.usage.load(Ordering::Relaxed)
+    }
+}
+
+/// Evicts from every cache in proportion to its usage until the total is back under budget.
+/// Must be called without holding the lock of any cache, whose eviction would deadlock.
+pub fn enforce() {
+    let budget = BUDGET.load(Ordering::Relaxed);
+    let consumers = CONSUMERS.lock().unwrap().clone();
+    let total: usize = consumers.iter().map(|consumer| consumer.usage()).sum();
+    if total <= budget || RECLAIMING.swap(true, Ordering::Acquire) {
+        return;
+    }
+    let evictable: usize = consumers
+        .iter()
+        .filter(|consumer| consumer.evict.is_some())
+        .map(|consumer| consumer.usage())
+        .sum();
+    if evictable == 0 {
+        RECLAIMING.store(false, Ordering::Release);
+        return;
+    }
+    let excess = (total - budget) as u128;
+    for consumer in consumers {
+        let Some(evict) = consumer.evict else {
+            continue;
+        };
+        let share = (excess * consumer.usage() as u128).div_ceil(evictable as u128) as usize;
+        if share > 0 {
+            evict(share);
+        }
+    }
+    RECLAIMING.store(false, Ordering::Release);
+}
+
+/// Evicts `percent` of what every cache holds, whatever the budget, returning how many bytes
+/// were freed. Must be called without holding the lock of any cache.
+pub fn shrink(percent: usize) -> usize {
+    let consumers = CONSUMERS.lock().unwrap().clone();
+    let before: usize = consumers.iter().map(|consumer| consumer.usage()).sum();
+    for consumer in consumers.iter() {
+        let Some(evict) = consumer.evict else {
+            continue;
+        };
+        let share = (consumer.usage() as u128 * percent.min(100) as u128).div_ceil(100) as usize;
+        if share > 0 {
+            evict(share);
+        }
+    }
+    let after: usize = consumers.iter().map(|consumer| consumer.usage()).sum();
+    before.saturating_sub(after)
+}
+
+/// Limits the memory the caches of all layers may hold together to `bytes`, evicting right away
+/// if they hold more. The budget is unlimited until this is called.
+#[no_mangle]
+pub extern "C" fn set_memory_budget(bytes: usize) {
+    BUDGET.store(bytes, Ordering::Relaxed);
+    enforce();
+}
+
+/// How much memory each consumer that has charged any holds, by name.
+pub fn usage() -> Vec<(&'static CStr, usize)> {
+    let consumers = CONSUMERS.lock().unwrap();
+    consumers
+        .iter()
+        .map(|consumer| (consumer.name, consumer.usage()))
+        .collect()
+}
+
+/// Fills `usage` with how much memory each consumer holds, up to `max` of them, and returns how
+/// many there are, so a caller can size the array with a first call passing 0. Names stay valid
+/// for the life of the process.
+///
+/// # Safety
+///
+/// This function must be called with room for `max` entries at `usage`, which may be null if
+/// `max` is 0
+#[no_mangle]
+pub unsafe extern "C" fn budget_usage(usage: *mut BudgetUsage, max: usize) -> usize {
+    let consumers = self::usage();
+    for (i, (name, bytes)) in consumers.iter().take(max).enumerate() {
+        ptr::write(
+            usage.add(i),
+            BudgetUsage {
+                name: name.as_ptr(),
+                bytes: *bytes,
+            },
+        );
+    }
+    consumers.len()
+}
+
+/// The budget set with [`set_memory_budget`], or `usize::MAX` if there is none.
+#[no_mangle]
+pub extern "C" fn memory_budget() -> usize {
+    BUDGET.load(Ordering::Relaxed)
+}
+
+/// Prints how much memory each cache holds against the budget.
+#[no_mangle]
+pub extern "C" fn budget_report() {
+    let consumers = CONSUMERS.lock().unwrap();
+    for consumer in consumers.iter() {
+        eprintln!(
+            "{}: {} bytes",
+            consumer.name.to_string_lossy(),
+            consumer.usage()
+        );
+    }
+    let total: usize = consumers.iter().map(|consumer| consumer.usage()).sum();
+    match BUDGET.load(Ordering::Relaxed) {
+        usize::MAX => eprintln!("total: {total} bytes, no budget"),
+        budget => eprintln!("total: {total} of {budget} bytes"),
+    }
+}
@@ -0,0 +1,185 @@
+use crate::fuse::{
+    fuse_bufvec, fuse_config, fuse_conn_info, fuse_file_info, fuse_operations, off_t,
+};
+use std::{
+    ffi::{c_char, c_int, c_uint, c_void},
+    mem::MaybeUninit,
+    ptr,
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut TARGET_LATENCY: Duration = Duration::ZERO;
+static mut MAX_WINDOW: usize = 0;
+static WINDOW: Mutex<Window> = Mutex::new(Window {
+    size: 1.0,
+    running: 0,
+    latency: Duration::ZERO,
+    last_decrease: None,
+});
+static CHANGED: Condvar = Condvar::new();
+
+/// An AIMD window over the data operations in flight against the next layer, like TCP's
+/// congestion window with backend latency standing in for packet loss.
+struct Window {
+    size: f64,
+    running: usize,
+    /// Moving average of recent operation latencies.
+    latency: Duration,
+    last_decrease: Option<Instant>,
+}
+
+/// Runs `op` once the window has room for it and adapts the window to how long it took.
+unsafe fn throttled<T>(op: impl FnOnce() -> T) -> T {
+    {
+        let window = WINDOW.lock().unwrap();
+        let mut window = CHANGED
+            .wait_while(window, |window| {
+                window.running as f64 >= window.size.floor()
+            })
+            .unwrap();
+        window.running += 1;
+    }
+    let start = Instant::now();
+    let res = op();
+    let elapsed = start.elapsed();
+
+    let mut window = WINDOW.lock().unwrap();
+    window.running -= 1;
+    window.latency = (window.latency * 7 + elapsed) / 8;
+    if window.latency > TARGET_LATENCY {
+        // halve at most once per round trip, the window needs that long to drain
+        if window
+            .last_decrease
+            .is_none_or(|last| last.elapsed() > window.latency)
+        {
+            window.size = (window.size / 2.0).max(1.0);
+            window.last_decrease = Some(Instant::now());
+            eprintln!(
+                "backend latency {:?} above {:?}, allowing {} operations in flight",
+                window.latency, TARGET_LATENCY, window.size as usize
+            );
+        }
+    } else {
+        window.size = (window.size + 1.0 / window.size).min(MAX_WINDOW as f64);
+    }
+    drop(window);
+    CHANGED.notify_all();
+    res
+}
+
+unsafe extern "C" fn init(conn: *mut fuse_conn_info, cfg: *mut fuse_config) -> *mut c_void {
+    let res = match NEXT.assume_init_ref().init {
+        Some(init) => init(conn, cfg),
+        None => ptr::null_mut(),
+    };
+    // the kernel stops queueing readahead and writeback for the guest once this many
+    // background requests are waiting, so replies held back by the window push back on it
+    (*conn).max_background = MAX_WINDOW as c_uint;
+    (*conn).congestion_threshold = (MAX_WINDOW * 3 / 4).max(1) as c_uint;
+    res
+}
+
+unsafe extern "C" fn read(
+    arg1: *const c_char,
+    arg2: *mut c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    throttled(|| NEXT.assume_init_ref().read.unwrap()(arg1, arg2, arg3, arg4, arg5))
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    throttled(|| NEXT.assume_init_ref().write.unwrap()(arg1, arg2, arg3, arg4, arg5))
+}
+
+unsafe extern "C" fn fsync(arg1: *const c_char, arg2: c_int, arg3: *mut fuse_file_info) -> c_int {
+    throttled(|| NEXT.assume_init_ref().fsync.unwrap()(arg1, arg2, arg3))
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    throttled(|| NEXT.assume_init_ref().write_buf.unwrap()(arg1, buf, off, arg2))
+}
+
+unsafe extern "C" fn read_buf(
+    arg1: *const c_char,
+    bufp: *mut *mut fuse_bufvec,
+    size: usize,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    throttled(|| NEXT.assume_init_ref().read_buf.unwrap()(arg1, bufp, size, off, arg2))
+}
+
+unsafe extern "C" fn fallocate(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: off_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    throttled(|| NEXT.assume_init_ref().fallocate.unwrap()(arg1, arg2, arg3, arg4, arg5))
+}
+
+unsafe extern "C" fn copy_file_range(
+    path_in: *const c_char,
+    fi_in: *mut fuse_file_info,
+    offset_in: off_t,
+    path_out: *const c_char,
+    fi_out: *mut fuse_file_info,
+    offset_out: off_t,
+    size: usize,
+    flags: c_int,
+) -> isize {
+    throttled(|| {
+        NEXT.assume_init_ref().copy_file_range.unwrap()(
+            path_in, fi_in, offset_in, path_out, fi_out, offset_out, size, flags,
+        )
+    })
+}
+
+/// Adapts how many data operations may be in flight against the next layer to its latency:
+/// the window grows by one operation per round trip while the average latency stays below
+/// `target_latency_us` and halves when it climbs above, up to `max_window`. Operations over
+/// the window wait for a slot, and since `max_background` is advertised as `max_window`, the
+/// guest's kernel sees the delayed replies and throttles its own readahead and writeback.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer
+#[no_mangle]
+pub unsafe extern "C" fn new_congestion_layer(
+    next: *const fuse_operations,
+    target_latency_us: c_uint,
+    max_window: c_uint,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    TARGET_LATENCY = Duration::from_micros(target_latency_us as u64);
+    MAX_WINDOW = (max_window as usize).max(1);
+    WINDOW.lock().unwrap().size = MAX_WINDOW as f64;
+    Box::into_raw(Box::new(fuse_operations {
+        read: next.read.and(Some(read)),
+        write: next.write.and(Some(write)),
+        fsync: next.fsync.and(Some(fsync)),
+        init: Some(init),
+        write_buf: next.write_buf.and(Some(write_buf)),
+        read_buf: next.read_buf.and(Some(read_buf)),
+        fallocate: next.fallocate.and(Some(fallocate)),
+        copy_file_range: next.copy_file_range.and(Some(copy_file_range)),
+        ..next
+    }))
+}
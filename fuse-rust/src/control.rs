@@ -0,0 +1,83 @@
+use std::{
+    ffi::{c_char, CStr, OsStr},
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::{ffi::OsStrExt, net::UnixListener},
+    path::PathBuf,
+    sync::Mutex,
+    thread,
+};
+
+/// A unix socket a layer serves line commands on, bound when the mount is initialized and
+/// removed when it is destroyed.
+pub struct Control {
+    socket: Mutex<Option<PathBuf>>,
+}
+
+impl Control {
+    pub const fn new() -> Self {
+        Control {
+            socket: Mutex::new(None),
+        }
+    }
+
+    /// Serves commands on the socket at `path` once started, or nowhere if it is null.
+    ///
+    /// # Safety
+    ///
+    /// This function must be called with a valid C string or null path
+    pub unsafe fn set(&self, path: *const c_char) {
+        *self.socket.lock().unwrap() = (!path.is_null())
+            .then(|| PathBuf::from(OsStr::from_bytes(CStr::from_ptr(path).to_bytes())));
+    }
+
+    /// Binds the socket and answers every line received with what `command` returns for it,
+    /// which should end with a newline. Each client gets a thread of its own, so a command
+    /// that takes a while does not hold up the others.
+    pub fn start(&self, command: fn(&[u8]) -> String) {
+        let Some(socket) = self.socket.lock().unwrap().clone() else {
+            return;
+        };
+        // a socket left behind by a previous run would make the bind fail
+        let _ = fs::remove_file(&socket);
+        match UnixListener::bind(&socket) {
+            Ok(listener) => {
+                thread::spawn(move || serve(listener, command));
+            }
+            Err(err) => eprintln!("failed to bind control socket {}: {err}", socket.display()),
+        }
+    }
+
+    pub fn stop(&self) {
+        if let Some(socket) = self.socket.lock().unwrap().as_ref() {
+            let _ = fs::remove_file(socket);
+        }
+    }
+}
+
+impl Default for Control {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn serve(listener: UnixListener, command: fn(&[u8]) -> String) {
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else {
+            continue;
+        };
+        let Ok(reader) = stream.try_clone().map(BufReader::new) else {
+            continue;
+        };
+        thread::spawn(move || {
+            for line in reader.split(b'\n') {
+                let Ok(line) = line else {
+                    break;
+                };
+                if stream.write_all(command(&line).as_bytes()).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
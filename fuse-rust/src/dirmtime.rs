@@ -0,0 +1,130 @@
+use crate::fuse::{
+    dev_t, fuse_file_info, fuse_operations, mode_t, timespec, UTIME_NOW, UTIME_OMIT,
+};
+use std::{
+    ffi::{c_char, c_int, c_uint, CStr, CString},
+    mem::MaybeUninit,
+    ptr,
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+
+/// Sets the mtime of the directory holding `path` to now, after an entry of it changed.
+unsafe fn touch_parent(path: *const c_char) {
+    if path.is_null() {
+        return;
+    }
+    let path = CStr::from_ptr(path).to_bytes();
+    let dir = match path.iter().rposition(|&c| c == b'/') {
+        Some(0) | None => CString::new("/").unwrap(),
+        Some(slash) => CString::new(&path[..slash]).unwrap(),
+    };
+    let times = [
+        timespec {
+            tv_sec: 0,
+            tv_nsec: UTIME_OMIT as _,
+        },
+        timespec {
+            tv_sec: 0,
+            tv_nsec: UTIME_NOW as _,
+        },
+    ];
+    // the change itself went through, so a backend refusing this is not reported
+    NEXT.assume_init_ref().utimens.unwrap()(dir.as_ptr(), times.as_ptr(), ptr::null_mut());
+}
+
+unsafe extern "C" fn mknod(arg1: *const c_char, arg2: mode_t, arg3: dev_t) -> c_int {
+    let res = NEXT.assume_init_ref().mknod.unwrap()(arg1, arg2, arg3);
+    if res == 0 {
+        touch_parent(arg1);
+    }
+    res
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    let res = NEXT.assume_init_ref().mkdir.unwrap()(arg1, arg2);
+    if res == 0 {
+        touch_parent(arg1);
+    }
+    res
+}
+
+unsafe extern "C" fn unlink(arg1: *const c_char) -> c_int {
+    let res = NEXT.assume_init_ref().unlink.unwrap()(arg1);
+    if res == 0 {
+        touch_parent(arg1);
+    }
+    res
+}
+
+unsafe extern "C" fn rmdir(arg1: *const c_char) -> c_int {
+    let res = NEXT.assume_init_ref().rmdir.unwrap()(arg1);
+    if res == 0 {
+        touch_parent(arg1);
+    }
+    res
+}
+
+unsafe extern "C" fn symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let res = NEXT.assume_init_ref().symlink.unwrap()(arg1, arg2);
+    if res == 0 {
+        touch_parent(arg2);
+    }
+    res
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    let res = NEXT.assume_init_ref().rename.unwrap()(arg1, arg2, flags);
+    if res == 0 {
+        touch_parent(arg1);
+        touch_parent(arg2);
+    }
+    res
+}
+
+unsafe extern "C" fn link(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let res = NEXT.assume_init_ref().link.unwrap()(arg1, arg2);
+    if res == 0 {
+        touch_parent(arg2);
+    }
+    res
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    let res = NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3);
+    if res == 0 {
+        touch_parent(arg1);
+    }
+    res
+}
+
+/// Sets the mtime of a directory to the current time whenever an entry is created, removed or
+/// renamed in it through the mount, for backends like some network filesystems that are slow
+/// to do so, which breaks tools like make and rsync deciding what changed by directory mtimes.
+/// The kernel already drops the attributes it cached for the directory after these operations,
+/// so it picks up the new mtime. Does nothing if the next layer cannot set times.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer
+#[no_mangle]
+pub unsafe extern "C" fn new_dirmtime_layer(
+    next: *const fuse_operations,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    if next.utimens.is_none() {
+        return Box::into_raw(Box::new(next));
+    }
+    Box::into_raw(Box::new(fuse_operations {
+        mknod: next.mknod.and(Some(mknod)),
+        mkdir: next.mkdir.and(Some(mkdir)),
+        unlink: next.unlink.and(Some(unlink)),
+        rmdir: next.rmdir.and(Some(rmdir)),
+        symlink: next.symlink.and(Some(symlink)),
+        rename: next.rename.and(Some(rename)),
+        link: next.link.and(Some(link)),
+        create: next.create.and(Some(create)),
+        ..next
+    }))
+}
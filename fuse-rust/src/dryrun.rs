@@ -0,0 +1,689 @@
+use crate::{
+    fuse::{
+        dev_t, fuse_buf_size, fuse_bufvec, fuse_file_info, fuse_fill_dir_flags, fuse_fill_dir_t,
+        fuse_get_context, fuse_operations, fuse_readdir_flags, gid_t, mode_t, off_t, stat,
+        timespec, uid_t, EEXIST, EISDIR, ENOENT, ENOTDIR, ENOTEMPTY, ENOTTY, EROFS, O_ACCMODE,
+        O_RDONLY, O_TRUNC, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG,
+    },
+    rmtree,
+};
+use std::{
+    collections::BTreeMap,
+    ffi::{c_char, c_int, c_uint, c_void, CStr, CString, OsStr},
+    fs::{File, OpenOptions},
+    io::Write,
+    mem::MaybeUninit,
+    os::unix::ffi::OsStrExt,
+    ptr,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+/// Handle given out for files that only exist in the dry run. The inject layer keeps its own
+/// handles below it.
+const DRY_FH: u64 = u64::MAX;
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut POLICY: DryRunPolicy = DryRunPolicy::Acknowledge;
+/// What the mount would look like by now where it differs from the next layer: the attributes
+/// of paths created, or `None` for paths removed.
+static CHANGES: Mutex<BTreeMap<Vec<u8>, Option<stat>>> = Mutex::new(BTreeMap::new());
+/// Where mutations are logged, or stderr if None.
+static AUDIT: Mutex<Option<File>> = Mutex::new(None);
+
+/// How mutating operations are answered once validated and logged.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DryRunPolicy {
+    /// Report success, as if the change had been made.
+    Acknowledge,
+    /// Fail with EROFS.
+    Refuse,
+}
+
+fn bytes<'a>(path: *const c_char) -> &'a [u8] {
+    match path.is_null() {
+        true => b"",
+        false => unsafe { CStr::from_ptr(path).to_bytes() },
+    }
+}
+
+fn parent(path: &[u8]) -> &[u8] {
+    match path.iter().rposition(|&c| c == b'/') {
+        Some(0) | None => b"/",
+        Some(slash) => &path[..slash],
+    }
+}
+
+fn join(dir: &[u8], name: &[u8]) -> Vec<u8> {
+    let mut path = dir.to_vec();
+    if path != b"/" {
+        path.push(b'/');
+    }
+    path.extend_from_slice(name);
+    path
+}
+
+/// Entries created in the dry run right in `dir`, by name.
+fn created_in(dir: &[u8]) -> Vec<(CString, stat)> {
+    CHANGES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(path, _)| path.as_slice() != dir && parent(path) == dir)
+        .filter_map(|(path, change)| {
+            let name = &path[path.iter().rposition(|&c| c == b'/')? + 1..];
+            Some((CString::new(name).ok()?, (*change)?))
+        })
+        .collect()
+}
+
+unsafe extern "C" fn collect(
+    buf: *mut c_void,
+    name: *const c_char,
+    _stbuf: *const stat,
+    _off: off_t,
+    _flags: fuse_fill_dir_flags,
+) -> c_int {
+    let names = &mut *buf.cast::<Vec<CString>>();
+    names.push(CStr::from_ptr(name).to_owned());
+    0
+}
+
+/// Whether the directory `path` has any entries as the dry run has it.
+unsafe fn has_entries(path: &[u8]) -> Result<bool, c_int> {
+    if !created_in(path).is_empty() {
+        return Ok(true);
+    }
+    // created in the dry run, so nothing of the next layer's is in there
+    if CHANGES.lock().unwrap().contains_key(path) {
+        return Ok(false);
+    }
+    let next = NEXT.assume_init_ref();
+    let Some(readdir) = next.readdir else {
+        return Ok(false);
+    };
+    let cpath = [path, b"\0"].concat();
+    let mut fi = MaybeUninit::<fuse_file_info>::zeroed().assume_init();
+    fi.flags = O_RDONLY as c_int;
+    if let Some(opendir) = next.opendir {
+        let res = opendir(cpath.as_ptr().cast(), &mut fi);
+        if res != 0 {
+            return Err(res);
+        }
+    }
+    let mut names: Vec<CString> = Vec::new();
+    let res = readdir(
+        cpath.as_ptr().cast(),
+        (&mut names as *mut Vec<CString>).cast(),
+        Some(collect),
+        0,
+        &mut fi,
+        0,
+    );
+    if let Some(releasedir) = next.releasedir {
+        releasedir(cpath.as_ptr().cast(), &mut fi);
+    }
+    if res != 0 {
+        return Err(res);
+    }
+    // the ones the dry run has in CHANGES are either removed or created, and those are counted
+    // above already
+    let changes = CHANGES.lock().unwrap();
+    Ok(names
+        .iter()
+        .filter(|name| !matches!(name.to_bytes(), b"." | b".."))
+        .any(|name| !changes.contains_key(&join(path, name.to_bytes()))))
+}
+
+/// Attributes of `path` as the dry run has it.
+unsafe fn lookup(path: &[u8]) -> Result<stat, c_int> {
+    if let Some(change) = CHANGES.lock().unwrap().get(path) {
+        return change.ok_or(-(ENOENT as c_int));
+    }
+    let path = [path, b"\0"].concat();
+    let mut st = MaybeUninit::<stat>::zeroed();
+    match NEXT.assume_init_ref().getattr.unwrap()(
+        path.as_ptr().cast(),
+        st.as_mut_ptr(),
+        ptr::null_mut(),
+    ) {
+        0 => Ok(st.assume_init()),
+        res => Err(res),
+    }
+}
+
+/// Checks that `path` could be created: its parent is a directory and it does not exist yet.
+unsafe fn check_new(path: &[u8]) -> Result<(), c_int> {
+    match lookup(parent(path)) {
+        Ok(st) if st.st_mode & S_IFMT == S_IFDIR => (),
+        Ok(_) => return Err(-(ENOTDIR as c_int)),
+        Err(res) => return Err(res),
+    }
+    match lookup(path) {
+        Ok(_) => Err(-(EEXIST as c_int)),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Logs a validated mutation and answers it according to the policy.
+unsafe fn mutation(op: &str, path: *const c_char, target: Option<*const c_char>) -> c_int {
+    let line = match target {
+        Some(target) => format!(
+            "dry run: {op} {} {}",
+            String::from_utf8_lossy(bytes(path)),
+            String::from_utf8_lossy(bytes(target))
+        ),
+        None => format!("dry run: {op} {}", String::from_utf8_lossy(bytes(path))),
+    };
+    match AUDIT.lock().unwrap().as_mut() {
+        Some(audit) => {
+            if let Err(err) = writeln!(audit, "{line}") {
+                eprintln!("failed to write dry run audit log: {err}");
+            }
+        }
+        None => eprintln!("{line}"),
+    }
+    match POLICY {
+        DryRunPolicy::Acknowledge => 0,
+        DryRunPolicy::Refuse => -(EROFS as c_int),
+    }
+}
+
+/// Logs a change to the existing `path`, failing like the next layer would if it does not.
+unsafe fn modify(op: &str, path: *const c_char) -> c_int {
+    if let Err(res) = lookup(bytes(path)) {
+        return res;
+    }
+    mutation(op, path, None)
+}
+
+/// Credentials of the caller, or root for requests layers above make on their own.
+unsafe fn caller() -> (uid_t, gid_t) {
+    fuse_get_context()
+        .as_ref()
+        .map_or((0, 0), |context| (context.uid, context.gid))
+}
+
+/// Logs the creation of `path` with `mode`, which later lookups see if acknowledged.
+unsafe fn create_entry(op: &str, path: *const c_char, mode: mode_t) -> c_int {
+    if let Err(res) = check_new(bytes(path)) {
+        return res;
+    }
+    let res = mutation(op, path, None);
+    if res == 0 {
+        let (uid, gid) = caller();
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut st = MaybeUninit::<stat>::zeroed().assume_init();
+        st.st_mode = mode;
+        st.st_nlink = if mode & S_IFMT == S_IFDIR { 2 } else { 1 };
+        st.st_uid = uid;
+        st.st_gid = gid;
+        st.st_atim.tv_sec = now.as_secs() as _;
+        st.st_mtim = st.st_atim;
+        st.st_ctim = st.st_atim;
+        CHANGES
+            .lock()
+            .unwrap()
+            .insert(bytes(path).to_vec(), Some(st));
+    }
+    res
+}
+
+unsafe extern "C" fn getattr(
+    arg1: *const c_char,
+    arg2: *mut stat,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    if !arg1.is_null() && (fi.is_null() || (*fi).fh == DRY_FH) {
+        return match lookup(bytes(arg1)) {
+            Ok(st) => {
+                *arg2 = st;
+                0
+            }
+            Err(res) => res,
+        };
+    }
+    NEXT.assume_init_ref().getattr.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn mknod(arg1: *const c_char, arg2: mode_t, _arg3: dev_t) -> c_int {
+    create_entry("mknod", arg1, arg2)
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    create_entry("mkdir", arg1, S_IFDIR | arg2)
+}
+
+unsafe extern "C" fn unlink(arg1: *const c_char) -> c_int {
+    match lookup(bytes(arg1)) {
+        Ok(st) if st.st_mode & S_IFMT == S_IFDIR => return -(EISDIR as c_int),
+        Ok(_) => (),
+        Err(res) => return res,
+    }
+    let res = mutation("unlink", arg1, None);
+    if res == 0 {
+        CHANGES.lock().unwrap().insert(bytes(arg1).to_vec(), None);
+    }
+    res
+}
+
+unsafe extern "C" fn rmdir(arg1: *const c_char) -> c_int {
+    match lookup(bytes(arg1)) {
+        Ok(st) if st.st_mode & S_IFMT != S_IFDIR => return -(ENOTDIR as c_int),
+        Ok(_) => (),
+        Err(res) => return res,
+    }
+    match has_entries(bytes(arg1)) {
+        Ok(true) => return -(ENOTEMPTY as c_int),
+        Ok(false) => (),
+        Err(res) => return res,
+    }
+    let res = mutation("rmdir", arg1, None);
+    if res == 0 {
+        CHANGES.lock().unwrap().insert(bytes(arg1).to_vec(), None);
+    }
+    res
+}
+
+unsafe extern "C" fn symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    if let Err(res) = check_new(bytes(arg2)) {
+        return res;
+    }
+    let res = mutation("symlink", arg2, Some(arg1));
+    if res == 0 {
+        let mut st = MaybeUninit::<stat>::zeroed().assume_init();
+        st.st_mode = S_IFLNK | 0o777;
+        st.st_nlink = 1;
+        st.st_size = bytes(arg1).len() as _;
+        (st.st_uid, st.st_gid) = caller();
+        CHANGES
+            .lock()
+            .unwrap()
+            .insert(bytes(arg2).to_vec(), Some(st));
+    }
+    res
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, _flags: c_uint) -> c_int {
+    let st = match lookup(bytes(arg1)) {
+        Ok(st) => st,
+        Err(res) => return res,
+    };
+    let res = mutation("rename", arg1, Some(arg2));
+    if res == 0 {
+        let mut changes = CHANGES.lock().unwrap();
+        changes.insert(bytes(arg1).to_vec(), None);
+        changes.insert(bytes(arg2).to_vec(), Some(st));
+    }
+    res
+}
+
+unsafe extern "C" fn link(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let st = match lookup(bytes(arg1)) {
+        Ok(st) => st,
+        Err(res) => return res,
+    };
+    if let Err(res) = check_new(bytes(arg2)) {
+        return res;
+    }
+    let res = mutation("link", arg1, Some(arg2));
+    if res == 0 {
+        CHANGES
+            .lock()
+            .unwrap()
+            .insert(bytes(arg2).to_vec(), Some(st));
+    }
+    res
+}
+
+unsafe extern "C" fn chmod(arg1: *const c_char, _arg2: mode_t, _fi: *mut fuse_file_info) -> c_int {
+    modify("chmod", arg1)
+}
+
+unsafe extern "C" fn chown(
+    arg1: *const c_char,
+    _arg2: uid_t,
+    _arg3: gid_t,
+    _fi: *mut fuse_file_info,
+) -> c_int {
+    modify("chown", arg1)
+}
+
+unsafe extern "C" fn truncate(
+    arg1: *const c_char,
+    _arg2: off_t,
+    _fi: *mut fuse_file_info,
+) -> c_int {
+    modify("truncate", arg1)
+}
+
+unsafe extern "C" fn utimens(
+    arg1: *const c_char,
+    _tv: *const timespec,
+    _fi: *mut fuse_file_info,
+) -> c_int {
+    modify("utimens", arg1)
+}
+
+unsafe extern "C" fn setxattr(
+    arg1: *const c_char,
+    _arg2: *const c_char,
+    _arg3: *const c_char,
+    _arg4: usize,
+    _arg5: c_int,
+) -> c_int {
+    modify("setxattr", arg1)
+}
+
+unsafe extern "C" fn removexattr(arg1: *const c_char, _arg2: *const c_char) -> c_int {
+    modify("removexattr", arg1)
+}
+
+unsafe extern "C" fn fallocate(
+    arg1: *const c_char,
+    _arg2: c_int,
+    _arg3: off_t,
+    _arg4: off_t,
+    _arg5: *mut fuse_file_info,
+) -> c_int {
+    mutation("fallocate", arg1, None)
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    let res = create_entry("create", arg1, S_IFREG | arg2);
+    if res == 0 {
+        (*arg3).fh = DRY_FH;
+    }
+    res
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let flags = (*arg2).flags;
+    let writing = flags & O_ACCMODE as c_int != O_RDONLY as c_int || flags & O_TRUNC as c_int != 0;
+    if !writing {
+        return NEXT.assume_init_ref().open.unwrap()(arg1, arg2);
+    }
+    if let Err(res) = lookup(bytes(arg1)) {
+        return res;
+    }
+    let res = mutation("open for writing", arg1, None);
+    if res != 0 {
+        return res;
+    }
+    if CHANGES.lock().unwrap().contains_key(bytes(arg1)) {
+        (*arg2).fh = DRY_FH;
+        return 0;
+    }
+    // reads see the file as it is, writes are answered here
+    (*arg2).flags = flags & !(O_ACCMODE | O_TRUNC) as c_int;
+    let res = NEXT.assume_init_ref().open.unwrap()(arg1, arg2);
+    (*arg2).flags = flags;
+    res
+}
+
+unsafe extern "C" fn read(
+    arg1: *const c_char,
+    arg2: *mut c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    if !arg5.is_null() && (*arg5).fh == DRY_FH {
+        return 0;
+    }
+    NEXT.assume_init_ref().read.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    _arg2: *const c_char,
+    arg3: usize,
+    _arg4: off_t,
+    _arg5: *mut fuse_file_info,
+) -> c_int {
+    match mutation("write", arg1, None) {
+        0 => arg3 as c_int,
+        res => res,
+    }
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    _off: off_t,
+    _arg2: *mut fuse_file_info,
+) -> c_int {
+    match mutation("write", arg1, None) {
+        0 => fuse_buf_size(buf) as c_int,
+        res => res,
+    }
+}
+
+unsafe extern "C" fn opendir(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    match CHANGES.lock().unwrap().get(bytes(arg1)) {
+        Some(Some(st)) if st.st_mode & S_IFMT == S_IFDIR => {
+            (*arg2).fh = DRY_FH;
+            return 0;
+        }
+        Some(Some(_)) => return -(ENOTDIR as c_int),
+        Some(None) => return -(ENOENT as c_int),
+        None => (),
+    }
+    match NEXT.assume_init_ref().opendir {
+        Some(opendir) => opendir(arg1, arg2),
+        None => 0,
+    }
+}
+
+/// What the readdir filler of the next layer needs, smuggled through its `buf` argument.
+struct Filler {
+    buf: *mut c_void,
+    filler: fuse_fill_dir_t,
+    dir: Vec<u8>,
+}
+
+unsafe extern "C" fn fill(
+    buf: *mut c_void,
+    name: *const c_char,
+    stbuf: *const stat,
+    off: off_t,
+    flags: fuse_fill_dir_flags,
+) -> c_int {
+    let filler = &*buf.cast::<Filler>();
+    let name = CStr::from_ptr(name);
+    // entries the dry run removed are left out, and those it created are listed afterwards
+    if !matches!(name.to_bytes(), b"." | b"..")
+        && CHANGES
+            .lock()
+            .unwrap()
+            .contains_key(&join(&filler.dir, name.to_bytes()))
+    {
+        return 0;
+    }
+    filler.filler.unwrap()(filler.buf, name.as_ptr(), stbuf, off, flags)
+}
+
+unsafe extern "C" fn readdir(
+    arg1: *const c_char,
+    arg2: *mut c_void,
+    arg3: fuse_fill_dir_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+    arg6: fuse_readdir_flags,
+) -> c_int {
+    if arg1.is_null() {
+        return NEXT.assume_init_ref().readdir.unwrap()(arg1, arg2, arg3, arg4, arg5, arg6);
+    }
+    if (*arg5).fh == DRY_FH {
+        for name in [c".", c".."] {
+            arg3.unwrap()(arg2, name.as_ptr(), ptr::null(), 0, 0);
+        }
+    } else {
+        let mut filler = Filler {
+            buf: arg2,
+            filler: arg3,
+            dir: bytes(arg1).to_vec(),
+        };
+        let res = NEXT.assume_init_ref().readdir.unwrap()(
+            arg1,
+            ptr::addr_of_mut!(filler).cast(),
+            Some(fill),
+            arg4,
+            arg5,
+            arg6,
+        );
+        if res != 0 {
+            return res;
+        }
+    }
+    // the whole listing goes in one call unless the next layer hands out offsets
+    if arg4 == 0 {
+        for (name, st) in created_in(bytes(arg1)) {
+            if arg3.unwrap()(arg2, name.as_ptr(), &st, 0, 0) != 0 {
+                break;
+            }
+        }
+    }
+    0
+}
+
+unsafe extern "C" fn releasedir(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    if (*arg2).fh == DRY_FH {
+        return 0;
+    }
+    match NEXT.assume_init_ref().releasedir {
+        Some(releasedir) => releasedir(arg1, arg2),
+        None => 0,
+    }
+}
+
+unsafe extern "C" fn flush(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    if (*arg2).fh == DRY_FH {
+        return 0;
+    }
+    NEXT.assume_init_ref().flush.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn release(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    if (*arg2).fh == DRY_FH {
+        return 0;
+    }
+    NEXT.assume_init_ref().release.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn fsync(arg1: *const c_char, arg2: c_int, arg3: *mut fuse_file_info) -> c_int {
+    if (*arg3).fh == DRY_FH {
+        return 0;
+    }
+    NEXT.assume_init_ref().fsync.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn copy_file_range(
+    _path_in: *const c_char,
+    _fi_in: *mut fuse_file_info,
+    _offset_in: off_t,
+    path_out: *const c_char,
+    _fi_out: *mut fuse_file_info,
+    _offset_out: off_t,
+    size: usize,
+    _flags: c_int,
+) -> isize {
+    match mutation("copy_file_range", path_out, None) {
+        0 => size as isize,
+        res => res as isize,
+    }
+}
+
+unsafe extern "C" fn ioctl(
+    arg1: *const c_char,
+    cmd: c_int,
+    arg: *mut c_void,
+    arg2: *mut fuse_file_info,
+    flags: c_uint,
+    data: *mut c_void,
+) -> c_int {
+    if !rmtree::is_rmtree(cmd) {
+        return NEXT.assume_init_ref().ioctl.unwrap()(arg1, cmd, arg, arg2, flags, data);
+    }
+    match POLICY {
+        // the guest then removes the tree entry by entry, which the dry run can follow
+        DryRunPolicy::Acknowledge => -(ENOTTY as c_int),
+        DryRunPolicy::Refuse => mutation("rmtree", arg1, None),
+    }
+}
+
+/// Runs the mount without changing the backing store, to profile what a container would write
+/// before granting it a writable volume. Mutating operations are checked against the tree as
+/// far as the next layer's attributes allow, logged as `dry run: <op> <path> [<target>]` to
+/// `audit_path`, which is appended to, or to stderr if it is null, and answered according to
+/// `policy`. When acknowledged, entries created, removed or renamed are remembered so lookups
+/// and directory listings agree with what the guest was told, though file contents stay those
+/// of the next layer, files created read back empty, directories created or renamed list only
+/// what was created in them since, and handles of such files cannot be locked. Listings of the
+/// next layer that hand out offsets, unlike the passthrough's, do not show created entries. The passthrough's `FSINTERPOSER_IOC_RMTREE` fails with `ENOTTY`
+/// when acknowledging, so the guest falls back to removing the tree entry by entry.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer, and `audit_path` must be null or
+/// a valid C string
+#[no_mangle]
+pub unsafe extern "C" fn new_dryrun_layer(
+    next: *const fuse_operations,
+    policy: DryRunPolicy,
+    audit_path: *const c_char,
+) -> *const fuse_operations {
+    if !audit_path.is_null() {
+        let audit_path = OsStr::from_bytes(CStr::from_ptr(audit_path).to_bytes());
+        match OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(audit_path)
+        {
+            Ok(audit) => *AUDIT.lock().unwrap() = Some(audit),
+            Err(err) => {
+                eprintln!("failed to open dry run audit log {audit_path:?}: {err}");
+                return ptr::null();
+            }
+        }
+    }
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    POLICY = policy;
+    Box::into_raw(Box::new(fuse_operations {
+        getattr: next.getattr.and(Some(getattr)),
+        mknod: Some(mknod),
+        mkdir: Some(mkdir),
+        unlink: Some(unlink),
+        rmdir: Some(rmdir),
+        symlink: Some(symlink),
+        rename: Some(rename),
+        link: Some(link),
+        chmod: Some(chmod),
+        chown: Some(chown),
+        truncate: Some(truncate),
+        open: next.open.and(Some(open)),
+        read: next.read.and(Some(read)),
+        write: Some(write),
+        flush: next.flush.and(Some(flush)),
+        release: next.release.and(Some(release)),
+        fsync: next.fsync.and(Some(fsync)),
+        opendir: Some(opendir),
+        readdir: next.readdir.and(Some(readdir)),
+        releasedir: Some(releasedir),
+        setxattr: Some(setxattr),
+        removexattr: Some(removexattr),
+        create: Some(create),
+        utimens: Some(utimens),
+        write_buf: Some(write_buf),
+        // read_buf would hand handles of files created in the dry run to the next layer
+        read_buf: None,
+        fallocate: Some(fallocate),
+        copy_file_range: Some(copy_file_range),
+        ioctl: next.ioctl.and(Some(ioctl)),
+        ..next
+    }))
+}
@@ -0,0 +1,470 @@
+use crate::fuse::{
+    dev_t, flock, fuse_bufvec, fuse_file_info, fuse_fill_dir_t, fuse_operations, fuse_pollhandle,
+    fuse_readdir_flags, gid_t, mode_t, off_t, stat, statvfs, timespec, uid_t, EACCES, EAGAIN,
+    EBADF, EBADMSG, EBUSY, ECOMM, ECONNRESET, EDQUOT, EEXIST, EFBIG, EHOSTDOWN, EILSEQ, EINTR,
+    EINVAL, EIO, EISDIR, ELOOP, EMFILE, EMLINK, ENAMETOOLONG, ENFILE, ENODATA, ENODEV, ENOENT,
+    ENOLINK, ENOMEM, ENOSPC, ENOSYS, ENOTBLK, ENOTCONN, ENOTDIR, ENOTEMPTY, ENOTSUP, ENXIO,
+    EOPNOTSUPP, EOVERFLOW, EPERM, EPROTO, ERANGE, EREMOTEIO, EROFS, ESPIPE, ESTALE, ETIMEDOUT,
+    ETXTBSY, EUCLEAN, EXDEV,
+};
+use std::{
+    collections::BTreeMap,
+    ffi::{c_char, c_int, c_uint, c_void, CStr},
+    mem::MaybeUninit,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Names accepted in a translation map besides plain numbers.
+const ERRNOS: [(&str, u32); 47] = [
+    ("EPERM", EPERM),
+    ("ENOENT", ENOENT),
+    ("EINTR", EINTR),
+    ("EIO", EIO),
+    ("ENXIO", ENXIO),
+    ("EBADF", EBADF),
+    ("EAGAIN", EAGAIN),
+    ("ENOMEM", ENOMEM),
+    ("EACCES", EACCES),
+    ("EBUSY", EBUSY),
+    ("EEXIST", EEXIST),
+    ("EXDEV", EXDEV),
+    ("ENODEV", ENODEV),
+    ("ENOTDIR", ENOTDIR),
+    ("EISDIR", EISDIR),
+    ("EINVAL", EINVAL),
+    ("ENFILE", ENFILE),
+    ("EMFILE", EMFILE),
+    ("ETXTBSY", ETXTBSY),
+    ("EFBIG", EFBIG),
+    ("ENOSPC", ENOSPC),
+    ("ESPIPE", ESPIPE),
+    ("EROFS", EROFS),
+    ("EMLINK", EMLINK),
+    ("ERANGE", ERANGE),
+    ("ENAMETOOLONG", ENAMETOOLONG),
+    ("ENOSYS", ENOSYS),
+    ("ENOTEMPTY", ENOTEMPTY),
+    ("ELOOP", ELOOP),
+    ("ENODATA", ENODATA),
+    ("ENOLINK", ENOLINK),
+    ("ECOMM", ECOMM),
+    ("EPROTO", EPROTO),
+    ("EBADMSG", EBADMSG),
+    ("EOVERFLOW", EOVERFLOW),
+    ("EILSEQ", EILSEQ),
+    ("EOPNOTSUPP", EOPNOTSUPP),
+    ("ENOTSUP", ENOTSUP),
+    ("ECONNRESET", ECONNRESET),
+    ("ENOTCONN", ENOTCONN),
+    ("ETIMEDOUT", ETIMEDOUT),
+    ("EHOSTDOWN", EHOSTDOWN),
+    ("ESTALE", ESTALE),
+    ("EUCLEAN", EUCLEAN),
+    ("EREMOTEIO", EREMOTEIO),
+    ("EDQUOT", EDQUOT),
+    ("ENOTBLK", ENOTBLK),
+];
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+/// Errno to report instead of each translated one, and how often that happened.
+static mut TRANSLATIONS: BTreeMap<c_int, (c_int, AtomicU64)> = BTreeMap::new();
+
+fn parse_errno(name: &str) -> Option<c_int> {
+    match ERRNOS.iter().find(|(known, _)| *known == name) {
+        Some(&(_, errno)) => Some(errno as c_int),
+        None => name.parse().ok().filter(|&errno| errno > 0),
+    }
+}
+
+fn errno_name(errno: c_int) -> String {
+    match ERRNOS.iter().find(|&&(_, known)| known as c_int == errno) {
+        Some((name, _)) => name.to_string(),
+        None => errno.to_string(),
+    }
+}
+
+unsafe fn translate(res: c_int) -> c_int {
+    match TRANSLATIONS.get(&-res) {
+        Some((to, count)) if res < 0 => {
+            count.fetch_add(1, Ordering::Relaxed);
+            -to
+        }
+        _ => res,
+    }
+}
+
+/// Like [`translate`], for operations returning sizes or offsets.
+unsafe fn translate_wide(res: i64) -> i64 {
+    match res {
+        res if res < 0 => translate(res as c_int) as i64,
+        res => res,
+    }
+}
+
+unsafe extern "C" fn getattr(
+    arg1: *const c_char,
+    arg2: *mut stat,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    translate(NEXT.assume_init_ref().getattr.unwrap()(arg1, arg2, fi))
+}
+
+unsafe extern "C" fn readlink(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    translate(NEXT.assume_init_ref().readlink.unwrap()(arg1, arg2, arg3))
+}
+
+unsafe extern "C" fn mknod(arg1: *const c_char, arg2: mode_t, arg3: dev_t) -> c_int {
+    translate(NEXT.assume_init_ref().mknod.unwrap()(arg1, arg2, arg3))
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    translate(NEXT.assume_init_ref().mkdir.unwrap()(arg1, arg2))
+}
+
+unsafe extern "C" fn unlink(arg1: *const c_char) -> c_int {
+    translate(NEXT.assume_init_ref().unlink.unwrap()(arg1))
+}
+
+unsafe extern "C" fn rmdir(arg1: *const c_char) -> c_int {
+    translate(NEXT.assume_init_ref().rmdir.unwrap()(arg1))
+}
+
+unsafe extern "C" fn symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    translate(NEXT.assume_init_ref().symlink.unwrap()(arg1, arg2))
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    translate(NEXT.assume_init_ref().rename.unwrap()(arg1, arg2, flags))
+}
+
+unsafe extern "C" fn link(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    translate(NEXT.assume_init_ref().link.unwrap()(arg1, arg2))
+}
+
+unsafe extern "C" fn chmod(arg1: *const c_char, arg2: mode_t, fi: *mut fuse_file_info) -> c_int {
+    translate(NEXT.assume_init_ref().chmod.unwrap()(arg1, arg2, fi))
+}
+
+unsafe extern "C" fn chown(
+    arg1: *const c_char,
+    arg2: uid_t,
+    arg3: gid_t,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    translate(NEXT.assume_init_ref().chown.unwrap()(arg1, arg2, arg3, fi))
+}
+
+unsafe extern "C" fn truncate(arg1: *const c_char, arg2: off_t, fi: *mut fuse_file_info) -> c_int {
+    translate(NEXT.assume_init_ref().truncate.unwrap()(arg1, arg2, fi))
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    translate(NEXT.assume_init_ref().open.unwrap()(arg1, arg2))
+}
+
+unsafe extern "C" fn read(
+    arg1: *const c_char,
+    arg2: *mut c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    translate(NEXT.assume_init_ref().read.unwrap()(
+        arg1, arg2, arg3, arg4, arg5,
+    ))
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    translate(NEXT.assume_init_ref().write.unwrap()(
+        arg1, arg2, arg3, arg4, arg5,
+    ))
+}
+
+unsafe extern "C" fn statfs(arg1: *const c_char, arg2: *mut statvfs) -> c_int {
+    translate(NEXT.assume_init_ref().statfs.unwrap()(arg1, arg2))
+}
+
+unsafe extern "C" fn flush(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    translate(NEXT.assume_init_ref().flush.unwrap()(arg1, arg2))
+}
+
+unsafe extern "C" fn release(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    translate(NEXT.assume_init_ref().release.unwrap()(arg1, arg2))
+}
+
+unsafe extern "C" fn fsync(arg1: *const c_char, arg2: c_int, arg3: *mut fuse_file_info) -> c_int {
+    translate(NEXT.assume_init_ref().fsync.unwrap()(arg1, arg2, arg3))
+}
+
+unsafe extern "C" fn setxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *const c_char,
+    arg4: usize,
+    arg5: c_int,
+) -> c_int {
+    translate(NEXT.assume_init_ref().setxattr.unwrap()(
+        arg1, arg2, arg3, arg4, arg5,
+    ))
+}
+
+unsafe extern "C" fn getxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *mut c_char,
+    arg4: usize,
+) -> c_int {
+    translate(NEXT.assume_init_ref().getxattr.unwrap()(
+        arg1, arg2, arg3, arg4,
+    ))
+}
+
+unsafe extern "C" fn listxattr(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    translate(NEXT.assume_init_ref().listxattr.unwrap()(arg1, arg2, arg3))
+}
+
+unsafe extern "C" fn removexattr(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    translate(NEXT.assume_init_ref().removexattr.unwrap()(arg1, arg2))
+}
+
+unsafe extern "C" fn opendir(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    translate(NEXT.assume_init_ref().opendir.unwrap()(arg1, arg2))
+}
+
+unsafe extern "C" fn readdir(
+    arg1: *const c_char,
+    arg2: *mut c_void,
+    arg3: fuse_fill_dir_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+    arg6: fuse_readdir_flags,
+) -> c_int {
+    translate(NEXT.assume_init_ref().readdir.unwrap()(
+        arg1, arg2, arg3, arg4, arg5, arg6,
+    ))
+}
+
+unsafe extern "C" fn releasedir(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    translate(NEXT.assume_init_ref().releasedir.unwrap()(arg1, arg2))
+}
+
+unsafe extern "C" fn fsyncdir(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: *mut fuse_file_info,
+) -> c_int {
+    translate(NEXT.assume_init_ref().fsyncdir.unwrap()(arg1, arg2, arg3))
+}
+
+unsafe extern "C" fn access(arg1: *const c_char, arg2: c_int) -> c_int {
+    translate(NEXT.assume_init_ref().access.unwrap()(arg1, arg2))
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    translate(NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3))
+}
+
+unsafe extern "C" fn lock(
+    arg1: *const c_char,
+    arg2: *mut fuse_file_info,
+    cmd: c_int,
+    arg3: *mut flock,
+) -> c_int {
+    translate(NEXT.assume_init_ref().lock.unwrap()(arg1, arg2, cmd, arg3))
+}
+
+unsafe extern "C" fn utimens(
+    arg1: *const c_char,
+    tv: *const timespec,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    translate(NEXT.assume_init_ref().utimens.unwrap()(arg1, tv, fi))
+}
+
+unsafe extern "C" fn bmap(arg1: *const c_char, blocksize: usize, idx: *mut u64) -> c_int {
+    translate(NEXT.assume_init_ref().bmap.unwrap()(arg1, blocksize, idx))
+}
+
+unsafe extern "C" fn ioctl(
+    arg1: *const c_char,
+    cmd: c_int,
+    arg: *mut c_void,
+    arg2: *mut fuse_file_info,
+    flags: c_uint,
+    data: *mut c_void,
+) -> c_int {
+    translate(NEXT.assume_init_ref().ioctl.unwrap()(
+        arg1, cmd, arg, arg2, flags, data,
+    ))
+}
+
+unsafe extern "C" fn poll(
+    arg1: *const c_char,
+    arg2: *mut fuse_file_info,
+    ph: *mut fuse_pollhandle,
+    reventsp: *mut c_uint,
+) -> c_int {
+    translate(NEXT.assume_init_ref().poll.unwrap()(
+        arg1, arg2, ph, reventsp,
+    ))
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    translate(NEXT.assume_init_ref().write_buf.unwrap()(
+        arg1, buf, off, arg2,
+    ))
+}
+
+unsafe extern "C" fn read_buf(
+    arg1: *const c_char,
+    bufp: *mut *mut fuse_bufvec,
+    size: usize,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    translate(NEXT.assume_init_ref().read_buf.unwrap()(
+        arg1, bufp, size, off, arg2,
+    ))
+}
+
+unsafe extern "C" fn flock(arg1: *const c_char, arg2: *mut fuse_file_info, op: c_int) -> c_int {
+    translate(NEXT.assume_init_ref().flock.unwrap()(arg1, arg2, op))
+}
+
+unsafe extern "C" fn fallocate(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: off_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    translate(NEXT.assume_init_ref().fallocate.unwrap()(
+        arg1, arg2, arg3, arg4, arg5,
+    ))
+}
+
+unsafe extern "C" fn copy_file_range(
+    path_in: *const c_char,
+    fi_in: *mut fuse_file_info,
+    offset_in: off_t,
+    path_out: *const c_char,
+    fi_out: *mut fuse_file_info,
+    offset_out: off_t,
+    size: usize,
+    flags: c_int,
+) -> isize {
+    translate_wide(NEXT.assume_init_ref().copy_file_range.unwrap()(
+        path_in, fi_in, offset_in, path_out, fi_out, offset_out, size, flags,
+    ) as i64) as isize
+}
+
+unsafe extern "C" fn lseek(
+    arg1: *const c_char,
+    off: off_t,
+    whence: c_int,
+    arg2: *mut fuse_file_info,
+) -> off_t {
+    translate_wide(NEXT.assume_init_ref().lseek.unwrap()(
+        arg1, off, whence, arg2,
+    ))
+}
+
+/// Prints how often each errno was translated.
+#[no_mangle]
+pub extern "C" fn errmap_report() {
+    for (from, (to, count)) in unsafe { TRANSLATIONS.iter() } {
+        eprintln!(
+            "{} -> {}: {} times",
+            errno_name(*from),
+            errno_name(*to),
+            count.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// Replaces errors the next layer returns with others the guest's applications cope with
+/// better, to normalize backend quirks like `EREMOTEIO` or `EUCLEAN` without patching them.
+/// `map` is a comma separated list of `from=to` pairs of errno names or numbers, e.g.
+/// `EREMOTEIO=EIO,EUCLEAN=EIO`. Unknown names are reported and ignored.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer and a valid C string
+#[no_mangle]
+pub unsafe extern "C" fn new_errmap_layer(
+    next: *const fuse_operations,
+    map: *const c_char,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    let map = CStr::from_ptr(map).to_string_lossy();
+    for pair in map
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+    {
+        let parsed = pair
+            .split_once('=')
+            .and_then(|(from, to)| Some((parse_errno(from.trim())?, parse_errno(to.trim())?)));
+        match parsed {
+            Some((from, to)) => {
+                TRANSLATIONS.insert(from, (to, AtomicU64::new(0)));
+            }
+            None => eprintln!("ignoring invalid errno translation {pair:?}"),
+        }
+    }
+    Box::into_raw(Box::new(fuse_operations {
+        getattr: next.getattr.and(Some(getattr)),
+        readlink: next.readlink.and(Some(readlink)),
+        mknod: next.mknod.and(Some(mknod)),
+        mkdir: next.mkdir.and(Some(mkdir)),
+        unlink: next.unlink.and(Some(unlink)),
+        rmdir: next.rmdir.and(Some(rmdir)),
+        symlink: next.symlink.and(Some(symlink)),
+        rename: next.rename.and(Some(rename)),
+        link: next.link.and(Some(link)),
+        chmod: next.chmod.and(Some(chmod)),
+        chown: next.chown.and(Some(chown)),
+        truncate: next.truncate.and(Some(truncate)),
+        open: next.open.and(Some(open)),
+        read: next.read.and(Some(read)),
+        write: next.write.and(Some(write)),
+        statfs: next.statfs.and(Some(statfs)),
+        flush: next.flush.and(Some(flush)),
+        release: next.release.and(Some(release)),
+        fsync: next.fsync.and(Some(fsync)),
+        setxattr: next.setxattr.and(Some(setxattr)),
+        getxattr: next.getxattr.and(Some(getxattr)),
+        listxattr: next.listxattr.and(Some(listxattr)),
+        removexattr: next.removexattr.and(Some(removexattr)),
+        opendir: next.opendir.and(Some(opendir)),
+        readdir: next.readdir.and(Some(readdir)),
+        releasedir: next.releasedir.and(Some(releasedir)),
+        fsyncdir: next.fsyncdir.and(Some(fsyncdir)),
+        access: next.access.and(Some(access)),
+        create: next.create.and(Some(create)),
+        lock: next.lock.and(Some(lock)),
+        utimens: next.utimens.and(Some(utimens)),
+        bmap: next.bmap.and(Some(bmap)),
+        ioctl: next.ioctl.and(Some(ioctl)),
+        poll: next.poll.and(Some(poll)),
+        write_buf: next.write_buf.and(Some(write_buf)),
+        read_buf: next.read_buf.and(Some(read_buf)),
+        flock: next.flock.and(Some(flock)),
+        fallocate: next.fallocate.and(Some(fallocate)),
+        copy_file_range: next.copy_file_range.and(Some(copy_file_range)),
+        lseek: next.lseek.and(Some(lseek)),
+        ..next
+    }))
+}
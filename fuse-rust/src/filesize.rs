@@ -0,0 +1,119 @@
+use crate::fuse::{
+    fuse_buf_size, fuse_bufvec, fuse_file_info, fuse_operations, off_t, EFBIG, FALLOC_FL_KEEP_SIZE,
+    FALLOC_FL_PUNCH_HOLE,
+};
+use std::{
+    ffi::{c_char, c_int},
+    mem::MaybeUninit,
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut MAX_FILE_SIZE: off_t = off_t::MAX;
+
+/// Number of bytes that may still be written at `offset`, or `None` if the offset is already at or
+/// beyond the limit.
+unsafe fn remaining(offset: off_t) -> Option<usize> {
+    (offset < MAX_FILE_SIZE).then(|| (MAX_FILE_SIZE - offset) as usize)
+}
+
+unsafe extern "C" fn truncate(arg1: *const c_char, arg2: off_t, fi: *mut fuse_file_info) -> c_int {
+    if arg2 > MAX_FILE_SIZE {
+        return -(EFBIG as c_int);
+    }
+    NEXT.assume_init_ref().truncate.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    match remaining(arg4) {
+        // like RLIMIT_FSIZE, a write straddling the limit is cut short instead of failing
+        Some(remaining) => {
+            NEXT.assume_init_ref().write.unwrap()(arg1, arg2, arg3.min(remaining), arg4, arg5)
+        }
+        None => -(EFBIG as c_int),
+    }
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    // the buffer vector cannot be shortened in place, so reject the whole write instead
+    match remaining(off) {
+        Some(remaining) if fuse_buf_size(buf) <= remaining => {
+            NEXT.assume_init_ref().write_buf.unwrap()(arg1, buf, off, arg2)
+        }
+        _ => -(EFBIG as c_int),
+    }
+}
+
+unsafe extern "C" fn fallocate(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: off_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    // neither keeping the size nor punching holes, which implies it, grows the file
+    let keep_size = arg2 & (FALLOC_FL_KEEP_SIZE | FALLOC_FL_PUNCH_HOLE) as c_int != 0;
+    if !keep_size && arg3.saturating_add(arg4) > MAX_FILE_SIZE {
+        return -(EFBIG as c_int);
+    }
+    NEXT.assume_init_ref().fallocate.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn copy_file_range(
+    path_in: *const c_char,
+    fi_in: *mut fuse_file_info,
+    offset_in: off_t,
+    path_out: *const c_char,
+    fi_out: *mut fuse_file_info,
+    offset_out: off_t,
+    size: usize,
+    flags: c_int,
+) -> isize {
+    match remaining(offset_out) {
+        Some(remaining) => NEXT.assume_init_ref().copy_file_range.unwrap()(
+            path_in,
+            fi_in,
+            offset_in,
+            path_out,
+            fi_out,
+            offset_out,
+            size.min(remaining),
+            flags,
+        ),
+        None => -(EFBIG as isize),
+    }
+}
+
+/// Caps the size of individual files, failing writes, truncates, fallocates and copies that would
+/// grow a file past `max_file_size` bytes with `EFBIG`.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer
+#[no_mangle]
+pub unsafe extern "C" fn new_filesize_layer(
+    next: *const fuse_operations,
+    max_file_size: off_t,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    MAX_FILE_SIZE = max_file_size;
+    Box::into_raw(Box::new(fuse_operations {
+        truncate: next.truncate.and(Some(truncate)),
+        write: next.write.and(Some(write)),
+        write_buf: next.write_buf.and(Some(write_buf)),
+        fallocate: next.fallocate.and(Some(fallocate)),
+        copy_file_range: next.copy_file_range.and(Some(copy_file_range)),
+        ..next
+    }))
+}
@@ -0,0 +1,421 @@
+use crate::{
+    control::Control,
+    fuse::{
+        dev_t, fuse_bufvec, fuse_config, fuse_conn_info, fuse_file_info, fuse_operations, gid_t,
+        mode_t, off_t, timespec, uid_t, EAGAIN, O_ACCMODE, O_RDONLY, O_TRUNC,
+    },
+    rmtree,
+};
+use std::{
+    collections::BTreeMap,
+    ffi::{c_char, c_int, c_uint, c_void, CStr, CString},
+    mem::MaybeUninit,
+    ptr,
+    sync::{Condvar, Mutex},
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static CONTROL: Control = Control::new();
+/// Whether mutating operations fail with EAGAIN while frozen instead of waiting for the thaw.
+static mut NONBLOCKING: bool = false;
+static STATE: Mutex<State> = Mutex::new(State {
+    frozen: false,
+    active: 0,
+});
+static CHANGED: Condvar = Condvar::new();
+/// Paths and handles of the files open for writing, by the handle number of the next layer.
+static WRITABLE: Mutex<BTreeMap<u64, (CString, fuse_file_info)>> = Mutex::new(BTreeMap::new());
+
+struct State {
+    frozen: bool,
+    /// Mutating operations in progress.
+    active: usize,
+}
+
+/// Lets a mutating operation run, counted as in progress until dropped.
+struct Gate;
+
+impl Drop for Gate {
+    fn drop(&mut self) {
+        STATE.lock().unwrap().active -= 1;
+        CHANGED.notify_all();
+    }
+}
+
+unsafe fn enter() -> Result<Gate, c_int> {
+    let mut state = STATE.lock().unwrap();
+    while state.frozen {
+        if NONBLOCKING {
+            return Err(-(EAGAIN as c_int));
+        }
+        state = CHANGED.wait(state).unwrap();
+    }
+    state.active += 1;
+    Ok(Gate)
+}
+
+/// Stops mutating operations, waits for those in progress to finish and syncs every file open
+/// for writing. Returns the first error a sync failed with, leaving the mount frozen anyway.
+pub fn freeze() -> Result<(), c_int> {
+    let mut state = STATE.lock().unwrap();
+    state.frozen = true;
+    while state.active > 0 {
+        state = CHANGED.wait(state).unwrap();
+    }
+    drop(state);
+    let mut res = 0;
+    unsafe {
+        if let Some(fsync) = NEXT.assume_init_ref().fsync {
+            for (path, fi) in WRITABLE.lock().unwrap().values_mut() {
+                let synced = fsync(path.as_ptr(), 0, fi);
+                if res == 0 {
+                    res = synced;
+                }
+            }
+        }
+    }
+    match res {
+        0 => Ok(()),
+        res => Err(res),
+    }
+}
+
+pub fn thaw() {
+    STATE.lock().unwrap().frozen = false;
+    CHANGED.notify_all();
+}
+
+/// Answers `freeze` and `thaw` commands on the control socket with `ok` or `error <errno>`.
+/// `freeze` is answered once the mount is quiesced.
+fn command(line: &[u8]) -> String {
+    match line {
+        b"freeze" => match freeze() {
+            Ok(()) => "ok\n".to_string(),
+            Err(res) => format!("error {}\n", -res),
+        },
+        b"thaw" => {
+            thaw();
+            "ok\n".to_string()
+        }
+        _ => "error unknown command\n".to_string(),
+    }
+}
+
+unsafe extern "C" fn init(conn: *mut fuse_conn_info, cfg: *mut fuse_config) -> *mut c_void {
+    CONTROL.start(command);
+    match NEXT.assume_init_ref().init {
+        Some(init) => init(conn, cfg),
+        None => ptr::null_mut(),
+    }
+}
+
+unsafe extern "C" fn destroy(private_data: *mut c_void) {
+    CONTROL.stop();
+    if let Some(destroy) = NEXT.assume_init_ref().destroy {
+        destroy(private_data);
+    }
+}
+
+unsafe fn track(path: *const c_char, fi: *mut fuse_file_info) {
+    if path.is_null() || (*fi).flags & O_ACCMODE as c_int == O_RDONLY as c_int {
+        return;
+    }
+    let path = CStr::from_ptr(path).to_owned();
+    WRITABLE.lock().unwrap().insert((*fi).fh, (path, *fi));
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    // truncating on open changes the file too
+    let _gate = match (*arg2).flags & O_TRUNC as c_int {
+        0 => None,
+        _ => match enter() {
+            Ok(gate) => Some(gate),
+            Err(res) => return res,
+        },
+    };
+    let res = NEXT.assume_init_ref().open.unwrap()(arg1, arg2);
+    if res == 0 {
+        track(arg1, arg2);
+    }
+    res
+}
+
+unsafe extern "C" fn release(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    WRITABLE.lock().unwrap().remove(&(*arg2).fh);
+    NEXT.assume_init_ref().release.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn mknod(arg1: *const c_char, arg2: mode_t, arg3: dev_t) -> c_int {
+    let _gate = match enter() {
+        Ok(gate) => gate,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().mknod.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    let _gate = match enter() {
+        Ok(gate) => gate,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().mkdir.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn unlink(arg1: *const c_char) -> c_int {
+    let _gate = match enter() {
+        Ok(gate) => gate,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().unlink.unwrap()(arg1)
+}
+
+unsafe extern "C" fn rmdir(arg1: *const c_char) -> c_int {
+    let _gate = match enter() {
+        Ok(gate) => gate,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().rmdir.unwrap()(arg1)
+}
+
+unsafe extern "C" fn symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let _gate = match enter() {
+        Ok(gate) => gate,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().symlink.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    let _gate = match enter() {
+        Ok(gate) => gate,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().rename.unwrap()(arg1, arg2, flags)
+}
+
+unsafe extern "C" fn link(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let _gate = match enter() {
+        Ok(gate) => gate,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().link.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn chmod(arg1: *const c_char, arg2: mode_t, fi: *mut fuse_file_info) -> c_int {
+    let _gate = match enter() {
+        Ok(gate) => gate,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().chmod.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn chown(
+    arg1: *const c_char,
+    arg2: uid_t,
+    arg3: gid_t,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let _gate = match enter() {
+        Ok(gate) => gate,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().chown.unwrap()(arg1, arg2, arg3, fi)
+}
+
+unsafe extern "C" fn truncate(arg1: *const c_char, arg2: off_t, fi: *mut fuse_file_info) -> c_int {
+    let _gate = match enter() {
+        Ok(gate) => gate,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().truncate.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let _gate = match enter() {
+        Ok(gate) => gate,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().write.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn setxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *const c_char,
+    arg4: usize,
+    arg5: c_int,
+) -> c_int {
+    let _gate = match enter() {
+        Ok(gate) => gate,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().setxattr.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn removexattr(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let _gate = match enter() {
+        Ok(gate) => gate,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().removexattr.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    let _gate = match enter() {
+        Ok(gate) => gate,
+        Err(res) => return res,
+    };
+    let res = NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3);
+    if res == 0 {
+        track(arg1, arg3);
+    }
+    res
+}
+
+unsafe extern "C" fn utimens(
+    arg1: *const c_char,
+    tv: *const timespec,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let _gate = match enter() {
+        Ok(gate) => gate,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().utimens.unwrap()(arg1, tv, fi)
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let _gate = match enter() {
+        Ok(gate) => gate,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().write_buf.unwrap()(arg1, buf, off, arg2)
+}
+
+unsafe extern "C" fn fallocate(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: off_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let _gate = match enter() {
+        Ok(gate) => gate,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().fallocate.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn copy_file_range(
+    path_in: *const c_char,
+    fi_in: *mut fuse_file_info,
+    offset_in: off_t,
+    path_out: *const c_char,
+    fi_out: *mut fuse_file_info,
+    offset_out: off_t,
+    size: usize,
+    flags: c_int,
+) -> isize {
+    let _gate = match enter() {
+        Ok(gate) => gate,
+        Err(res) => return res as isize,
+    };
+    NEXT.assume_init_ref().copy_file_range.unwrap()(
+        path_in, fi_in, offset_in, path_out, fi_out, offset_out, size, flags,
+    )
+}
+
+/// Freezes the mount, see [`freeze`]. Returns 0 once it is quiesced, or the negative errno
+/// syncing a file failed with.
+#[no_mangle]
+pub extern "C" fn freeze_mount() -> c_int {
+    match freeze() {
+        Ok(()) => 0,
+        Err(res) => res,
+    }
+}
+
+/// Lets the operations waiting on a frozen mount continue.
+#[no_mangle]
+pub extern "C" fn thaw_mount() {
+    thaw();
+}
+
+unsafe extern "C" fn ioctl(
+    arg1: *const c_char,
+    cmd: c_int,
+    arg: *mut c_void,
+    arg2: *mut fuse_file_info,
+    flags: c_uint,
+    data: *mut c_void,
+) -> c_int {
+    let _gate = match rmtree::is_rmtree(cmd) {
+        true => match enter() {
+            Ok(gate) => Some(gate),
+            Err(res) => return res,
+        },
+        false => None,
+    };
+    NEXT.assume_init_ref().ioctl.unwrap()(arg1, cmd, arg, arg2, flags, data)
+}
+
+/// Quiesces the whole mount on request, so host backup tools can snapshot the backing directory
+/// consistently while the container keeps running: once frozen, operations that change files
+/// wait until the mount is thawed, or fail with EAGAIN if `nonblocking` is set, those in
+/// progress are waited for, and the files open for writing are synced. Freezing and thawing is
+/// requested with `freeze` and `thaw` lines on the unix socket at `control_socket` when it is
+/// not null, or by calling [`freeze_mount`] and [`thaw_mount`]. Reads carry on while frozen, and
+/// so do ioctls other than the passthrough's tree removal.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer and a valid C string or null
+/// control socket
+#[no_mangle]
+pub unsafe extern "C" fn new_freeze_layer(
+    next: *const fuse_operations,
+    control_socket: *const c_char,
+    nonblocking: bool,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    NONBLOCKING = nonblocking;
+    CONTROL.set(control_socket);
+    Box::into_raw(Box::new(fuse_operations {
+        init: Some(init),
+        destroy: Some(destroy),
+        mknod: next.mknod.and(Some(mknod)),
+        mkdir: next.mkdir.and(Some(mkdir)),
+        unlink: next.unlink.and(Some(unlink)),
+        rmdir: next.rmdir.and(Some(rmdir)),
+        symlink: next.symlink.and(Some(symlink)),
+        rename: next.rename.and(Some(rename)),
+        link: next.link.and(Some(link)),
+        chmod: next.chmod.and(Some(chmod)),
+        chown: next.chown.and(Some(chown)),
+        truncate: next.truncate.and(Some(truncate)),
+        write: next.write.and(Some(write)),
+        setxattr: next.setxattr.and(Some(setxattr)),
+        removexattr: next.removexattr.and(Some(removexattr)),
+        create: next.create.and(Some(create)),
+        utimens: next.utimens.and(Some(utimens)),
+        write_buf: next.write_buf.and(Some(write_buf)),
+        fallocate: next.fallocate.and(Some(fallocate)),
+        copy_file_range: next.copy_file_range.and(Some(copy_file_range)),
+        open: next.open.and(Some(open)),
+        release: next.release.and(Some(release)),
+        ioctl: next.ioctl.and(Some(ioctl)),
+        ..next
+    }))
+}
@@ -0,0 +1,110 @@
+use crate::fuse::{fuse_file_info, fuse_operations};
+use std::{
+    collections::BTreeMap,
+    ffi::{c_char, c_int, c_uint, CStr},
+    mem::MaybeUninit,
+    sync::{Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut WINDOW: Duration = Duration::ZERO;
+static mut DEADLINE: Duration = Duration::ZERO;
+static BATCHES: Mutex<BTreeMap<Vec<u8>, Batch>> = Mutex::new(BTreeMap::new());
+static SYNCED: Condvar = Condvar::new();
+
+/// The fsyncs of one file, numbered in the order they arrived.
+#[derive(Default)]
+struct Batch {
+    requested: u64,
+    /// Every request numbered up to this one was covered by a finished sync.
+    synced: u64,
+    /// Result of the sync that covered `synced`.
+    res: c_int,
+    /// Whether a request still waiting needs metadata synced too.
+    full: bool,
+    /// When the oldest request not covered by a sync yet arrived.
+    oldest: Option<Instant>,
+    running: bool,
+    /// Requests that have not returned yet, the batch is dropped with the last one.
+    waiting: usize,
+}
+
+/// Makes the first fsync of a burst wait for the others to arrive, then issues a single sync
+/// for all of them. Requests arriving while a sync runs form the next batch, since that sync
+/// may have started before their writes landed.
+unsafe extern "C" fn fsync(arg1: *const c_char, arg2: c_int, arg3: *mut fuse_file_info) -> c_int {
+    if arg1.is_null() {
+        return NEXT.assume_init_ref().fsync.unwrap()(arg1, arg2, arg3);
+    }
+    let path = CStr::from_ptr(arg1).to_bytes();
+    let mut batches = BATCHES.lock().unwrap();
+    let batch = batches.entry(path.to_vec()).or_default();
+    batch.requested += 1;
+    batch.waiting += 1;
+    batch.full |= arg2 == 0;
+    batch.oldest.get_or_insert_with(Instant::now);
+    let ticket = batch.requested;
+    loop {
+        let batch = batches.get_mut(path).unwrap();
+        if batch.synced >= ticket {
+            let res = batch.res;
+            batch.waiting -= 1;
+            if batch.waiting == 0 {
+                batches.remove(path);
+            }
+            return res;
+        }
+        if batch.running {
+            batches = SYNCED.wait(batches).unwrap();
+            continue;
+        }
+        // lead the next batch, gathering requests for as long as the oldest one can still wait
+        batch.running = true;
+        let waited = batch.oldest.unwrap().elapsed();
+        drop(batches);
+        thread::sleep(WINDOW.min(DEADLINE.saturating_sub(waited)));
+
+        batches = BATCHES.lock().unwrap();
+        let batch = batches.get_mut(path).unwrap();
+        let covered = batch.requested;
+        let datasync = if batch.full { 0 } else { arg2 };
+        batch.full = false;
+        batch.oldest = None;
+        drop(batches);
+        let res = NEXT.assume_init_ref().fsync.unwrap()(arg1, datasync, arg3);
+
+        batches = BATCHES.lock().unwrap();
+        let batch = batches.get_mut(path).unwrap();
+        batch.synced = covered;
+        batch.res = res;
+        batch.running = false;
+        SYNCED.notify_all();
+    }
+}
+
+/// Coalesces the `fsync` and `fdatasync` calls made on a file within `window_us` microseconds
+/// into a single sync of the file, cutting down on the sync storms chatty databases cause. No
+/// request waits longer than `deadline_us` microseconds for its batch to start, on top of the
+/// time the sync in progress and its own take. A batch is synced with `fsync` if any request
+/// in it asked for one, and with `fdatasync` otherwise.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer
+#[no_mangle]
+pub unsafe extern "C" fn new_fsyncbatch_layer(
+    next: *const fuse_operations,
+    window_us: c_uint,
+    deadline_us: c_uint,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    WINDOW = Duration::from_micros(window_us as u64);
+    DEADLINE = Duration::from_micros(deadline_us as u64);
+    Box::into_raw(Box::new(fuse_operations {
+        fsync: next.fsync.and(Some(fsync)),
+        ..next
+    }))
+}
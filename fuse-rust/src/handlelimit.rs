@@ -0,0 +1,113 @@
+use crate::fuse::{fuse_file_info, fuse_get_context, fuse_operations, mode_t, uid_t, EMFILE};
+use std::{
+    collections::BTreeMap,
+    ffi::{c_char, c_int, c_uint},
+    mem::MaybeUninit,
+    sync::Mutex,
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut MAX_HANDLES: usize = 0;
+static mut MAX_HANDLES_PER_UID: usize = 0;
+static HANDLES: Mutex<Handles> = Mutex::new(Handles {
+    owners: BTreeMap::new(),
+    per_uid: BTreeMap::new(),
+});
+
+struct Handles {
+    /// Owner of every open handle, keyed by the handle the next layer returned. Handles layers
+    /// above open on their own, outside any request, have no owner and only count against the
+    /// limit of the mount.
+    owners: BTreeMap<u64, Option<uid_t>>,
+    per_uid: BTreeMap<uid_t, usize>,
+}
+
+/// Warns once a count climbs past 90% of its limit.
+fn warn_if_close(what: &str, count: usize, limit: usize) {
+    if limit != 0 && count == limit * 9 / 10 + 1 {
+        eprintln!("{what} has {count} of at most {limit} handles open");
+    }
+}
+
+/// Reserves a handle for the calling user, or fails with `EMFILE` if a limit is reached.
+unsafe fn acquire(fi: *mut fuse_file_info, open: impl FnOnce() -> c_int) -> c_int {
+    let uid = fuse_get_context().as_ref().map(|context| context.uid);
+    {
+        let handles = HANDLES.lock().unwrap();
+        let count = uid
+            .and_then(|uid| handles.per_uid.get(&uid).copied())
+            .unwrap_or_default();
+        if (MAX_HANDLES != 0 && handles.owners.len() >= MAX_HANDLES)
+            || (MAX_HANDLES_PER_UID != 0 && count >= MAX_HANDLES_PER_UID)
+        {
+            return -(EMFILE as c_int);
+        }
+    }
+    // the lock is not held while opening, so concurrent opens may overshoot the limits slightly
+    let res = open();
+    if res == 0 {
+        let mut handles = HANDLES.lock().unwrap();
+        handles.owners.insert((*fi).fh, uid);
+        if let Some(uid) = uid {
+            let count = handles.per_uid.entry(uid).or_default();
+            *count += 1;
+            let count = *count;
+            warn_if_close(&format!("uid {uid}"), count, MAX_HANDLES_PER_UID);
+        }
+        warn_if_close("mount", handles.owners.len(), MAX_HANDLES);
+    }
+    res
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    acquire(arg2, || NEXT.assume_init_ref().open.unwrap()(arg1, arg2))
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    acquire(arg3, || {
+        NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3)
+    })
+}
+
+unsafe extern "C" fn release(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let mut handles = HANDLES.lock().unwrap();
+    if let Some(Some(uid)) = handles.owners.remove(&(*arg2).fh) {
+        let count = handles.per_uid.get_mut(&uid).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            handles.per_uid.remove(&uid);
+        }
+    }
+    drop(handles);
+    match NEXT.assume_init_ref().release {
+        Some(release) => release(arg1, arg2),
+        None => 0,
+    }
+}
+
+/// Limits the number of simultaneously open file handles to `max_handles` for the whole mount
+/// and `max_handles_per_uid` for each user, failing further opens with `EMFILE`. A limit of 0
+/// disables it.
+///
+/// Handles are told apart by the `fh` the next layer assigns, which must be unique per open file.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer
+#[no_mangle]
+pub unsafe extern "C" fn new_handlelimit_layer(
+    next: *const fuse_operations,
+    max_handles: c_uint,
+    max_handles_per_uid: c_uint,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    MAX_HANDLES = max_handles as usize;
+    MAX_HANDLES_PER_UID = max_handles_per_uid as usize;
+    Box::into_raw(Box::new(fuse_operations {
+        open: next.open.and(Some(open)),
+        create: next.create.and(Some(create)),
+        release: Some(release),
+        ..next
+    }))
+}
@@ -0,0 +1,331 @@
+use crate::fuse::{
+    dev_t, fuse_file_info, fuse_fill_dir_flags, fuse_fill_dir_t, fuse_operations,
+    fuse_readdir_flags, gid_t, mode_t, off_t, stat, timespec, uid_t, ENOENT,
+};
+use std::{
+    ffi::{c_char, c_int, c_uint, c_void, CStr},
+    mem::MaybeUninit,
+    ptr,
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut PATTERNS: Vec<Vec<u8>> = Vec::new();
+
+/// Shell style matching of `name` against `pattern`, supporting `*` and `?`. Neither matches `/`.
+pub(crate) fn glob(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            glob(rest, name)
+                || name.first().is_some_and(|&c| c != b'/') && glob(pattern, &name[1..])
+        }
+        (Some((b'?', rest)), Some((&c, name))) => c != b'/' && glob(rest, name),
+        (Some((p, rest)), Some((c, name))) => p == c && glob(rest, name),
+        _ => false,
+    }
+}
+
+/// Whether `path` or any of its parents matches a hide pattern. Patterns containing a `/` are
+/// matched against the whole path from the root, others against each path component.
+unsafe fn is_hidden_path(path: &[u8]) -> bool {
+    let mut end = 0;
+    for component in path.split(|&c| c == b'/') {
+        end += component.len() + 1;
+        if component.is_empty() {
+            continue;
+        }
+        let prefix = &path[..end - 1];
+        if PATTERNS.iter().any(|pattern| {
+            if pattern.contains(&b'/') {
+                glob(pattern, prefix)
+            } else {
+                glob(pattern, component)
+            }
+        }) {
+            return true;
+        }
+    }
+    false
+}
+
+unsafe fn is_hidden(path: *const c_char) -> bool {
+    !path.is_null() && is_hidden_path(CStr::from_ptr(path).to_bytes())
+}
+
+unsafe extern "C" fn getattr(
+    arg1: *const c_char,
+    arg2: *mut stat,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().getattr.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn readlink(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().readlink.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn mknod(arg1: *const c_char, arg2: mode_t, arg3: dev_t) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().mknod.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().mkdir.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn unlink(arg1: *const c_char) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().unlink.unwrap()(arg1)
+}
+
+unsafe extern "C" fn rmdir(arg1: *const c_char) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().rmdir.unwrap()(arg1)
+}
+
+unsafe extern "C" fn symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    // the first argument is the link target, which is never resolved here
+    if is_hidden(arg2) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().symlink.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    if is_hidden(arg1) || is_hidden(arg2) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().rename.unwrap()(arg1, arg2, flags)
+}
+
+unsafe extern "C" fn link(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    if is_hidden(arg1) || is_hidden(arg2) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().link.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn chmod(arg1: *const c_char, arg2: mode_t, fi: *mut fuse_file_info) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().chmod.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn chown(
+    arg1: *const c_char,
+    arg2: uid_t,
+    arg3: gid_t,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().chown.unwrap()(arg1, arg2, arg3, fi)
+}
+
+unsafe extern "C" fn truncate(arg1: *const c_char, arg2: off_t, fi: *mut fuse_file_info) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().truncate.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().open.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn setxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *const c_char,
+    arg4: usize,
+    arg5: c_int,
+) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().setxattr.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn getxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *mut c_char,
+    arg4: usize,
+) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().getxattr.unwrap()(arg1, arg2, arg3, arg4)
+}
+
+unsafe extern "C" fn listxattr(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().listxattr.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn removexattr(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().removexattr.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn opendir(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().opendir.unwrap()(arg1, arg2)
+}
+
+/// What the readdir filler of the next layer needs, smuggled through its `buf` argument.
+struct Filler {
+    buf: *mut c_void,
+    filler: fuse_fill_dir_t,
+    dir: Vec<u8>,
+}
+
+unsafe extern "C" fn fill(
+    buf: *mut c_void,
+    name: *const c_char,
+    stbuf: *const stat,
+    off: off_t,
+    flags: fuse_fill_dir_flags,
+) -> c_int {
+    let filler = &*buf.cast::<Filler>();
+    let name = CStr::from_ptr(name);
+    if !matches!(name.to_bytes(), b"." | b"..") {
+        let mut path = filler.dir.clone();
+        if path != b"/" {
+            path.push(b'/');
+        }
+        path.extend_from_slice(name.to_bytes());
+        if is_hidden_path(&path) {
+            return 0;
+        }
+    }
+    filler.filler.unwrap()(filler.buf, name.as_ptr(), stbuf, off, flags)
+}
+
+unsafe extern "C" fn readdir(
+    arg1: *const c_char,
+    arg2: *mut c_void,
+    arg3: fuse_fill_dir_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+    arg6: fuse_readdir_flags,
+) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    if arg1.is_null() {
+        return NEXT.assume_init_ref().readdir.unwrap()(arg1, arg2, arg3, arg4, arg5, arg6);
+    }
+    let mut filler = Filler {
+        buf: arg2,
+        filler: arg3,
+        dir: CStr::from_ptr(arg1).to_bytes().to_vec(),
+    };
+    NEXT.assume_init_ref().readdir.unwrap()(
+        arg1,
+        ptr::addr_of_mut!(filler).cast(),
+        Some(fill),
+        arg4,
+        arg5,
+        arg6,
+    )
+}
+
+unsafe extern "C" fn access(arg1: *const c_char, arg2: c_int) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().access.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn utimens(
+    arg1: *const c_char,
+    tv: *const timespec,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().utimens.unwrap()(arg1, tv, fi)
+}
+
+/// Hides files matching any of the comma separated glob `patterns` from the guest: they are left
+/// out of directory listings and every operation naming them, or anything below them, fails with
+/// `ENOENT` as if they did not exist. Use it for host side clutter like `lost+found` or sidecar
+/// metadata, e.g. `lost+found,.snapshot,/var/cache/*.db`.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer and a valid C string
+#[no_mangle]
+pub unsafe extern "C" fn new_hide_layer(
+    next: *const fuse_operations,
+    patterns: *const c_char,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    PATTERNS = CStr::from_ptr(patterns)
+        .to_bytes()
+        .split(|&c| c == b',')
+        .filter(|pattern| !pattern.is_empty())
+        .map(<[u8]>::to_vec)
+        .collect();
+    Box::into_raw(Box::new(fuse_operations {
+        getattr: next.getattr.and(Some(getattr)),
+        readlink: next.readlink.and(Some(readlink)),
+        mknod: next.mknod.and(Some(mknod)),
+        mkdir: next.mkdir.and(Some(mkdir)),
+        unlink: next.unlink.and(Some(unlink)),
+        rmdir: next.rmdir.and(Some(rmdir)),
+        symlink: next.symlink.and(Some(symlink)),
+        rename: next.rename.and(Some(rename)),
+        link: next.link.and(Some(link)),
+        chmod: next.chmod.and(Some(chmod)),
+        chown: next.chown.and(Some(chown)),
+        truncate: next.truncate.and(Some(truncate)),
+        open: next.open.and(Some(open)),
+        setxattr: next.setxattr.and(Some(setxattr)),
+        getxattr: next.getxattr.and(Some(getxattr)),
+        listxattr: next.listxattr.and(Some(listxattr)),
+        removexattr: next.removexattr.and(Some(removexattr)),
+        opendir: next.opendir.and(Some(opendir)),
+        readdir: next.readdir.and(Some(readdir)),
+        access: next.access.and(Some(access)),
+        create: next.create.and(Some(create)),
+        utimens: next.utimens.and(Some(utimens)),
+        ..next
+    }))
+}
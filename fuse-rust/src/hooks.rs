@@ -0,0 +1,367 @@
+use crate::{
+    fuse::{dev_t, fuse_file_info, fuse_operations, gid_t, mode_t, off_t, timespec, uid_t, EINVAL},
+    rmtree,
+};
+use std::{
+    ffi::{c_char, c_int, c_uint, c_void, CStr, CString},
+    mem::MaybeUninit,
+    ptr,
+    sync::RwLock,
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static HOOKS: RwLock<Vec<Hook>> = RwLock::new(Vec::new());
+
+/// Operations hooks can be registered for.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HookOp {
+    Create,
+    Open,
+    Mknod,
+    Mkdir,
+    /// The target is the content of the link.
+    Symlink,
+    /// The target is the new path, which cannot be rewritten.
+    Link,
+    Unlink,
+    Rmdir,
+    /// The target is the new path, which can be rewritten as well.
+    Rename,
+    Chmod,
+    Chown,
+    Truncate,
+    Utimens,
+    Setxattr,
+    Removexattr,
+    /// Called after the file was closed; the result is ignored.
+    Release,
+    /// The passthrough's `FSINTERPOSER_IOC_RMTREE` on the directory, removing everything below.
+    /// The path cannot be rewritten, as the removal works on the open directory.
+    Rmtree,
+}
+
+impl HookOp {
+    /// Whether hooks may rewrite the path. The guest looks up an object it created by the
+    /// path it used right after creating it, so it must not end up anywhere else.
+    fn rewrites_path(self) -> bool {
+        !matches!(
+            self,
+            HookOp::Create
+                | HookOp::Mknod
+                | HookOp::Mkdir
+                | HookOp::Symlink
+                | HookOp::Release
+                | HookOp::Rmtree
+        )
+    }
+}
+
+/// Called with the `data` it was registered with, the operation, its path, depending on the
+/// operation a second path or null, and two buffers of `HOOK_PATH_MAX` bytes. Returning a
+/// negative errno fails the operation with it without running later hooks. Returning
+/// `HOOK_REWRITE` after writing a null terminated path to `rewrite` passes the operation on for
+/// that path instead, and `HOOK_REWRITE_TARGET`, which may be or'ed to it, does the same for
+/// the second path written to `rewrite_target`. Later hooks see the rewritten paths. Rewriting
+/// a path the operation does not allow to fails it with `EINVAL`. Anything else lets the
+/// operation through as it is.
+pub type HookFn = unsafe extern "C" fn(
+    data: *mut c_void,
+    op: HookOp,
+    path: *const c_char,
+    target: *const c_char,
+    rewrite: *mut c_char,
+    rewrite_target: *mut c_char,
+) -> c_int;
+
+/// Returned by a hook that wrote the path the operation should be made on to `rewrite`.
+pub const HOOK_REWRITE: c_int = 1;
+/// Returned by a hook that wrote the second path of a rename to `rewrite_target`.
+pub const HOOK_REWRITE_TARGET: c_int = 2;
+/// Size of the buffer hooks may write a rewritten path to, including the terminating null.
+pub const HOOK_PATH_MAX: usize = 4096;
+
+struct Hook {
+    /// Bit `1 << op` is set for every operation the hook wants to see.
+    ops: u64,
+    hook: HookFn,
+    data: *mut c_void,
+}
+
+// the registering party promises the hook may be called with `data` from any thread
+unsafe impl Send for Hook {}
+unsafe impl Sync for Hook {}
+
+/// Paths as rewritten by the hooks, or None where none did.
+struct Rewritten {
+    path: Option<CString>,
+    target: Option<CString>,
+}
+
+/// Copies a path a hook wrote to `buf`. A hook that filled the whole buffer still leaves a
+/// terminated path.
+unsafe fn take(buf: &mut [c_char; HOOK_PATH_MAX]) -> CString {
+    buf[HOOK_PATH_MAX - 1] = 0;
+    CStr::from_ptr(buf.as_ptr()).to_owned()
+}
+
+/// Runs the hooks registered for `op`, returning the paths the last of them rewrote `path`
+/// and `target` to, or the first error.
+unsafe fn run_hooks(
+    op: HookOp,
+    path: *const c_char,
+    target: *const c_char,
+) -> Result<Rewritten, c_int> {
+    let mut rewrite = [0 as c_char; HOOK_PATH_MAX];
+    let mut rewrite_target = [0 as c_char; HOOK_PATH_MAX];
+    let mut now = Rewritten {
+        path: None,
+        target: None,
+    };
+    for hook in HOOKS.read().unwrap().iter() {
+        if hook.ops & 1 << op as u64 == 0 {
+            continue;
+        }
+        rewrite[0] = 0;
+        rewrite_target[0] = 0;
+        let res = (hook.hook)(
+            hook.data,
+            op,
+            rewritten(&now.path, path),
+            rewritten(&now.target, target),
+            rewrite.as_mut_ptr(),
+            rewrite_target.as_mut_ptr(),
+        );
+        if res < 0 {
+            return Err(res);
+        }
+        let rewrites_target = res & HOOK_REWRITE_TARGET != 0;
+        if res & HOOK_REWRITE != 0 && !op.rewrites_path() || rewrites_target && op != HookOp::Rename
+        {
+            eprintln!("hook tried to rewrite a path the operation does not allow to");
+            return Err(-(EINVAL as c_int));
+        }
+        if res & HOOK_REWRITE != 0 {
+            now.path = Some(take(&mut rewrite));
+        }
+        if rewrites_target {
+            now.target = Some(take(&mut rewrite_target));
+        }
+    }
+    Ok(now)
+}
+
+/// The path a hook rewrote `path` to, or `path` if none did.
+fn rewritten(path_now: &Option<CString>, path: *const c_char) -> *const c_char {
+    path_now.as_ref().map_or(path, |path| path.as_ptr())
+}
+
+/// Registers `hook` to be called before every operation in the bitmask `ops`, where bit
+/// `1 << op` selects a `HookOp`, in registration order. Hooks should be registered before the
+/// file system is mounted and must be thread safe.
+///
+/// # Safety
+///
+/// `hook` must be safe to call with `data` from any thread for as long as the layer is in use
+#[no_mangle]
+pub unsafe extern "C" fn register_hook(ops: u64, hook: HookFn, data: *mut c_void) {
+    HOOKS.write().unwrap().push(Hook { ops, hook, data });
+}
+
+unsafe extern "C" fn mknod(arg1: *const c_char, arg2: mode_t, arg3: dev_t) -> c_int {
+    if let Err(res) = run_hooks(HookOp::Mknod, arg1, ptr::null()) {
+        return res;
+    }
+    NEXT.assume_init_ref().mknod.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    if let Err(res) = run_hooks(HookOp::Mkdir, arg1, ptr::null()) {
+        return res;
+    }
+    NEXT.assume_init_ref().mkdir.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn unlink(arg1: *const c_char) -> c_int {
+    let paths = match run_hooks(HookOp::Unlink, arg1, ptr::null()) {
+        Ok(paths) => paths,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().unlink.unwrap()(rewritten(&paths.path, arg1))
+}
+
+unsafe extern "C" fn rmdir(arg1: *const c_char) -> c_int {
+    let paths = match run_hooks(HookOp::Rmdir, arg1, ptr::null()) {
+        Ok(paths) => paths,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().rmdir.unwrap()(rewritten(&paths.path, arg1))
+}
+
+unsafe extern "C" fn symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    if let Err(res) = run_hooks(HookOp::Symlink, arg2, arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().symlink.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    let paths = match run_hooks(HookOp::Rename, arg1, arg2) {
+        Ok(paths) => paths,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().rename.unwrap()(
+        rewritten(&paths.path, arg1),
+        rewritten(&paths.target, arg2),
+        flags,
+    )
+}
+
+unsafe extern "C" fn link(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let paths = match run_hooks(HookOp::Link, arg1, arg2) {
+        Ok(paths) => paths,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().link.unwrap()(rewritten(&paths.path, arg1), arg2)
+}
+
+unsafe extern "C" fn chmod(arg1: *const c_char, arg2: mode_t, fi: *mut fuse_file_info) -> c_int {
+    let paths = match run_hooks(HookOp::Chmod, arg1, ptr::null()) {
+        Ok(paths) => paths,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().chmod.unwrap()(rewritten(&paths.path, arg1), arg2, fi)
+}
+
+unsafe extern "C" fn chown(
+    arg1: *const c_char,
+    arg2: uid_t,
+    arg3: gid_t,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let paths = match run_hooks(HookOp::Chown, arg1, ptr::null()) {
+        Ok(paths) => paths,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().chown.unwrap()(rewritten(&paths.path, arg1), arg2, arg3, fi)
+}
+
+unsafe extern "C" fn truncate(arg1: *const c_char, arg2: off_t, fi: *mut fuse_file_info) -> c_int {
+    let paths = match run_hooks(HookOp::Truncate, arg1, ptr::null()) {
+        Ok(paths) => paths,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().truncate.unwrap()(rewritten(&paths.path, arg1), arg2, fi)
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let paths = match run_hooks(HookOp::Open, arg1, ptr::null()) {
+        Ok(paths) => paths,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().open.unwrap()(rewritten(&paths.path, arg1), arg2)
+}
+
+unsafe extern "C" fn release(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let res = match NEXT.assume_init_ref().release {
+        Some(release) => release(arg1, arg2),
+        None => 0,
+    };
+    // the file is closed either way, so hooks cannot veto this
+    let _ = run_hooks(HookOp::Release, arg1, ptr::null());
+    res
+}
+
+unsafe extern "C" fn setxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *const c_char,
+    arg4: usize,
+    arg5: c_int,
+) -> c_int {
+    let paths = match run_hooks(HookOp::Setxattr, arg1, ptr::null()) {
+        Ok(paths) => paths,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().setxattr.unwrap()(rewritten(&paths.path, arg1), arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn removexattr(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let paths = match run_hooks(HookOp::Removexattr, arg1, ptr::null()) {
+        Ok(paths) => paths,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().removexattr.unwrap()(rewritten(&paths.path, arg1), arg2)
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    if let Err(res) = run_hooks(HookOp::Create, arg1, ptr::null()) {
+        return res;
+    }
+    NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn utimens(
+    arg1: *const c_char,
+    tv: *const timespec,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let paths = match run_hooks(HookOp::Utimens, arg1, ptr::null()) {
+        Ok(paths) => paths,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().utimens.unwrap()(rewritten(&paths.path, arg1), tv, fi)
+}
+
+unsafe extern "C" fn ioctl(
+    arg1: *const c_char,
+    cmd: c_int,
+    arg: *mut c_void,
+    arg2: *mut fuse_file_info,
+    flags: c_uint,
+    data: *mut c_void,
+) -> c_int {
+    if rmtree::is_rmtree(cmd) {
+        if let Err(res) = run_hooks(HookOp::Rmtree, arg1, ptr::null()) {
+            return res;
+        }
+    }
+    NEXT.assume_init_ref().ioctl.unwrap()(arg1, cmd, arg, arg2, flags, data)
+}
+
+/// Lets hooks added with [`register_hook`] inspect, veto and redirect selected operations, so
+/// operators can plug in custom policies written in any language with a C ABI without changing
+/// the interposer, e.g. to refuse creating files outside an allow list, to move renamed files
+/// into a directory of their own or to tag files once they are closed. Only paths can be
+/// rewritten, not the other arguments, and not those of objects being created, which the guest
+/// looks up by the path it used. Operations on a handle opened for a rewritten path still carry
+/// the path the guest used.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer
+#[no_mangle]
+pub unsafe extern "C" fn new_hooks_layer(next: *const fuse_operations) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    Box::into_raw(Box::new(fuse_operations {
+        mknod: next.mknod.and(Some(mknod)),
+        mkdir: next.mkdir.and(Some(mkdir)),
+        unlink: next.unlink.and(Some(unlink)),
+        rmdir: next.rmdir.and(Some(rmdir)),
+        symlink: next.symlink.and(Some(symlink)),
+        rename: next.rename.and(Some(rename)),
+        link: next.link.and(Some(link)),
+        chmod: next.chmod.and(Some(chmod)),
+        chown: next.chown.and(Some(chown)),
+        truncate: next.truncate.and(Some(truncate)),
+        open: next.open.and(Some(open)),
+        release: Some(release),
+        setxattr: next.setxattr.and(Some(setxattr)),
+        removexattr: next.removexattr.and(Some(removexattr)),
+        create: next.create.and(Some(create)),
+        utimens: next.utimens.and(Some(utimens)),
+        ioctl: next.ioctl.and(Some(ioctl)),
+        ..next
+    }))
+}
@@ -0,0 +1,506 @@
+use crate::fuse::{
+    dev_t, free, fuse_buf, fuse_bufvec, fuse_file_info, fuse_fill_dir_flags, fuse_fill_dir_t,
+    fuse_operations, fuse_readdir_flags, gid_t, malloc, mode_t, off_t, stat, timespec, uid_t,
+    EEXIST, ENODATA, ENOMEM, ENXIO, EROFS, EXDEV, O_ACCMODE, O_RDONLY, O_TRUNC, S_IFREG, W_OK,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::{c_char, c_int, c_uint, c_void, CStr, OsStr},
+    fs,
+    mem::{self, MaybeUninit},
+    os::unix::ffi::OsStrExt,
+    ptr, slice,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// Linux values of the `lseek` whences only exposed by the libc headers under `_GNU_SOURCE`.
+const SEEK_DATA: c_int = 3;
+const SEEK_HOLE: c_int = 4;
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut FILES: BTreeMap<Vec<u8>, Injected> = BTreeMap::new();
+/// Guest path of every open handle of an injected file.
+static HANDLES: Mutex<BTreeMap<u64, Vec<u8>>> = Mutex::new(BTreeMap::new());
+/// Handles of injected files count down from the top so they never clash with host fds. The
+/// very top is left to the dry run layer, whose files that only exist in the dry run share it.
+static NEXT_FH: AtomicU64 = AtomicU64::new(u64::MAX - 1);
+
+struct Injected {
+    ino: u64,
+    content: Vec<u8>,
+}
+
+fn split(path: &[u8]) -> (&[u8], &[u8]) {
+    let slash = path.iter().rposition(|&c| c == b'/').unwrap_or(0);
+    match &path[..slash] {
+        b"" => (b"/", &path[slash + 1..]),
+        parent => (parent, &path[slash + 1..]),
+    }
+}
+
+unsafe fn injected(path: *const c_char) -> Option<&'static Injected> {
+    if path.is_null() {
+        return None;
+    }
+    FILES.get(CStr::from_ptr(path).to_bytes())
+}
+
+unsafe fn handle(fi: *mut fuse_file_info) -> Option<&'static Injected> {
+    // every handle above the next one to hand out is ours, anything else goes to the next
+    // layer without taking the lock
+    if fi.is_null() || (*fi).fh <= NEXT_FH.load(Ordering::Relaxed) {
+        return None;
+    }
+    let handles = HANDLES.lock().unwrap();
+    FILES.get(handles.get(&(*fi).fh)?)
+}
+
+unsafe extern "C" fn getattr(
+    arg1: *const c_char,
+    arg2: *mut stat,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let Some(file) = injected(arg1).or_else(|| handle(fi)) else {
+        return NEXT.assume_init_ref().getattr.unwrap()(arg1, arg2, fi);
+    };
+    // ownership and timestamps are borrowed from the directory the file is injected into
+    let path = match injected(arg1) {
+        Some(_) => CStr::from_ptr(arg1).to_bytes().to_vec(),
+        None => HANDLES.lock().unwrap()[&(*fi).fh].clone(),
+    };
+    let parent = [split(&path).0, b"\0"].concat();
+    let res =
+        NEXT.assume_init_ref().getattr.unwrap()(parent.as_ptr().cast(), arg2, ptr::null_mut());
+    if res != 0 {
+        return res;
+    }
+    let st = &mut *arg2;
+    st.st_ino = file.ino as _;
+    st.st_mode = S_IFREG | 0o444;
+    st.st_nlink = 1;
+    st.st_size = file.content.len() as off_t;
+    st.st_blocks = file.content.len().div_ceil(512) as _;
+    0
+}
+
+unsafe extern "C" fn mknod(arg1: *const c_char, arg2: mode_t, arg3: dev_t) -> c_int {
+    if injected(arg1).is_some() {
+        return -(EEXIST as c_int);
+    }
+    NEXT.assume_init_ref().mknod.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    if injected(arg1).is_some() {
+        return -(EEXIST as c_int);
+    }
+    NEXT.assume_init_ref().mkdir.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn unlink(arg1: *const c_char) -> c_int {
+    if injected(arg1).is_some() {
+        return -(EROFS as c_int);
+    }
+    NEXT.assume_init_ref().unlink.unwrap()(arg1)
+}
+
+unsafe extern "C" fn symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    if injected(arg2).is_some() {
+        return -(EEXIST as c_int);
+    }
+    NEXT.assume_init_ref().symlink.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    if injected(arg1).is_some() || injected(arg2).is_some() {
+        return -(EROFS as c_int);
+    }
+    NEXT.assume_init_ref().rename.unwrap()(arg1, arg2, flags)
+}
+
+unsafe extern "C" fn link(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    if injected(arg1).is_some() {
+        return -(EROFS as c_int);
+    }
+    if injected(arg2).is_some() {
+        return -(EEXIST as c_int);
+    }
+    NEXT.assume_init_ref().link.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn chmod(arg1: *const c_char, arg2: mode_t, fi: *mut fuse_file_info) -> c_int {
+    if injected(arg1).or_else(|| handle(fi)).is_some() {
+        return -(EROFS as c_int);
+    }
+    NEXT.assume_init_ref().chmod.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn chown(
+    arg1: *const c_char,
+    arg2: uid_t,
+    arg3: gid_t,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    if injected(arg1).or_else(|| handle(fi)).is_some() {
+        return -(EROFS as c_int);
+    }
+    NEXT.assume_init_ref().chown.unwrap()(arg1, arg2, arg3, fi)
+}
+
+unsafe extern "C" fn truncate(arg1: *const c_char, arg2: off_t, fi: *mut fuse_file_info) -> c_int {
+    if injected(arg1).or_else(|| handle(fi)).is_some() {
+        return -(EROFS as c_int);
+    }
+    NEXT.assume_init_ref().truncate.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    if injected(arg1).is_none() {
+        return NEXT.assume_init_ref().open.unwrap()(arg1, arg2);
+    }
+    let flags = (*arg2).flags;
+    if flags & O_ACCMODE as c_int != O_RDONLY as c_int || flags & O_TRUNC as c_int != 0 {
+        return -(EROFS as c_int);
+    }
+    (*arg2).fh = NEXT_FH.fetch_sub(1, Ordering::Relaxed);
+    HANDLES
+        .lock()
+        .unwrap()
+        .insert((*arg2).fh, CStr::from_ptr(arg1).to_bytes().to_vec());
+    0
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    if injected(arg1).is_some() {
+        return -(EROFS as c_int);
+    }
+    NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3)
+}
+
+fn window(content: &[u8], size: usize, off: off_t) -> &[u8] {
+    let start = (off.max(0) as usize).min(content.len());
+    &content[start..content.len().min(start.saturating_add(size))]
+}
+
+unsafe extern "C" fn read(
+    arg1: *const c_char,
+    arg2: *mut c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let Some(file) = handle(arg5) else {
+        return NEXT.assume_init_ref().read.unwrap()(arg1, arg2, arg3, arg4, arg5);
+    };
+    let data = window(&file.content, arg3, arg4);
+    slice::from_raw_parts_mut(arg2.cast::<u8>(), data.len()).copy_from_slice(data);
+    data.len() as c_int
+}
+
+unsafe extern "C" fn read_buf(
+    arg1: *const c_char,
+    bufp: *mut *mut fuse_bufvec,
+    size: usize,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let Some(file) = handle(arg2) else {
+        return NEXT.assume_init_ref().read_buf.unwrap()(arg1, bufp, size, off, arg2);
+    };
+    // libfuse releases the vector and its memory with free()
+    let data = window(&file.content, size, off);
+    let buf = malloc(mem::size_of::<fuse_bufvec>()).cast::<fuse_bufvec>();
+    let mem = malloc(data.len().max(1));
+    if buf.is_null() || mem.is_null() {
+        free(buf.cast());
+        free(mem);
+        return -(ENOMEM as c_int);
+    }
+    slice::from_raw_parts_mut(mem.cast::<u8>(), data.len()).copy_from_slice(data);
+    buf.write(fuse_bufvec {
+        count: 1,
+        idx: 0,
+        off: 0,
+        buf: [fuse_buf {
+            size: data.len(),
+            flags: 0,
+            mem,
+            fd: -1,
+            pos: 0,
+        }],
+    });
+    *bufp = buf;
+    0
+}
+
+unsafe extern "C" fn flush(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    if handle(arg2).is_some() {
+        return 0;
+    }
+    NEXT.assume_init_ref().flush.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn release(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    if HANDLES.lock().unwrap().remove(&(*arg2).fh).is_some() {
+        return 0;
+    }
+    match NEXT.assume_init_ref().release {
+        Some(release) => release(arg1, arg2),
+        None => 0,
+    }
+}
+
+unsafe extern "C" fn fsync(arg1: *const c_char, arg2: c_int, arg3: *mut fuse_file_info) -> c_int {
+    if handle(arg3).is_some() {
+        return 0;
+    }
+    NEXT.assume_init_ref().fsync.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn setxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *const c_char,
+    arg4: usize,
+    arg5: c_int,
+) -> c_int {
+    if injected(arg1).is_some() {
+        return -(EROFS as c_int);
+    }
+    NEXT.assume_init_ref().setxattr.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn getxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *mut c_char,
+    arg4: usize,
+) -> c_int {
+    if injected(arg1).is_some() {
+        return -(ENODATA as c_int);
+    }
+    NEXT.assume_init_ref().getxattr.unwrap()(arg1, arg2, arg3, arg4)
+}
+
+unsafe extern "C" fn listxattr(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    if injected(arg1).is_some() {
+        return 0;
+    }
+    NEXT.assume_init_ref().listxattr.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn removexattr(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    if injected(arg1).is_some() {
+        return -(EROFS as c_int);
+    }
+    NEXT.assume_init_ref().removexattr.unwrap()(arg1, arg2)
+}
+
+/// What the readdir filler of the next layer needs, smuggled through its `buf` argument.
+struct Filler {
+    buf: *mut c_void,
+    filler: fuse_fill_dir_t,
+    /// Names injected into the directory being listed, which hide host files of the same name.
+    names: BTreeSet<&'static [u8]>,
+}
+
+unsafe extern "C" fn fill(
+    buf: *mut c_void,
+    name: *const c_char,
+    stbuf: *const stat,
+    off: off_t,
+    flags: fuse_fill_dir_flags,
+) -> c_int {
+    let filler = &*buf.cast::<Filler>();
+    if filler.names.contains(CStr::from_ptr(name).to_bytes()) {
+        return 0;
+    }
+    filler.filler.unwrap()(filler.buf, name, stbuf, off, flags)
+}
+
+unsafe extern "C" fn readdir(
+    arg1: *const c_char,
+    arg2: *mut c_void,
+    arg3: fuse_fill_dir_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+    arg6: fuse_readdir_flags,
+) -> c_int {
+    let readdir = NEXT.assume_init_ref().readdir.unwrap();
+    // injected entries are only merged into full listings, which is what libfuse asks for
+    // when the next layer passes 0 offsets to the filler
+    if arg1.is_null() || arg4 != 0 {
+        return readdir(arg1, arg2, arg3, arg4, arg5, arg6);
+    }
+    let dir = CStr::from_ptr(arg1).to_bytes();
+    let mut filler = Filler {
+        buf: arg2,
+        filler: arg3,
+        names: FILES
+            .keys()
+            .map(|path| split(path))
+            .filter(|&(parent, _)| parent == dir)
+            .map(|(_, name)| name)
+            .collect(),
+    };
+    if filler.names.is_empty() {
+        return readdir(arg1, arg2, arg3, arg4, arg5, arg6);
+    }
+    let res = readdir(
+        arg1,
+        ptr::addr_of_mut!(filler).cast(),
+        Some(fill),
+        arg4,
+        arg5,
+        arg6,
+    );
+    if res != 0 {
+        return res;
+    }
+    for name in filler.names {
+        let name = [name, b"\0"].concat();
+        if arg3.unwrap()(arg2, name.as_ptr().cast(), ptr::null(), 0, 0) != 0 {
+            break;
+        }
+    }
+    0
+}
+
+unsafe extern "C" fn access(arg1: *const c_char, arg2: c_int) -> c_int {
+    if injected(arg1).is_some() {
+        return if arg2 & W_OK as c_int != 0 {
+            -(EROFS as c_int)
+        } else {
+            0
+        };
+    }
+    NEXT.assume_init_ref().access.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn utimens(
+    arg1: *const c_char,
+    arg2: *const timespec,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    if injected(arg1).or_else(|| handle(fi)).is_some() {
+        return -(EROFS as c_int);
+    }
+    NEXT.assume_init_ref().utimens.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn copy_file_range(
+    path_in: *const c_char,
+    fi_in: *mut fuse_file_info,
+    offset_in: off_t,
+    path_out: *const c_char,
+    fi_out: *mut fuse_file_info,
+    offset_out: off_t,
+    size: usize,
+    flags: c_int,
+) -> isize {
+    // there is no host file to copy from, so let the kernel fall back to reads and writes
+    if handle(fi_in).is_some() || handle(fi_out).is_some() {
+        return -(EXDEV as isize);
+    }
+    NEXT.assume_init_ref().copy_file_range.unwrap()(
+        path_in, fi_in, offset_in, path_out, fi_out, offset_out, size, flags,
+    )
+}
+
+unsafe extern "C" fn lseek(
+    arg1: *const c_char,
+    off: off_t,
+    whence: c_int,
+    arg2: *mut fuse_file_info,
+) -> off_t {
+    let Some(file) = handle(arg2) else {
+        return NEXT.assume_init_ref().lseek.unwrap()(arg1, off, whence, arg2);
+    };
+    let len = file.content.len() as off_t;
+    match whence {
+        SEEK_DATA | SEEK_HOLE if off < 0 || off >= len => -(ENXIO as off_t),
+        // injected files have no holes
+        SEEK_HOLE => len,
+        _ => off,
+    }
+}
+
+/// Presents extra read-only files to the guest without touching the backing directory. `spec`
+/// holds one `guest_path=source` entry per line, where the source is either `file:` followed by
+/// a host path, read once when the layer is created, or `data:` followed by the content itself.
+/// An injected file hides a host file at the same path; its directory must exist on the host.
+///
+/// Injected files are served from memory, so they are meant for small files like
+/// `/etc/resolv.conf` overrides or license texts. Returns null if a source cannot be read.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer and a valid C string
+#[no_mangle]
+pub unsafe extern "C" fn new_inject_layer(
+    next: *const fuse_operations,
+    spec: *const c_char,
+) -> *const fuse_operations {
+    let mut files = BTreeMap::new();
+    for line in CStr::from_ptr(spec).to_bytes().split(|&c| c == b'\n') {
+        let Some(eq) = line.iter().position(|&c| c == b'=') else {
+            continue;
+        };
+        let (path, source) = (&line[..eq], &line[eq + 1..]);
+        let content = if let Some(host_path) = source.strip_prefix(b"file:") {
+            match fs::read(OsStr::from_bytes(host_path)) {
+                Ok(content) => content,
+                Err(err) => {
+                    eprintln!(
+                        "failed to inject {}: {err}",
+                        String::from_utf8_lossy(host_path)
+                    );
+                    return ptr::null();
+                }
+            }
+        } else if let Some(data) = source.strip_prefix(b"data:") {
+            data.to_vec()
+        } else {
+            eprintln!("unknown source for {}", String::from_utf8_lossy(path));
+            return ptr::null();
+        };
+        // count down from the top like the handles, away from the inode numbers of host files
+        let ino = u64::MAX - files.len() as u64;
+        files.insert(path.to_vec(), Injected { ino, content });
+    }
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    FILES = files;
+    Box::into_raw(Box::new(fuse_operations {
+        getattr: next.getattr.and(Some(getattr)),
+        mknod: next.mknod.and(Some(mknod)),
+        mkdir: next.mkdir.and(Some(mkdir)),
+        unlink: next.unlink.and(Some(unlink)),
+        symlink: next.symlink.and(Some(symlink)),
+        rename: next.rename.and(Some(rename)),
+        link: next.link.and(Some(link)),
+        chmod: next.chmod.and(Some(chmod)),
+        chown: next.chown.and(Some(chown)),
+        truncate: next.truncate.and(Some(truncate)),
+        open: next.open.and(Some(open)),
+        read: next.read.and(Some(read)),
+        flush: next.flush.and(Some(flush)),
+        release: Some(release),
+        fsync: next.fsync.and(Some(fsync)),
+        setxattr: next.setxattr.and(Some(setxattr)),
+        getxattr: next.getxattr.and(Some(getxattr)),
+        listxattr: next.listxattr.and(Some(listxattr)),
+        removexattr: next.removexattr.and(Some(removexattr)),
+        readdir: next.readdir.and(Some(readdir)),
+        access: next.access.and(Some(access)),
+        create: next.create.and(Some(create)),
+        utimens: next.utimens.and(Some(utimens)),
+        read_buf: next.read_buf.and(Some(read_buf)),
+        copy_file_range: next.copy_file_range.and(Some(copy_file_range)),
+        lseek: next.lseek.and(Some(lseek)),
+        ..next
+    }))
+}
@@ -0,0 +1,329 @@
+use crate::{
+    budget::{self, Consumer},
+    fuse::{
+        fuse_buf_flags_FUSE_BUF_IS_FD, fuse_buf_size, fuse_bufvec, fuse_file_info, fuse_operations,
+        mode_t, off_t, stat, ENODATA, ENOSYS, EPERM, ERANGE,
+    },
+};
+use std::{
+    collections::BTreeMap,
+    ffi::{c_char, c_int, CStr},
+    mem::{self, MaybeUninit},
+    ptr, slice,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+const NAME: &CStr = c"user.fsinterposer.stats";
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+/// Activity of every file opened through the mount, by device and inode number, as inode
+/// numbers repeat across the filesystems mounted below the shared directory.
+static STATS: Mutex<BTreeMap<File, Stats>> = Mutex::new(BTreeMap::new());
+/// The file behind each open handle.
+static HANDLES: Mutex<BTreeMap<u64, File>> = Mutex::new(BTreeMap::new());
+static MEMORY: Consumer = Consumer::new(c"iostats", evict);
+/// Rough memory held by an entry of `STATS`.
+const ENTRY: usize = mem::size_of::<(File, Stats)>();
+
+/// Device and inode number.
+type File = (u64, u64);
+
+#[derive(Default)]
+struct Stats {
+    opens: u64,
+    reads: u64,
+    read_bytes: u64,
+    writes: u64,
+    write_bytes: u64,
+    /// Seconds since the epoch of the last open, read or write.
+    last_access: u64,
+}
+
+/// Drops the stats of the files accessed longest ago.
+fn evict(bytes: usize) {
+    let mut stats = STATS.lock().unwrap();
+    let mut by_age: Vec<_> = stats
+        .iter()
+        .map(|(&file, stats)| (stats.last_access, file))
+        .collect();
+    by_age.sort_unstable();
+    let count = bytes.div_ceil(ENTRY).min(by_age.len());
+    for (_, file) in &by_age[..count] {
+        stats.remove(file);
+    }
+    MEMORY.release(count * ENTRY);
+}
+
+fn entry(stats: &mut BTreeMap<File, Stats>, file: File) -> &mut Stats {
+    stats.entry(file).or_insert_with(|| {
+        MEMORY.charge(ENTRY);
+        Stats::default()
+    })
+}
+
+unsafe fn getattr(path: *const c_char, fi: *mut fuse_file_info) -> Option<stat> {
+    let mut st = MaybeUninit::<stat>::zeroed();
+    match NEXT.assume_init_ref().getattr.unwrap()(path, st.as_mut_ptr(), fi) {
+        0 => Some(st.assume_init()),
+        _ => None,
+    }
+}
+
+unsafe fn file(path: *const c_char, fi: *mut fuse_file_info) -> Option<File> {
+    getattr(path, fi).map(|st| (st.st_dev, st.st_ino))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Counts a transfer of `res` bytes through the handle in `fi`, if it succeeded.
+unsafe fn record(fi: *mut fuse_file_info, res: isize, write: bool) {
+    if fi.is_null() || res < 0 {
+        return;
+    }
+    let Some(&file) = HANDLES.lock().unwrap().get(&(*fi).fh) else {
+        return;
+    };
+    let mut stats = STATS.lock().unwrap();
+    let stats = entry(&mut stats, file);
+    if write {
+        stats.writes += 1;
+        stats.write_bytes += res as u64;
+    } else {
+        stats.reads += 1;
+        stats.read_bytes += res as u64;
+    }
+    stats.last_access = now();
+}
+
+unsafe fn track(path: *const c_char, fi: *mut fuse_file_info) {
+    let Some(file) = file(path, fi) else {
+        return;
+    };
+    HANDLES.lock().unwrap().insert((*fi).fh, file);
+    {
+        let mut stats = STATS.lock().unwrap();
+        let stats = entry(&mut stats, file);
+        stats.opens += 1;
+        stats.last_access = now();
+    }
+    budget::enforce();
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let res = NEXT.assume_init_ref().open.unwrap()(arg1, arg2);
+    if res == 0 {
+        track(arg1, arg2);
+    }
+    res
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    let res = NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3);
+    if res == 0 {
+        track(arg1, arg3);
+    }
+    res
+}
+
+unsafe extern "C" fn read(
+    arg1: *const c_char,
+    arg2: *mut c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let res = NEXT.assume_init_ref().read.unwrap()(arg1, arg2, arg3, arg4, arg5);
+    record(arg5, res as isize, false);
+    res
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let res = NEXT.assume_init_ref().write.unwrap()(arg1, arg2, arg3, arg4, arg5);
+    record(arg5, res as isize, true);
+    res
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let res = NEXT.assume_init_ref().write_buf.unwrap()(arg1, buf, off, arg2);
+    record(arg2, res as isize, true);
+    res
+}
+
+unsafe extern "C" fn read_buf(
+    arg1: *const c_char,
+    bufp: *mut *mut fuse_bufvec,
+    size: usize,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let res = NEXT.assume_init_ref().read_buf.unwrap()(arg1, bufp, size, off, arg2);
+    if res == 0 {
+        record(arg2, read_size(arg1, *bufp, off, arg2) as isize, false);
+    }
+    res
+}
+
+/// Bytes a successful `read_buf` at `off` handed out in `bufv`. Buffers in memory hold just
+/// what was read, while those referring to a file descriptor give the size asked for, of
+/// which libfuse later copies to the guest what the file has up to its end.
+unsafe fn read_size(
+    path: *const c_char,
+    bufv: *const fuse_bufvec,
+    off: off_t,
+    fi: *mut fuse_file_info,
+) -> usize {
+    let size = fuse_buf_size(bufv);
+    let bufs = slice::from_raw_parts((*bufv).buf.as_ptr(), (*bufv).count);
+    if bufs
+        .iter()
+        .all(|buf| buf.flags & fuse_buf_flags_FUSE_BUF_IS_FD == 0)
+    {
+        return size;
+    }
+    match getattr(path, fi) {
+        Some(st) => size.min((st.st_size - off).max(0) as usize),
+        None => size,
+    }
+}
+
+unsafe extern "C" fn copy_file_range(
+    path_in: *const c_char,
+    fi_in: *mut fuse_file_info,
+    offset_in: off_t,
+    path_out: *const c_char,
+    fi_out: *mut fuse_file_info,
+    offset_out: off_t,
+    size: usize,
+    flags: c_int,
+) -> isize {
+    let res = NEXT.assume_init_ref().copy_file_range.unwrap()(
+        path_in, fi_in, offset_in, path_out, fi_out, offset_out, size, flags,
+    );
+    record(fi_in, res, false);
+    record(fi_out, res, true);
+    res
+}
+
+unsafe extern "C" fn release(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    HANDLES.lock().unwrap().remove(&(*arg2).fh);
+    match NEXT.assume_init_ref().release {
+        Some(release) => release(arg1, arg2),
+        None => 0,
+    }
+}
+
+unsafe extern "C" fn getxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *mut c_char,
+    arg4: usize,
+) -> c_int {
+    if CStr::from_ptr(arg2) != NAME {
+        return match NEXT.assume_init_ref().getxattr {
+            Some(getxattr) => getxattr(arg1, arg2, arg3, arg4),
+            None => -(ENOSYS as c_int),
+        };
+    }
+    let Some((dev, ino)) = file(arg1, ptr::null_mut()) else {
+        return -(ENODATA as c_int);
+    };
+    let info = {
+        let stats = STATS.lock().unwrap();
+        let default = Stats::default();
+        let stats = stats.get(&(dev, ino)).unwrap_or(&default);
+        format!(
+            "{{\"dev\":{dev},\"ino\":{ino},\"opens\":{},\"reads\":{},\"read_bytes\":{},\"writes\":{},\"write_bytes\":{},\"last_access\":{}}}",
+            stats.opens,
+            stats.reads,
+            stats.read_bytes,
+            stats.writes,
+            stats.write_bytes,
+            stats.last_access,
+        )
+    };
+    let info = info.as_bytes();
+    if arg4 != 0 {
+        if arg4 < info.len() {
+            return -(ERANGE as c_int);
+        }
+        slice::from_raw_parts_mut(arg3.cast::<u8>(), info.len()).copy_from_slice(info);
+    }
+    info.len() as c_int
+}
+
+unsafe extern "C" fn setxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *const c_char,
+    arg4: usize,
+    arg5: c_int,
+) -> c_int {
+    if CStr::from_ptr(arg2) == NAME {
+        return -(EPERM as c_int);
+    }
+    match NEXT.assume_init_ref().setxattr {
+        Some(setxattr) => setxattr(arg1, arg2, arg3, arg4, arg5),
+        None => -(ENOSYS as c_int),
+    }
+}
+
+unsafe extern "C" fn removexattr(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    if CStr::from_ptr(arg2) == NAME {
+        return -(ENODATA as c_int);
+    }
+    match NEXT.assume_init_ref().removexattr {
+        Some(removexattr) => removexattr(arg1, arg2),
+        None => -(ENOSYS as c_int),
+    }
+}
+
+/// Counts the opens, reads and writes made through the mount for every file and serves them as
+/// a JSON document through the `user.fsinterposer.stats` xattr, so debugging tools in the guest
+/// can see what the interposer did without access to the host. Files are told apart by device
+/// and inode number, and the time of the last access is in seconds since the epoch. The xattr can be read
+/// on any file but is not listed, so copies preserving xattrs do not try to carry it along.
+/// The stats count against the memory budget, and those of the files accessed longest ago are
+/// dropped first when it is exceeded.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer
+#[no_mangle]
+pub unsafe extern "C" fn new_iostats_layer(next: *const fuse_operations) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    if next.getattr.is_none() {
+        return Box::into_raw(Box::new(next));
+    }
+    Box::into_raw(Box::new(fuse_operations {
+        open: next.open.and(Some(open)),
+        read: next.read.and(Some(read)),
+        write: next.write.and(Some(write)),
+        release: Some(release),
+        setxattr: Some(setxattr),
+        getxattr: Some(getxattr),
+        removexattr: Some(removexattr),
+        create: next.create.and(Some(create)),
+        write_buf: next.write_buf.and(Some(write_buf)),
+        read_buf: next.read_buf.and(Some(read_buf)),
+        copy_file_range: next.copy_file_range.and(Some(copy_file_range)),
+        ..next
+    }))
+}
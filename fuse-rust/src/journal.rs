@@ -0,0 +1,621 @@
+use crate::{
+    control::Control,
+    fuse::{
+        dev_t, fuse_bufvec, fuse_config, fuse_conn_info, fuse_file_info, fuse_operations, gid_t,
+        mode_t, off_t, stat, timespec, uid_t, O_TRUNC, RENAME_EXCHANGE,
+    },
+    ocilayer::export_layer,
+    rmtree::{self, RmtreeRequest},
+};
+use std::{
+    collections::BTreeMap,
+    ffi::{c_char, c_int, c_uint, c_void, CStr, OsStr},
+    fs::{File, OpenOptions},
+    io::{self, BufReader, Read, Write},
+    mem::MaybeUninit,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    ptr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static LOG: Mutex<Option<File>> = Mutex::new(None);
+static mut LOG_PATH: PathBuf = PathBuf::new();
+/// The backing directory file contents are exported from, if known.
+static mut ROOT: Option<PathBuf> = None;
+static CONTROL: Control = Control::new();
+
+/// Operations recorded in the journal. The values are part of the on-disk format.
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum JournalOp {
+    Create = 1,
+    Mknod,
+    Mkdir,
+    Symlink,
+    Link,
+    Unlink,
+    Rmdir,
+    Rename,
+    Exchange,
+    Chmod,
+    Chown,
+    Utimens,
+    Truncate,
+    Write,
+    Fallocate,
+    Setxattr,
+    Removexattr,
+    /// Entries below the directory were removed by the passthrough's tree removal.
+    EmptyDir,
+}
+
+impl JournalOp {
+    fn from_u8(op: u8) -> Option<Self> {
+        use JournalOp::*;
+        [
+            Create,
+            Mknod,
+            Mkdir,
+            Symlink,
+            Link,
+            Unlink,
+            Rmdir,
+            Rename,
+            Exchange,
+            Chmod,
+            Chown,
+            Utimens,
+            Truncate,
+            Write,
+            Fallocate,
+            Setxattr,
+            Removexattr,
+            EmptyDir,
+        ]
+        .into_iter()
+        .find(|&candidate| candidate as u8 == op)
+    }
+}
+
+/// One successful mutating operation.
+pub struct JournalEntry {
+    pub op: JournalOp,
+    /// Nanoseconds since the epoch at which the operation completed.
+    pub time: u64,
+    pub path: Vec<u8>,
+    /// The new path for `Rename`, `Exchange` and `Link`, empty otherwise.
+    pub target: Vec<u8>,
+    /// How much the operation grew (or with a negative value shrank) the file.
+    pub size_delta: i64,
+}
+
+/// Appends an entry as a single write, so entries never interleave and a crash loses at most
+/// the last one. Layout: op (u8), time (u64), size delta (i64), then path and target, each
+/// as a u32 length followed by the bytes, all little endian.
+fn record(op: JournalOp, path: *const c_char, target: *const c_char, size_delta: i64) {
+    if path.is_null() {
+        return;
+    }
+    let path = unsafe { CStr::from_ptr(path) }.to_bytes();
+    let target = match target.is_null() {
+        true => &[][..],
+        false => unsafe { CStr::from_ptr(target) }.to_bytes(),
+    };
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let mut entry = Vec::with_capacity(29 + path.len() + target.len());
+    entry.push(op as u8);
+    entry.extend_from_slice(&time.to_le_bytes());
+    entry.extend_from_slice(&size_delta.to_le_bytes());
+    for name in [path, target] {
+        entry.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        entry.extend_from_slice(name);
+    }
+    if let Some(log) = LOG.lock().unwrap().as_mut() {
+        if let Err(err) = log.write_all(&entry) {
+            eprintln!("failed to write change journal: {err}");
+        }
+    }
+}
+
+unsafe fn size(path: *const c_char, fi: *mut fuse_file_info) -> i64 {
+    let mut st = MaybeUninit::<stat>::zeroed();
+    match NEXT.assume_init_ref().getattr {
+        Some(getattr) if getattr(path, st.as_mut_ptr(), fi) == 0 => st.assume_init().st_size,
+        _ => 0,
+    }
+}
+
+unsafe extern "C" fn mknod(arg1: *const c_char, arg2: mode_t, arg3: dev_t) -> c_int {
+    let res = NEXT.assume_init_ref().mknod.unwrap()(arg1, arg2, arg3);
+    if res == 0 {
+        record(JournalOp::Mknod, arg1, ptr::null(), 0);
+    }
+    res
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    let res = NEXT.assume_init_ref().mkdir.unwrap()(arg1, arg2);
+    if res == 0 {
+        record(JournalOp::Mkdir, arg1, ptr::null(), 0);
+    }
+    res
+}
+
+unsafe extern "C" fn unlink(arg1: *const c_char) -> c_int {
+    let size = size(arg1, ptr::null_mut());
+    let res = NEXT.assume_init_ref().unlink.unwrap()(arg1);
+    if res == 0 {
+        record(JournalOp::Unlink, arg1, ptr::null(), -size);
+    }
+    res
+}
+
+unsafe extern "C" fn rmdir(arg1: *const c_char) -> c_int {
+    let res = NEXT.assume_init_ref().rmdir.unwrap()(arg1);
+    if res == 0 {
+        record(JournalOp::Rmdir, arg1, ptr::null(), 0);
+    }
+    res
+}
+
+unsafe extern "C" fn symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let res = NEXT.assume_init_ref().symlink.unwrap()(arg1, arg2);
+    if res == 0 {
+        record(JournalOp::Symlink, arg2, ptr::null(), 0);
+    }
+    res
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    let res = NEXT.assume_init_ref().rename.unwrap()(arg1, arg2, flags);
+    if res == 0 {
+        let op = match flags & RENAME_EXCHANGE {
+            0 => JournalOp::Rename,
+            _ => JournalOp::Exchange,
+        };
+        record(op, arg1, arg2, 0);
+    }
+    res
+}
+
+unsafe extern "C" fn link(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let res = NEXT.assume_init_ref().link.unwrap()(arg1, arg2);
+    if res == 0 {
+        record(JournalOp::Link, arg1, arg2, 0);
+    }
+    res
+}
+
+unsafe extern "C" fn chmod(arg1: *const c_char, arg2: mode_t, fi: *mut fuse_file_info) -> c_int {
+    let res = NEXT.assume_init_ref().chmod.unwrap()(arg1, arg2, fi);
+    if res == 0 {
+        record(JournalOp::Chmod, arg1, ptr::null(), 0);
+    }
+    res
+}
+
+unsafe extern "C" fn chown(
+    arg1: *const c_char,
+    arg2: uid_t,
+    arg3: gid_t,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let res = NEXT.assume_init_ref().chown.unwrap()(arg1, arg2, arg3, fi);
+    if res == 0 {
+        record(JournalOp::Chown, arg1, ptr::null(), 0);
+    }
+    res
+}
+
+unsafe extern "C" fn truncate(arg1: *const c_char, arg2: off_t, fi: *mut fuse_file_info) -> c_int {
+    let before = size(arg1, fi);
+    let res = NEXT.assume_init_ref().truncate.unwrap()(arg1, arg2, fi);
+    if res == 0 {
+        record(JournalOp::Truncate, arg1, ptr::null(), arg2 - before);
+    }
+    res
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    if (*arg2).flags & O_TRUNC as c_int == 0 {
+        return NEXT.assume_init_ref().open.unwrap()(arg1, arg2);
+    }
+    let before = size(arg1, ptr::null_mut());
+    let res = NEXT.assume_init_ref().open.unwrap()(arg1, arg2);
+    if res == 0 {
+        record(JournalOp::Truncate, arg1, ptr::null(), -before);
+    }
+    res
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let before = size(arg1, arg5);
+    let res = NEXT.assume_init_ref().write.unwrap()(arg1, arg2, arg3, arg4, arg5);
+    if res > 0 {
+        let delta = (arg4 + res as off_t - before).max(0);
+        record(JournalOp::Write, arg1, ptr::null(), delta);
+    }
+    res
+}
+
+unsafe extern "C" fn setxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *const c_char,
+    arg4: usize,
+    arg5: c_int,
+) -> c_int {
+    let res = NEXT.assume_init_ref().setxattr.unwrap()(arg1, arg2, arg3, arg4, arg5);
+    if res == 0 {
+        record(JournalOp::Setxattr, arg1, ptr::null(), 0);
+    }
+    res
+}
+
+unsafe extern "C" fn removexattr(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let res = NEXT.assume_init_ref().removexattr.unwrap()(arg1, arg2);
+    if res == 0 {
+        record(JournalOp::Removexattr, arg1, ptr::null(), 0);
+    }
+    res
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    let res = NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3);
+    if res == 0 {
+        record(JournalOp::Create, arg1, ptr::null(), 0);
+    }
+    res
+}
+
+unsafe extern "C" fn utimens(
+    arg1: *const c_char,
+    tv: *const timespec,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let res = NEXT.assume_init_ref().utimens.unwrap()(arg1, tv, fi);
+    if res == 0 {
+        record(JournalOp::Utimens, arg1, ptr::null(), 0);
+    }
+    res
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let before = size(arg1, arg2);
+    let res = NEXT.assume_init_ref().write_buf.unwrap()(arg1, buf, off, arg2);
+    if res > 0 {
+        let delta = (off + res as off_t - before).max(0);
+        record(JournalOp::Write, arg1, ptr::null(), delta);
+    }
+    res
+}
+
+unsafe extern "C" fn fallocate(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: off_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let before = size(arg1, arg5);
+    let res = NEXT.assume_init_ref().fallocate.unwrap()(arg1, arg2, arg3, arg4, arg5);
+    if res == 0 {
+        let delta = size(arg1, arg5) - before;
+        record(JournalOp::Fallocate, arg1, ptr::null(), delta);
+    }
+    res
+}
+
+unsafe extern "C" fn copy_file_range(
+    path_in: *const c_char,
+    fi_in: *mut fuse_file_info,
+    offset_in: off_t,
+    path_out: *const c_char,
+    fi_out: *mut fuse_file_info,
+    offset_out: off_t,
+    size: usize,
+    flags: c_int,
+) -> isize {
+    let before = self::size(path_out, fi_out);
+    let res = NEXT.assume_init_ref().copy_file_range.unwrap()(
+        path_in, fi_in, offset_in, path_out, fi_out, offset_out, size, flags,
+    );
+    if res > 0 {
+        let delta = (offset_out + res as off_t - before).max(0);
+        record(JournalOp::Write, path_out, ptr::null(), delta);
+    }
+    res
+}
+
+fn read_name(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let mut name = vec![0; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut name)?;
+    Ok(name)
+}
+
+/// Reads back the journal at `log_path`. A truncated last entry, left by a crash, is ignored.
+pub fn read_journal(log_path: &Path) -> io::Result<Vec<JournalEntry>> {
+    let mut reader = BufReader::new(File::open(log_path)?);
+    let mut entries = Vec::new();
+    loop {
+        let mut header = [0; 17];
+        let entry = reader.read_exact(&mut header).and_then(|()| {
+            Ok(JournalEntry {
+                op: JournalOp::from_u8(header[0]).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "unknown journal op")
+                })?,
+                time: u64::from_le_bytes(header[1..9].try_into().unwrap()),
+                size_delta: i64::from_le_bytes(header[9..17].try_into().unwrap()),
+                path: read_name(&mut reader)?,
+                target: read_name(&mut reader)?,
+            })
+        });
+        match entry {
+            Ok(entry) => entries.push(entry),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(entries),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// How a path differs from what was there before the journal started.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Change {
+    /// The file or directory itself was created or modified.
+    Modified,
+    /// The path was removed.
+    Deleted,
+    /// Everything at and below the path was moved there, so the whole subtree is new.
+    Tree,
+}
+
+/// Whether `key` is `path` itself or lies below it.
+fn is_under(key: &[u8], path: &[u8]) -> bool {
+    key.strip_prefix(path)
+        .is_some_and(|rest| rest.is_empty() || rest[0] == b'/')
+}
+
+fn take_subtree(changes: &mut BTreeMap<Vec<u8>, Change>, path: &[u8]) -> Vec<(Vec<u8>, Change)> {
+    let keys: Vec<_> = changes
+        .keys()
+        .filter(|key| is_under(key, path))
+        .cloned()
+        .collect();
+    keys.into_iter()
+        .map(|key| {
+            let change = changes.remove(&key).unwrap();
+            (key[path.len()..].to_vec(), change)
+        })
+        .collect()
+}
+
+/// Folds journal entries into the set of paths that differ from the tree as it was when the
+/// journal started, the way a container image layer describes them.
+pub fn changed_paths(entries: &[JournalEntry]) -> BTreeMap<Vec<u8>, Change> {
+    let mut changes = BTreeMap::new();
+    for entry in entries {
+        match entry.op {
+            JournalOp::Unlink | JournalOp::Rmdir => {
+                take_subtree(&mut changes, &entry.path);
+                changes.insert(entry.path.clone(), Change::Deleted);
+            }
+            JournalOp::Rename | JournalOp::Exchange => {
+                let (from, to) = (&entry.path, &entry.target);
+                let moved = take_subtree(&mut changes, from);
+                let replaced = take_subtree(&mut changes, to);
+                changes.insert(to.clone(), Change::Tree);
+                for (suffix, change) in moved.into_iter().filter(|(suffix, _)| !suffix.is_empty()) {
+                    changes.insert([to, &suffix[..]].concat(), change);
+                }
+                if entry.op == JournalOp::Exchange {
+                    changes.insert(from.clone(), Change::Tree);
+                    for (suffix, change) in replaced {
+                        if !suffix.is_empty() {
+                            changes.insert([from, &suffix[..]].concat(), change);
+                        }
+                    }
+                } else {
+                    changes.insert(from.clone(), Change::Deleted);
+                }
+            }
+            JournalOp::EmptyDir => {
+                // the names removed are not known, so the directory as a whole is new
+                take_subtree(&mut changes, &entry.path);
+                changes.insert(entry.path.clone(), Change::Tree);
+            }
+            JournalOp::Link => {
+                changes.insert(entry.target.clone(), Change::Modified);
+            }
+            _ => {
+                // a subtree moved here stays a subtree
+                changes
+                    .entry(entry.path.clone())
+                    .and_modify(|change| {
+                        if *change == Change::Deleted {
+                            *change = Change::Modified;
+                        }
+                    })
+                    .or_insert(Change::Modified);
+            }
+        }
+    }
+    changes
+}
+
+/// Writes the paths changed according to the journal at `log_path` to `out`, one per line
+/// prefixed with `M ` (modified), `D ` (deleted) or `T ` (whole subtree new).
+pub fn export_changes(log_path: &Path, out: &mut impl Write) -> io::Result<()> {
+    let changes = changed_paths(&read_journal(log_path)?);
+    for (path, change) in changes {
+        out.write_all(match change {
+            Change::Modified => b"M ",
+            Change::Deleted => b"D ",
+            Change::Tree => b"T ",
+        })?;
+        out.write_all(&path)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()
+}
+
+/// Writes the paths changed according to the journal at `log_path` to `out_path` like
+/// [`export_changes`]. The `journal-export` binary does the same from the command line.
+///
+/// # Safety
+///
+/// This function must be called with valid C strings
+#[no_mangle]
+pub unsafe extern "C" fn journal_export_changes(
+    log_path: *const c_char,
+    out_path: *const c_char,
+) -> c_int {
+    let log_path = CStr::from_ptr(log_path).to_string_lossy().into_owned();
+    let out_path = CStr::from_ptr(out_path).to_string_lossy().into_owned();
+    let result = File::create(&out_path)
+        .and_then(|out| export_changes(Path::new(&log_path), &mut io::BufWriter::new(out)));
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("failed to export changes from {log_path}: {err}");
+            -1
+        }
+    }
+}
+
+/// Answers `export-changes <path>` and `export-layer <path>` commands on the control socket
+/// with `ok` or `error <message>` once the list of changed paths or the OCI layer tarball has
+/// been written to the host file at `path`.
+fn command(line: &[u8]) -> String {
+    let log_path = unsafe { &LOG_PATH };
+    let result = if let Some(out_path) = line.strip_prefix(b"export-changes ") {
+        File::create(OsStr::from_bytes(out_path))
+            .and_then(|out| export_changes(log_path, &mut io::BufWriter::new(out)))
+    } else if let Some(out_path) = line.strip_prefix(b"export-layer ") {
+        let Some(root) = (unsafe { ROOT.as_ref() }) else {
+            return "error no backing directory to export from\n".to_string();
+        };
+        File::create(OsStr::from_bytes(out_path)).and_then(|out| export_layer(log_path, root, out))
+    } else {
+        return "error unknown command\n".to_string();
+    };
+    match result {
+        Ok(()) => "ok\n".to_string(),
+        Err(err) => format!("error {err}\n"),
+    }
+}
+
+unsafe extern "C" fn init(conn: *mut fuse_conn_info, cfg: *mut fuse_config) -> *mut c_void {
+    CONTROL.start(command);
+    match NEXT.assume_init_ref().init {
+        Some(init) => init(conn, cfg),
+        None => ptr::null_mut(),
+    }
+}
+
+unsafe extern "C" fn destroy(private_data: *mut c_void) {
+    CONTROL.stop();
+    if let Some(destroy) = NEXT.assume_init_ref().destroy {
+        destroy(private_data);
+    }
+}
+
+unsafe extern "C" fn ioctl(
+    arg1: *const c_char,
+    cmd: c_int,
+    arg: *mut c_void,
+    arg2: *mut fuse_file_info,
+    flags: c_uint,
+    data: *mut c_void,
+) -> c_int {
+    let res = NEXT.assume_init_ref().ioctl.unwrap()(arg1, cmd, arg, arg2, flags, data);
+    if res == 0 && rmtree::is_rmtree(cmd) && (*data.cast::<RmtreeRequest>()).removed > 0 {
+        record(JournalOp::EmptyDir, arg1, ptr::null(), 0);
+    }
+    res
+}
+
+/// Appends every successful mutating operation to the change journal at `log_path`, so the
+/// paths a container modified can be listed later with [`journal_export_changes`] or the
+/// `journal-export` binary without scanning the whole tree.
+///
+/// Size deltas of writes, truncates, `O_TRUNC` opens and fallocates cost an extra getattr each.
+/// Returns null if the journal cannot be opened.
+///
+/// When `control_socket` is not null, `export-changes <path>` and `export-layer <path>` lines on
+/// that unix socket write the list of changed paths, or an OCI layer tarball like
+/// [`journal_export_layer`](crate::ocilayer::journal_export_layer) makes with file contents
+/// taken from the backing directory `root`, to a file on the host.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer, a valid C string log path and
+/// valid C strings or null root and control socket
+#[no_mangle]
+pub unsafe extern "C" fn new_journal_layer(
+    next: *const fuse_operations,
+    log_path: *const c_char,
+    root: *const c_char,
+    control_socket: *const c_char,
+) -> *const fuse_operations {
+    let log_path = OsStr::from_bytes(CStr::from_ptr(log_path).to_bytes());
+    match OpenOptions::new().create(true).append(true).open(log_path) {
+        Ok(log) => *LOG.lock().unwrap() = Some(log),
+        Err(err) => {
+            eprintln!(
+                "failed to open change journal {}: {err}",
+                log_path.to_string_lossy()
+            );
+            return ptr::null();
+        }
+    }
+    LOG_PATH = PathBuf::from(log_path);
+    ROOT = (!root.is_null())
+        .then(|| PathBuf::from(OsStr::from_bytes(CStr::from_ptr(root).to_bytes())));
+    CONTROL.set(control_socket);
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    Box::into_raw(Box::new(fuse_operations {
+        init: Some(init),
+        destroy: Some(destroy),
+        mknod: next.mknod.and(Some(mknod)),
+        mkdir: next.mkdir.and(Some(mkdir)),
+        unlink: next.unlink.and(Some(unlink)),
+        rmdir: next.rmdir.and(Some(rmdir)),
+        symlink: next.symlink.and(Some(symlink)),
+        rename: next.rename.and(Some(rename)),
+        link: next.link.and(Some(link)),
+        chmod: next.chmod.and(Some(chmod)),
+        chown: next.chown.and(Some(chown)),
+        truncate: next.truncate.and(Some(truncate)),
+        open: next.open.and(Some(open)),
+        write: next.write.and(Some(write)),
+        setxattr: next.setxattr.and(Some(setxattr)),
+        removexattr: next.removexattr.and(Some(removexattr)),
+        create: next.create.and(Some(create)),
+        utimens: next.utimens.and(Some(utimens)),
+        write_buf: next.write_buf.and(Some(write_buf)),
+        fallocate: next.fallocate.and(Some(fallocate)),
+        copy_file_range: next.copy_file_range.and(Some(copy_file_range)),
+        ioctl: next.ioctl.and(Some(ioctl)),
+        ..next
+    }))
+}
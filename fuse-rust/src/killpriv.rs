@@ -0,0 +1,126 @@
+use crate::fuse::{
+    fuse_bufvec, fuse_file_info, fuse_get_context, fuse_operations, off_t, stat, ENODATA,
+};
+use std::{
+    ffi::{c_char, c_int, CStr},
+    mem::MaybeUninit,
+    ptr,
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut POLICY: KillprivPolicy = KillprivPolicy::Always;
+
+/// When this layer drops `security.capability` from a file that is being modified.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum KillprivPolicy {
+    /// Drop capabilities on every write, truncate and fallocate, like the kernel does.
+    Always,
+    /// Only drop capabilities when the file is modified by someone other than its owner.
+    NonOwner,
+    /// Never drop capabilities here, leaving it to the filesystems below.
+    Never,
+}
+
+const CAPABILITY: &CStr = c"security.capability";
+
+/// Drops the capabilities of the file at `path` if it has any and the policy says so. A
+/// removal the layers below refuse, for lack of `CAP_SETFCAP` say, is logged and does not fail
+/// the modification.
+unsafe fn kill_priv(path: *const c_char, fi: *mut fuse_file_info) {
+    let next = NEXT.assume_init_ref();
+    let (Some(getxattr), Some(removexattr)) = (next.getxattr, next.removexattr) else {
+        return;
+    };
+    match POLICY {
+        KillprivPolicy::Always => (),
+        KillprivPolicy::NonOwner => {
+            let context = fuse_get_context();
+            let mut st = MaybeUninit::<stat>::zeroed();
+            // without a request to go by, there is no one to tell apart from the owner
+            if context.is_null() || next.getattr.unwrap()(path, st.as_mut_ptr(), fi) < 0 {
+                return;
+            }
+            if st.assume_init().st_uid == (*context).uid {
+                return;
+            }
+        }
+        KillprivPolicy::Never => return,
+    }
+    // most files have no capabilities, and asking is allowed where removing may not be
+    if getxattr(path, CAPABILITY.as_ptr(), ptr::null_mut(), 0) < 0 {
+        return;
+    }
+    match removexattr(path, CAPABILITY.as_ptr()) {
+        0 => (),
+        res if res == -(ENODATA as c_int) => (),
+        res => eprintln!(
+            "failed to drop capabilities of {}: error {}",
+            CStr::from_ptr(path).to_string_lossy(),
+            -res
+        ),
+    }
+}
+
+unsafe extern "C" fn truncate(arg1: *const c_char, arg2: off_t, fi: *mut fuse_file_info) -> c_int {
+    kill_priv(arg1, fi);
+    NEXT.assume_init_ref().truncate.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    kill_priv(arg1, arg5);
+    NEXT.assume_init_ref().write.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    kill_priv(arg1, arg2);
+    NEXT.assume_init_ref().write_buf.unwrap()(arg1, buf, off, arg2)
+}
+
+unsafe extern "C" fn fallocate(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: off_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    kill_priv(arg1, arg5);
+    NEXT.assume_init_ref().fallocate.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+/// Drops the `security.capability` xattr from files modified through the mount according to
+/// `policy`, for filesystems below that do not do so themselves. Files without capabilities
+/// are left alone, and modifications go through even if the capabilities cannot be dropped.
+/// The policy only governs this layer: with `NonOwner` or `Never`, a kernel below that drops
+/// capabilities on writes, like the host's under the passthrough, still does.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer
+#[no_mangle]
+pub unsafe extern "C" fn new_killpriv_layer(
+    next: *const fuse_operations,
+    policy: KillprivPolicy,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    POLICY = policy;
+    Box::into_raw(Box::new(fuse_operations {
+        truncate: next.truncate.and(Some(truncate)),
+        write: next.write.and(Some(write)),
+        write_buf: next.write_buf.and(Some(write_buf)),
+        fallocate: next.fallocate.and(Some(fallocate)),
+        ..next
+    }))
+}
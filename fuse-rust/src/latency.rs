@@ -0,0 +1,309 @@
+use crate::fuse::{
+    dev_t, fuse_buf_size, fuse_bufvec, fuse_file_info, fuse_fill_dir_t, fuse_operations,
+    fuse_readdir_flags, gid_t, mode_t, off_t, stat, timespec, uid_t,
+};
+use std::{
+    ffi::{c_char, c_int, c_uint, c_void},
+    mem::MaybeUninit,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut PROFILE: Profile = LatencyProfile::Ssd.profile();
+static RNG: AtomicU64 = AtomicU64::new(0);
+/// Point in time at which the emulated link finishes transferring all data queued so far.
+static LINK_BUSY_UNTIL: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Storage classes whose performance characteristics can be emulated.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub enum LatencyProfile {
+    /// Spinning disk: a seek per request and moderate sequential bandwidth.
+    Hdd,
+    /// Local SSD: sub-millisecond requests and high bandwidth.
+    Ssd,
+    /// Network filesystem across a WAN link: round trips dominate.
+    NfsWan,
+    /// Object storage: cheap listings but a long time to first byte.
+    ColdObjectStore,
+}
+
+#[derive(Clone, Copy)]
+struct Profile {
+    /// Mean latency of requests that do not transfer file data.
+    metadata: Duration,
+    /// Mean latency until the first byte of a read or write.
+    data: Duration,
+    /// Bandwidth of the link shared by all reads and writes, in bytes per second.
+    bandwidth: u64,
+}
+
+impl LatencyProfile {
+    const fn profile(self) -> Profile {
+        match self {
+            Self::Hdd => Profile {
+                metadata: Duration::from_millis(4),
+                data: Duration::from_millis(8),
+                bandwidth: 150_000_000,
+            },
+            Self::Ssd => Profile {
+                metadata: Duration::from_micros(100),
+                data: Duration::from_micros(150),
+                bandwidth: 500_000_000,
+            },
+            Self::NfsWan => Profile {
+                metadata: Duration::from_millis(40),
+                data: Duration::from_millis(60),
+                bandwidth: 12_500_000,
+            },
+            Self::ColdObjectStore => Profile {
+                metadata: Duration::from_millis(30),
+                data: Duration::from_millis(200),
+                bandwidth: 50_000_000,
+            },
+        }
+    }
+}
+
+/// Uniformly distributed number in `(0, 1]`, from a xorshift generator shared by all threads.
+fn uniform() -> f64 {
+    let mut x = RNG.load(Ordering::Relaxed);
+    loop {
+        let mut next = x;
+        next ^= next << 13;
+        next ^= next >> 7;
+        next ^= next << 17;
+        match RNG.compare_exchange_weak(x, next, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return ((next >> 11) + 1) as f64 / (1u64 << 53) as f64,
+            Err(current) => x = current,
+        }
+    }
+}
+
+/// Sleeps for a latency with the given mean: half of it is fixed and the other half exponentially
+/// distributed, giving the long tail typical of real storage.
+fn wait(mean: Duration) {
+    let half = mean / 2;
+    thread::sleep(half + half.mul_f64(-uniform().ln()));
+}
+
+/// Waits for the first byte and then for `size` bytes to go through the shared link.
+unsafe fn transfer(size: usize) {
+    wait(PROFILE.data);
+    let duration = Duration::from_secs_f64(size as f64 / PROFILE.bandwidth as f64);
+    let done = {
+        let mut busy_until = LINK_BUSY_UNTIL.lock().unwrap();
+        let start = busy_until.map_or(Instant::now(), |t| t.max(Instant::now()));
+        *busy_until.insert(start + duration)
+    };
+    thread::sleep(done.saturating_duration_since(Instant::now()));
+}
+
+unsafe fn metadata() {
+    wait(PROFILE.metadata);
+}
+
+unsafe extern "C" fn getattr(
+    arg1: *const c_char,
+    arg2: *mut stat,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    metadata();
+    NEXT.assume_init_ref().getattr.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn readlink(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    metadata();
+    NEXT.assume_init_ref().readlink.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn mknod(arg1: *const c_char, arg2: mode_t, arg3: dev_t) -> c_int {
+    metadata();
+    NEXT.assume_init_ref().mknod.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    metadata();
+    NEXT.assume_init_ref().mkdir.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn unlink(arg1: *const c_char) -> c_int {
+    metadata();
+    NEXT.assume_init_ref().unlink.unwrap()(arg1)
+}
+
+unsafe extern "C" fn rmdir(arg1: *const c_char) -> c_int {
+    metadata();
+    NEXT.assume_init_ref().rmdir.unwrap()(arg1)
+}
+
+unsafe extern "C" fn symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    metadata();
+    NEXT.assume_init_ref().symlink.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    metadata();
+    NEXT.assume_init_ref().rename.unwrap()(arg1, arg2, flags)
+}
+
+unsafe extern "C" fn link(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    metadata();
+    NEXT.assume_init_ref().link.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn chmod(arg1: *const c_char, arg2: mode_t, fi: *mut fuse_file_info) -> c_int {
+    metadata();
+    NEXT.assume_init_ref().chmod.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn chown(
+    arg1: *const c_char,
+    arg2: uid_t,
+    arg3: gid_t,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    metadata();
+    NEXT.assume_init_ref().chown.unwrap()(arg1, arg2, arg3, fi)
+}
+
+unsafe extern "C" fn truncate(arg1: *const c_char, arg2: off_t, fi: *mut fuse_file_info) -> c_int {
+    metadata();
+    NEXT.assume_init_ref().truncate.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    metadata();
+    NEXT.assume_init_ref().open.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn read(
+    arg1: *const c_char,
+    arg2: *mut c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    transfer(arg3);
+    NEXT.assume_init_ref().read.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    transfer(arg3);
+    NEXT.assume_init_ref().write.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn fsync(arg1: *const c_char, arg2: c_int, arg3: *mut fuse_file_info) -> c_int {
+    metadata();
+    NEXT.assume_init_ref().fsync.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn opendir(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    metadata();
+    NEXT.assume_init_ref().opendir.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn readdir(
+    arg1: *const c_char,
+    arg2: *mut c_void,
+    arg3: fuse_fill_dir_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+    arg6: fuse_readdir_flags,
+) -> c_int {
+    metadata();
+    NEXT.assume_init_ref().readdir.unwrap()(arg1, arg2, arg3, arg4, arg5, arg6)
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    metadata();
+    NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn utimens(
+    arg1: *const c_char,
+    tv: *const timespec,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    metadata();
+    NEXT.assume_init_ref().utimens.unwrap()(arg1, tv, fi)
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    transfer(fuse_buf_size(buf));
+    NEXT.assume_init_ref().write_buf.unwrap()(arg1, buf, off, arg2)
+}
+
+unsafe extern "C" fn read_buf(
+    arg1: *const c_char,
+    bufp: *mut *mut fuse_bufvec,
+    size: usize,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    transfer(size);
+    NEXT.assume_init_ref().read_buf.unwrap()(arg1, bufp, size, off, arg2)
+}
+
+/// Delays requests to mimic the latency distribution and bandwidth of the storage class described
+/// by `profile`.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer
+#[no_mangle]
+pub unsafe extern "C" fn new_latency_layer(
+    next: *const fuse_operations,
+    profile: LatencyProfile,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    PROFILE = profile.profile();
+    let seed = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64;
+    // xorshift never leaves the all-zero state
+    RNG.store(seed | 1, Ordering::Relaxed);
+    Box::into_raw(Box::new(fuse_operations {
+        getattr: next.getattr.and(Some(getattr)),
+        readlink: next.readlink.and(Some(readlink)),
+        mknod: next.mknod.and(Some(mknod)),
+        mkdir: next.mkdir.and(Some(mkdir)),
+        unlink: next.unlink.and(Some(unlink)),
+        rmdir: next.rmdir.and(Some(rmdir)),
+        symlink: next.symlink.and(Some(symlink)),
+        rename: next.rename.and(Some(rename)),
+        link: next.link.and(Some(link)),
+        chmod: next.chmod.and(Some(chmod)),
+        chown: next.chown.and(Some(chown)),
+        truncate: next.truncate.and(Some(truncate)),
+        open: next.open.and(Some(open)),
+        read: next.read.and(Some(read)),
+        write: next.write.and(Some(write)),
+        fsync: next.fsync.and(Some(fsync)),
+        opendir: next.opendir.and(Some(opendir)),
+        readdir: next.readdir.and(Some(readdir)),
+        create: next.create.and(Some(create)),
+        utimens: next.utimens.and(Some(utimens)),
+        write_buf: next.write_buf.and(Some(write_buf)),
+        read_buf: next.read_buf.and(Some(read_buf)),
+        ..next
+    }))
+}
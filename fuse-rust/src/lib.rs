@@ -1,3 +1,10 @@
+//! Layers that interpose on the operations of a FUSE filesystem. Each `new_<name>_layer`
+//! constructor takes the `fuse_operations` of the filesystem below it and returns the table to
+//! hand to libfuse, or to the next layer up, so a stack is built by chaining constructors from
+//! the backing filesystem upwards. They are exported for C callers and, through the rlib, for
+//! Rust projects embedding the interposer. Layers keep their state in statics, so each one can
+//! appear at most once per process.
+
 pub mod fuse {
     #![allow(non_upper_case_globals)]
     #![allow(non_camel_case_types)]
@@ -7,4 +14,64 @@ pub mod fuse {
     include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 }
 
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("the jemalloc and mimalloc features are mutually exclusive");
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static ALLOCATOR: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static ALLOCATOR: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+pub mod alias;
+pub mod attest;
+pub mod backup;
+pub mod budget;
+pub mod congestion;
+pub mod control;
+pub mod dirmtime;
+pub mod dryrun;
+pub mod errmap;
+pub mod filesize;
+pub mod freeze;
+pub mod fsyncbatch;
+pub mod handlelimit;
+pub mod hide;
+pub mod hooks;
+pub mod idle;
+pub mod inject;
+pub mod iostats;
+pub mod journal;
+pub mod killpriv;
+pub mod latency;
+pub mod lowspace;
+pub mod mountinfo;
+pub mod namelen;
+pub mod nlink;
 pub mod nop;
+pub mod ocilayer;
+pub mod oplimit;
+pub mod peer;
+pub mod prefetch;
+pub mod prefix;
+pub mod priority;
+pub mod probe;
+pub mod readdirplus;
+pub mod rebind;
+pub mod replicate;
+pub mod rmtree;
+pub mod scan;
+pub mod shadow;
+pub mod sharemode;
+pub mod shmcache;
+pub mod slo;
+pub mod special;
+pub mod symlink;
+pub mod timegran;
+pub mod trim;
+pub mod typeblock;
+pub mod utf8;
+pub mod xattrlimit;
+pub mod xattrstore;
@@ -0,0 +1,204 @@
+use crate::fuse::{
+    dev_t, fuse_buf_size, fuse_bufvec, fuse_file_info, fuse_operations, mode_t, off_t, stat,
+    statvfs, ENOSPC, FALLOC_FL_PUNCH_HOLE, O_APPEND,
+};
+use std::{
+    ffi::{c_char, c_int},
+    mem::MaybeUninit,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How long a free space reading is trusted before asking the backing filesystem again.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut MIN_FREE_BYTES: u64 = 0;
+/// When free space was last checked, and whether it was below the threshold then.
+static STATE: Mutex<Option<(Instant, bool)>> = Mutex::new(None);
+
+/// Whether the backing filesystem has less than the configured space left for unprivileged
+/// users, checking at most once per `CHECK_INTERVAL`.
+unsafe fn low_on_space() -> bool {
+    let mut state = STATE.lock().unwrap();
+    if let Some((checked, low)) = *state {
+        if checked.elapsed() < CHECK_INTERVAL {
+            return low;
+        }
+    }
+    let mut st = MaybeUninit::<statvfs>::zeroed();
+    let low = match NEXT.assume_init_ref().statfs.unwrap()(c"/".as_ptr(), st.as_mut_ptr()) {
+        0 => {
+            let st = st.assume_init();
+            let free = st.f_bavail.saturating_mul(st.f_frsize);
+            let low = free < MIN_FREE_BYTES;
+            let was_low = state.is_some_and(|(_, low)| low);
+            if low && !was_low {
+                eprintln!("backing store has {free} bytes left, failing writes with ENOSPC");
+            } else if !low && was_low {
+                eprintln!("backing store has {free} bytes left again, accepting writes");
+            }
+            low
+        }
+        // keep the previous verdict rather than guessing
+        res => {
+            eprintln!("failed to check free space: {res}");
+            state.is_some_and(|(_, low)| low)
+        }
+    };
+    *state = Some((Instant::now(), low));
+    low
+}
+
+/// Whether writing up to `end` through `fi` makes the file larger, which it does for appends
+/// and, to be safe, when its size cannot be told.
+unsafe fn extends(path: *const c_char, fi: *mut fuse_file_info, end: off_t) -> bool {
+    if !fi.is_null() && (*fi).flags & O_APPEND as c_int != 0 {
+        return true;
+    }
+    let mut st = MaybeUninit::<stat>::zeroed();
+    match NEXT.assume_init_ref().getattr {
+        Some(getattr) if getattr(path, st.as_mut_ptr(), fi) == 0 => end > st.assume_init().st_size,
+        _ => true,
+    }
+}
+
+/// Fails a write ending at `end` with `ENOSPC` if space is low and it would grow the file.
+unsafe fn check_write(path: *const c_char, fi: *mut fuse_file_info, end: off_t) -> c_int {
+    if low_on_space() && extends(path, fi, end) {
+        return -(ENOSPC as c_int);
+    }
+    0
+}
+
+unsafe extern "C" fn mknod(arg1: *const c_char, arg2: mode_t, arg3: dev_t) -> c_int {
+    if low_on_space() {
+        return -(ENOSPC as c_int);
+    }
+    NEXT.assume_init_ref().mknod.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    if low_on_space() {
+        return -(ENOSPC as c_int);
+    }
+    NEXT.assume_init_ref().mkdir.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    if low_on_space() {
+        return -(ENOSPC as c_int);
+    }
+    NEXT.assume_init_ref().symlink.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let res = check_write(arg1, arg5, arg4.saturating_add(arg3 as off_t));
+    if res != 0 {
+        return res;
+    }
+    NEXT.assume_init_ref().write.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn setxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *const c_char,
+    arg4: usize,
+    arg5: c_int,
+) -> c_int {
+    if low_on_space() {
+        return -(ENOSPC as c_int);
+    }
+    NEXT.assume_init_ref().setxattr.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    if low_on_space() {
+        return -(ENOSPC as c_int);
+    }
+    NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let res = check_write(arg1, arg2, off.saturating_add(fuse_buf_size(buf) as off_t));
+    if res != 0 {
+        return res;
+    }
+    NEXT.assume_init_ref().write_buf.unwrap()(arg1, buf, off, arg2)
+}
+
+unsafe extern "C" fn fallocate(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: off_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    // punching holes only ever frees space, anything else may take more of it
+    if arg2 & FALLOC_FL_PUNCH_HOLE as c_int == 0 && low_on_space() {
+        return -(ENOSPC as c_int);
+    }
+    NEXT.assume_init_ref().fallocate.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn copy_file_range(
+    path_in: *const c_char,
+    fi_in: *mut fuse_file_info,
+    offset_in: off_t,
+    path_out: *const c_char,
+    fi_out: *mut fuse_file_info,
+    offset_out: off_t,
+    size: usize,
+    flags: c_int,
+) -> isize {
+    let res = check_write(path_out, fi_out, offset_out.saturating_add(size as off_t));
+    if res != 0 {
+        return res as isize;
+    }
+    NEXT.assume_init_ref().copy_file_range.unwrap()(
+        path_in, fi_in, offset_in, path_out, fi_out, offset_out, size, flags,
+    )
+}
+
+/// Fails writes growing files and the creation of new files with `ENOSPC` once less than
+/// `min_free_bytes` are available on the backing filesystem, so a container filling its volume
+/// runs out of space before the host does. Writes within the current size of a file, hole
+/// punching, truncation and deletion keep working so the guest can free space again, while
+/// appends, writes past the end and other fallocate modes are refused.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer whose statfs is set
+#[no_mangle]
+pub unsafe extern "C" fn new_lowspace_layer(
+    next: *const fuse_operations,
+    min_free_bytes: u64,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    MIN_FREE_BYTES = min_free_bytes;
+    Box::into_raw(Box::new(fuse_operations {
+        mknod: next.mknod.and(Some(mknod)),
+        mkdir: next.mkdir.and(Some(mkdir)),
+        symlink: next.symlink.and(Some(symlink)),
+        write: next.write.and(Some(write)),
+        setxattr: next.setxattr.and(Some(setxattr)),
+        create: next.create.and(Some(create)),
+        write_buf: next.write_buf.and(Some(write_buf)),
+        fallocate: next.fallocate.and(Some(fallocate)),
+        copy_file_range: next.copy_file_range.and(Some(copy_file_range)),
+        ..next
+    }))
+}
@@ -0,0 +1,130 @@
+use crate::fuse::{fuse_operations, ENODATA, ENOSYS, ERANGE};
+use std::{
+    ffi::{c_char, c_int, CStr},
+    fmt::Write,
+    mem::MaybeUninit,
+    slice,
+};
+
+const NAME: &CStr = c"user.fsinterposer.mount";
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut INFO: String = String::new();
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// 64-bit FNV-1a, which unlike the std hashers is guaranteed to stay the same across releases.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+unsafe fn is_root(path: *const c_char) -> bool {
+    CStr::from_ptr(path).to_bytes() == b"/"
+}
+
+unsafe extern "C" fn getxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *mut c_char,
+    arg4: usize,
+) -> c_int {
+    if !is_root(arg1) || CStr::from_ptr(arg2) != NAME {
+        return match NEXT.assume_init_ref().getxattr {
+            Some(getxattr) => getxattr(arg1, arg2, arg3, arg4),
+            None => -(ENOSYS as c_int),
+        };
+    }
+    let info = INFO.as_bytes();
+    if arg4 != 0 {
+        if arg4 < info.len() {
+            return -(ERANGE as c_int);
+        }
+        slice::from_raw_parts_mut(arg3.cast::<u8>(), info.len()).copy_from_slice(info);
+    }
+    info.len() as c_int
+}
+
+unsafe extern "C" fn listxattr(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    let res = match NEXT.assume_init_ref().listxattr {
+        Some(listxattr) => listxattr(arg1, arg2, arg3),
+        None => 0,
+    };
+    if !is_root(arg1) {
+        return res;
+    }
+    let len = match res {
+        res if res >= 0 => res as usize,
+        res if res == -(ERANGE as c_int) => return res,
+        _ => 0,
+    };
+    let name = NAME.to_bytes_with_nul();
+    if arg3 != 0 {
+        if arg3 < len + name.len() {
+            return -(ERANGE as c_int);
+        }
+        slice::from_raw_parts_mut(arg2.cast::<u8>().add(len), name.len()).copy_from_slice(name);
+    }
+    (len + name.len()) as c_int
+}
+
+unsafe extern "C" fn removexattr(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    if is_root(arg1) && CStr::from_ptr(arg2) == NAME {
+        return -(ENODATA as c_int);
+    }
+    match NEXT.assume_init_ref().removexattr {
+        Some(removexattr) => removexattr(arg1, arg2),
+        None => -(ENOSYS as c_int),
+    }
+}
+
+/// Describes the mount in a JSON document readable through the `user.fsinterposer.mount` xattr
+/// on the root directory, so agents in the container can tell which configuration they are
+/// running on top of. `layers` is a comma separated list of the layers making up the stack; the
+/// backing path itself is only exposed as a hash.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer and valid C strings
+#[no_mangle]
+pub unsafe extern "C" fn new_mountinfo_layer(
+    next: *const fuse_operations,
+    tag: *const c_char,
+    backing_path: *const c_char,
+    layers: *const c_char,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    let layers = CStr::from_ptr(layers).to_string_lossy();
+    INFO = format!(
+        "{{\"tag\":{},\"backing_path_hash\":\"{:016x}\",\"version\":{},\"layers\":[{}]}}",
+        json_string(&CStr::from_ptr(tag).to_string_lossy()),
+        fnv1a(CStr::from_ptr(backing_path).to_bytes()),
+        json_string(env!("CARGO_PKG_VERSION")),
+        layers
+            .split(',')
+            .filter(|layer| !layer.is_empty())
+            .map(json_string)
+            .collect::<Vec<_>>()
+            .join(","),
+    );
+    Box::into_raw(Box::new(fuse_operations {
+        getxattr: Some(getxattr),
+        listxattr: Some(listxattr),
+        removexattr: Some(removexattr),
+        ..next
+    }))
+}
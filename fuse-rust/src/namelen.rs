@@ -0,0 +1,269 @@
+use crate::fuse::{
+    dev_t, fuse_file_info, fuse_operations, gid_t, mode_t, off_t, stat, statvfs, timespec, uid_t,
+    ENAMETOOLONG,
+};
+use std::{
+    ffi::{c_char, c_int, c_uint, CStr},
+    mem::MaybeUninit,
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut NAME_MAX: usize = 255;
+static mut PATH_MAX: usize = 4096;
+
+/// Refuses paths the backing filesystem or the host could not take, before any layer below
+/// builds a longer path from them: paths of `PATH_MAX` bytes or more, counting the terminating
+/// null like the kernel does, and components longer than `NAME_MAX` bytes.
+unsafe fn check(path: *const c_char) -> Result<(), c_int> {
+    if path.is_null() {
+        return Ok(());
+    }
+    let path = CStr::from_ptr(path).to_bytes();
+    if path.len() >= PATH_MAX || path.split(|&c| c == b'/').any(|name| name.len() > NAME_MAX) {
+        return Err(-(ENAMETOOLONG as c_int));
+    }
+    Ok(())
+}
+
+unsafe extern "C" fn getattr(
+    arg1: *const c_char,
+    arg2: *mut stat,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().getattr.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn readlink(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().readlink.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn mknod(arg1: *const c_char, arg2: mode_t, arg3: dev_t) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().mknod.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().mkdir.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn unlink(arg1: *const c_char) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().unlink.unwrap()(arg1)
+}
+
+unsafe extern "C" fn rmdir(arg1: *const c_char) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().rmdir.unwrap()(arg1)
+}
+
+unsafe extern "C" fn symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    if let Err(res) = check(arg2) {
+        return res;
+    }
+    if !arg1.is_null() && CStr::from_ptr(arg1).to_bytes().len() >= PATH_MAX {
+        return -(ENAMETOOLONG as c_int);
+    }
+    NEXT.assume_init_ref().symlink.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    if let Err(res) = check(arg2) {
+        return res;
+    }
+    NEXT.assume_init_ref().rename.unwrap()(arg1, arg2, flags)
+}
+
+unsafe extern "C" fn link(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    if let Err(res) = check(arg2) {
+        return res;
+    }
+    NEXT.assume_init_ref().link.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn chmod(arg1: *const c_char, arg2: mode_t, fi: *mut fuse_file_info) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().chmod.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn chown(
+    arg1: *const c_char,
+    arg2: uid_t,
+    arg3: gid_t,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().chown.unwrap()(arg1, arg2, arg3, fi)
+}
+
+unsafe extern "C" fn truncate(arg1: *const c_char, arg2: off_t, fi: *mut fuse_file_info) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().truncate.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().open.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn statfs(arg1: *const c_char, arg2: *mut statvfs) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().statfs.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn setxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *const c_char,
+    arg4: usize,
+    arg5: c_int,
+) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().setxattr.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn getxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *mut c_char,
+    arg4: usize,
+) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().getxattr.unwrap()(arg1, arg2, arg3, arg4)
+}
+
+unsafe extern "C" fn listxattr(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().listxattr.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn removexattr(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().removexattr.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn opendir(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().opendir.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn access(arg1: *const c_char, arg2: c_int) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().access.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn utimens(
+    arg1: *const c_char,
+    tv: *const timespec,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().utimens.unwrap()(arg1, tv, fi)
+}
+
+unsafe extern "C" fn bmap(arg1: *const c_char, blocksize: usize, idx: *mut u64) -> c_int {
+    if let Err(res) = check(arg1) {
+        return res;
+    }
+    NEXT.assume_init_ref().bmap.unwrap()(arg1, blocksize, idx)
+}
+
+/// Fails operations on paths with a component longer than `name_max` bytes or a total length of
+/// `path_max` bytes or more with ENAMETOOLONG, as do symlinks with targets that long, instead of
+/// passing them on to layers that extend paths, like the prefix, alias and rebind layers, where
+/// they end up as errors from the host that do not say which name was at fault, or as truncated
+/// paths. The passthrough hands paths to the host as they are, so without such layers below the
+/// host's own limits apply. Operations on open handles were checked when the handle was opened.
+/// `name_max` and `path_max` are usually 255 and 4096, less what the layers below prepend.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer
+#[no_mangle]
+pub unsafe extern "C" fn new_namelen_layer(
+    next: *const fuse_operations,
+    name_max: usize,
+    path_max: usize,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    NAME_MAX = name_max;
+    PATH_MAX = path_max;
+    Box::into_raw(Box::new(fuse_operations {
+        getattr: next.getattr.and(Some(getattr)),
+        readlink: next.readlink.and(Some(readlink)),
+        mknod: next.mknod.and(Some(mknod)),
+        mkdir: next.mkdir.and(Some(mkdir)),
+        unlink: next.unlink.and(Some(unlink)),
+        rmdir: next.rmdir.and(Some(rmdir)),
+        symlink: next.symlink.and(Some(symlink)),
+        rename: next.rename.and(Some(rename)),
+        link: next.link.and(Some(link)),
+        chmod: next.chmod.and(Some(chmod)),
+        chown: next.chown.and(Some(chown)),
+        truncate: next.truncate.and(Some(truncate)),
+        open: next.open.and(Some(open)),
+        statfs: next.statfs.and(Some(statfs)),
+        setxattr: next.setxattr.and(Some(setxattr)),
+        getxattr: next.getxattr.and(Some(getxattr)),
+        listxattr: next.listxattr.and(Some(listxattr)),
+        removexattr: next.removexattr.and(Some(removexattr)),
+        opendir: next.opendir.and(Some(opendir)),
+        access: next.access.and(Some(access)),
+        create: next.create.and(Some(create)),
+        utimens: next.utimens.and(Some(utimens)),
+        bmap: next.bmap.and(Some(bmap)),
+        ..next
+    }))
+}
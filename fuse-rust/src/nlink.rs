@@ -0,0 +1,300 @@
+use crate::{
+    budget::Consumer,
+    fuse::{
+        fuse_file_info, fuse_fill_dir_flags, fuse_fill_dir_t, fuse_operations, fuse_readdir_flags,
+        mode_t, nlink_t, off_t, stat, O_RDONLY, S_IFDIR, S_IFMT,
+    },
+    rmtree,
+};
+use std::{
+    collections::BTreeMap,
+    ffi::{c_char, c_int, c_uint, c_void, CStr, CString},
+    mem::{self, MaybeUninit},
+    ptr,
+    sync::Mutex,
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+/// Number of subdirectories of each directory counted so far.
+static SUBDIRS: Mutex<BTreeMap<Vec<u8>, nlink_t>> = Mutex::new(BTreeMap::new());
+static MEMORY: Consumer = Consumer::new(c"subdirectory counts", evict);
+
+fn footprint(path: &[u8]) -> usize {
+    mem::size_of::<(Vec<u8>, nlink_t)>() + path.len()
+}
+
+fn evict(bytes: usize) {
+    let mut subdirs = SUBDIRS.lock().unwrap();
+    let mut freed = 0;
+    while freed < bytes {
+        let Some((path, _)) = subdirs.pop_first() else {
+            break;
+        };
+        freed += footprint(&path);
+    }
+    MEMORY.release(freed);
+}
+
+fn join(dir: &[u8], name: &[u8]) -> CString {
+    let mut path = dir.to_vec();
+    if path != b"/" {
+        path.push(b'/');
+    }
+    path.extend_from_slice(name);
+    CString::new(path).unwrap()
+}
+
+fn parent(path: &[u8]) -> &[u8] {
+    match path.iter().rposition(|&c| c == b'/') {
+        Some(0) => b"/",
+        Some(slash) => &path[..slash],
+        None => path,
+    }
+}
+
+/// File type of `path`, asking the next layer since the entry listing it had none.
+unsafe fn file_type(path: &CStr) -> mode_t {
+    let mut st = MaybeUninit::<stat>::zeroed();
+    match NEXT.assume_init_ref().getattr.unwrap()(path.as_ptr(), st.as_mut_ptr(), ptr::null_mut()) {
+        0 => st.assume_init().st_mode & S_IFMT,
+        _ => 0,
+    }
+}
+
+unsafe extern "C" fn collect(
+    buf: *mut c_void,
+    name: *const c_char,
+    stbuf: *const stat,
+    _off: off_t,
+    _flags: fuse_fill_dir_flags,
+) -> c_int {
+    let entries = &mut *buf.cast::<Vec<(CString, mode_t)>>();
+    let mode = match stbuf.is_null() {
+        true => 0,
+        false => (*stbuf).st_mode & S_IFMT,
+    };
+    entries.push((CStr::from_ptr(name).to_owned(), mode));
+    0
+}
+
+/// Counts the subdirectories of `path` by listing it through the next layer.
+unsafe fn count_subdirs(path: &CStr) -> Option<nlink_t> {
+    let next = NEXT.assume_init_ref();
+    let mut fi = MaybeUninit::<fuse_file_info>::zeroed().assume_init();
+    fi.flags = O_RDONLY as c_int;
+    if let Some(opendir) = next.opendir {
+        if opendir(path.as_ptr(), &mut fi) != 0 {
+            return None;
+        }
+    }
+    let mut entries: Vec<(CString, mode_t)> = Vec::new();
+    let res = next.readdir?(
+        path.as_ptr(),
+        (&mut entries as *mut Vec<(CString, mode_t)>).cast(),
+        Some(collect),
+        0,
+        &mut fi,
+        0,
+    );
+    if let Some(releasedir) = next.releasedir {
+        releasedir(path.as_ptr(), &mut fi);
+    }
+    if res != 0 {
+        return None;
+    }
+    let dir = path.to_bytes();
+    let count = entries
+        .iter()
+        .filter(|(name, _)| !matches!(name.to_bytes(), b"." | b".."))
+        .filter(|(name, mode)| match *mode {
+            0 => file_type(&join(dir, name.to_bytes())) == S_IFDIR,
+            mode => mode == S_IFDIR,
+        })
+        .count();
+    Some(count as nlink_t)
+}
+
+unsafe fn subdirs(path: &CStr) -> Option<nlink_t> {
+    if let Some(&count) = SUBDIRS.lock().unwrap().get(path.to_bytes()) {
+        return Some(count);
+    }
+    let count = count_subdirs(path)?;
+    let path = path.to_bytes().to_vec();
+    let size = footprint(&path);
+    // another thread may have counted it meanwhile
+    if SUBDIRS.lock().unwrap().insert(path, count).is_none() {
+        MEMORY.charge(size);
+    }
+    crate::budget::enforce();
+    Some(count)
+}
+
+fn is_under(dir: &[u8], path: &[u8]) -> bool {
+    dir == b"/"
+        || path
+            .strip_prefix(dir)
+            .is_some_and(|rest| rest.is_empty() || rest[0] == b'/')
+}
+
+/// Forgets the count of the directory holding `path`, whose subdirectories may have changed,
+/// and with `itself` those of `path` and everything below it, which went away, came or moved.
+unsafe fn forget(path: *const c_char, itself: bool) {
+    if path.is_null() {
+        return;
+    }
+    let path = CStr::from_ptr(path).to_bytes();
+    let mut subdirs = SUBDIRS.lock().unwrap();
+    if let Some((dir, _)) = subdirs.remove_entry(parent(path)) {
+        MEMORY.release(footprint(&dir));
+    }
+    if !itself {
+        return;
+    }
+    let below: Vec<Vec<u8>> = subdirs
+        .range(path.to_vec()..)
+        .map(|(dir, _)| dir)
+        .take_while(|dir| dir.starts_with(path))
+        .filter(|dir| is_under(path, dir))
+        .cloned()
+        .collect();
+    for dir in below {
+        subdirs.remove(&dir);
+        MEMORY.release(footprint(&dir));
+    }
+}
+
+unsafe extern "C" fn getattr(
+    arg1: *const c_char,
+    arg2: *mut stat,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let res = NEXT.assume_init_ref().getattr.unwrap()(arg1, arg2, fi);
+    if res != 0 {
+        return res;
+    }
+    let st = &mut *arg2;
+    if st.st_mode & S_IFMT != S_IFDIR {
+        st.st_nlink = st.st_nlink.max(1);
+    } else if arg1.is_null() {
+        st.st_nlink = st.st_nlink.max(2);
+    } else if let Some(count) = subdirs(CStr::from_ptr(arg1)) {
+        // one link from the parent, one from "." and one from the ".." of each subdirectory
+        st.st_nlink = 2 + count;
+    }
+    0
+}
+
+struct Filler {
+    buf: *mut c_void,
+    filler: fuse_fill_dir_t,
+    dir: Vec<u8>,
+}
+
+unsafe extern "C" fn fill(
+    buf: *mut c_void,
+    name: *const c_char,
+    stbuf: *const stat,
+    off: off_t,
+    flags: fuse_fill_dir_flags,
+) -> c_int {
+    let filler = &*buf.cast::<Filler>();
+    if !stbuf.is_null() && (*stbuf).st_mode & S_IFMT != 0 {
+        return filler.filler.unwrap()(filler.buf, name, stbuf, off, flags);
+    }
+    let mut st = match stbuf.is_null() {
+        true => MaybeUninit::<stat>::zeroed().assume_init(),
+        false => *stbuf,
+    };
+    st.st_mode |= match CStr::from_ptr(name).to_bytes() {
+        b"." | b".." => S_IFDIR,
+        entry => file_type(&join(&filler.dir, entry)),
+    };
+    filler.filler.unwrap()(filler.buf, name, &st, off, flags)
+}
+
+unsafe extern "C" fn readdir(
+    arg1: *const c_char,
+    arg2: *mut c_void,
+    arg3: fuse_fill_dir_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+    arg6: fuse_readdir_flags,
+) -> c_int {
+    if arg1.is_null() {
+        return NEXT.assume_init_ref().readdir.unwrap()(arg1, arg2, arg3, arg4, arg5, arg6);
+    }
+    let mut filler = Filler {
+        buf: arg2,
+        filler: arg3,
+        dir: CStr::from_ptr(arg1).to_bytes().to_vec(),
+    };
+    NEXT.assume_init_ref().readdir.unwrap()(
+        arg1,
+        (&mut filler as *mut Filler).cast(),
+        Some(fill),
+        arg4,
+        arg5,
+        arg6,
+    )
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    let res = NEXT.assume_init_ref().mkdir.unwrap()(arg1, arg2);
+    // a directory of the same name may have been counted before the host removed it
+    forget(arg1, true);
+    res
+}
+
+unsafe extern "C" fn rmdir(arg1: *const c_char) -> c_int {
+    let res = NEXT.assume_init_ref().rmdir.unwrap()(arg1);
+    forget(arg1, true);
+    res
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    let res = NEXT.assume_init_ref().rename.unwrap()(arg1, arg2, flags);
+    forget(arg1, true);
+    forget(arg2, true);
+    res
+}
+
+unsafe extern "C" fn ioctl(
+    arg1: *const c_char,
+    cmd: c_int,
+    arg: *mut c_void,
+    arg2: *mut fuse_file_info,
+    flags: c_uint,
+    data: *mut c_void,
+) -> c_int {
+    let res = NEXT.assume_init_ref().ioctl.unwrap()(arg1, cmd, arg, arg2, flags, data);
+    if rmtree::is_rmtree(cmd) {
+        // the directory is emptied, so nothing below it has subdirectories any more
+        forget(arg1, true);
+    }
+    res
+}
+
+/// Synthesizes link counts and file types for backends that lack them, like object stores or
+/// FAT: directories get two links plus one per subdirectory, counted by listing them and cached
+/// until a subdirectory is made, removed or renamed through the mount, including by the
+/// passthrough's `FSINTERPOSER_IOC_RMTREE`, other files at least
+/// one, and directory entries without a file type get the one `getattr` reports, so `find -type
+/// d` and `ls -l` work. Counts cached while the host changes the tree go stale.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer
+#[no_mangle]
+pub unsafe extern "C" fn new_nlink_layer(next: *const fuse_operations) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    Box::into_raw(Box::new(fuse_operations {
+        getattr: next.getattr.and(Some(getattr)),
+        readdir: next.readdir.and(Some(readdir)),
+        mkdir: next.mkdir.and(Some(mkdir)),
+        rmdir: next.rmdir.and(Some(rmdir)),
+        rename: next.rename.and(Some(rename)),
+        ioctl: next.ioctl.and(Some(ioctl)),
+        ..next
+    }))
+}
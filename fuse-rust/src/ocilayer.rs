@@ -0,0 +1,286 @@
+use crate::journal::{changed_paths, read_journal, Change};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::{c_char, c_int, CStr, OsStr},
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::{FileTypeExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+};
+
+const BLOCK: usize = 512;
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+const OPAQUE_WHITEOUT: &[u8] = b".wh..wh..opq";
+
+/// Fields of a tar header, in ustar where they fit and in a PAX extended header otherwise.
+struct Entry<'a> {
+    name: &'a [u8],
+    typeflag: u8,
+    mode: u32,
+    uid: u64,
+    gid: u64,
+    size: u64,
+    mtime: i64,
+    linkname: &'a [u8],
+    rdev: u64,
+}
+
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{value:0width$o}", width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+}
+
+fn pax_record(records: &mut Vec<u8>, key: &str, value: &[u8]) {
+    // the length prefix counts itself
+    let len = key.len() + value.len() + 3;
+    let mut total = len + len.to_string().len();
+    if total.to_string().len() != len.to_string().len() {
+        total += 1;
+    }
+    records.extend_from_slice(format!("{total} {key}=").as_bytes());
+    records.extend_from_slice(value);
+    records.push(b'\n');
+}
+
+fn fit(value: &[u8], len: usize) -> &[u8] {
+    &value[..value.len().min(len)]
+}
+
+fn write_header(out: &mut impl Write, entry: &Entry, name: &[u8], typeflag: u8) -> io::Result<()> {
+    let mut header = [0u8; BLOCK];
+    header[..fit(name, 100).len()].copy_from_slice(fit(name, 100));
+    octal(&mut header[100..108], (entry.mode & 0o7777) as u64);
+    octal(&mut header[108..116], entry.uid.min(0o7777777));
+    octal(&mut header[116..124], entry.gid.min(0o7777777));
+    octal(&mut header[124..136], entry.size.min(0o77777777777));
+    octal(&mut header[136..148], entry.mtime.max(0) as u64);
+    header[156] = typeflag;
+    header[157..157 + fit(entry.linkname, 100).len()].copy_from_slice(fit(entry.linkname, 100));
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    let major = ((entry.rdev >> 8) & 0xfff) | ((entry.rdev >> 32) & !0xfff);
+    let minor = (entry.rdev & 0xff) | ((entry.rdev >> 12) & !0xff);
+    octal(&mut header[329..337], major.min(0o7777777));
+    octal(&mut header[337..345], minor.min(0o7777777));
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    octal(&mut header[148..155], checksum as u64);
+    out.write_all(&header)
+}
+
+fn write_entry(out: &mut impl Write, entry: &Entry) -> io::Result<()> {
+    let mut pax = Vec::new();
+    if entry.name.len() > 100 {
+        pax_record(&mut pax, "path", entry.name);
+    }
+    if entry.linkname.len() > 100 {
+        pax_record(&mut pax, "linkpath", entry.linkname);
+    }
+    if entry.size > 0o77777777777 {
+        pax_record(&mut pax, "size", entry.size.to_string().as_bytes());
+    }
+    if entry.uid > 0o7777777 {
+        pax_record(&mut pax, "uid", entry.uid.to_string().as_bytes());
+    }
+    if entry.gid > 0o7777777 {
+        pax_record(&mut pax, "gid", entry.gid.to_string().as_bytes());
+    }
+    if !pax.is_empty() {
+        let pax_entry = Entry {
+            size: pax.len() as u64,
+            linkname: b"",
+            ..*entry
+        };
+        write_header(out, &pax_entry, b"PaxHeader", b'x')?;
+        write_padded(out, &pax[..], pax.len() as u64)?;
+    }
+    write_header(out, entry, entry.name, entry.typeflag)
+}
+
+/// Copies `size` bytes of content and pads them to a whole block.
+fn write_padded(out: &mut impl Write, content: impl io::Read, size: u64) -> io::Result<()> {
+    let copied = io::copy(&mut content.take(size), out)?;
+    if copied != size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "file shrank while exporting",
+        ));
+    }
+    let padding = (BLOCK - (size as usize % BLOCK)) % BLOCK;
+    out.write_all(&[0; BLOCK][..padding])
+}
+
+fn write_whiteout(out: &mut impl Write, name: &[u8]) -> io::Result<()> {
+    write_entry(
+        out,
+        &Entry {
+            name,
+            typeflag: b'0',
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            mtime: 0,
+            linkname: b"",
+            rdev: 0,
+        },
+    )
+}
+
+/// Adds the file at `name` below `root` as it is now. Sockets cannot be represented and are
+/// skipped, and so are files removed behind the journal's back.
+fn write_file(out: &mut impl Write, root: &Path, name: &[u8]) -> io::Result<()> {
+    let path = root.join(OsStr::from_bytes(name));
+    let metadata = match fs::symlink_metadata(&path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        result => result?,
+    };
+    let file_type = metadata.file_type();
+    let target = match file_type.is_symlink() {
+        true => fs::read_link(&path)?.into_os_string().into_vec(),
+        false => Vec::new(),
+    };
+    let (typeflag, size) = match () {
+        _ if file_type.is_file() => (b'0', metadata.len()),
+        _ if file_type.is_dir() => (b'5', 0),
+        _ if file_type.is_symlink() => (b'2', 0),
+        _ if file_type.is_char_device() => (b'3', 0),
+        _ if file_type.is_block_device() => (b'4', 0),
+        _ if file_type.is_fifo() => (b'6', 0),
+        _ => return Ok(()),
+    };
+    let mut name = name.to_vec();
+    if file_type.is_dir() {
+        name.push(b'/');
+    }
+    write_entry(
+        out,
+        &Entry {
+            name: &name,
+            typeflag,
+            mode: metadata.mode(),
+            uid: metadata.uid() as u64,
+            gid: metadata.gid() as u64,
+            size,
+            mtime: metadata.mtime(),
+            linkname: &target,
+            rdev: metadata.rdev(),
+        },
+    )?;
+    if typeflag == b'0' {
+        write_padded(out, File::open(&path)?, size)?;
+    }
+    Ok(())
+}
+
+/// Adds the file at `name` and, for directories, everything below it.
+fn write_tree(out: &mut impl Write, root: &Path, name: &[u8]) -> io::Result<()> {
+    write_file(out, root, name)?;
+    let path = root.join(OsStr::from_bytes(name));
+    if !fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_dir()) {
+        return Ok(());
+    }
+    let mut children: Vec<_> = fs::read_dir(&path)?
+        .map(|child| child.map(|child| child.file_name()))
+        .collect::<io::Result<_>>()?;
+    children.sort();
+    for child in children {
+        write_tree(out, root, &[name, b"/", child.as_bytes()].concat())?;
+    }
+    Ok(())
+}
+
+fn parent(name: &[u8]) -> Option<&[u8]> {
+    name.iter()
+        .rposition(|&c| c == b'/')
+        .map(|slash| &name[..slash])
+}
+
+/// Writes an OCI image layer tarball to `out` holding everything that changed below `root`
+/// according to the change journal at `log_path`: changed files as they are now, and a
+/// whiteout for every deleted one. Directories whose whole content was moved in are marked
+/// opaque so they hide what the lower layers had there.
+pub fn export_layer(log_path: &Path, root: &Path, out: impl Write) -> io::Result<()> {
+    let mut changes: BTreeMap<Vec<u8>, Change> = BTreeMap::new();
+    for (path, change) in changed_paths(&read_journal(log_path)?) {
+        // journal paths are absolute within the mount, tar names are relative
+        let name = path.strip_prefix(b"/").unwrap_or(&path).to_vec();
+        if !name.is_empty() {
+            changes.insert(name, change);
+        }
+    }
+    let mut out = BufWriter::new(out);
+    let mut written = BTreeSet::new();
+    for (name, &change) in &changes {
+        // anything below a deleted directory or a fully exported tree is already covered
+        let mut ancestor = parent(name);
+        let mut covered = false;
+        while let Some(dir) = ancestor {
+            covered |= matches!(changes.get(dir), Some(Change::Deleted | Change::Tree));
+            ancestor = parent(dir);
+        }
+        if covered {
+            continue;
+        }
+        // parents come first so extracting the layer recreates their metadata
+        let mut ancestors = Vec::new();
+        let mut ancestor = parent(name);
+        while let Some(dir) = ancestor {
+            ancestors.push(dir);
+            ancestor = parent(dir);
+        }
+        for dir in ancestors.into_iter().rev() {
+            if written.insert(dir.to_vec()) {
+                write_file(&mut out, root, dir)?;
+            }
+        }
+        match change {
+            Change::Deleted => {
+                let whiteout = match parent(name) {
+                    Some(dir) => [dir, b"/", WHITEOUT_PREFIX, &name[dir.len() + 1..]].concat(),
+                    None => [WHITEOUT_PREFIX, name].concat(),
+                };
+                write_whiteout(&mut out, &whiteout)?;
+            }
+            Change::Modified => write_file(&mut out, root, name)?,
+            Change::Tree => {
+                write_tree(&mut out, root, name)?;
+                let path = root.join(OsStr::from_bytes(name));
+                if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_dir()) {
+                    write_whiteout(&mut out, &[&name[..], b"/", OPAQUE_WHITEOUT].concat())?;
+                }
+            }
+        }
+        written.insert(name.clone());
+    }
+    // end of archive
+    out.write_all(&[0; 2 * BLOCK])?;
+    out.flush()
+}
+
+/// Writes the OCI layer tarball of the changes recorded in the journal at `log_path` to
+/// `out_path`, taking file contents from the backing directory `root`. Returns 0 on success.
+///
+/// # Safety
+///
+/// This function must be called with valid C strings
+#[no_mangle]
+pub unsafe extern "C" fn journal_export_layer(
+    log_path: *const c_char,
+    root: *const c_char,
+    out_path: *const c_char,
+) -> c_int {
+    let log_path = PathBuf::from(OsStr::from_bytes(CStr::from_ptr(log_path).to_bytes()));
+    let root = PathBuf::from(OsStr::from_bytes(CStr::from_ptr(root).to_bytes()));
+    let out_path = PathBuf::from(OsStr::from_bytes(CStr::from_ptr(out_path).to_bytes()));
+    match File::create(&out_path).and_then(|out| export_layer(&log_path, &root, out)) {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("failed to export layer to {}: {err}", out_path.display());
+            -1
+        }
+    }
+}
@@ -0,0 +1,477 @@
+use crate::fuse::{
+    dev_t, fuse_bufvec, fuse_file_info, fuse_fill_dir_t, fuse_operations, fuse_readdir_flags,
+    gid_t, mode_t, off_t, stat, statvfs, timespec, uid_t,
+};
+use std::{
+    ffi::{c_char, c_int, c_uint, c_void, CStr},
+    mem::MaybeUninit,
+    ptr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Condvar, Mutex,
+    },
+    time::Instant,
+};
+
+/// Names of the operations that can be limited, in the order of their wrappers' indices.
+const OPS: [&str; 35] = [
+    "getattr",
+    "readlink",
+    "mknod",
+    "mkdir",
+    "unlink",
+    "rmdir",
+    "symlink",
+    "rename",
+    "link",
+    "chmod",
+    "chown",
+    "truncate",
+    "open",
+    "read",
+    "write",
+    "statfs",
+    "flush",
+    "fsync",
+    "setxattr",
+    "getxattr",
+    "listxattr",
+    "removexattr",
+    "opendir",
+    "readdir",
+    "fsyncdir",
+    "access",
+    "create",
+    "utimens",
+    "bmap",
+    "ioctl",
+    "write_buf",
+    "read_buf",
+    "fallocate",
+    "copy_file_range",
+    "lseek",
+];
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut LIMITS: Vec<Option<Limit>> = Vec::new();
+
+struct Limit {
+    max: usize,
+    /// Operations running and waiting to run.
+    state: Mutex<(usize, usize)>,
+    cond: Condvar,
+    waits: AtomicU64,
+    wait_ns: AtomicU64,
+    max_queue: AtomicUsize,
+}
+
+struct Permit(Option<&'static Limit>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(limit) = self.0 {
+            limit.state.lock().unwrap().0 -= 1;
+            limit.cond.notify_one();
+        }
+    }
+}
+
+/// Waits until fewer than the configured number of operations `op` are running.
+unsafe fn acquire(op: usize) -> Permit {
+    let Some(limit) = LIMITS[op].as_ref() else {
+        return Permit(None);
+    };
+    let mut state = limit.state.lock().unwrap();
+    if state.0 >= limit.max {
+        let start = Instant::now();
+        state.1 += 1;
+        limit.max_queue.fetch_max(state.1, Ordering::Relaxed);
+        state = limit
+            .cond
+            .wait_while(state, |state| state.0 >= limit.max)
+            .unwrap();
+        state.1 -= 1;
+        limit.waits.fetch_add(1, Ordering::Relaxed);
+        limit
+            .wait_ns
+            .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+    state.0 += 1;
+    Permit(Some(limit))
+}
+
+unsafe extern "C" fn getattr(
+    arg1: *const c_char,
+    arg2: *mut stat,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let _permit = acquire(0);
+    NEXT.assume_init_ref().getattr.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn readlink(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    let _permit = acquire(1);
+    NEXT.assume_init_ref().readlink.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn mknod(arg1: *const c_char, arg2: mode_t, arg3: dev_t) -> c_int {
+    let _permit = acquire(2);
+    NEXT.assume_init_ref().mknod.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    let _permit = acquire(3);
+    NEXT.assume_init_ref().mkdir.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn unlink(arg1: *const c_char) -> c_int {
+    let _permit = acquire(4);
+    NEXT.assume_init_ref().unlink.unwrap()(arg1)
+}
+
+unsafe extern "C" fn rmdir(arg1: *const c_char) -> c_int {
+    let _permit = acquire(5);
+    NEXT.assume_init_ref().rmdir.unwrap()(arg1)
+}
+
+unsafe extern "C" fn symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let _permit = acquire(6);
+    NEXT.assume_init_ref().symlink.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    let _permit = acquire(7);
+    NEXT.assume_init_ref().rename.unwrap()(arg1, arg2, flags)
+}
+
+unsafe extern "C" fn link(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let _permit = acquire(8);
+    NEXT.assume_init_ref().link.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn chmod(arg1: *const c_char, arg2: mode_t, fi: *mut fuse_file_info) -> c_int {
+    let _permit = acquire(9);
+    NEXT.assume_init_ref().chmod.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn chown(
+    arg1: *const c_char,
+    arg2: uid_t,
+    arg3: gid_t,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let _permit = acquire(10);
+    NEXT.assume_init_ref().chown.unwrap()(arg1, arg2, arg3, fi)
+}
+
+unsafe extern "C" fn truncate(arg1: *const c_char, arg2: off_t, fi: *mut fuse_file_info) -> c_int {
+    let _permit = acquire(11);
+    NEXT.assume_init_ref().truncate.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let _permit = acquire(12);
+    NEXT.assume_init_ref().open.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn read(
+    arg1: *const c_char,
+    arg2: *mut c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let _permit = acquire(13);
+    NEXT.assume_init_ref().read.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let _permit = acquire(14);
+    NEXT.assume_init_ref().write.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn statfs(arg1: *const c_char, arg2: *mut statvfs) -> c_int {
+    let _permit = acquire(15);
+    NEXT.assume_init_ref().statfs.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn flush(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let _permit = acquire(16);
+    NEXT.assume_init_ref().flush.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn fsync(arg1: *const c_char, arg2: c_int, arg3: *mut fuse_file_info) -> c_int {
+    let _permit = acquire(17);
+    NEXT.assume_init_ref().fsync.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn setxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *const c_char,
+    arg4: usize,
+    arg5: c_int,
+) -> c_int {
+    let _permit = acquire(18);
+    NEXT.assume_init_ref().setxattr.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn getxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *mut c_char,
+    arg4: usize,
+) -> c_int {
+    let _permit = acquire(19);
+    NEXT.assume_init_ref().getxattr.unwrap()(arg1, arg2, arg3, arg4)
+}
+
+unsafe extern "C" fn listxattr(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    let _permit = acquire(20);
+    NEXT.assume_init_ref().listxattr.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn removexattr(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let _permit = acquire(21);
+    NEXT.assume_init_ref().removexattr.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn opendir(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let _permit = acquire(22);
+    NEXT.assume_init_ref().opendir.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn readdir(
+    arg1: *const c_char,
+    arg2: *mut c_void,
+    arg3: fuse_fill_dir_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+    arg6: fuse_readdir_flags,
+) -> c_int {
+    let _permit = acquire(23);
+    NEXT.assume_init_ref().readdir.unwrap()(arg1, arg2, arg3, arg4, arg5, arg6)
+}
+
+unsafe extern "C" fn fsyncdir(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: *mut fuse_file_info,
+) -> c_int {
+    let _permit = acquire(24);
+    NEXT.assume_init_ref().fsyncdir.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn access(arg1: *const c_char, arg2: c_int) -> c_int {
+    let _permit = acquire(25);
+    NEXT.assume_init_ref().access.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    let _permit = acquire(26);
+    NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn utimens(
+    arg1: *const c_char,
+    tv: *const timespec,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let _permit = acquire(27);
+    NEXT.assume_init_ref().utimens.unwrap()(arg1, tv, fi)
+}
+
+unsafe extern "C" fn bmap(arg1: *const c_char, blocksize: usize, idx: *mut u64) -> c_int {
+    let _permit = acquire(28);
+    NEXT.assume_init_ref().bmap.unwrap()(arg1, blocksize, idx)
+}
+
+unsafe extern "C" fn ioctl(
+    arg1: *const c_char,
+    cmd: c_int,
+    arg: *mut c_void,
+    arg2: *mut fuse_file_info,
+    flags: c_uint,
+    data: *mut c_void,
+) -> c_int {
+    let _permit = acquire(29);
+    NEXT.assume_init_ref().ioctl.unwrap()(arg1, cmd, arg, arg2, flags, data)
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let _permit = acquire(30);
+    NEXT.assume_init_ref().write_buf.unwrap()(arg1, buf, off, arg2)
+}
+
+unsafe extern "C" fn read_buf(
+    arg1: *const c_char,
+    bufp: *mut *mut fuse_bufvec,
+    size: usize,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let _permit = acquire(31);
+    NEXT.assume_init_ref().read_buf.unwrap()(arg1, bufp, size, off, arg2)
+}
+
+unsafe extern "C" fn fallocate(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: off_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let _permit = acquire(32);
+    NEXT.assume_init_ref().fallocate.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn copy_file_range(
+    path_in: *const c_char,
+    fi_in: *mut fuse_file_info,
+    offset_in: off_t,
+    path_out: *const c_char,
+    fi_out: *mut fuse_file_info,
+    offset_out: off_t,
+    size: usize,
+    flags: c_int,
+) -> isize {
+    let _permit = acquire(33);
+    NEXT.assume_init_ref().copy_file_range.unwrap()(
+        path_in, fi_in, offset_in, path_out, fi_out, offset_out, size, flags,
+    )
+}
+
+unsafe extern "C" fn lseek(
+    arg1: *const c_char,
+    off: off_t,
+    whence: c_int,
+    arg2: *mut fuse_file_info,
+) -> off_t {
+    let _permit = acquire(34);
+    NEXT.assume_init_ref().lseek.unwrap()(arg1, off, whence, arg2)
+}
+
+/// Parses a comma separated list of `op=max` pairs into the limit of each operation.
+fn parse_limits(spec: &[u8]) -> Result<Vec<Option<Limit>>, String> {
+    let spec = std::str::from_utf8(spec).map_err(|err| err.to_string())?;
+    let mut limits: Vec<_> = OPS.iter().map(|_| None).collect();
+    for pair in spec.split(',') {
+        if pair.is_empty() {
+            continue;
+        }
+        let Some((op, max)) = pair.split_once('=') else {
+            return Err(format!("invalid operation limit {pair}"));
+        };
+        let Some(op) = OPS.iter().position(|&name| name == op) else {
+            return Err(format!("unknown or unlimitable operation {op}"));
+        };
+        let Some(max) = max.parse().ok().filter(|&max| max > 0) else {
+            return Err(format!("invalid operation limit {pair}"));
+        };
+        limits[op] = Some(Limit {
+            max,
+            state: Mutex::new((0, 0)),
+            cond: Condvar::new(),
+            waits: AtomicU64::new(0),
+            wait_ns: AtomicU64::new(0),
+            max_queue: AtomicUsize::new(0),
+        });
+    }
+    Ok(limits)
+}
+
+/// Prints how often each limited operation had to wait for a slot, for how long in total and
+/// the longest queue seen.
+#[no_mangle]
+pub extern "C" fn oplimit_report() {
+    for (op, limit) in unsafe { LIMITS.iter() }.enumerate() {
+        let Some(limit) = limit else {
+            continue;
+        };
+        eprintln!(
+            "{}: limit {}, {} waits, {}ms waiting, longest queue {}",
+            OPS[op],
+            limit.max,
+            limit.waits.load(Ordering::Relaxed),
+            limit.wait_ns.load(Ordering::Relaxed) / 1_000_000,
+            limit.max_queue.load(Ordering::Relaxed),
+        );
+    }
+}
+
+/// Caps how many of each kind of operation may run against the next layer at once, queueing
+/// the rest, to keep fragile backends such as some network filesystems from collapsing under
+/// bursty load. `limits` is a comma separated list of `op=max` pairs naming `fuse_operations`
+/// members, e.g. `readdir=4,fsync=2`; operations not listed are not limited. Locks and polls,
+/// which may wait on other requests for as long as they like, and releases cannot be limited, so
+/// that a full queue never keeps the operation it waits for from running. Returns null if
+/// `limits` is malformed.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer and a valid C string
+#[no_mangle]
+pub unsafe extern "C" fn new_oplimit_layer(
+    next: *const fuse_operations,
+    limits: *const c_char,
+) -> *const fuse_operations {
+    let limits = match parse_limits(CStr::from_ptr(limits).to_bytes()) {
+        Ok(limits) => limits,
+        Err(err) => {
+            eprintln!("failed to parse operation limits: {err}");
+            return ptr::null();
+        }
+    };
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    LIMITS = limits;
+    Box::into_raw(Box::new(fuse_operations {
+        getattr: next.getattr.and(Some(getattr)),
+        readlink: next.readlink.and(Some(readlink)),
+        mknod: next.mknod.and(Some(mknod)),
+        mkdir: next.mkdir.and(Some(mkdir)),
+        unlink: next.unlink.and(Some(unlink)),
+        rmdir: next.rmdir.and(Some(rmdir)),
+        symlink: next.symlink.and(Some(symlink)),
+        rename: next.rename.and(Some(rename)),
+        link: next.link.and(Some(link)),
+        chmod: next.chmod.and(Some(chmod)),
+        chown: next.chown.and(Some(chown)),
+        truncate: next.truncate.and(Some(truncate)),
+        open: next.open.and(Some(open)),
+        read: next.read.and(Some(read)),
+        write: next.write.and(Some(write)),
+        statfs: next.statfs.and(Some(statfs)),
+        flush: next.flush.and(Some(flush)),
+        fsync: next.fsync.and(Some(fsync)),
+        setxattr: next.setxattr.and(Some(setxattr)),
+        getxattr: next.getxattr.and(Some(getxattr)),
+        listxattr: next.listxattr.and(Some(listxattr)),
+        removexattr: next.removexattr.and(Some(removexattr)),
+        opendir: next.opendir.and(Some(opendir)),
+        readdir: next.readdir.and(Some(readdir)),
+        fsyncdir: next.fsyncdir.and(Some(fsyncdir)),
+        access: next.access.and(Some(access)),
+        create: next.create.and(Some(create)),
+        utimens: next.utimens.and(Some(utimens)),
+        bmap: next.bmap.and(Some(bmap)),
+        ioctl: next.ioctl.and(Some(ioctl)),
+        write_buf: next.write_buf.and(Some(write_buf)),
+        read_buf: next.read_buf.and(Some(read_buf)),
+        fallocate: next.fallocate.and(Some(fallocate)),
+        copy_file_range: next.copy_file_range.and(Some(copy_file_range)),
+        lseek: next.lseek.and(Some(lseek)),
+        ..next
+    }))
+}
@@ -0,0 +1,143 @@
+use crate::{
+    budget::Consumer,
+    fuse::{pid_t, poll, pollfd, syscall, SYS_pidfd_open, POLLIN},
+};
+use std::{
+    collections::BTreeMap,
+    ffi::c_long,
+    fs, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+/// Entries kept before the cache is pruned of processes that have exited.
+const MAX_CACHED: usize = 1024;
+
+static PEERS: Mutex<BTreeMap<pid_t, Arc<Peer>>> = Mutex::new(BTreeMap::new());
+static MEMORY: Consumer = Consumer::new(c"peer cache", evict);
+
+/// What the host knows about a process issuing requests, for layers applying policy or keeping
+/// metrics per process rather than per user.
+pub struct Peer {
+    pub pid: pid_t,
+    pub comm: String,
+    /// Unset if the executable cannot be read, e.g. for kernel threads or without ptrace access.
+    pub exe: Option<PathBuf>,
+    /// The unified hierarchy path, or the first line of `/proc/<pid>/cgroup` on cgroup v1 hosts.
+    pub cgroup: String,
+    /// Start time in clock ticks since boot, which tells a process apart from a later one that
+    /// reused its pid where there is no pidfd.
+    start_time: u64,
+    /// Refers to the process itself rather than its pid, unless the kernel predates pidfds or,
+    /// before Linux 6.9, the pid is that of a thread other than the main one.
+    pidfd: Option<OwnedFd>,
+}
+
+impl Peer {
+    /// Whether `pid` still names this process, which it does for as long as the process runs.
+    fn is_current(&self) -> bool {
+        let Some(pidfd) = &self.pidfd else {
+            return start_time(self.pid) == Some(self.start_time);
+        };
+        // a pidfd becomes readable once its process exits
+        let mut pfd = pollfd {
+            fd: pidfd.as_raw_fd(),
+            events: POLLIN as _,
+            revents: 0,
+        };
+        unsafe { poll(&mut pfd, 1, 0) == 0 }
+    }
+}
+
+fn pidfd_open(pid: pid_t) -> Option<OwnedFd> {
+    let fd = unsafe { syscall(SYS_pidfd_open as c_long, pid, 0) };
+    (fd >= 0).then(|| unsafe { OwnedFd::from_raw_fd(fd as _) })
+}
+
+fn start_time(pid: pid_t) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // comm may contain spaces and parentheses, so count fields from the last closing one
+    let (_, rest) = stat.rsplit_once(')')?;
+    // rest starts at field 3 (state); starttime is field 22
+    rest.split_whitespace().nth(19)?.parse().ok()
+}
+
+fn read_peer(pid: pid_t) -> Option<Peer> {
+    // taken first, so the process found running at the end is the one the files below are of
+    let pidfd = pidfd_open(pid);
+    let start_time = start_time(pid)?;
+    let proc = PathBuf::from(format!("/proc/{pid}"));
+    let comm = fs::read_to_string(proc.join("comm")).ok()?;
+    let cgroup = fs::read_to_string(proc.join("cgroup")).ok()?;
+    let cgroup = cgroup
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .or_else(|| cgroup.lines().next())
+        .unwrap_or_default();
+    let peer = Peer {
+        pid,
+        comm: comm.trim_end().to_string(),
+        exe: fs::read_link(proc.join("exe")).ok(),
+        cgroup: cgroup.to_string(),
+        start_time,
+        pidfd,
+    };
+    // the pid may have been reused while the files above were read
+    peer.is_current().then_some(peer)
+}
+
+/// Rough memory held by a cached entry.
+fn footprint(peer: &Peer) -> usize {
+    mem::size_of::<(pid_t, Arc<Peer>)>()
+        + mem::size_of::<Peer>()
+        + peer.comm.len()
+        + peer.cgroup.len()
+        + peer.exe.as_ref().map_or(0, |exe| exe.as_os_str().len())
+}
+
+fn evict(bytes: usize) {
+    let mut peers = PEERS.lock().unwrap();
+    let mut freed = 0;
+    while freed < bytes {
+        let Some((_, peer)) = peers.pop_first() else {
+            break;
+        };
+        freed += footprint(&peer);
+    }
+    MEMORY.release(freed);
+}
+
+/// Looks up the process `pid`, usually the `pid` of the current `fuse_get_context()`. Returns
+/// `None` if it has exited or sits in a pid namespace the daemon cannot see into, in which case
+/// the kernel reports a pid of 0.
+///
+/// Results are cached until the pid is found to belong to a different process, or evicted to
+/// stay within the memory budget. Each cached process holds a pidfd telling when it exits.
+pub fn peer(pid: pid_t) -> Option<Arc<Peer>> {
+    if pid <= 0 {
+        return None;
+    }
+    let cached = PEERS.lock().unwrap().get(&pid).cloned();
+    if let Some(peer) = cached.filter(|peer| peer.is_current()) {
+        return Some(peer);
+    }
+    let peer = Arc::new(read_peer(pid)?);
+    let mut peers = PEERS.lock().unwrap();
+    if peers.len() >= MAX_CACHED {
+        peers.retain(|_, peer| {
+            let alive = peer.is_current();
+            if !alive {
+                MEMORY.release(footprint(peer));
+            }
+            alive
+        });
+    }
+    MEMORY.charge(footprint(&peer));
+    if let Some(replaced) = peers.insert(pid, peer.clone()) {
+        MEMORY.release(footprint(&replaced));
+    }
+    drop(peers);
+    crate::budget::enforce();
+    Some(peer)
+}
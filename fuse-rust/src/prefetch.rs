@@ -0,0 +1,220 @@
+use crate::{
+    control::Control,
+    fuse::{
+        fuse_config, fuse_conn_info, fuse_file_info, fuse_fill_dir_flags, fuse_operations, off_t,
+        stat, O_RDONLY, S_IFDIR, S_IFMT, S_IFREG,
+    },
+};
+use std::{
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    mem::MaybeUninit,
+    ptr, thread,
+};
+
+/// Size of the reads made to warm file contents.
+const CHUNK: usize = 128 * 1024;
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static CONTROL: Control = Control::new();
+
+/// What a walk went through.
+#[derive(Default)]
+pub struct Prefetched {
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+unsafe extern "C" fn collect(
+    buf: *mut c_void,
+    name: *const c_char,
+    _stbuf: *const stat,
+    _off: off_t,
+    _flags: fuse_fill_dir_flags,
+) -> c_int {
+    let names = &mut *buf.cast::<Vec<Vec<u8>>>();
+    let name = CStr::from_ptr(name).to_bytes();
+    if !matches!(name, b"." | b"..") {
+        names.push(name.to_vec());
+    }
+    0
+}
+
+unsafe fn list(path: &CStr) -> Result<Vec<Vec<u8>>, c_int> {
+    let next = NEXT.assume_init_ref();
+    let mut fi = MaybeUninit::<fuse_file_info>::zeroed().assume_init();
+    fi.flags = O_RDONLY as c_int;
+    if let Some(opendir) = next.opendir {
+        let res = opendir(path.as_ptr(), &mut fi);
+        if res != 0 {
+            return Err(res);
+        }
+    }
+    let mut names: Vec<Vec<u8>> = Vec::new();
+    let res = match next.readdir {
+        Some(readdir) => readdir(
+            path.as_ptr(),
+            (&mut names as *mut Vec<Vec<u8>>).cast(),
+            Some(collect),
+            0,
+            &mut fi,
+            0,
+        ),
+        None => 0,
+    };
+    if let Some(releasedir) = next.releasedir {
+        releasedir(path.as_ptr(), &mut fi);
+    }
+    match res {
+        0 => Ok(names),
+        res => Err(res),
+    }
+}
+
+/// Reads the whole file at `path` through the next layer, returning how many bytes it read.
+unsafe fn read_all(path: &CStr) -> u64 {
+    let next = NEXT.assume_init_ref();
+    let Some(read) = next.read else {
+        return 0;
+    };
+    let mut fi = MaybeUninit::<fuse_file_info>::zeroed().assume_init();
+    fi.flags = O_RDONLY as c_int;
+    if let Some(open) = next.open {
+        if open(path.as_ptr(), &mut fi) != 0 {
+            return 0;
+        }
+    }
+    let mut buf = vec![0u8; CHUNK];
+    let mut total = 0;
+    loop {
+        let res = read(
+            path.as_ptr(),
+            buf.as_mut_ptr().cast(),
+            CHUNK,
+            total as off_t,
+            &mut fi,
+        );
+        if res <= 0 {
+            break;
+        }
+        total += res as u64;
+    }
+    if let Some(release) = next.release {
+        release(path.as_ptr(), &mut fi);
+    }
+    total
+}
+
+/// Walks the subtree at `root` through the next layer, getting the attributes of everything
+/// in it and, with `data`, reading every regular file, so the caches of the layers below are
+/// warm by the time the guest gets there. Entries that fail are skipped.
+pub fn prefetch(root: &[u8], data: bool) -> Prefetched {
+    let mut prefetched = Prefetched::default();
+    let mut pending = vec![root.to_vec()];
+    while let Some(path) = pending.pop() {
+        let Ok(path) = CString::new(path) else {
+            continue;
+        };
+        let mut st = MaybeUninit::<stat>::zeroed();
+        let res = unsafe {
+            NEXT.assume_init_ref().getattr.unwrap()(path.as_ptr(), st.as_mut_ptr(), ptr::null_mut())
+        };
+        if res != 0 {
+            continue;
+        }
+        prefetched.entries += 1;
+        match unsafe { st.assume_init() }.st_mode & S_IFMT {
+            S_IFDIR => {
+                let Ok(names) = (unsafe { list(&path) }) else {
+                    continue;
+                };
+                let dir = path.as_bytes();
+                for name in names {
+                    let mut child = dir.to_vec();
+                    if child != b"/" {
+                        child.push(b'/');
+                    }
+                    child.extend_from_slice(&name);
+                    pending.push(child);
+                }
+            }
+            S_IFREG if data => prefetched.bytes += unsafe { read_all(&path) },
+            _ => (),
+        }
+    }
+    prefetched
+}
+
+/// Answers `prefetch <path>` and `prefetch-data <path>` commands on the control socket with
+/// `ok <entries> <bytes>` once the walk is done.
+fn command(line: &[u8]) -> String {
+    match (
+        line.strip_prefix(b"prefetch "),
+        line.strip_prefix(b"prefetch-data "),
+    ) {
+        (Some(root), _) | (_, Some(root)) => {
+            let prefetched = prefetch(root, line.starts_with(b"prefetch-data "));
+            format!("ok {} {}\n", prefetched.entries, prefetched.bytes)
+        }
+        _ => "error unknown command\n".to_string(),
+    }
+}
+
+unsafe extern "C" fn init(conn: *mut fuse_conn_info, cfg: *mut fuse_config) -> *mut c_void {
+    CONTROL.start(command);
+    match NEXT.assume_init_ref().init {
+        Some(init) => init(conn, cfg),
+        None => ptr::null_mut(),
+    }
+}
+
+unsafe extern "C" fn destroy(private_data: *mut c_void) {
+    CONTROL.stop();
+    if let Some(destroy) = NEXT.assume_init_ref().destroy {
+        destroy(private_data);
+    }
+}
+
+/// Starts warming the subtree at `path` in the background, see [`prefetch`], logging what was
+/// walked once done.
+///
+/// # Safety
+///
+/// This function must be called with a valid C string
+#[no_mangle]
+pub unsafe extern "C" fn prefetch_subtree(path: *const c_char, data: bool) {
+    let root = CStr::from_ptr(path).to_bytes().to_vec();
+    thread::spawn(move || {
+        let prefetched = prefetch(&root, data);
+        eprintln!(
+            "prefetched {}: {} entries, {} bytes",
+            String::from_utf8_lossy(&root),
+            prefetched.entries,
+            prefetched.bytes
+        );
+    });
+}
+
+/// Warms the caches of the layers below, like the shared attribute cache and the host page
+/// cache, for subtrees named ahead of container start, hiding the cold start latency of
+/// applications with large dependency trees. A walk is requested with a `prefetch <path>` line
+/// on the unix socket at `control_socket` when it is not null, or `prefetch-data <path>` to
+/// read file contents too, or by calling [`prefetch_subtree`]. Paths are within the mount.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer and a valid C string or null
+/// control socket
+#[no_mangle]
+pub unsafe extern "C" fn new_prefetch_layer(
+    next: *const fuse_operations,
+    control_socket: *const c_char,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    CONTROL.set(control_socket);
+    Box::into_raw(Box::new(fuse_operations {
+        init: Some(init),
+        destroy: Some(destroy),
+        ..next
+    }))
+}
@@ -0,0 +1,689 @@
+use crate::fuse::{
+    dev_t, flock, fuse_bufvec, fuse_file_info, fuse_fill_dir_t, fuse_operations, fuse_pollhandle,
+    fuse_readdir_flags, gid_t, mode_t, off_t, stat, statvfs, timespec, uid_t, EBUSY, EINVAL,
+    ENODATA, ENOENT, EROFS, S_IFDIR, W_OK,
+};
+use std::{
+    ffi::{c_char, c_int, c_uint, c_void, CStr, CString},
+    mem::MaybeUninit,
+    ptr,
+};
+
+/// Longest symlink target read from the next layer.
+const MAX_TARGET: usize = 4096;
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+/// Where the guest finds the root of the next layer, without trailing slashes.
+static mut PREFIX: Vec<u8> = Vec::new();
+/// Host directory being shared, without trailing slashes.
+static mut HOST_ROOT: Option<Vec<u8>> = None;
+
+/// Where a guest path lies relative to the prefix.
+enum Place<'a> {
+    /// At or below the prefix, with the path within the next layer.
+    Inner(&'a [u8]),
+    /// A directory on the way to the prefix, with the name of the one entry it contains.
+    Synthetic(&'a [u8]),
+    Outside,
+}
+
+unsafe fn place(path: &[u8]) -> Place<'_> {
+    match path.strip_prefix(&PREFIX[..]) {
+        Some(b"") => return Place::Inner(b"/"),
+        Some(rest) if rest.starts_with(b"/") => return Place::Inner(rest),
+        _ => (),
+    }
+    let start = match path {
+        b"/" => 1,
+        path if PREFIX.starts_with(path) && PREFIX[path.len()] == b'/' => path.len() + 1,
+        _ => return Place::Outside,
+    };
+    let child = &PREFIX[start..];
+    let end = child.iter().position(|&c| c == b'/').unwrap_or(child.len());
+    Place::Synthetic(&child[..end])
+}
+
+/// A guest path moved out from under the prefix, or null if the guest path was.
+struct Stripped(Option<CString>);
+
+impl Stripped {
+    fn ptr(&self) -> *const c_char {
+        self.0.as_ref().map_or(ptr::null(), |path| path.as_ptr())
+    }
+
+    fn is_root(&self) -> bool {
+        self.0.as_deref() == Some(c"/")
+    }
+}
+
+/// Maps a guest path to the next layer. Only paths below the prefix exist there, and the
+/// directories leading to it cannot be changed.
+unsafe fn stripped(path: *const c_char) -> Result<Stripped, c_int> {
+    if path.is_null() {
+        return Ok(Stripped(None));
+    }
+    match place(CStr::from_ptr(path).to_bytes()) {
+        Place::Inner(path) => Ok(Stripped(Some(CString::new(path).unwrap()))),
+        _ => Err(-(EROFS as c_int)),
+    }
+}
+
+/// Replaces the leading `from` of an absolute symlink target with `to`, if it starts with it.
+fn replace_root(target: &[u8], from: &[u8], to: &[u8]) -> Option<Vec<u8>> {
+    let rest = target.strip_prefix(from)?;
+    if !rest.is_empty() && !rest.starts_with(b"/") {
+        return None;
+    }
+    let replaced = [to, rest].concat();
+    match replaced.is_empty() {
+        true => Some(b"/".to_vec()),
+        false => Some(replaced),
+    }
+}
+
+unsafe extern "C" fn getattr(
+    arg1: *const c_char,
+    arg2: *mut stat,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let next = NEXT.assume_init_ref();
+    if arg1.is_null() {
+        return next.getattr.unwrap()(arg1, arg2, fi);
+    }
+    let path = CStr::from_ptr(arg1).to_bytes();
+    match place(path) {
+        Place::Inner(path) => {
+            let path = CString::new(path).unwrap();
+            next.getattr.unwrap()(path.as_ptr(), arg2, fi)
+        }
+        Place::Synthetic(_) => {
+            // directories on the way take their owner and times from the root they lead to
+            let res = next.getattr.unwrap()(c"/".as_ptr(), arg2, ptr::null_mut());
+            if res == 0 {
+                // each at its own depth, numbered down from the top of the inode range where
+                // the next layer's inodes are not found
+                let depth = path.split(|&c| c == b'/').filter(|c| !c.is_empty()).count();
+                (*arg2).st_ino = u64::MAX - depth as u64;
+                (*arg2).st_mode = S_IFDIR | 0o555;
+                (*arg2).st_nlink = 3;
+                (*arg2).st_size = 0;
+            }
+            res
+        }
+        Place::Outside => -(ENOENT as c_int),
+    }
+}
+
+unsafe extern "C" fn readlink(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    let path = match place(CStr::from_ptr(arg1).to_bytes()) {
+        Place::Inner(path) => CString::new(path).unwrap(),
+        Place::Synthetic(_) => return -(EINVAL as c_int),
+        Place::Outside => return -(ENOENT as c_int),
+    };
+    let mut target = [0u8; MAX_TARGET];
+    let res = NEXT.assume_init_ref().readlink.unwrap()(
+        path.as_ptr(),
+        target.as_mut_ptr().cast(),
+        MAX_TARGET,
+    );
+    if res != 0 {
+        return res;
+    }
+    let target = CStr::from_bytes_until_nul(&target).unwrap().to_bytes();
+    // absolute targets within the share point to where the guest sees it
+    let target = match HOST_ROOT.as_ref() {
+        Some(root) => replace_root(target, root, &PREFIX).unwrap_or(target.to_vec()),
+        None => target.to_vec(),
+    };
+    let len = target.len().min(arg3.saturating_sub(1));
+    let buf = std::slice::from_raw_parts_mut(arg2.cast::<u8>(), arg3);
+    buf[..len].copy_from_slice(&target[..len]);
+    buf[len] = 0;
+    0
+}
+
+unsafe extern "C" fn symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let arg2 = match stripped(arg2) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    let target = CStr::from_ptr(arg1).to_bytes();
+    let target = match HOST_ROOT.as_ref() {
+        Some(root) => replace_root(target, &PREFIX, root).unwrap_or(target.to_vec()),
+        None => target.to_vec(),
+    };
+    let target = CString::new(target).unwrap();
+    NEXT.assume_init_ref().symlink.unwrap()(target.as_ptr(), arg2.ptr())
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    let arg2 = match stripped(arg2) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    // the prefix is where the share is mounted as far as the guest is concerned
+    if arg1.is_root() || arg2.is_root() {
+        return -(EBUSY as c_int);
+    }
+    NEXT.assume_init_ref().rename.unwrap()(arg1.ptr(), arg2.ptr(), flags)
+}
+
+unsafe extern "C" fn rmdir(arg1: *const c_char) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    if arg1.is_root() {
+        return -(EBUSY as c_int);
+    }
+    NEXT.assume_init_ref().rmdir.unwrap()(arg1.ptr())
+}
+
+unsafe extern "C" fn statfs(arg1: *const c_char, arg2: *mut statvfs) -> c_int {
+    let path = match place(CStr::from_ptr(arg1).to_bytes()) {
+        Place::Inner(path) => CString::new(path).unwrap(),
+        _ => c"/".to_owned(),
+    };
+    NEXT.assume_init_ref().statfs.unwrap()(path.as_ptr(), arg2)
+}
+
+unsafe extern "C" fn getxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *mut c_char,
+    arg4: usize,
+) -> c_int {
+    match place(CStr::from_ptr(arg1).to_bytes()) {
+        Place::Inner(path) => {
+            let path = CString::new(path).unwrap();
+            NEXT.assume_init_ref().getxattr.unwrap()(path.as_ptr(), arg2, arg3, arg4)
+        }
+        Place::Synthetic(_) => -(ENODATA as c_int),
+        Place::Outside => -(ENOENT as c_int),
+    }
+}
+
+unsafe extern "C" fn listxattr(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    match place(CStr::from_ptr(arg1).to_bytes()) {
+        Place::Inner(path) => {
+            let path = CString::new(path).unwrap();
+            NEXT.assume_init_ref().listxattr.unwrap()(path.as_ptr(), arg2, arg3)
+        }
+        Place::Synthetic(_) => 0,
+        Place::Outside => -(ENOENT as c_int),
+    }
+}
+
+unsafe extern "C" fn opendir(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    match place(CStr::from_ptr(arg1).to_bytes()) {
+        Place::Inner(path) => {
+            let path = CString::new(path).unwrap();
+            match NEXT.assume_init_ref().opendir {
+                Some(opendir) => opendir(path.as_ptr(), arg2),
+                None => 0,
+            }
+        }
+        Place::Synthetic(_) => 0,
+        Place::Outside => -(ENOENT as c_int),
+    }
+}
+
+unsafe extern "C" fn readdir(
+    arg1: *const c_char,
+    arg2: *mut c_void,
+    arg3: fuse_fill_dir_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+    arg6: fuse_readdir_flags,
+) -> c_int {
+    match place(CStr::from_ptr(arg1).to_bytes()) {
+        Place::Inner(path) => {
+            let path = CString::new(path).unwrap();
+            NEXT.assume_init_ref().readdir.unwrap()(path.as_ptr(), arg2, arg3, arg4, arg5, arg6)
+        }
+        Place::Synthetic(child) => {
+            let child = CString::new(child).unwrap();
+            for name in [c".", c"..", &child] {
+                arg3.unwrap()(arg2, name.as_ptr(), ptr::null(), 0, 0);
+            }
+            0
+        }
+        Place::Outside => -(ENOENT as c_int),
+    }
+}
+
+unsafe extern "C" fn releasedir(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    match place(CStr::from_ptr(arg1).to_bytes()) {
+        Place::Inner(path) => {
+            let path = CString::new(path).unwrap();
+            match NEXT.assume_init_ref().releasedir {
+                Some(releasedir) => releasedir(path.as_ptr(), arg2),
+                None => 0,
+            }
+        }
+        _ => 0,
+    }
+}
+
+unsafe extern "C" fn fsyncdir(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: *mut fuse_file_info,
+) -> c_int {
+    match place(CStr::from_ptr(arg1).to_bytes()) {
+        Place::Inner(path) => {
+            let path = CString::new(path).unwrap();
+            NEXT.assume_init_ref().fsyncdir.unwrap()(path.as_ptr(), arg2, arg3)
+        }
+        _ => 0,
+    }
+}
+
+unsafe extern "C" fn access(arg1: *const c_char, arg2: c_int) -> c_int {
+    match place(CStr::from_ptr(arg1).to_bytes()) {
+        Place::Inner(path) => {
+            let path = CString::new(path).unwrap();
+            NEXT.assume_init_ref().access.unwrap()(path.as_ptr(), arg2)
+        }
+        Place::Synthetic(_) if arg2 & W_OK as c_int != 0 => -(EROFS as c_int),
+        Place::Synthetic(_) => 0,
+        Place::Outside => -(ENOENT as c_int),
+    }
+}
+
+unsafe extern "C" fn mknod(arg1: *const c_char, arg2: mode_t, arg3: dev_t) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().mknod.unwrap()(arg1.ptr(), arg2, arg3)
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().mkdir.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn unlink(arg1: *const c_char) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().unlink.unwrap()(arg1.ptr())
+}
+
+unsafe extern "C" fn link(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    let arg2 = match stripped(arg2) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().link.unwrap()(arg1.ptr(), arg2.ptr())
+}
+
+unsafe extern "C" fn chmod(arg1: *const c_char, arg2: mode_t, fi: *mut fuse_file_info) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().chmod.unwrap()(arg1.ptr(), arg2, fi)
+}
+
+unsafe extern "C" fn chown(
+    arg1: *const c_char,
+    arg2: uid_t,
+    arg3: gid_t,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().chown.unwrap()(arg1.ptr(), arg2, arg3, fi)
+}
+
+unsafe extern "C" fn truncate(arg1: *const c_char, arg2: off_t, fi: *mut fuse_file_info) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().truncate.unwrap()(arg1.ptr(), arg2, fi)
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().open.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn read(
+    arg1: *const c_char,
+    arg2: *mut c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().read.unwrap()(arg1.ptr(), arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().write.unwrap()(arg1.ptr(), arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn flush(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().flush.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn release(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().release.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn fsync(arg1: *const c_char, arg2: c_int, arg3: *mut fuse_file_info) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().fsync.unwrap()(arg1.ptr(), arg2, arg3)
+}
+
+unsafe extern "C" fn setxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *const c_char,
+    arg4: usize,
+    arg5: c_int,
+) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().setxattr.unwrap()(arg1.ptr(), arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn removexattr(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().removexattr.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().create.unwrap()(arg1.ptr(), arg2, arg3)
+}
+
+unsafe extern "C" fn lock(
+    arg1: *const c_char,
+    arg2: *mut fuse_file_info,
+    cmd: c_int,
+    arg3: *mut flock,
+) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().lock.unwrap()(arg1.ptr(), arg2, cmd, arg3)
+}
+
+unsafe extern "C" fn utimens(
+    arg1: *const c_char,
+    tv: *const timespec,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().utimens.unwrap()(arg1.ptr(), tv, fi)
+}
+
+unsafe extern "C" fn bmap(arg1: *const c_char, blocksize: usize, idx: *mut u64) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().bmap.unwrap()(arg1.ptr(), blocksize, idx)
+}
+
+unsafe extern "C" fn ioctl(
+    arg1: *const c_char,
+    cmd: c_int,
+    arg: *mut c_void,
+    arg2: *mut fuse_file_info,
+    flags: c_uint,
+    data: *mut c_void,
+) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().ioctl.unwrap()(arg1.ptr(), cmd, arg, arg2, flags, data)
+}
+
+unsafe extern "C" fn poll(
+    arg1: *const c_char,
+    arg2: *mut fuse_file_info,
+    ph: *mut fuse_pollhandle,
+    reventsp: *mut c_uint,
+) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().poll.unwrap()(arg1.ptr(), arg2, ph, reventsp)
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().write_buf.unwrap()(arg1.ptr(), buf, off, arg2)
+}
+
+unsafe extern "C" fn read_buf(
+    arg1: *const c_char,
+    bufp: *mut *mut fuse_bufvec,
+    size: usize,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().read_buf.unwrap()(arg1.ptr(), bufp, size, off, arg2)
+}
+
+unsafe extern "C" fn flock(arg1: *const c_char, arg2: *mut fuse_file_info, op: c_int) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().flock.unwrap()(arg1.ptr(), arg2, op)
+}
+
+unsafe extern "C" fn fallocate(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: off_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res,
+    };
+    NEXT.assume_init_ref().fallocate.unwrap()(arg1.ptr(), arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn copy_file_range(
+    path_in: *const c_char,
+    fi_in: *mut fuse_file_info,
+    offset_in: off_t,
+    path_out: *const c_char,
+    fi_out: *mut fuse_file_info,
+    offset_out: off_t,
+    size: usize,
+    flags: c_int,
+) -> isize {
+    let path_in = match stripped(path_in) {
+        Ok(path) => path,
+        Err(res) => return res as isize,
+    };
+    let path_out = match stripped(path_out) {
+        Ok(path) => path,
+        Err(res) => return res as isize,
+    };
+    NEXT.assume_init_ref().copy_file_range.unwrap()(
+        path_in.ptr(),
+        fi_in,
+        offset_in,
+        path_out.ptr(),
+        fi_out,
+        offset_out,
+        size,
+        flags,
+    )
+}
+
+unsafe extern "C" fn lseek(
+    arg1: *const c_char,
+    off: off_t,
+    whence: c_int,
+    arg2: *mut fuse_file_info,
+) -> off_t {
+    let arg1 = match stripped(arg1) {
+        Ok(path) => path,
+        Err(res) => return res as off_t,
+    };
+    NEXT.assume_init_ref().lseek.unwrap()(arg1.ptr(), off, whence, arg2)
+}
+
+/// Presents the next layer under `prefix` instead of at the root of the mount, e.g. with a
+/// prefix of `/data/app` the guest finds the share at `<mountpoint>/data/app`, matching the
+/// layout of a container volume without bind mounts in the VM. The directories leading to the
+/// prefix contain nothing else, take their owner and times from the share's root but have
+/// inodes of their own at the top of the inode range, and cannot be changed, while the prefix
+/// itself cannot be renamed or removed, like a mountpoint. When `host_root` is given, absolute
+/// symlink targets pointing into that host directory are shown pointing below the prefix, and
+/// those the guest creates below the prefix are stored pointing into the host directory, so
+/// symlinks resolve on both sides.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer, a valid C string prefix and a
+/// valid C string or null host root
+#[no_mangle]
+pub unsafe extern "C" fn new_prefix_layer(
+    next: *const fuse_operations,
+    prefix: *const c_char,
+    host_root: *const c_char,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    let trim = |path: &[u8]| {
+        let len = path
+            .iter()
+            .rposition(|&c| c != b'/')
+            .map_or(0, |last| last + 1);
+        path[..len].to_vec()
+    };
+    let prefix = trim(CStr::from_ptr(prefix).to_bytes());
+    if prefix.is_empty() || next.getattr.is_none() {
+        return Box::into_raw(Box::new(next));
+    }
+    PREFIX = match prefix.starts_with(b"/") {
+        true => prefix,
+        false => [&b"/"[..], &prefix].concat(),
+    };
+    if !host_root.is_null() {
+        HOST_ROOT = Some(trim(CStr::from_ptr(host_root).to_bytes()));
+    }
+    Box::into_raw(Box::new(fuse_operations {
+        getattr: Some(getattr),
+        readlink: next.readlink.and(Some(readlink)),
+        mknod: next.mknod.and(Some(mknod)),
+        mkdir: next.mkdir.and(Some(mkdir)),
+        unlink: next.unlink.and(Some(unlink)),
+        rmdir: next.rmdir.and(Some(rmdir)),
+        symlink: next.symlink.and(Some(symlink)),
+        rename: next.rename.and(Some(rename)),
+        link: next.link.and(Some(link)),
+        chmod: next.chmod.and(Some(chmod)),
+        chown: next.chown.and(Some(chown)),
+        truncate: next.truncate.and(Some(truncate)),
+        open: next.open.and(Some(open)),
+        read: next.read.and(Some(read)),
+        write: next.write.and(Some(write)),
+        statfs: next.statfs.and(Some(statfs)),
+        flush: next.flush.and(Some(flush)),
+        release: next.release.and(Some(release)),
+        fsync: next.fsync.and(Some(fsync)),
+        setxattr: next.setxattr.and(Some(setxattr)),
+        getxattr: next.getxattr.and(Some(getxattr)),
+        listxattr: next.listxattr.and(Some(listxattr)),
+        removexattr: next.removexattr.and(Some(removexattr)),
+        opendir: Some(opendir),
+        readdir: next.readdir.and(Some(readdir)),
+        releasedir: Some(releasedir),
+        fsyncdir: next.fsyncdir.and(Some(fsyncdir)),
+        access: next.access.and(Some(access)),
+        create: next.create.and(Some(create)),
+        lock: next.lock.and(Some(lock)),
+        utimens: next.utimens.and(Some(utimens)),
+        bmap: next.bmap.and(Some(bmap)),
+        ioctl: next.ioctl.and(Some(ioctl)),
+        poll: next.poll.and(Some(poll)),
+        write_buf: next.write_buf.and(Some(write_buf)),
+        read_buf: next.read_buf.and(Some(read_buf)),
+        flock: next.flock.and(Some(flock)),
+        fallocate: next.fallocate.and(Some(fallocate)),
+        copy_file_range: next.copy_file_range.and(Some(copy_file_range)),
+        lseek: next.lseek.and(Some(lseek)),
+        ..next
+    }))
+}
@@ -0,0 +1,452 @@
+use crate::{
+    fuse::{
+        dev_t, fuse_bufvec, fuse_file_info, fuse_fill_dir_t, fuse_operations, fuse_readdir_flags,
+        gid_t, mode_t, off_t, stat, statvfs, timespec, uid_t,
+    },
+    hide::glob,
+};
+use std::{
+    ffi::{c_char, c_int, c_uint, c_void, CStr},
+    mem::MaybeUninit,
+    ptr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Condvar, Mutex,
+    },
+};
+
+/// Operations that are bulk work unless configured otherwise.
+const DEFAULT_BULK: &str = "write,write_buf,fallocate,copy_file_range";
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut SLOTS: usize = 0;
+/// Reads of more bytes than this are bulk work.
+static mut SMALL_READ: usize = 0;
+/// Operations, and patterns their path must match if any, that make bulk work.
+static mut RULES: Vec<(String, Option<Vec<u8>>)> = Vec::new();
+static STATE: Mutex<State> = Mutex::new(State {
+    running: 0,
+    waiting: 0,
+});
+static FREED: Condvar = Condvar::new();
+static INTERACTIVE: AtomicU64 = AtomicU64::new(0);
+static BULK: AtomicU64 = AtomicU64::new(0);
+/// Bulk operations that had to wait for interactive ones.
+static DEFERRED: AtomicU64 = AtomicU64::new(0);
+
+struct State {
+    running: usize,
+    /// Interactive operations waiting for a slot.
+    waiting: usize,
+}
+
+/// A slot to run an operation in, given back when dropped.
+struct Slot;
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        STATE.lock().unwrap().running -= 1;
+        FREED.notify_all();
+    }
+}
+
+/// Whether `pattern` matches `path` or one of its parents, so a directory's pattern covers
+/// everything below it.
+fn matches(pattern: &[u8], path: &[u8]) -> bool {
+    path.iter()
+        .enumerate()
+        .filter(|&(i, &c)| c == b'/' && i > 0)
+        .any(|(i, _)| glob(pattern, &path[..i]))
+        || glob(pattern, path)
+}
+
+unsafe fn is_bulk(op: &str, path: *const c_char) -> bool {
+    RULES.iter().any(|(rule, pattern)| {
+        rule == op
+            && pattern.as_ref().is_none_or(|pattern| {
+                !path.is_null() && matches(pattern, CStr::from_ptr(path).to_bytes())
+            })
+    })
+}
+
+/// Waits for a slot. Interactive operations take the next free slot, bulk ones only get a slot
+/// no interactive operation is waiting for.
+unsafe fn schedule(bulk: bool) -> Slot {
+    let mut state = STATE.lock().unwrap();
+    if bulk {
+        BULK.fetch_add(1, Ordering::Relaxed);
+        if state.running >= SLOTS || state.waiting > 0 {
+            DEFERRED.fetch_add(1, Ordering::Relaxed);
+        }
+        state = FREED
+            .wait_while(state, |state| state.running >= SLOTS || state.waiting > 0)
+            .unwrap();
+    } else {
+        INTERACTIVE.fetch_add(1, Ordering::Relaxed);
+        state.waiting += 1;
+        state = FREED
+            .wait_while(state, |state| state.running >= SLOTS)
+            .unwrap();
+        state.waiting -= 1;
+    }
+    state.running += 1;
+    Slot
+}
+
+unsafe extern "C" fn getattr(
+    arg1: *const c_char,
+    arg2: *mut stat,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let _slot = schedule(is_bulk("getattr", arg1));
+    NEXT.assume_init_ref().getattr.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn readlink(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    let _slot = schedule(is_bulk("readlink", arg1));
+    NEXT.assume_init_ref().readlink.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn mknod(arg1: *const c_char, arg2: mode_t, arg3: dev_t) -> c_int {
+    let _slot = schedule(is_bulk("mknod", arg1));
+    NEXT.assume_init_ref().mknod.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    let _slot = schedule(is_bulk("mkdir", arg1));
+    NEXT.assume_init_ref().mkdir.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn unlink(arg1: *const c_char) -> c_int {
+    let _slot = schedule(is_bulk("unlink", arg1));
+    NEXT.assume_init_ref().unlink.unwrap()(arg1)
+}
+
+unsafe extern "C" fn rmdir(arg1: *const c_char) -> c_int {
+    let _slot = schedule(is_bulk("rmdir", arg1));
+    NEXT.assume_init_ref().rmdir.unwrap()(arg1)
+}
+
+unsafe extern "C" fn symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let _slot = schedule(is_bulk("symlink", arg1));
+    NEXT.assume_init_ref().symlink.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    let _slot = schedule(is_bulk("rename", arg1));
+    NEXT.assume_init_ref().rename.unwrap()(arg1, arg2, flags)
+}
+
+unsafe extern "C" fn link(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let _slot = schedule(is_bulk("link", arg1));
+    NEXT.assume_init_ref().link.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn chmod(arg1: *const c_char, arg2: mode_t, fi: *mut fuse_file_info) -> c_int {
+    let _slot = schedule(is_bulk("chmod", arg1));
+    NEXT.assume_init_ref().chmod.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn chown(
+    arg1: *const c_char,
+    arg2: uid_t,
+    arg3: gid_t,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let _slot = schedule(is_bulk("chown", arg1));
+    NEXT.assume_init_ref().chown.unwrap()(arg1, arg2, arg3, fi)
+}
+
+unsafe extern "C" fn truncate(arg1: *const c_char, arg2: off_t, fi: *mut fuse_file_info) -> c_int {
+    let _slot = schedule(is_bulk("truncate", arg1));
+    NEXT.assume_init_ref().truncate.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let _slot = schedule(is_bulk("open", arg1));
+    NEXT.assume_init_ref().open.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn read(
+    arg1: *const c_char,
+    arg2: *mut c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let _slot = schedule(arg3 > SMALL_READ || is_bulk("read", arg1));
+    NEXT.assume_init_ref().read.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let _slot = schedule(is_bulk("write", arg1));
+    NEXT.assume_init_ref().write.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn statfs(arg1: *const c_char, arg2: *mut statvfs) -> c_int {
+    let _slot = schedule(is_bulk("statfs", arg1));
+    NEXT.assume_init_ref().statfs.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn flush(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let _slot = schedule(is_bulk("flush", arg1));
+    NEXT.assume_init_ref().flush.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn fsync(arg1: *const c_char, arg2: c_int, arg3: *mut fuse_file_info) -> c_int {
+    let _slot = schedule(is_bulk("fsync", arg1));
+    NEXT.assume_init_ref().fsync.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn setxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *const c_char,
+    arg4: usize,
+    arg5: c_int,
+) -> c_int {
+    let _slot = schedule(is_bulk("setxattr", arg1));
+    NEXT.assume_init_ref().setxattr.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn getxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *mut c_char,
+    arg4: usize,
+) -> c_int {
+    let _slot = schedule(is_bulk("getxattr", arg1));
+    NEXT.assume_init_ref().getxattr.unwrap()(arg1, arg2, arg3, arg4)
+}
+
+unsafe extern "C" fn listxattr(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    let _slot = schedule(is_bulk("listxattr", arg1));
+    NEXT.assume_init_ref().listxattr.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn removexattr(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let _slot = schedule(is_bulk("removexattr", arg1));
+    NEXT.assume_init_ref().removexattr.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn opendir(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let _slot = schedule(is_bulk("opendir", arg1));
+    NEXT.assume_init_ref().opendir.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn readdir(
+    arg1: *const c_char,
+    arg2: *mut c_void,
+    arg3: fuse_fill_dir_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+    arg6: fuse_readdir_flags,
+) -> c_int {
+    let _slot = schedule(is_bulk("readdir", arg1));
+    NEXT.assume_init_ref().readdir.unwrap()(arg1, arg2, arg3, arg4, arg5, arg6)
+}
+
+unsafe extern "C" fn fsyncdir(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: *mut fuse_file_info,
+) -> c_int {
+    let _slot = schedule(is_bulk("fsyncdir", arg1));
+    NEXT.assume_init_ref().fsyncdir.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn access(arg1: *const c_char, arg2: c_int) -> c_int {
+    let _slot = schedule(is_bulk("access", arg1));
+    NEXT.assume_init_ref().access.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    let _slot = schedule(is_bulk("create", arg1));
+    NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn utimens(
+    arg1: *const c_char,
+    tv: *const timespec,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let _slot = schedule(is_bulk("utimens", arg1));
+    NEXT.assume_init_ref().utimens.unwrap()(arg1, tv, fi)
+}
+
+unsafe extern "C" fn bmap(arg1: *const c_char, blocksize: usize, idx: *mut u64) -> c_int {
+    let _slot = schedule(is_bulk("bmap", arg1));
+    NEXT.assume_init_ref().bmap.unwrap()(arg1, blocksize, idx)
+}
+
+unsafe extern "C" fn ioctl(
+    arg1: *const c_char,
+    cmd: c_int,
+    arg: *mut c_void,
+    arg2: *mut fuse_file_info,
+    flags: c_uint,
+    data: *mut c_void,
+) -> c_int {
+    let _slot = schedule(is_bulk("ioctl", arg1));
+    NEXT.assume_init_ref().ioctl.unwrap()(arg1, cmd, arg, arg2, flags, data)
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let _slot = schedule(is_bulk("write_buf", arg1));
+    NEXT.assume_init_ref().write_buf.unwrap()(arg1, buf, off, arg2)
+}
+
+unsafe extern "C" fn read_buf(
+    arg1: *const c_char,
+    bufp: *mut *mut fuse_bufvec,
+    size: usize,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let _slot = schedule(size > SMALL_READ || is_bulk("read_buf", arg1));
+    NEXT.assume_init_ref().read_buf.unwrap()(arg1, bufp, size, off, arg2)
+}
+
+unsafe extern "C" fn fallocate(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: off_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let _slot = schedule(is_bulk("fallocate", arg1));
+    NEXT.assume_init_ref().fallocate.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn copy_file_range(
+    path_in: *const c_char,
+    fi_in: *mut fuse_file_info,
+    offset_in: off_t,
+    path_out: *const c_char,
+    fi_out: *mut fuse_file_info,
+    offset_out: off_t,
+    size: usize,
+    flags: c_int,
+) -> isize {
+    let _slot = schedule(is_bulk("copy_file_range", path_in));
+    NEXT.assume_init_ref().copy_file_range.unwrap()(
+        path_in, fi_in, offset_in, path_out, fi_out, offset_out, size, flags,
+    )
+}
+
+unsafe extern "C" fn lseek(
+    arg1: *const c_char,
+    off: off_t,
+    whence: c_int,
+    arg2: *mut fuse_file_info,
+) -> off_t {
+    let _slot = schedule(is_bulk("lseek", arg1));
+    NEXT.assume_init_ref().lseek.unwrap()(arg1, off, whence, arg2)
+}
+
+/// Prints how many operations ran in each class and how many bulk ones were held back.
+#[no_mangle]
+pub extern "C" fn priority_report() {
+    eprintln!(
+        "{} interactive operations, {} bulk, {} bulk deferred",
+        INTERACTIVE.load(Ordering::Relaxed),
+        BULK.load(Ordering::Relaxed),
+        DEFERRED.load(Ordering::Relaxed)
+    );
+}
+
+/// Runs at most `slots` operations against the next layer at once, in two classes: bulk work
+/// only gets a slot no interactive operation is waiting for, so metadata operations and small
+/// reads stay responsive while background jobs in the container churn data. Reads of more
+/// than `small_read` bytes are bulk, as are the operations listed in `bulk`, a comma separated
+/// list of `fuse_operations` members, each optionally followed by `@` and a glob pattern the
+/// path or one of its parents must match, e.g. `write,read@/var/lib/backup/*`. An empty list
+/// makes writes, fallocate and copy_file_range bulk. Bulk work can starve as long as
+/// interactive operations keep every slot busy. Locks and polls, which may wait on other
+/// requests for as long as they like, and releases go through without a slot.
+/// `priority_report` prints the totals. Returns null if `slots` is 0 or `bulk` is malformed.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer and a valid C string
+#[no_mangle]
+pub unsafe extern "C" fn new_priority_layer(
+    next: *const fuse_operations,
+    slots: usize,
+    small_read: usize,
+    bulk: *const c_char,
+) -> *const fuse_operations {
+    if slots == 0 {
+        eprintln!("priority scheduling needs at least one slot");
+        return ptr::null();
+    }
+    let bulk = match CStr::from_ptr(bulk).to_str() {
+        Ok("") => DEFAULT_BULK,
+        Ok(bulk) => bulk,
+        Err(err) => {
+            eprintln!("failed to parse bulk operations: {err}");
+            return ptr::null();
+        }
+    };
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    SLOTS = slots;
+    SMALL_READ = small_read;
+    RULES = bulk
+        .split(',')
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| match rule.split_once('@') {
+            Some((op, pattern)) => (op.to_string(), Some(pattern.as_bytes().to_vec())),
+            None => (rule.to_string(), None),
+        })
+        .collect();
+    Box::into_raw(Box::new(fuse_operations {
+        getattr: next.getattr.and(Some(getattr)),
+        readlink: next.readlink.and(Some(readlink)),
+        mknod: next.mknod.and(Some(mknod)),
+        mkdir: next.mkdir.and(Some(mkdir)),
+        unlink: next.unlink.and(Some(unlink)),
+        rmdir: next.rmdir.and(Some(rmdir)),
+        symlink: next.symlink.and(Some(symlink)),
+        rename: next.rename.and(Some(rename)),
+        link: next.link.and(Some(link)),
+        chmod: next.chmod.and(Some(chmod)),
+        chown: next.chown.and(Some(chown)),
+        truncate: next.truncate.and(Some(truncate)),
+        open: next.open.and(Some(open)),
+        read: next.read.and(Some(read)),
+        write: next.write.and(Some(write)),
+        statfs: next.statfs.and(Some(statfs)),
+        flush: next.flush.and(Some(flush)),
+        fsync: next.fsync.and(Some(fsync)),
+        setxattr: next.setxattr.and(Some(setxattr)),
+        getxattr: next.getxattr.and(Some(getxattr)),
+        listxattr: next.listxattr.and(Some(listxattr)),
+        removexattr: next.removexattr.and(Some(removexattr)),
+        opendir: next.opendir.and(Some(opendir)),
+        readdir: next.readdir.and(Some(readdir)),
+        fsyncdir: next.fsyncdir.and(Some(fsyncdir)),
+        access: next.access.and(Some(access)),
+        create: next.create.and(Some(create)),
+        utimens: next.utimens.and(Some(utimens)),
+        bmap: next.bmap.and(Some(bmap)),
+        ioctl: next.ioctl.and(Some(ioctl)),
+        write_buf: next.write_buf.and(Some(write_buf)),
+        read_buf: next.read_buf.and(Some(read_buf)),
+        fallocate: next.fallocate.and(Some(fallocate)),
+        copy_file_range: next.copy_file_range.and(Some(copy_file_range)),
+        lseek: next.lseek.and(Some(lseek)),
+        ..next
+    }))
+}
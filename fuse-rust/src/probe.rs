@@ -0,0 +1,142 @@
+use crate::fuse::{
+    fuse_config, fuse_conn_info, fuse_operations, FUSE_CAP_CACHE_SYMLINKS, FUSE_CAP_EXPIRE_ONLY,
+    FUSE_CAP_HANDLE_KILLPRIV, FUSE_CAP_HANDLE_KILLPRIV_V2, FUSE_CAP_POSIX_ACL,
+    FUSE_CAP_READDIRPLUS, FUSE_CAP_SETXATTR_EXT, FUSE_CAP_WRITEBACK_CACHE,
+};
+use std::{
+    ffi::{c_uint, c_void},
+    fs,
+    mem::MaybeUninit,
+    ptr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+/// Capabilities offered by the kernel, known once the mount is initialized.
+static CAPABLE: AtomicU32 = AtomicU32::new(0);
+static PROTO_MINOR: AtomicU32 = AtomicU32::new(0);
+/// Capabilities some layer asked for that the kernel does not offer.
+static DISABLED: AtomicU32 = AtomicU32::new(0);
+static HOST_KERNEL: Mutex<String> = Mutex::new(String::new());
+
+/// A feature of the FUSE protocol layers may depend on.
+struct Feature {
+    name: &'static str,
+    cap: c_uint,
+    /// First protocol minor version the kernel can offer it with.
+    proto_minor: c_uint,
+}
+
+const FEATURES: [Feature; 8] = [
+    Feature {
+        name: "readdirplus",
+        cap: FUSE_CAP_READDIRPLUS,
+        proto_minor: 21,
+    },
+    Feature {
+        name: "writeback cache",
+        cap: FUSE_CAP_WRITEBACK_CACHE,
+        proto_minor: 23,
+    },
+    Feature {
+        name: "posix acl",
+        cap: FUSE_CAP_POSIX_ACL,
+        proto_minor: 26,
+    },
+    Feature {
+        name: "killpriv",
+        cap: FUSE_CAP_HANDLE_KILLPRIV,
+        proto_minor: 26,
+    },
+    Feature {
+        name: "symlink cache",
+        cap: FUSE_CAP_CACHE_SYMLINKS,
+        proto_minor: 28,
+    },
+    Feature {
+        name: "killpriv v2",
+        cap: FUSE_CAP_HANDLE_KILLPRIV_V2,
+        proto_minor: 33,
+    },
+    Feature {
+        name: "extended setxattr",
+        cap: FUSE_CAP_SETXATTR_EXT,
+        proto_minor: 33,
+    },
+    Feature {
+        name: "expire only",
+        cap: FUSE_CAP_EXPIRE_ONLY,
+        proto_minor: 37,
+    },
+];
+
+unsafe extern "C" fn init(conn: *mut fuse_conn_info, cfg: *mut fuse_config) -> *mut c_void {
+    let conn = &mut *conn;
+    CAPABLE.store(conn.capable, Ordering::Relaxed);
+    PROTO_MINOR.store(conn.proto_minor, Ordering::Relaxed);
+    let release = fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+    *HOST_KERNEL.lock().unwrap() = release.trim().to_string();
+
+    let res = match NEXT.assume_init_ref().init {
+        Some(init) => init(conn, cfg),
+        None => ptr::null_mut(),
+    };
+    // the layers below have said what they want by now, and libfuse would refuse to mount when
+    // asked for more than the kernel offers
+    let missing = conn.want & !conn.capable;
+    for feature in FEATURES.iter().filter(|feature| missing & feature.cap != 0) {
+        eprintln!(
+            "disabling {}: not offered by the kernel (protocol 7.{}, needs 7.{})",
+            feature.name, conn.proto_minor, feature.proto_minor
+        );
+    }
+    conn.want &= conn.capable;
+    DISABLED.store(missing, Ordering::Relaxed);
+    res
+}
+
+/// Prints which features the kernel offers for the mount and which were asked for but
+/// disabled.
+#[no_mangle]
+pub extern "C" fn probe_report() {
+    eprintln!(
+        "host kernel {}, FUSE protocol 7.{}",
+        HOST_KERNEL.lock().unwrap(),
+        PROTO_MINOR.load(Ordering::Relaxed)
+    );
+    let disabled = DISABLED.load(Ordering::Relaxed);
+    for feature in FEATURES.iter() {
+        let state = if CAPABLE.load(Ordering::Relaxed) & feature.cap != 0 {
+            "offered"
+        } else if disabled & feature.cap != 0 {
+            "not offered, disabled"
+        } else {
+            "not offered"
+        };
+        eprintln!("{}: {state}", feature.name);
+    }
+    // both only exist in protocol versions libfuse does not negotiate through this API
+    eprintln!("supplementary groups: not available");
+    eprintln!("dax: not available, virtiofs only");
+}
+
+/// Records the FUSE features the kernel offers when the mount is initialized and drops those the
+/// layers below want but the kernel lacks, logging each, so the mount comes up without them
+/// instead of failing. Layers check what is offered in their own `init`. Should be the
+/// outermost layer so it sees what every other layer wants. `probe_report` prints the matrix.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer
+#[no_mangle]
+pub unsafe extern "C" fn new_probe_layer(next: *const fuse_operations) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    Box::into_raw(Box::new(fuse_operations {
+        init: Some(init),
+        ..next
+    }))
+}
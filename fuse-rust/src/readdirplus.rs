@@ -0,0 +1,187 @@
+use crate::{
+    budget::Consumer,
+    fuse::{
+        fuse_config, fuse_conn_info, fuse_file_info, fuse_fill_dir_flags,
+        fuse_fill_dir_flags_FUSE_FILL_DIR_PLUS, fuse_fill_dir_t, fuse_operations,
+        fuse_readdir_flags, fuse_readdir_flags_FUSE_READDIR_PLUS, off_t, stat,
+        FUSE_CAP_READDIRPLUS, FUSE_CAP_READDIRPLUS_AUTO,
+    },
+};
+use std::{
+    collections::BTreeSet,
+    ffi::{c_char, c_int, c_uint, c_void, CStr},
+    mem::{self, MaybeUninit},
+    ptr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut MAX_ENTRIES: usize = 0;
+/// Directories found to hold more than `MAX_ENTRIES` entries when last listed.
+static LARGE: Mutex<BTreeSet<Vec<u8>>> = Mutex::new(BTreeSet::new());
+/// Listings answered without attributes.
+static PLAIN: AtomicU64 = AtomicU64::new(0);
+static MEMORY: Consumer = Consumer::new(c"large directories", evict);
+
+fn footprint(path: &[u8]) -> usize {
+    mem::size_of::<Vec<u8>>() + path.len()
+}
+
+/// Forgets directories, which are listed with attributes again until found to be large anew.
+fn evict(bytes: usize) {
+    let mut large = LARGE.lock().unwrap();
+    let mut freed = 0;
+    while freed < bytes {
+        let Some(path) = large.pop_first() else {
+            break;
+        };
+        freed += footprint(&path);
+    }
+    MEMORY.release(freed);
+}
+
+fn forget(path: &[u8]) {
+    if LARGE.lock().unwrap().remove(path) {
+        MEMORY.release(footprint(path));
+    }
+}
+
+struct Filler {
+    buf: *mut c_void,
+    filler: fuse_fill_dir_t,
+    /// Entries passed on so far.
+    entries: usize,
+    plain: bool,
+    /// Whether the kernel's buffer filled up before the listing ended.
+    full: bool,
+}
+
+unsafe extern "C" fn fill(
+    buf: *mut c_void,
+    name: *const c_char,
+    stbuf: *const stat,
+    off: off_t,
+    flags: fuse_fill_dir_flags,
+) -> c_int {
+    let filler = &mut *buf.cast::<Filler>();
+    filler.entries += 1;
+    // past the threshold, the rest of the listing goes without attributes too
+    filler.plain |= filler.entries > MAX_ENTRIES;
+    let flags = match filler.plain {
+        true => flags & !fuse_fill_dir_flags_FUSE_FILL_DIR_PLUS,
+        false => flags,
+    };
+    let res = filler.filler.unwrap()(filler.buf, name, stbuf, off, flags);
+    filler.full |= res != 0;
+    res
+}
+
+unsafe extern "C" fn init(conn: *mut fuse_conn_info, cfg: *mut fuse_config) -> *mut c_void {
+    let conn = &mut *conn;
+    // with the automatic mode the kernel itself falls back to plain listings when the guest
+    // does not look the entries up afterwards
+    conn.want |= conn.capable & (FUSE_CAP_READDIRPLUS | FUSE_CAP_READDIRPLUS_AUTO);
+    match NEXT.assume_init_ref().init {
+        Some(init) => init(conn, cfg),
+        None => ptr::null_mut(),
+    }
+}
+
+unsafe extern "C" fn readdir(
+    arg1: *const c_char,
+    arg2: *mut c_void,
+    arg3: fuse_fill_dir_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+    arg6: fuse_readdir_flags,
+) -> c_int {
+    if arg1.is_null() || arg6 & fuse_readdir_flags_FUSE_READDIR_PLUS == 0 {
+        return NEXT.assume_init_ref().readdir.unwrap()(arg1, arg2, arg3, arg4, arg5, arg6);
+    }
+    let path = CStr::from_ptr(arg1).to_bytes();
+    let large = LARGE.lock().unwrap().contains(path);
+    let flags = match large {
+        // listing without attributes spares the layers below a stat per entry
+        true => arg6 & !fuse_readdir_flags_FUSE_READDIR_PLUS,
+        false => arg6,
+    };
+    let mut filler = Filler {
+        buf: arg2,
+        filler: arg3,
+        entries: 0,
+        plain: large,
+        full: false,
+    };
+    let res = NEXT.assume_init_ref().readdir.unwrap()(
+        arg1,
+        (&mut filler as *mut Filler).cast(),
+        Some(fill),
+        arg4,
+        arg5,
+        flags,
+    );
+    if filler.plain {
+        PLAIN.fetch_add(1, Ordering::Relaxed);
+    }
+    // only a listing from the start that ran to the end tells that a directory has shrunk
+    if filler.entries > MAX_ENTRIES {
+        if !large && LARGE.lock().unwrap().insert(path.to_vec()) {
+            MEMORY.charge(footprint(path));
+            crate::budget::enforce();
+        }
+    } else if large && res == 0 && arg4 == 0 && !filler.full {
+        forget(path);
+    }
+    res
+}
+
+unsafe extern "C" fn rmdir(arg1: *const c_char) -> c_int {
+    let res = NEXT.assume_init_ref().rmdir.unwrap()(arg1);
+    if res == 0 {
+        forget(CStr::from_ptr(arg1).to_bytes());
+    }
+    res
+}
+
+/// Prints which directories are listed without attributes and how many listings were.
+#[no_mangle]
+pub extern "C" fn readdirplus_report() {
+    let large = LARGE.lock().unwrap();
+    for path in large.iter() {
+        eprintln!("{}: plain listings", String::from_utf8_lossy(path));
+    }
+    eprintln!(
+        "{} listings without attributes, {} large directories",
+        PLAIN.load(Ordering::Relaxed),
+        large.len()
+    );
+}
+
+/// Keeps listings with attributes for small directories but answers those of directories with
+/// more than `max_entries` entries without them, like a plain readdir, so listing a huge
+/// directory does not set off a storm of lookups through the layers below. A directory counts
+/// as large from the listing that went past the threshold on, and as small again once a full
+/// listing of it stays within it. The kernel is asked for its automatic mode as well, which
+/// only requests attributes while the guest looks entries up after listing them.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer
+#[no_mangle]
+pub unsafe extern "C" fn new_readdirplus_layer(
+    next: *const fuse_operations,
+    max_entries: c_uint,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    MAX_ENTRIES = max_entries as usize;
+    Box::into_raw(Box::new(fuse_operations {
+        init: Some(init),
+        readdir: next.readdir.and(Some(readdir)),
+        rmdir: next.rmdir.and(Some(rmdir)),
+        ..next
+    }))
+}
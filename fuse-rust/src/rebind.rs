@@ -0,0 +1,763 @@
+use crate::{
+    control::Control,
+    fuse::{
+        dev_t, flock, fuse, fuse_bufvec, fuse_config, fuse_conn_info, fuse_file_info,
+        fuse_fill_dir_t, fuse_get_context, fuse_invalidate_path, fuse_operations, fuse_pollhandle,
+        fuse_readdir_flags, gid_t, mode_t, off_t, stat, statvfs, timespec, uid_t, ENOTDIR, O_CREAT,
+        O_EXCL, O_TRUNC, RENAME_EXCHANGE, S_IFDIR, S_IFMT,
+    },
+};
+use std::{
+    collections::BTreeMap,
+    ffi::{c_char, c_int, c_uint, c_void, CStr, CString},
+    mem::MaybeUninit,
+    ptr,
+    sync::{
+        atomic::{AtomicPtr, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static CONTROL: Control = Control::new();
+/// Host directory currently served as the root of the mount, without trailing slashes.
+static ROOT: RwLock<Vec<u8>> = RwLock::new(Vec::new());
+/// Open handles by the handle number given to the guest, which stays the same across rebinds.
+static HANDLES: Mutex<BTreeMap<u64, Handle>> = Mutex::new(BTreeMap::new());
+static NEXT_FH: AtomicU64 = AtomicU64::new(1);
+static FUSE: AtomicPtr<fuse> = AtomicPtr::new(ptr::null_mut());
+
+struct Handle {
+    /// Path within the mount the handle is currently reachable at, kept up to date across
+    /// renames and cleared once the file is unlinked or replaced.
+    path: Option<Vec<u8>>,
+    /// Handle of the next layer, replaced when the handle is moved to a new root.
+    backing: Arc<Backing>,
+}
+
+/// A handle of the next layer. Requests hold a reference for as long as they use it, so a
+/// rebind replacing it only closes it once the last of them is done.
+struct Backing {
+    /// Path the handle was opened at, below the root it was opened in.
+    path: CString,
+    flags: c_int,
+    dir: bool,
+    fh: u64,
+    closed: bool,
+}
+
+impl Backing {
+    unsafe fn close(mut self) -> c_int {
+        self.closed = true;
+        release_backing(self.path.as_ptr(), self.flags, self.dir, self.fh)
+    }
+}
+
+impl Drop for Backing {
+    fn drop(&mut self) {
+        if !self.closed {
+            unsafe { release_backing(self.path.as_ptr(), self.flags, self.dir, self.fh) };
+        }
+    }
+}
+
+/// A guest path moved below the current root, or null if the guest path was.
+struct Rooted(Option<CString>);
+
+impl Rooted {
+    fn ptr(&self) -> *const c_char {
+        self.0.as_ref().map_or(ptr::null(), |path| path.as_ptr())
+    }
+}
+
+fn join(root: &[u8], path: &[u8]) -> CString {
+    let path = match path {
+        b"/" if !root.is_empty() => &b""[..],
+        path => path,
+    };
+    CString::new([root, path].concat()).unwrap()
+}
+
+unsafe fn rooted(path: *const c_char) -> Rooted {
+    if path.is_null() {
+        return Rooted(None);
+    }
+    Rooted(Some(join(
+        &ROOT.read().unwrap(),
+        CStr::from_ptr(path).to_bytes(),
+    )))
+}
+
+/// Puts the next layer's handle into `fi` for the duration of a call and the guest's back
+/// afterwards, keeping the next layer's handle open until then.
+struct Swapped {
+    fi: *mut fuse_file_info,
+    fh: u64,
+    _backing: Option<Arc<Backing>>,
+}
+
+impl Swapped {
+    unsafe fn swap(fi: *mut fuse_file_info) -> Swapped {
+        if fi.is_null() {
+            return Swapped {
+                fi,
+                fh: 0,
+                _backing: None,
+            };
+        }
+        let fh = (*fi).fh;
+        let backing = HANDLES
+            .lock()
+            .unwrap()
+            .get(&fh)
+            .map(|handle| handle.backing.clone());
+        if let Some(backing) = backing.as_ref() {
+            (*fi).fh = backing.fh;
+        }
+        Swapped {
+            fi,
+            fh,
+            _backing: backing,
+        }
+    }
+}
+
+impl Drop for Swapped {
+    fn drop(&mut self) {
+        if !self.fi.is_null() {
+            unsafe { (*self.fi).fh = self.fh };
+        }
+    }
+}
+
+/// Opens `path` through the next layer with the open `fi` describes, returning its handle.
+unsafe fn open_backing(path: &CStr, fi: &fuse_file_info, dir: bool) -> Result<u64, c_int> {
+    let mut fi = *fi;
+    let next = NEXT.assume_init_ref();
+    let res = match (dir, next.opendir, next.open) {
+        (true, Some(opendir), _) => opendir(path.as_ptr(), &mut fi),
+        (false, _, Some(open)) => open(path.as_ptr(), &mut fi),
+        _ => 0,
+    };
+    match res {
+        0 => Ok(fi.fh),
+        res => Err(res),
+    }
+}
+
+unsafe fn release_backing(path: *const c_char, flags: c_int, dir: bool, fh: u64) -> c_int {
+    let mut fi = MaybeUninit::<fuse_file_info>::zeroed().assume_init();
+    fi.flags = flags;
+    fi.fh = fh;
+    let next = NEXT.assume_init_ref();
+    match (dir, next.releasedir, next.release) {
+        (true, Some(releasedir), _) => releasedir(path, &mut fi),
+        (false, _, Some(release)) => release(path, &mut fi),
+        _ => 0,
+    }
+}
+
+/// Hands the guest a handle of its own for the handle the next layer just opened in `fi` at
+/// `rooted`.
+unsafe fn track(path: *const c_char, rooted: &Rooted, fi: *mut fuse_file_info, dir: bool) {
+    let fh = NEXT_FH.fetch_add(1, Ordering::Relaxed);
+    let backing = Backing {
+        path: rooted.0.clone().unwrap_or_default(),
+        flags: (*fi).flags,
+        dir,
+        fh: (*fi).fh,
+        closed: false,
+    };
+    HANDLES.lock().unwrap().insert(
+        fh,
+        Handle {
+            path: Some(CStr::from_ptr(path).to_bytes().to_vec()),
+            backing: Arc::new(backing),
+        },
+    );
+    (*fi).fh = fh;
+}
+
+unsafe fn untrack(path: *const c_char, fi: *mut fuse_file_info, dir: bool) -> c_int {
+    let handle = HANDLES.lock().unwrap().remove(&(*fi).fh);
+    match handle {
+        // requests still using the handle close it when they are done
+        Some(handle) => Arc::try_unwrap(handle.backing).map_or(0, |backing| backing.close()),
+        None => release_backing(rooted(path).ptr(), (*fi).flags, dir, (*fi).fh),
+    }
+}
+
+fn below<'a>(path: &'a [u8], dir: &[u8]) -> Option<&'a [u8]> {
+    match path.strip_prefix(dir)? {
+        rest if rest.is_empty() || rest.starts_with(b"/") => Some(rest),
+        _ => None,
+    }
+}
+
+/// Forgets the path of the handles open at or below `path`, which no longer exists.
+unsafe fn removed(path: *const c_char) {
+    let path = CStr::from_ptr(path).to_bytes();
+    for handle in HANDLES.lock().unwrap().values_mut() {
+        if handle
+            .path
+            .as_deref()
+            .is_some_and(|open| below(open, path).is_some())
+        {
+            handle.path = None;
+        }
+    }
+}
+
+/// Moves the handles open at or below `from` to `to`, forgetting those `to` replaced, or
+/// swaps both when they were exchanged.
+unsafe fn renamed(from: *const c_char, to: *const c_char, flags: c_uint) {
+    let from = CStr::from_ptr(from).to_bytes();
+    let to = CStr::from_ptr(to).to_bytes();
+    let exchange = flags & RENAME_EXCHANGE != 0;
+    for handle in HANDLES.lock().unwrap().values_mut() {
+        let Some(open) = handle.path.as_ref() else {
+            continue;
+        };
+        handle.path = if let Some(rest) = below(open, from) {
+            Some([to, rest].concat())
+        } else if let Some(rest) = below(open, to) {
+            exchange.then(|| [from, rest].concat())
+        } else {
+            continue;
+        };
+    }
+}
+
+/// Switches the mount over to serving `root`. Open handles whose current path also exists below
+/// the new root are reopened there, the others, and those whose file was unlinked, keep reading
+/// and writing the old files until closed.
+/// Whatever the kernel cached about the old tree is invalidated on a best effort basis.
+pub fn rebind(root: &[u8]) -> Result<(), c_int> {
+    let len = root
+        .iter()
+        .rposition(|&c| c != b'/')
+        .map_or(0, |last| last + 1);
+    let root = &root[..len];
+    unsafe {
+        let next = NEXT.assume_init_ref();
+        let mut st = MaybeUninit::<stat>::zeroed();
+        match next.getattr.unwrap()(join(root, b"/").as_ptr(), st.as_mut_ptr(), ptr::null_mut()) {
+            0 if st.assume_init().st_mode & S_IFMT == S_IFDIR => (),
+            0 => return Err(-(ENOTDIR as c_int)),
+            res => return Err(res),
+        }
+        let mut handles = HANDLES.lock().unwrap();
+        let mut current = ROOT.write().unwrap();
+        let mut moved = 0;
+        // closed once the locks are released and the requests still using them are done
+        let mut replaced = Vec::new();
+        for handle in handles.values_mut() {
+            let Some(path) = handle.path.as_ref() else {
+                continue;
+            };
+            let path = join(root, path);
+            let mut fi = MaybeUninit::<fuse_file_info>::zeroed().assume_init();
+            // the file was created and truncated the first time around
+            fi.flags = handle.backing.flags & !((O_CREAT | O_EXCL | O_TRUNC) as c_int);
+            let Ok(fh) = open_backing(&path, &fi, handle.backing.dir) else {
+                continue;
+            };
+            let backing = Backing {
+                path,
+                flags: fi.flags,
+                dir: handle.backing.dir,
+                fh,
+                closed: false,
+            };
+            replaced.push(std::mem::replace(&mut handle.backing, Arc::new(backing)));
+            moved += 1;
+        }
+        eprintln!(
+            "rebound mount from {} to {}, moved {moved} of {} open handles",
+            String::from_utf8_lossy(&current),
+            String::from_utf8_lossy(root),
+            handles.len()
+        );
+        *current = root.to_vec();
+        drop(current);
+
+        let fuse = FUSE.load(Ordering::Relaxed);
+        if !fuse.is_null() {
+            fuse_invalidate_path(fuse, c"/".as_ptr());
+            for path in handles.values().filter_map(|handle| handle.path.as_ref()) {
+                let path = CString::new(path.clone()).unwrap();
+                fuse_invalidate_path(fuse, path.as_ptr());
+            }
+        }
+        drop(handles);
+        drop(replaced);
+    }
+    Ok(())
+}
+
+/// Answers `rebind <host directory>` commands on the control socket with `ok` or
+/// `error <errno>`.
+fn command(line: &[u8]) -> String {
+    match line.strip_prefix(b"rebind ") {
+        Some(root) => match rebind(root) {
+            Ok(()) => "ok\n".to_string(),
+            Err(res) => format!("error {}\n", -res),
+        },
+        None => "error unknown command\n".to_string(),
+    }
+}
+
+unsafe extern "C" fn init(conn: *mut fuse_conn_info, cfg: *mut fuse_config) -> *mut c_void {
+    // the conformance report initializes the layers outside a mount
+    let fuse = fuse_get_context()
+        .as_ref()
+        .map_or(ptr::null_mut(), |context| context.fuse);
+    FUSE.store(fuse, Ordering::Relaxed);
+    CONTROL.start(command);
+    match NEXT.assume_init_ref().init {
+        Some(init) => init(conn, cfg),
+        None => ptr::null_mut(),
+    }
+}
+
+unsafe extern "C" fn destroy(private_data: *mut c_void) {
+    CONTROL.stop();
+    if let Some(destroy) = NEXT.assume_init_ref().destroy {
+        destroy(private_data);
+    }
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let rooted = rooted(arg1);
+    let res = NEXT.assume_init_ref().open.unwrap()(rooted.ptr(), arg2);
+    if res == 0 {
+        track(arg1, &rooted, arg2, false);
+    }
+    res
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    let rooted = rooted(arg1);
+    let res = NEXT.assume_init_ref().create.unwrap()(rooted.ptr(), arg2, arg3);
+    if res == 0 {
+        track(arg1, &rooted, arg3, false);
+    }
+    res
+}
+
+unsafe extern "C" fn opendir(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let rooted = rooted(arg1);
+    let res = NEXT.assume_init_ref().opendir.unwrap()(rooted.ptr(), arg2);
+    if res == 0 {
+        track(arg1, &rooted, arg2, true);
+    }
+    res
+}
+
+unsafe extern "C" fn release(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    untrack(arg1, arg2, false)
+}
+
+unsafe extern "C" fn releasedir(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    untrack(arg1, arg2, true)
+}
+
+unsafe extern "C" fn getattr(
+    arg1: *const c_char,
+    arg2: *mut stat,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = rooted(arg1);
+    let _fi = Swapped::swap(fi);
+    NEXT.assume_init_ref().getattr.unwrap()(arg1.ptr(), arg2, fi)
+}
+
+unsafe extern "C" fn readlink(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    let arg1 = rooted(arg1);
+    NEXT.assume_init_ref().readlink.unwrap()(arg1.ptr(), arg2, arg3)
+}
+
+unsafe extern "C" fn mknod(arg1: *const c_char, arg2: mode_t, arg3: dev_t) -> c_int {
+    let arg1 = rooted(arg1);
+    NEXT.assume_init_ref().mknod.unwrap()(arg1.ptr(), arg2, arg3)
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    let arg1 = rooted(arg1);
+    NEXT.assume_init_ref().mkdir.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn unlink(arg1: *const c_char) -> c_int {
+    let path = rooted(arg1);
+    let res = NEXT.assume_init_ref().unlink.unwrap()(path.ptr());
+    if res == 0 {
+        removed(arg1);
+    }
+    res
+}
+
+unsafe extern "C" fn rmdir(arg1: *const c_char) -> c_int {
+    let path = rooted(arg1);
+    let res = NEXT.assume_init_ref().rmdir.unwrap()(path.ptr());
+    if res == 0 {
+        removed(arg1);
+    }
+    res
+}
+
+unsafe extern "C" fn symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let arg2 = rooted(arg2);
+    NEXT.assume_init_ref().symlink.unwrap()(arg1, arg2.ptr())
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    let from = rooted(arg1);
+    let to = rooted(arg2);
+    let res = NEXT.assume_init_ref().rename.unwrap()(from.ptr(), to.ptr(), flags);
+    if res == 0 {
+        renamed(arg1, arg2, flags);
+    }
+    res
+}
+
+unsafe extern "C" fn link(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let arg1 = rooted(arg1);
+    let arg2 = rooted(arg2);
+    NEXT.assume_init_ref().link.unwrap()(arg1.ptr(), arg2.ptr())
+}
+
+unsafe extern "C" fn chmod(arg1: *const c_char, arg2: mode_t, fi: *mut fuse_file_info) -> c_int {
+    let arg1 = rooted(arg1);
+    let _fi = Swapped::swap(fi);
+    NEXT.assume_init_ref().chmod.unwrap()(arg1.ptr(), arg2, fi)
+}
+
+unsafe extern "C" fn chown(
+    arg1: *const c_char,
+    arg2: uid_t,
+    arg3: gid_t,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = rooted(arg1);
+    let _fi = Swapped::swap(fi);
+    NEXT.assume_init_ref().chown.unwrap()(arg1.ptr(), arg2, arg3, fi)
+}
+
+unsafe extern "C" fn truncate(arg1: *const c_char, arg2: off_t, fi: *mut fuse_file_info) -> c_int {
+    let arg1 = rooted(arg1);
+    let _fi = Swapped::swap(fi);
+    NEXT.assume_init_ref().truncate.unwrap()(arg1.ptr(), arg2, fi)
+}
+
+unsafe extern "C" fn read(
+    arg1: *const c_char,
+    arg2: *mut c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = rooted(arg1);
+    let _arg5 = Swapped::swap(arg5);
+    NEXT.assume_init_ref().read.unwrap()(arg1.ptr(), arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = rooted(arg1);
+    let _arg5 = Swapped::swap(arg5);
+    NEXT.assume_init_ref().write.unwrap()(arg1.ptr(), arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn statfs(arg1: *const c_char, arg2: *mut statvfs) -> c_int {
+    let arg1 = rooted(arg1);
+    NEXT.assume_init_ref().statfs.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn flush(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let arg1 = rooted(arg1);
+    let _arg2 = Swapped::swap(arg2);
+    NEXT.assume_init_ref().flush.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn fsync(arg1: *const c_char, arg2: c_int, arg3: *mut fuse_file_info) -> c_int {
+    let arg1 = rooted(arg1);
+    let _arg3 = Swapped::swap(arg3);
+    NEXT.assume_init_ref().fsync.unwrap()(arg1.ptr(), arg2, arg3)
+}
+
+unsafe extern "C" fn setxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *const c_char,
+    arg4: usize,
+    arg5: c_int,
+) -> c_int {
+    let arg1 = rooted(arg1);
+    NEXT.assume_init_ref().setxattr.unwrap()(arg1.ptr(), arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn getxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *mut c_char,
+    arg4: usize,
+) -> c_int {
+    let arg1 = rooted(arg1);
+    NEXT.assume_init_ref().getxattr.unwrap()(arg1.ptr(), arg2, arg3, arg4)
+}
+
+unsafe extern "C" fn listxattr(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    let arg1 = rooted(arg1);
+    NEXT.assume_init_ref().listxattr.unwrap()(arg1.ptr(), arg2, arg3)
+}
+
+unsafe extern "C" fn removexattr(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let arg1 = rooted(arg1);
+    NEXT.assume_init_ref().removexattr.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn readdir(
+    arg1: *const c_char,
+    arg2: *mut c_void,
+    arg3: fuse_fill_dir_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+    arg6: fuse_readdir_flags,
+) -> c_int {
+    let arg1 = rooted(arg1);
+    let _arg5 = Swapped::swap(arg5);
+    NEXT.assume_init_ref().readdir.unwrap()(arg1.ptr(), arg2, arg3, arg4, arg5, arg6)
+}
+
+unsafe extern "C" fn fsyncdir(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = rooted(arg1);
+    let _arg3 = Swapped::swap(arg3);
+    NEXT.assume_init_ref().fsyncdir.unwrap()(arg1.ptr(), arg2, arg3)
+}
+
+unsafe extern "C" fn access(arg1: *const c_char, arg2: c_int) -> c_int {
+    let arg1 = rooted(arg1);
+    NEXT.assume_init_ref().access.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn lock(
+    arg1: *const c_char,
+    arg2: *mut fuse_file_info,
+    cmd: c_int,
+    arg3: *mut flock,
+) -> c_int {
+    let arg1 = rooted(arg1);
+    let _arg2 = Swapped::swap(arg2);
+    NEXT.assume_init_ref().lock.unwrap()(arg1.ptr(), arg2, cmd, arg3)
+}
+
+unsafe extern "C" fn utimens(
+    arg1: *const c_char,
+    tv: *const timespec,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = rooted(arg1);
+    let _fi = Swapped::swap(fi);
+    NEXT.assume_init_ref().utimens.unwrap()(arg1.ptr(), tv, fi)
+}
+
+unsafe extern "C" fn bmap(arg1: *const c_char, blocksize: usize, idx: *mut u64) -> c_int {
+    let arg1 = rooted(arg1);
+    NEXT.assume_init_ref().bmap.unwrap()(arg1.ptr(), blocksize, idx)
+}
+
+unsafe extern "C" fn ioctl(
+    arg1: *const c_char,
+    cmd: c_int,
+    arg: *mut c_void,
+    arg2: *mut fuse_file_info,
+    flags: c_uint,
+    data: *mut c_void,
+) -> c_int {
+    let arg1 = rooted(arg1);
+    let _arg2 = Swapped::swap(arg2);
+    NEXT.assume_init_ref().ioctl.unwrap()(arg1.ptr(), cmd, arg, arg2, flags, data)
+}
+
+unsafe extern "C" fn poll(
+    arg1: *const c_char,
+    arg2: *mut fuse_file_info,
+    ph: *mut fuse_pollhandle,
+    reventsp: *mut c_uint,
+) -> c_int {
+    let arg1 = rooted(arg1);
+    let _arg2 = Swapped::swap(arg2);
+    NEXT.assume_init_ref().poll.unwrap()(arg1.ptr(), arg2, ph, reventsp)
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = rooted(arg1);
+    let _arg2 = Swapped::swap(arg2);
+    NEXT.assume_init_ref().write_buf.unwrap()(arg1.ptr(), buf, off, arg2)
+}
+
+unsafe extern "C" fn read_buf(
+    arg1: *const c_char,
+    bufp: *mut *mut fuse_bufvec,
+    size: usize,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = rooted(arg1);
+    let _arg2 = Swapped::swap(arg2);
+    NEXT.assume_init_ref().read_buf.unwrap()(arg1.ptr(), bufp, size, off, arg2)
+}
+
+unsafe extern "C" fn flock(arg1: *const c_char, arg2: *mut fuse_file_info, op: c_int) -> c_int {
+    let arg1 = rooted(arg1);
+    let _arg2 = Swapped::swap(arg2);
+    NEXT.assume_init_ref().flock.unwrap()(arg1.ptr(), arg2, op)
+}
+
+unsafe extern "C" fn fallocate(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: off_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = rooted(arg1);
+    let _arg5 = Swapped::swap(arg5);
+    NEXT.assume_init_ref().fallocate.unwrap()(arg1.ptr(), arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn copy_file_range(
+    path_in: *const c_char,
+    fi_in: *mut fuse_file_info,
+    offset_in: off_t,
+    path_out: *const c_char,
+    fi_out: *mut fuse_file_info,
+    offset_out: off_t,
+    size: usize,
+    flags: c_int,
+) -> isize {
+    let path_in = rooted(path_in);
+    let _fi_in = Swapped::swap(fi_in);
+    let path_out = rooted(path_out);
+    let _fi_out = Swapped::swap(fi_out);
+    NEXT.assume_init_ref().copy_file_range.unwrap()(
+        path_in.ptr(),
+        fi_in,
+        offset_in,
+        path_out.ptr(),
+        fi_out,
+        offset_out,
+        size,
+        flags,
+    )
+}
+
+unsafe extern "C" fn lseek(
+    arg1: *const c_char,
+    off: off_t,
+    whence: c_int,
+    arg2: *mut fuse_file_info,
+) -> off_t {
+    let arg1 = rooted(arg1);
+    let _arg2 = Swapped::swap(arg2);
+    NEXT.assume_init_ref().lseek.unwrap()(arg1.ptr(), off, whence, arg2)
+}
+
+/// Switches the mount to serve the host directory `root`, see [`rebind`]. Returns 0 on
+/// success and a negative errno otherwise.
+///
+/// # Safety
+///
+/// This function must be called with a valid C string
+#[no_mangle]
+pub unsafe extern "C" fn rebind_root(root: *const c_char) -> c_int {
+    match rebind(CStr::from_ptr(root).to_bytes()) {
+        Ok(()) => 0,
+        Err(res) => res,
+    }
+}
+
+/// Serves the host directory `root` as the root of the mount, with paths handed to the next
+/// layer moved below it, and allows switching to another directory such as a fresh snapshot
+/// while the mount stays up, for blue/green content updates. The switch is requested with a
+/// `rebind <host directory>` line on the unix socket at `control_socket` when it is not null,
+/// or by calling [`rebind_root`].
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer, a valid C string root and a
+/// valid C string or null control socket
+#[no_mangle]
+pub unsafe extern "C" fn new_rebind_layer(
+    next: *const fuse_operations,
+    root: *const c_char,
+    control_socket: *const c_char,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    let root = CStr::from_ptr(root).to_bytes();
+    let len = root
+        .iter()
+        .rposition(|&c| c != b'/')
+        .map_or(0, |last| last + 1);
+    *ROOT.write().unwrap() = root[..len].to_vec();
+    CONTROL.set(control_socket);
+    Box::into_raw(Box::new(fuse_operations {
+        getattr: next.getattr.and(Some(getattr)),
+        readlink: next.readlink.and(Some(readlink)),
+        mknod: next.mknod.and(Some(mknod)),
+        mkdir: next.mkdir.and(Some(mkdir)),
+        unlink: next.unlink.and(Some(unlink)),
+        rmdir: next.rmdir.and(Some(rmdir)),
+        symlink: next.symlink.and(Some(symlink)),
+        rename: next.rename.and(Some(rename)),
+        link: next.link.and(Some(link)),
+        chmod: next.chmod.and(Some(chmod)),
+        chown: next.chown.and(Some(chown)),
+        truncate: next.truncate.and(Some(truncate)),
+        open: next.open.and(Some(open)),
+        read: next.read.and(Some(read)),
+        write: next.write.and(Some(write)),
+        statfs: next.statfs.and(Some(statfs)),
+        flush: next.flush.and(Some(flush)),
+        release: Some(release),
+        fsync: next.fsync.and(Some(fsync)),
+        setxattr: next.setxattr.and(Some(setxattr)),
+        getxattr: next.getxattr.and(Some(getxattr)),
+        listxattr: next.listxattr.and(Some(listxattr)),
+        removexattr: next.removexattr.and(Some(removexattr)),
+        opendir: next.opendir.and(Some(opendir)),
+        readdir: next.readdir.and(Some(readdir)),
+        releasedir: Some(releasedir),
+        fsyncdir: next.fsyncdir.and(Some(fsyncdir)),
+        init: Some(init),
+        destroy: Some(destroy),
+        access: next.access.and(Some(access)),
+        create: next.create.and(Some(create)),
+        lock: next.lock.and(Some(lock)),
+        utimens: next.utimens.and(Some(utimens)),
+        bmap: next.bmap.and(Some(bmap)),
+        ioctl: next.ioctl.and(Some(ioctl)),
+        poll: next.poll.and(Some(poll)),
+        write_buf: next.write_buf.and(Some(write_buf)),
+        read_buf: next.read_buf.and(Some(read_buf)),
+        flock: next.flock.and(Some(flock)),
+        fallocate: next.fallocate.and(Some(fallocate)),
+        copy_file_range: next.copy_file_range.and(Some(copy_file_range)),
+        lseek: next.lseek.and(Some(lseek)),
+    }))
+}
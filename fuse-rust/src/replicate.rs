@@ -0,0 +1,792 @@
+use crate::{
+    fuse::{
+        dev_t, free, fuse_buf, fuse_buf_copy, fuse_buf_size, fuse_bufvec, fuse_file_info,
+        fuse_operations, gid_t, lremovexattr, lsetxattr, malloc, mode_t, off_t, stat, timespec,
+        uid_t, EHOSTDOWN, EIO, ENOMEM, ENOTCONN, EREMOTEIO, ESTALE, ETIMEDOUT, O_TRUNC,
+        RENAME_EXCHANGE, S_IFMT, S_IFREG,
+    },
+    rmtree::{self, RmtreeRequest},
+};
+use std::{
+    collections::BTreeSet,
+    ffi::{c_char, c_int, c_uint, c_void, CStr, CString, OsStr},
+    fs::{self, DirBuilder, File, FileTimes, OpenOptions, Permissions},
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{self, Write},
+    mem::{self, MaybeUninit},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{DirBuilderExt, FileExt, OpenOptionsExt, PermissionsExt},
+    },
+    path::PathBuf,
+    slice,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, SyncSender},
+        Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut SECONDARY: Option<PathBuf> = None;
+/// Queue to the thread applying operations to the secondary, when replicating asynchronously.
+static mut QUEUE: Option<SyncSender<Op>> = None;
+/// Where operations that could not be applied to the secondary are recorded.
+static DIVERGENCE: Mutex<Option<File>> = Mutex::new(None);
+/// Paths recorded in the divergence journal, whose secondary copy cannot be trusted.
+static DIVERGED: Mutex<BTreeSet<Vec<u8>>> = Mutex::new(BTreeSet::new());
+/// Operations queued that have not been applied to the secondary yet.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+static FAILOVERS: AtomicU64 = AtomicU64::new(0);
+/// File last written to on the secondary, kept open for the writes that usually follow.
+static LAST_WRITTEN: Mutex<Option<(Vec<u8>, File)>> = Mutex::new(None);
+/// Locks held by an operation from when it starts on the primary until it is handed to the
+/// secondary, so operations on the same path reach the secondary in the order they completed
+/// on the primary. Paths share them by hash.
+static ORDER: [Mutex<()>; 64] = [const { Mutex::new(()) }; 64];
+/// Held shared by operations on given paths and exclusively by those that create, move or
+/// remove directories, which the paths of the others may lie below.
+static TREES: RwLock<()> = RwLock::new(());
+
+/// A mutating operation that succeeded on the primary, to be repeated on the secondary.
+enum Op {
+    Create(Vec<u8>, mode_t, bool),
+    Mkdir(Vec<u8>, mode_t),
+    Symlink(Vec<u8>, Vec<u8>),
+    Link(Vec<u8>, Vec<u8>),
+    Unlink(Vec<u8>),
+    Rmdir(Vec<u8>),
+    /// Removes everything below the directory.
+    EmptyDir(Vec<u8>),
+    Rename(Vec<u8>, Vec<u8>, c_uint),
+    Chmod(Vec<u8>, mode_t),
+    Chown(Vec<u8>, uid_t, gid_t),
+    Times(Vec<u8>, SystemTime, SystemTime),
+    Truncate(Vec<u8>, off_t),
+    Write(Vec<u8>, off_t, Vec<u8>),
+    /// Grows the file to at least the given size.
+    Allocate(Vec<u8>, off_t),
+    CopyRange(Vec<u8>, off_t, Vec<u8>, off_t, usize),
+    Setxattr(Vec<u8>, CString, Vec<u8>, c_int),
+    Removexattr(Vec<u8>, CString),
+    /// An operation with no equivalent on the secondary, which only gets recorded.
+    Unsupported(&'static str, Vec<u8>),
+}
+
+impl Op {
+    fn describe(&self) -> (&'static str, &[u8]) {
+        match self {
+            Op::Create(path, ..) => ("create", path),
+            Op::Mkdir(path, _) => ("mkdir", path),
+            Op::Symlink(_, path) => ("symlink", path),
+            Op::Link(_, path) => ("link", path),
+            Op::Unlink(path) => ("unlink", path),
+            Op::Rmdir(path) => ("rmdir", path),
+            Op::EmptyDir(path) => ("rmtree", path),
+            Op::Rename(path, ..) => ("rename", path),
+            Op::Chmod(path, _) => ("chmod", path),
+            Op::Chown(path, ..) => ("chown", path),
+            Op::Times(path, ..) => ("utimens", path),
+            Op::Truncate(path, _) => ("truncate", path),
+            Op::Write(path, ..) => ("write", path),
+            Op::Allocate(path, _) => ("fallocate", path),
+            Op::CopyRange(_, _, path, ..) => ("copy_file_range", path),
+            Op::Setxattr(path, ..) => ("setxattr", path),
+            Op::Removexattr(path, _) => ("removexattr", path),
+            Op::Unsupported(op, path) => (op, path),
+        }
+    }
+}
+
+unsafe fn secondary(path: &[u8]) -> PathBuf {
+    let path = path.strip_prefix(b"/").unwrap_or(path);
+    SECONDARY.as_ref().unwrap().join(OsStr::from_bytes(path))
+}
+
+fn owned(path: *const c_char) -> Vec<u8> {
+    unsafe { CStr::from_ptr(path) }.to_bytes().to_vec()
+}
+
+fn last_os_error(res: c_int) -> io::Result<()> {
+    match res {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Runs `f` on the secondary copy of the file at `path`, opened for writing.
+unsafe fn with_file(path: &[u8], f: impl FnOnce(&File) -> io::Result<()>) -> io::Result<()> {
+    let mut last = LAST_WRITTEN.lock().unwrap();
+    if last.as_ref().is_none_or(|(last, _)| last != path) {
+        let file = OpenOptions::new().write(true).open(secondary(path))?;
+        *last = Some((path.to_vec(), file));
+    }
+    f(&last.as_ref().unwrap().1)
+}
+
+/// Closes the file kept open for writing, whose path may now name something else.
+fn forget() {
+    *LAST_WRITTEN.lock().unwrap() = None;
+}
+
+unsafe fn apply(op: &Op) -> io::Result<()> {
+    match op {
+        Op::Create(path, mode, truncate) => OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(*truncate)
+            .mode(*mode)
+            .open(secondary(path))
+            .map(drop),
+        Op::Mkdir(path, mode) => DirBuilder::new().mode(*mode).create(secondary(path)),
+        Op::Symlink(target, path) => {
+            std::os::unix::fs::symlink(OsStr::from_bytes(target), secondary(path))
+        }
+        Op::Link(from, to) => fs::hard_link(secondary(from), secondary(to)),
+        Op::Unlink(path) => {
+            forget();
+            fs::remove_file(secondary(path))
+        }
+        Op::Rmdir(path) => fs::remove_dir(secondary(path)),
+        Op::EmptyDir(path) => {
+            forget();
+            for entry in fs::read_dir(secondary(path))? {
+                let entry = entry?;
+                match entry.file_type()?.is_dir() {
+                    true => fs::remove_dir_all(entry.path())?,
+                    false => fs::remove_file(entry.path())?,
+                }
+            }
+            Ok(())
+        }
+        Op::Rename(from, to, flags) => {
+            forget();
+            if flags & RENAME_EXCHANGE == 0 {
+                return fs::rename(secondary(from), secondary(to));
+            }
+            let aside = secondary(&[to, &b".replicate-exchange"[..]].concat());
+            fs::rename(secondary(to), &aside)?;
+            fs::rename(secondary(from), secondary(to))?;
+            fs::rename(aside, secondary(from))
+        }
+        Op::Chmod(path, mode) => {
+            fs::set_permissions(secondary(path), Permissions::from_mode(*mode))
+        }
+        Op::Chown(path, uid, gid) => std::os::unix::fs::lchown(
+            secondary(path),
+            (*uid != uid_t::MAX).then_some(*uid),
+            (*gid != gid_t::MAX).then_some(*gid),
+        ),
+        Op::Times(path, accessed, modified) => File::open(secondary(path))?.set_times(
+            FileTimes::new()
+                .set_accessed(*accessed)
+                .set_modified(*modified),
+        ),
+        Op::Truncate(path, size) => with_file(path, |file| file.set_len(*size as u64)),
+        Op::Write(path, off, data) => with_file(path, |file| file.write_all_at(data, *off as u64)),
+        Op::Allocate(path, size) => with_file(path, |file| {
+            if file.metadata()?.len() < *size as u64 {
+                file.set_len(*size as u64)?;
+            }
+            Ok(())
+        }),
+        Op::CopyRange(from, off_in, to, off_out, len) => {
+            let mut data = vec![0u8; *len];
+            File::open(secondary(from))?.read_exact_at(&mut data, *off_in as u64)?;
+            with_file(to, |file| file.write_all_at(&data, *off_out as u64))
+        }
+        Op::Setxattr(path, name, value, flags) => {
+            let path = CString::new(secondary(path).as_os_str().as_bytes()).unwrap();
+            last_os_error(lsetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                *flags,
+            ))
+        }
+        Op::Removexattr(path, name) => {
+            let path = CString::new(secondary(path).as_os_str().as_bytes()).unwrap();
+            last_os_error(lremovexattr(path.as_ptr(), name.as_ptr()))
+        }
+        Op::Unsupported(..) => Err(io::ErrorKind::Unsupported.into()),
+    }
+}
+
+/// Applies `op` to the secondary, recording it in the divergence journal if that fails.
+unsafe fn run(op: Op) {
+    let Err(err) = apply(&op) else {
+        return;
+    };
+    let (name, path) = op.describe();
+    DIVERGED.lock().unwrap().insert(path.to_vec());
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let line = format!("{time} {name} {} {err}\n", String::from_utf8_lossy(path));
+    match DIVERGENCE.lock().unwrap().as_mut() {
+        Some(journal) => {
+            if let Err(err) = journal.write_all(line.as_bytes()) {
+                eprintln!("failed to write divergence journal: {err}");
+            }
+        }
+        None => eprint!("secondary diverged: {line}"),
+    }
+}
+
+/// What keeps other operations from overtaking one on its way to the secondary.
+enum Order {
+    Paths {
+        _trees: RwLockReadGuard<'static, ()>,
+        _paths: Vec<MutexGuard<'static, ()>>,
+    },
+    Tree {
+        _trees: RwLockWriteGuard<'static, ()>,
+    },
+}
+
+/// Orders an operation on `paths`, any of which may be null, against others on the same paths
+/// and on whole trees.
+unsafe fn order(paths: &[*const c_char]) -> Order {
+    let trees = TREES.read().unwrap();
+    let mut stripes: Vec<usize> = paths
+        .iter()
+        .filter(|path| !path.is_null())
+        .map(|&path| {
+            let mut hasher = DefaultHasher::new();
+            CStr::from_ptr(path).to_bytes().hash(&mut hasher);
+            hasher.finish() as usize % ORDER.len()
+        })
+        .collect();
+    // always in the same order, so two operations never wait for each other
+    stripes.sort_unstable();
+    stripes.dedup();
+    Order::Paths {
+        _trees: trees,
+        _paths: stripes
+            .into_iter()
+            .map(|stripe| ORDER[stripe].lock().unwrap())
+            .collect(),
+    }
+}
+
+/// Orders an operation that creates, moves or removes a directory against all others.
+fn order_tree() -> Order {
+    Order::Tree {
+        _trees: TREES.write().unwrap(),
+    }
+}
+
+/// Repeats `op` on the secondary, or queues it to be if replicating asynchronously.
+unsafe fn replicate(op: Op) {
+    match QUEUE.as_ref() {
+        // blocks while the queue is full, so the secondary never falls too far behind
+        Some(queue) => {
+            PENDING.fetch_add(1, Ordering::Relaxed);
+            queue.send(op).unwrap();
+        }
+        None => run(op),
+    }
+}
+
+/// Serves a read the primary failed with `res` from the secondary copy of the file at `path`,
+/// as long as the failure lies with the primary's storage and the copy is known to be up to
+/// date. Returns the primary's error otherwise.
+unsafe fn read_replica(
+    path: *const c_char,
+    size: usize,
+    off: off_t,
+    res: c_int,
+) -> Result<Vec<u8>, c_int> {
+    let storage_error = [EIO, EREMOTEIO, ETIMEDOUT, ENOTCONN, EHOSTDOWN, ESTALE]
+        .iter()
+        .any(|&errno| res == -(errno as c_int));
+    if path.is_null() || !storage_error || PENDING.load(Ordering::Relaxed) != 0 {
+        return Err(res);
+    }
+    let path = CStr::from_ptr(path).to_bytes();
+    if DIVERGED.lock().unwrap().contains(path) {
+        return Err(res);
+    }
+    let mut data = vec![0u8; size];
+    let read = (|| {
+        let file = File::open(secondary(path))?;
+        let mut len = 0;
+        while len < size {
+            match file.read_at(&mut data[len..], off as u64 + len as u64)? {
+                0 => break,
+                read => len += read,
+            }
+        }
+        io::Result::Ok(len)
+    })();
+    let path = String::from_utf8_lossy(path);
+    match read {
+        Ok(len) => {
+            FAILOVERS.fetch_add(1, Ordering::Relaxed);
+            eprintln!(
+                "read of {path} failed with errno {}, served from the secondary",
+                -res
+            );
+            data.truncate(len);
+            Ok(data)
+        }
+        Err(err) => {
+            eprintln!(
+                "read of {path} failed with errno {} and on the secondary: {err}",
+                -res
+            );
+            Err(res)
+        }
+    }
+}
+
+unsafe extern "C" fn read(
+    arg1: *const c_char,
+    arg2: *mut c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let res = NEXT.assume_init_ref().read.unwrap()(arg1, arg2, arg3, arg4, arg5);
+    if res >= 0 {
+        return res;
+    }
+    match read_replica(arg1, arg3, arg4, res) {
+        Ok(data) => {
+            slice::from_raw_parts_mut(arg2.cast::<u8>(), data.len()).copy_from_slice(&data);
+            data.len() as c_int
+        }
+        Err(res) => res,
+    }
+}
+
+unsafe extern "C" fn read_buf(
+    arg1: *const c_char,
+    bufp: *mut *mut fuse_bufvec,
+    size: usize,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let res = NEXT.assume_init_ref().read_buf.unwrap()(arg1, bufp, size, off, arg2);
+    if res >= 0 {
+        return res;
+    }
+    let data = match read_replica(arg1, size, off, res) {
+        Ok(data) => data,
+        Err(res) => return res,
+    };
+    // libfuse releases the vector and its memory with free()
+    let buf = malloc(mem::size_of::<fuse_bufvec>()).cast::<fuse_bufvec>();
+    let mem = malloc(data.len().max(1));
+    if buf.is_null() || mem.is_null() {
+        free(buf.cast());
+        free(mem);
+        return -(ENOMEM as c_int);
+    }
+    slice::from_raw_parts_mut(mem.cast::<u8>(), data.len()).copy_from_slice(&data);
+    buf.write(fuse_bufvec {
+        count: 1,
+        idx: 0,
+        off: 0,
+        buf: [fuse_buf {
+            size: data.len(),
+            flags: 0,
+            mem,
+            fd: -1,
+            pos: 0,
+        }],
+    });
+    *bufp = buf;
+    0
+}
+
+unsafe extern "C" fn mknod(arg1: *const c_char, arg2: mode_t, arg3: dev_t) -> c_int {
+    let _order = order(&[arg1]);
+    let res = NEXT.assume_init_ref().mknod.unwrap()(arg1, arg2, arg3);
+    if res == 0 {
+        replicate(match arg2 & S_IFMT {
+            S_IFREG => Op::Create(owned(arg1), arg2 & !S_IFMT, false),
+            _ => Op::Unsupported("mknod", owned(arg1)),
+        });
+    }
+    res
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    let _order = order_tree();
+    let res = NEXT.assume_init_ref().mkdir.unwrap()(arg1, arg2);
+    if res == 0 {
+        replicate(Op::Mkdir(owned(arg1), arg2));
+    }
+    res
+}
+
+unsafe extern "C" fn unlink(arg1: *const c_char) -> c_int {
+    let _order = order(&[arg1]);
+    let res = NEXT.assume_init_ref().unlink.unwrap()(arg1);
+    if res == 0 {
+        replicate(Op::Unlink(owned(arg1)));
+    }
+    res
+}
+
+unsafe extern "C" fn rmdir(arg1: *const c_char) -> c_int {
+    let _order = order_tree();
+    let res = NEXT.assume_init_ref().rmdir.unwrap()(arg1);
+    if res == 0 {
+        replicate(Op::Rmdir(owned(arg1)));
+    }
+    res
+}
+
+unsafe extern "C" fn symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let _order = order(&[arg2]);
+    let res = NEXT.assume_init_ref().symlink.unwrap()(arg1, arg2);
+    if res == 0 {
+        replicate(Op::Symlink(owned(arg1), owned(arg2)));
+    }
+    res
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    let _order = order_tree();
+    let res = NEXT.assume_init_ref().rename.unwrap()(arg1, arg2, flags);
+    if res == 0 {
+        replicate(Op::Rename(owned(arg1), owned(arg2), flags));
+    }
+    res
+}
+
+unsafe extern "C" fn link(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let _order = order(&[arg1, arg2]);
+    let res = NEXT.assume_init_ref().link.unwrap()(arg1, arg2);
+    if res == 0 {
+        replicate(Op::Link(owned(arg1), owned(arg2)));
+    }
+    res
+}
+
+unsafe extern "C" fn chmod(arg1: *const c_char, arg2: mode_t, fi: *mut fuse_file_info) -> c_int {
+    let _order = order(&[arg1]);
+    let res = NEXT.assume_init_ref().chmod.unwrap()(arg1, arg2, fi);
+    if res == 0 && !arg1.is_null() {
+        replicate(Op::Chmod(owned(arg1), arg2 & !S_IFMT));
+    }
+    res
+}
+
+unsafe extern "C" fn chown(
+    arg1: *const c_char,
+    arg2: uid_t,
+    arg3: gid_t,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let _order = order(&[arg1]);
+    let res = NEXT.assume_init_ref().chown.unwrap()(arg1, arg2, arg3, fi);
+    if res == 0 && !arg1.is_null() {
+        replicate(Op::Chown(owned(arg1), arg2, arg3));
+    }
+    res
+}
+
+unsafe extern "C" fn truncate(arg1: *const c_char, arg2: off_t, fi: *mut fuse_file_info) -> c_int {
+    let _order = order(&[arg1]);
+    let res = NEXT.assume_init_ref().truncate.unwrap()(arg1, arg2, fi);
+    if res == 0 && !arg1.is_null() {
+        replicate(Op::Truncate(owned(arg1), arg2));
+    }
+    res
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let _order = order(&[arg1]);
+    let res = NEXT.assume_init_ref().open.unwrap()(arg1, arg2);
+    if res == 0 && (*arg2).flags & O_TRUNC as c_int != 0 {
+        replicate(Op::Truncate(owned(arg1), 0));
+    }
+    res
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let _order = order(&[arg1]);
+    let res = NEXT.assume_init_ref().write.unwrap()(arg1, arg2, arg3, arg4, arg5);
+    if res > 0 && !arg1.is_null() {
+        let data = slice::from_raw_parts(arg2.cast::<u8>(), res as usize);
+        replicate(Op::Write(owned(arg1), arg4, data.to_vec()));
+    }
+    res
+}
+
+unsafe extern "C" fn setxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *const c_char,
+    arg4: usize,
+    arg5: c_int,
+) -> c_int {
+    let _order = order(&[arg1]);
+    let res = NEXT.assume_init_ref().setxattr.unwrap()(arg1, arg2, arg3, arg4, arg5);
+    if res == 0 {
+        let value = slice::from_raw_parts(arg3.cast::<u8>(), arg4);
+        replicate(Op::Setxattr(
+            owned(arg1),
+            CStr::from_ptr(arg2).to_owned(),
+            value.to_vec(),
+            arg5,
+        ));
+    }
+    res
+}
+
+unsafe extern "C" fn removexattr(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let _order = order(&[arg1]);
+    let res = NEXT.assume_init_ref().removexattr.unwrap()(arg1, arg2);
+    if res == 0 {
+        replicate(Op::Removexattr(
+            owned(arg1),
+            CStr::from_ptr(arg2).to_owned(),
+        ));
+    }
+    res
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    let _order = order(&[arg1]);
+    let res = NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3);
+    if res == 0 {
+        let truncate = (*arg3).flags & O_TRUNC as c_int != 0;
+        replicate(Op::Create(owned(arg1), arg2 & !S_IFMT, truncate));
+    }
+    res
+}
+
+fn system_time(ts: timespec) -> SystemTime {
+    UNIX_EPOCH + Duration::new(ts.tv_sec.max(0) as u64, ts.tv_nsec as u32)
+}
+
+unsafe extern "C" fn utimens(
+    arg1: *const c_char,
+    tv: *const timespec,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let _order = order(&[arg1]);
+    let res = NEXT.assume_init_ref().utimens.unwrap()(arg1, tv, fi);
+    if res != 0 || arg1.is_null() {
+        return res;
+    }
+    // the times may have been given relative to now, so the ones the primary ended up with
+    // are copied instead
+    let mut st = MaybeUninit::<stat>::zeroed();
+    if NEXT.assume_init_ref().getattr.unwrap()(arg1, st.as_mut_ptr(), fi) == 0 {
+        let st = st.assume_init();
+        replicate(Op::Times(
+            owned(arg1),
+            system_time(st.st_atim),
+            system_time(st.st_mtim),
+        ));
+    } else {
+        replicate(Op::Unsupported("utimens", owned(arg1)));
+    }
+    res
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    if arg1.is_null() {
+        return NEXT.assume_init_ref().write_buf.unwrap()(arg1, buf, off, arg2);
+    }
+    // the data may sit in a pipe, which can only be read once, so it is copied into memory to
+    // be written to both sides from there
+    let mut data = vec![0u8; fuse_buf_size(buf)];
+    let mut mem = fuse_bufvec {
+        count: 1,
+        idx: 0,
+        off: 0,
+        buf: [fuse_buf {
+            size: data.len(),
+            flags: 0,
+            mem: data.as_mut_ptr().cast(),
+            fd: -1,
+            pos: 0,
+        }],
+    };
+    let copied = fuse_buf_copy(&mut mem, buf, 0);
+    if copied < 0 {
+        return copied as c_int;
+    }
+    data.truncate(copied as usize);
+    mem.buf[0].size = data.len();
+    let _order = order(&[arg1]);
+    let res = NEXT.assume_init_ref().write_buf.unwrap()(arg1, &mut mem, off, arg2);
+    if res > 0 {
+        data.truncate(res as usize);
+        replicate(Op::Write(owned(arg1), off, data));
+    }
+    res
+}
+
+unsafe extern "C" fn fallocate(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: off_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let _order = order(&[arg1]);
+    let res = NEXT.assume_init_ref().fallocate.unwrap()(arg1, arg2, arg3, arg4, arg5);
+    if res == 0 && !arg1.is_null() {
+        replicate(match arg2 {
+            0 => Op::Allocate(owned(arg1), arg3 + arg4),
+            _ => Op::Unsupported("fallocate", owned(arg1)),
+        });
+    }
+    res
+}
+
+unsafe extern "C" fn copy_file_range(
+    path_in: *const c_char,
+    fi_in: *mut fuse_file_info,
+    offset_in: off_t,
+    path_out: *const c_char,
+    fi_out: *mut fuse_file_info,
+    offset_out: off_t,
+    size: usize,
+    flags: c_int,
+) -> isize {
+    let _order = order(&[path_in, path_out]);
+    let res = NEXT.assume_init_ref().copy_file_range.unwrap()(
+        path_in, fi_in, offset_in, path_out, fi_out, offset_out, size, flags,
+    );
+    if res > 0 && !path_in.is_null() && !path_out.is_null() {
+        replicate(Op::CopyRange(
+            owned(path_in),
+            offset_in,
+            owned(path_out),
+            offset_out,
+            res as usize,
+        ));
+    }
+    res
+}
+
+/// Returns how many reads were served from the secondary after failing on the primary.
+#[no_mangle]
+pub extern "C" fn replicate_failovers() -> u64 {
+    FAILOVERS.load(Ordering::Relaxed)
+}
+
+unsafe extern "C" fn ioctl(
+    arg1: *const c_char,
+    cmd: c_int,
+    arg: *mut c_void,
+    arg2: *mut fuse_file_info,
+    flags: c_uint,
+    data: *mut c_void,
+) -> c_int {
+    if !rmtree::is_rmtree(cmd) || arg1.is_null() {
+        return NEXT.assume_init_ref().ioctl.unwrap()(arg1, cmd, arg, arg2, flags, data);
+    }
+    let _order = order_tree();
+    let res = NEXT.assume_init_ref().ioctl.unwrap()(arg1, cmd, arg, arg2, flags, data);
+    if res != 0 {
+        return res;
+    }
+    let request = &*data.cast::<RmtreeRequest>();
+    if request.error == 0 {
+        replicate(Op::EmptyDir(owned(arg1)));
+    } else if request.removed > 0 {
+        // which entries went before the removal stopped is not known
+        replicate(Op::Unsupported("rmtree", owned(arg1)));
+    }
+    res
+}
+
+/// Mirrors every mutating operation that succeeds on the next layer to `secondary`, a host
+/// directory holding a copy of the share, possibly on different storage, which then serves as
+/// a warm standby of the volume. Operations on the same path reach the secondary in the order
+/// they completed on the primary, which runs them one at a time, and those that create, move
+/// or remove directories run alone. With a `queue_len` of zero operations are applied to the
+/// secondary before the guest sees them complete. Otherwise they are queued to a thread and
+/// applied in the background, and the guest is held up only while `queue_len` operations are
+/// waiting. Operations that fail on the secondary, or have no equivalent there such as
+/// punching holes, are appended to `divergence_log` as lines of the form
+/// `<seconds since epoch> <operation> <path> <error>`, telling what needs to be resynced.
+/// Without a log they are printed to stderr. Writes to files that are open but no longer have a
+/// name are not mirrored. The passthrough's `FSINTERPOSER_IOC_RMTREE` empties the directory on
+/// the secondary as well. Reads failing on the next layer because of its storage, with `EIO` or
+/// a timeout, are retried against the secondary and logged, unless the file has diverged or
+/// queued operations have yet to reach the secondary, and `replicate_failovers` counts them.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer, a valid C string secondary and
+/// a valid C string or null divergence log
+#[no_mangle]
+pub unsafe extern "C" fn new_replicate_layer(
+    next: *const fuse_operations,
+    secondary: *const c_char,
+    queue_len: c_uint,
+    divergence_log: *const c_char,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    SECONDARY = Some(PathBuf::from(OsStr::from_bytes(
+        CStr::from_ptr(secondary).to_bytes(),
+    )));
+    if !divergence_log.is_null() {
+        let path = OsStr::from_bytes(CStr::from_ptr(divergence_log).to_bytes());
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(journal) => *DIVERGENCE.lock().unwrap() = Some(journal),
+            Err(err) => eprintln!("failed to open divergence journal: {err}"),
+        }
+    }
+    if queue_len != 0 {
+        let (queue, ops) = mpsc::sync_channel(queue_len as usize);
+        thread::spawn(move || {
+            for op in ops {
+                unsafe { run(op) };
+                PENDING.fetch_sub(1, Ordering::Relaxed);
+            }
+        });
+        QUEUE = Some(queue);
+    }
+    Box::into_raw(Box::new(fuse_operations {
+        mknod: next.mknod.and(Some(mknod)),
+        mkdir: next.mkdir.and(Some(mkdir)),
+        unlink: next.unlink.and(Some(unlink)),
+        rmdir: next.rmdir.and(Some(rmdir)),
+        symlink: next.symlink.and(Some(symlink)),
+        rename: next.rename.and(Some(rename)),
+        link: next.link.and(Some(link)),
+        chmod: next.chmod.and(Some(chmod)),
+        chown: next.chown.and(Some(chown)),
+        truncate: next.truncate.and(Some(truncate)),
+        open: next.open.and(Some(open)),
+        read: next.read.and(Some(read)),
+        write: next.write.and(Some(write)),
+        setxattr: next.setxattr.and(Some(setxattr)),
+        removexattr: next.removexattr.and(Some(removexattr)),
+        create: next.create.and(Some(create)),
+        utimens: next.utimens.and(next.getattr).and(Some(utimens)),
+        write_buf: next.write_buf.and(Some(write_buf)),
+        read_buf: next.read_buf.and(Some(read_buf)),
+        fallocate: next.fallocate.and(Some(fallocate)),
+        copy_file_range: next.copy_file_range.and(Some(copy_file_range)),
+        ioctl: next.ioctl.and(Some(ioctl)),
+        ..next
+    }))
+}
@@ -0,0 +1,19 @@
+use std::ffi::c_int;
+
+/// `FSINTERPOSER_IOC_RMTREE` of `fuse/passthrough/rmtree.h`, which has the passthrough remove
+/// everything below a directory on the host in one request. Layers that hold back, mirror or
+/// record mutations have to handle it, as the removals never reach them one by one.
+pub const FSINTERPOSER_IOC_RMTREE: u32 = 0xc010_6601;
+
+/// Layout of `struct fsinterposer_rmtree`, the data of [`FSINTERPOSER_IOC_RMTREE`].
+#[repr(C)]
+pub struct RmtreeRequest {
+    pub max_depth: u32,
+    /// Errno the removal stopped at, 0 if it removed everything.
+    pub error: i32,
+    pub removed: u64,
+}
+
+pub fn is_rmtree(cmd: c_int) -> bool {
+    cmd as u32 == FSINTERPOSER_IOC_RMTREE
+}
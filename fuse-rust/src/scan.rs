@@ -0,0 +1,239 @@
+use crate::fuse::{
+    fuse_bufvec, fuse_file_info, fuse_operations, mode_t, off_t, ENOSYS, O_ACCMODE, O_RDONLY,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::{c_char, c_int, c_uint, CStr, CString, OsStr},
+    io::{BufRead, BufReader, Write},
+    mem::MaybeUninit,
+    os::unix::{ffi::OsStrExt, net::UnixStream},
+    path::PathBuf,
+    sync::{Condvar, Mutex},
+    time::{Duration, SystemTime},
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut SOCKET: Option<PathBuf> = None;
+static mut ROOT: Vec<u8> = Vec::new();
+static mut QUARANTINE: Vec<u8> = Vec::new();
+/// How long to wait for the scanner, forever if `None`.
+static mut TIMEOUT: Option<Duration> = None;
+static mut FAIL_OPEN: bool = false;
+/// Path of every handle open for writing, and whether anything was written through it.
+static WRITERS: Mutex<BTreeMap<u64, (Vec<u8>, bool)>> = Mutex::new(BTreeMap::new());
+/// Files waiting for a verdict, which cannot be opened until they get it.
+static SCANNING: Mutex<BTreeSet<Vec<u8>>> = Mutex::new(BTreeSet::new());
+static SCANNED: Condvar = Condvar::new();
+
+/// Asks the scanner about the file at `path` on the host, which answers `clean` or `infected`.
+/// Returns whether the file is to be quarantined, following the failure policy when the scanner
+/// gives any other answer or none in time.
+unsafe fn is_malicious(path: &[u8]) -> bool {
+    let result = (|| {
+        let mut stream = UnixStream::connect(SOCKET.as_ref().unwrap())?;
+        stream.set_read_timeout(TIMEOUT)?;
+        stream.set_write_timeout(TIMEOUT)?;
+        stream.write_all(&[b"scan ", path, b"\n"].concat())?;
+        let mut answer = String::new();
+        BufReader::new(stream).read_line(&mut answer)?;
+        Ok::<_, std::io::Error>(answer)
+    })();
+    match result.as_deref().map(str::trim_end) {
+        Ok("clean") => false,
+        Ok("infected") => true,
+        answer => {
+            eprintln!(
+                "scanning {} failed ({answer:?}), failing {}",
+                String::from_utf8_lossy(path),
+                if FAIL_OPEN { "open" } else { "closed" }
+            );
+            !FAIL_OPEN
+        }
+    }
+}
+
+/// Moves the file at `path` into the quarantine directory, under its name prefixed with the
+/// time so files with the same name do not clash.
+unsafe fn quarantine(path: &[u8]) {
+    let next = NEXT.assume_init_ref();
+    let dir = CString::new(QUARANTINE.clone()).unwrap();
+    if let Some(mkdir) = next.mkdir {
+        mkdir(dir.as_ptr(), 0o700);
+    }
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let name = &path[path
+        .iter()
+        .rposition(|&c| c == b'/')
+        .map_or(0, |slash| slash + 1)..];
+    let dest = [&QUARANTINE[..], format!("/{time}-").as_bytes(), name].concat();
+    let from = CString::new(path).unwrap();
+    let dest = CString::new(dest).unwrap();
+    let res = match next.rename {
+        Some(rename) => rename(from.as_ptr(), dest.as_ptr(), 0),
+        None => -(ENOSYS as c_int),
+    };
+    eprintln!(
+        "quarantined {}{}",
+        String::from_utf8_lossy(path),
+        if res == 0 { "" } else { " failed" }
+    );
+}
+
+unsafe fn wait_for_verdict(path: *const c_char) {
+    let path = CStr::from_ptr(path).to_bytes();
+    let scanning = SCANNING.lock().unwrap();
+    drop(
+        SCANNED
+            .wait_while(scanning, |scanning| scanning.contains(path))
+            .unwrap(),
+    );
+}
+
+unsafe fn track(path: *const c_char, fi: *mut fuse_file_info) {
+    if (*fi).flags & O_ACCMODE as c_int != O_RDONLY as c_int {
+        let path = CStr::from_ptr(path).to_bytes().to_vec();
+        WRITERS.lock().unwrap().insert((*fi).fh, (path, false));
+    }
+}
+
+unsafe fn written(fi: *mut fuse_file_info) {
+    if fi.is_null() {
+        return;
+    }
+    if let Some((_, written)) = WRITERS.lock().unwrap().get_mut(&(*fi).fh) {
+        *written = true;
+    }
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    wait_for_verdict(arg1);
+    let res = NEXT.assume_init_ref().open.unwrap()(arg1, arg2);
+    if res == 0 {
+        track(arg1, arg2);
+    }
+    res
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    wait_for_verdict(arg1);
+    let res = NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3);
+    if res == 0 {
+        track(arg1, arg3);
+    }
+    res
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    written(arg5);
+    NEXT.assume_init_ref().write.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    written(arg2);
+    NEXT.assume_init_ref().write_buf.unwrap()(arg1, buf, off, arg2)
+}
+
+unsafe extern "C" fn copy_file_range(
+    path_in: *const c_char,
+    fi_in: *mut fuse_file_info,
+    offset_in: off_t,
+    path_out: *const c_char,
+    fi_out: *mut fuse_file_info,
+    offset_out: off_t,
+    size: usize,
+    flags: c_int,
+) -> isize {
+    written(fi_out);
+    NEXT.assume_init_ref().copy_file_range.unwrap()(
+        path_in, fi_in, offset_in, path_out, fi_out, offset_out, size, flags,
+    )
+}
+
+unsafe extern "C" fn release(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let release = || match NEXT.assume_init_ref().release {
+        Some(release) => release(arg1, arg2),
+        None => 0,
+    };
+    // the path the file was opened at, in case it was renamed since
+    let Some((opened_at, true)) = WRITERS.lock().unwrap().remove(&(*arg2).fh) else {
+        return release();
+    };
+    let path = match arg1.is_null() {
+        true => opened_at,
+        false => CStr::from_ptr(arg1).to_bytes().to_vec(),
+    };
+    // before the handle goes, so no open slips in between the last write and the scan
+    SCANNING.lock().unwrap().insert(path.clone());
+    let res = release();
+    if is_malicious(&[&ROOT[..], &path].concat()) {
+        quarantine(&path);
+    }
+    SCANNING.lock().unwrap().remove(&path);
+    SCANNED.notify_all();
+    res
+}
+
+/// Submits every file written through the mount to a scanner listening on the unix socket
+/// `socket` once the handle it was written through is closed, and moves the files it flags into
+/// the directory `quarantine_dir` within the mount, which is best hidden from the guest with the
+/// hide layer. Opening a file waits until its scan is done, so no one reads it before the
+/// verdict.
+///
+/// The scanner is sent a `scan <path>` line with the file's path on the host, `root` followed by
+/// its path within the mount, and answers `clean` or `infected`. If it fails to answer within
+/// `timeout_ms` milliseconds, files are left alone if `fail_open` is set and quarantined
+/// otherwise. A `timeout_ms` of 0 waits for the scanner however long it takes.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer and valid C strings
+#[no_mangle]
+pub unsafe extern "C" fn new_scan_layer(
+    next: *const fuse_operations,
+    socket: *const c_char,
+    root: *const c_char,
+    quarantine_dir: *const c_char,
+    timeout_ms: c_uint,
+    fail_open: bool,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    SOCKET = Some(PathBuf::from(OsStr::from_bytes(
+        CStr::from_ptr(socket).to_bytes(),
+    )));
+    let root = CStr::from_ptr(root).to_bytes();
+    ROOT = root[..root
+        .iter()
+        .rposition(|&c| c != b'/')
+        .map_or(0, |last| last + 1)]
+        .to_vec();
+    QUARANTINE = CStr::from_ptr(quarantine_dir).to_bytes().to_vec();
+    TIMEOUT = match timeout_ms {
+        0 => None,
+        timeout_ms => Some(Duration::from_millis(timeout_ms as u64)),
+    };
+    FAIL_OPEN = fail_open;
+    Box::into_raw(Box::new(fuse_operations {
+        open: next.open.and(Some(open)),
+        write: next.write.and(Some(write)),
+        release: Some(release),
+        create: next.create.and(Some(create)),
+        write_buf: next.write_buf.and(Some(write_buf)),
+        copy_file_range: next.copy_file_range.and(Some(copy_file_range)),
+        ..next
+    }))
+}
@@ -0,0 +1,231 @@
+use crate::fuse::{
+    free, fuse_buf, fuse_buf_copy, fuse_buf_flags_FUSE_BUF_IS_FD, fuse_buf_size, fuse_bufvec,
+    fuse_file_info, fuse_operations, malloc, off_t, ENOMEM, O_RDONLY,
+};
+use std::{
+    ffi::{c_char, c_int, c_uint, CStr},
+    mem::{self, MaybeUninit},
+    slice,
+    sync::atomic::{AtomicU64, Ordering},
+    time::SystemTime,
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+/// The stack whose reads are checked against those of the next layer.
+static mut SHADOW: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut SAMPLE_PERCENT: c_uint = 0;
+static RNG: AtomicU64 = AtomicU64::new(0);
+static COMPARED: AtomicU64 = AtomicU64::new(0);
+static MISMATCHED: AtomicU64 = AtomicU64::new(0);
+
+/// Uniformly distributed number in `(0, 1]`, from a xorshift generator shared by all threads.
+fn uniform() -> f64 {
+    let mut x = RNG.load(Ordering::Relaxed);
+    loop {
+        let mut next = x;
+        next ^= next << 13;
+        next ^= next >> 7;
+        next ^= next << 17;
+        match RNG.compare_exchange_weak(x, next, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return ((next >> 11) + 1) as f64 / (1u64 << 53) as f64,
+            Err(current) => x = current,
+        }
+    }
+}
+
+unsafe fn sampled() -> bool {
+    uniform() * 100.0 <= SAMPLE_PERCENT as f64
+}
+
+/// Reads the same range through the shadow stack, opening the file just for this read.
+unsafe fn read_shadow(path: *const c_char, size: usize, off: off_t) -> Result<Vec<u8>, c_int> {
+    let shadow = SHADOW.assume_init_ref();
+    let mut fi = MaybeUninit::<fuse_file_info>::zeroed().assume_init();
+    fi.flags = O_RDONLY as c_int;
+    if let Some(open) = shadow.open {
+        let res = open(path, &mut fi);
+        if res != 0 {
+            return Err(res);
+        }
+    }
+    let mut data = vec![0u8; size];
+    let res = shadow.read.unwrap()(path, data.as_mut_ptr().cast(), size, off, &mut fi);
+    if let Some(release) = shadow.release {
+        release(path, &mut fi);
+    }
+    if res < 0 {
+        return Err(res);
+    }
+    data.truncate(res as usize);
+    Ok(data)
+}
+
+/// Compares what the next layer returned for a read with what the shadow stack returns for
+/// the same read, logging any difference.
+unsafe fn compare(path: *const c_char, size: usize, off: off_t, primary: Result<&[u8], c_int>) {
+    let shadow = read_shadow(path, size, off);
+    COMPARED.fetch_add(1, Ordering::Relaxed);
+    let difference = match (primary, shadow.as_deref().map_err(|&res| res)) {
+        (Ok(primary), Ok(shadow)) if primary == shadow => return,
+        (Err(primary), Err(shadow)) if primary == shadow => return,
+        (Ok(primary), Ok(shadow)) => match primary.iter().zip(shadow).position(|(a, b)| a != b) {
+            Some(pos) => format!("bytes differ from offset {}", off + pos as off_t),
+            None => format!("{} bytes read, shadow read {}", primary.len(), shadow.len()),
+        },
+        (Ok(primary), Err(shadow)) => {
+            format!(
+                "{} bytes read, shadow failed with errno {}",
+                primary.len(),
+                -shadow
+            )
+        }
+        (Err(primary), Ok(shadow)) => {
+            format!(
+                "failed with errno {}, shadow read {} bytes",
+                -primary,
+                shadow.len()
+            )
+        }
+        (Err(primary), Err(shadow)) => {
+            format!(
+                "failed with errno {}, shadow with errno {}",
+                -primary, -shadow
+            )
+        }
+    };
+    MISMATCHED.fetch_add(1, Ordering::Relaxed);
+    eprintln!(
+        "shadow mismatch reading {} bytes at {off} of {}: {difference}",
+        size,
+        CStr::from_ptr(path).to_string_lossy()
+    );
+}
+
+unsafe extern "C" fn read(
+    arg1: *const c_char,
+    arg2: *mut c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let res = NEXT.assume_init_ref().read.unwrap()(arg1, arg2, arg3, arg4, arg5);
+    if !arg1.is_null() && sampled() {
+        let data = match res {
+            0.. => Ok(slice::from_raw_parts(arg2.cast::<u8>(), res as usize)),
+            _ => Err(res),
+        };
+        compare(arg1, arg3, arg4, data);
+    }
+    res
+}
+
+/// Frees a buffer vector returned by `read_buf` the way libfuse would.
+unsafe fn free_bufvec(bufv: *mut fuse_bufvec) {
+    let bufs = slice::from_raw_parts((*bufv).buf.as_ptr(), (*bufv).count);
+    for buf in bufs {
+        if buf.flags & fuse_buf_flags_FUSE_BUF_IS_FD == 0 {
+            free(buf.mem);
+        }
+    }
+    free(bufv.cast());
+}
+
+unsafe extern "C" fn read_buf(
+    arg1: *const c_char,
+    bufp: *mut *mut fuse_bufvec,
+    size: usize,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let res = NEXT.assume_init_ref().read_buf.unwrap()(arg1, bufp, size, off, arg2);
+    if arg1.is_null() || !sampled() {
+        return res;
+    }
+    if res != 0 {
+        compare(arg1, size, off, Err(res));
+        return res;
+    }
+    // the data may sit in a file or pipe, so it is copied into memory to compare and handed
+    // back to libfuse from there
+    let len = fuse_buf_size(*bufp);
+    let buf = malloc(mem::size_of::<fuse_bufvec>()).cast::<fuse_bufvec>();
+    let mem = malloc(len.max(1));
+    if buf.is_null() || mem.is_null() {
+        free(buf.cast());
+        free(mem);
+        free_bufvec(*bufp);
+        return -(ENOMEM as c_int);
+    }
+    buf.write(fuse_bufvec {
+        count: 1,
+        idx: 0,
+        off: 0,
+        buf: [fuse_buf {
+            size: len,
+            flags: 0,
+            mem,
+            fd: -1,
+            pos: 0,
+        }],
+    });
+    let copied = fuse_buf_copy(buf, *bufp, 0);
+    free_bufvec(*bufp);
+    if copied < 0 {
+        free_bufvec(buf);
+        return copied as c_int;
+    }
+    (*buf).buf[0].size = copied as usize;
+    *bufp = buf;
+    let data = slice::from_raw_parts(mem.cast::<u8>(), copied as usize);
+    compare(arg1, size, off, Ok(data));
+    0
+}
+
+/// Prints how many reads were compared and how many of them differed.
+#[no_mangle]
+pub extern "C" fn shadow_report() {
+    eprintln!(
+        "{} reads compared, {} mismatched",
+        COMPARED.load(Ordering::Relaxed),
+        MISMATCHED.load(Ordering::Relaxed)
+    );
+}
+
+/// Checks a stack of layers against another by repeating `sample_percent` percent of the reads
+/// made through the next layer against `shadow`, and logging every read where the data or the
+/// error returned differs, e.g. with a new caching layer as next layer and the passthrough it
+/// sits on as shadow. Only the results of the next layer reach the guest. Shadow reads open the
+/// file just for the read, and a write landing in between may show up as a mismatch. Only the
+/// next stack gets initialized and destroyed by libfuse, so the shadow should be built from
+/// layers of that stack, like the passthrough both sit on, or layers needing neither.
+/// `shadow_report` prints the totals.
+///
+/// # Safety
+///
+/// This function must be called with non-null next and shadow pointers
+#[no_mangle]
+pub unsafe extern "C" fn new_shadow_layer(
+    next: *const fuse_operations,
+    shadow: *const fuse_operations,
+    sample_percent: c_uint,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    let shadow = unsafe { shadow.read() };
+    SHADOW.write(shadow);
+    SAMPLE_PERCENT = sample_percent;
+    let seed = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    // xorshift never leaves the all-zero state
+    RNG.store(seed | 1, Ordering::Relaxed);
+    if shadow.read.is_none() {
+        return Box::into_raw(Box::new(next));
+    }
+    Box::into_raw(Box::new(fuse_operations {
+        read: next.read.and(Some(read)),
+        read_buf: next.read_buf.and(Some(read_buf)),
+        ..next
+    }))
+}
@@ -0,0 +1,232 @@
+use crate::{
+    budget::Consumer,
+    fuse::{
+        dev_t, fuse_file_info, fuse_operations, ino_t, mode_t, stat, EBUSY, ETXTBSY, O_ACCMODE,
+        O_RDONLY, O_TRUNC,
+    },
+};
+use std::{
+    collections::BTreeMap,
+    ffi::{c_char, c_int},
+    mem::{self, MaybeUninit},
+    sync::Mutex,
+};
+
+/// Open flag the kernel sets when opening a file for `execve`.
+const FMODE_EXEC: c_int = 0x20;
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut POLICY: SharePolicy = SharePolicy::DenyWriteWhileExecuting;
+static OPENS: Mutex<Opens> = Mutex::new(Opens {
+    files: BTreeMap::new(),
+    handles: BTreeMap::new(),
+});
+/// Open handles cannot be forgotten without letting conflicting opens through, so they are only
+/// counted against the budget.
+static MEMORY: Consumer = Consumer::pinned(c"share modes");
+const FILE: usize = mem::size_of::<(Inode, Counts)>();
+const HANDLE: usize = mem::size_of::<(u64, (Inode, Access))>();
+
+/// Which combinations of concurrent opens of the same file are refused.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SharePolicy {
+    /// Fail with `ETXTBSY` when opening a file for writing while it is being executed and vice
+    /// versa, like local filesystems do.
+    DenyWriteWhileExecuting,
+    /// Fail with `EBUSY` when opening a file for writing while it is open at all, or opening a
+    /// file while it is open for writing, like the default Windows share modes.
+    DenyWrite,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Default)]
+struct Counts {
+    readers: usize,
+    writers: usize,
+    executors: usize,
+}
+
+impl Counts {
+    fn get_mut(&mut self, access: Access) -> &mut usize {
+        match access {
+            Access::Read => &mut self.readers,
+            Access::Write => &mut self.writers,
+            Access::Execute => &mut self.executors,
+        }
+    }
+}
+
+/// Files are told apart by inode, so renames and unlinks carry over to their open handles and
+/// a new file taking the name of an open one starts out unshared.
+type Inode = (dev_t, ino_t);
+
+struct Opens {
+    files: BTreeMap<Inode, Counts>,
+    /// Inode and access of every open handle, keyed by the handle the next layer returned.
+    handles: BTreeMap<u64, (Inode, Access)>,
+}
+
+fn access(flags: c_int) -> Access {
+    if flags & FMODE_EXEC != 0 {
+        Access::Execute
+    } else if flags & O_ACCMODE as c_int == O_RDONLY as c_int {
+        Access::Read
+    } else {
+        Access::Write
+    }
+}
+
+unsafe fn conflict(counts: &Counts, access: Access) -> c_int {
+    match (POLICY, access) {
+        (SharePolicy::DenyWriteWhileExecuting, Access::Write) if counts.executors > 0 => {
+            -(ETXTBSY as c_int)
+        }
+        (SharePolicy::DenyWriteWhileExecuting, Access::Execute) if counts.writers > 0 => {
+            -(ETXTBSY as c_int)
+        }
+        (SharePolicy::DenyWrite, Access::Write)
+            if counts.readers + counts.writers + counts.executors > 0 =>
+        {
+            -(EBUSY as c_int)
+        }
+        (SharePolicy::DenyWrite, _) if counts.writers > 0 => -(EBUSY as c_int),
+        _ => 0,
+    }
+}
+
+impl Opens {
+    fn insert(&mut self, fh: u64, inode: Inode, access: Access) {
+        let mut size = HANDLE;
+        if !self.files.contains_key(&inode) {
+            size += FILE;
+        }
+        *self.files.entry(inode).or_default().get_mut(access) += 1;
+        self.handles.insert(fh, (inode, access));
+        MEMORY.charge(size);
+    }
+
+    fn remove(&mut self, fh: u64) {
+        if let Some((inode, access)) = self.handles.remove(&fh) {
+            let mut size = HANDLE;
+            let counts = self.files.get_mut(&inode).unwrap();
+            *counts.get_mut(access) -= 1;
+            if counts.readers + counts.writers + counts.executors == 0 {
+                self.files.remove(&inode);
+                size += FILE;
+            }
+            MEMORY.release(size);
+        }
+    }
+}
+
+unsafe fn release_next(path: *const c_char, fi: *mut fuse_file_info) -> c_int {
+    match NEXT.assume_init_ref().release {
+        Some(release) => release(path, fi),
+        None => 0,
+    }
+}
+
+/// Opens the file through `open` and keeps the handle unless it conflicts with the handles
+/// already open on the file. Truncation is held back until the handle is kept, so a refused
+/// open leaves the file as it was.
+unsafe fn open_checked(
+    path: *const c_char,
+    fi: *mut fuse_file_info,
+    open: impl FnOnce() -> c_int,
+) -> c_int {
+    let access = access((*fi).flags);
+    let truncate = (*fi).flags & O_TRUNC as c_int != 0;
+    (*fi).flags &= !(O_TRUNC as c_int);
+    let res = open();
+    if truncate {
+        (*fi).flags |= O_TRUNC as c_int;
+    }
+    if res != 0 {
+        return res;
+    }
+    let next = NEXT.assume_init_ref();
+    let mut st = MaybeUninit::<stat>::zeroed();
+    let res = next.getattr.unwrap()(path, st.as_mut_ptr(), fi);
+    if res != 0 {
+        release_next(path, fi);
+        return res;
+    }
+    let st = st.assume_init();
+    let inode = (st.st_dev, st.st_ino);
+    {
+        // checked and counted at once, so of two conflicting opens only one is kept
+        let mut opens = OPENS.lock().unwrap();
+        let res = opens
+            .files
+            .get(&inode)
+            .map_or(0, |counts| conflict(counts, access));
+        if res < 0 {
+            drop(opens);
+            release_next(path, fi);
+            return res;
+        }
+        opens.insert((*fi).fh, inode, access);
+    }
+    crate::budget::enforce();
+    if truncate && st.st_size != 0 {
+        let res = match next.truncate {
+            Some(truncate) => truncate(path, 0, fi),
+            None => 0,
+        };
+        if res != 0 {
+            OPENS.lock().unwrap().remove((*fi).fh);
+            release_next(path, fi);
+            return res;
+        }
+    }
+    0
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    open_checked(arg1, arg2, || {
+        NEXT.assume_init_ref().open.unwrap()(arg1, arg2)
+    })
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    open_checked(arg1, arg3, || {
+        NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3)
+    })
+}
+
+unsafe extern "C" fn release(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    OPENS.lock().unwrap().remove((*arg2).fh);
+    release_next(arg1, arg2)
+}
+
+/// Refuses opens that conflict with handles already open on the same file according to
+/// `policy`, to emulate platforms with stricter sharing semantics.
+///
+/// Handles are told apart by the `fh` the next layer assigns, which must be unique per open file,
+/// and files by the inode the next layer's `getattr` reports for the new handle.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer
+#[no_mangle]
+pub unsafe extern "C" fn new_sharemode_layer(
+    next: *const fuse_operations,
+    policy: SharePolicy,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    POLICY = policy;
+    Box::into_raw(Box::new(fuse_operations {
+        open: next.open.and(Some(open)),
+        create: next.create.and(Some(create)),
+        release: Some(release),
+        ..next
+    }))
+}
@@ -0,0 +1,419 @@
+use crate::fuse::{
+    dev_t, fuse_bufvec, fuse_file_info, fuse_operations, gid_t, mmap, mode_t, off_t, stat,
+    timespec, uid_t, MAP_SHARED, PROT_READ, PROT_WRITE,
+};
+use std::{
+    ffi::{c_char, c_int, c_uint, c_void, CStr, OsStr},
+    fs::OpenOptions,
+    hint, io,
+    mem::{self, MaybeUninit},
+    os::unix::{ffi::OsStrExt, fs::OpenOptionsExt, io::AsRawFd},
+    ptr,
+    sync::atomic::{fence, AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut SLOTS: *mut Slot = ptr::null_mut();
+static mut SLOT_COUNT: usize = 0;
+static mut TTL_MS: u64 = 0;
+
+/// How long a slot may stay locked before its writer is taken to have died holding it.
+const STALE_MS: u32 = 1000;
+
+/// One cached `getattr` result in the shared mapping, guarded by a seqlock: writers make `seq`
+/// odd while they update the slot, readers retry or give up when it changed under them.
+#[repr(C)]
+struct Slot {
+    /// The sequence number in the low half and, while it is odd, the wall clock time in
+    /// milliseconds the slot was locked at, truncated to the high half.
+    seq: AtomicU64,
+    /// Hash of the path the slot holds, 0 when empty.
+    key: u64,
+    /// Wall clock time in milliseconds after which the entry is stale, the same in every
+    /// process sharing the mapping.
+    expires_ms: u64,
+    st: stat,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// FNV-1a, which gives the same hash in every process, unlike `DefaultHasher`.
+fn key(path: &[u8]) -> u64 {
+    let hash = path.iter().fold(0xcbf29ce484222325u64, |hash, &c| {
+        (hash ^ c as u64).wrapping_mul(0x100000001b3)
+    });
+    hash.max(1)
+}
+
+unsafe fn slot(key: u64) -> *mut Slot {
+    // other processes write to the mapping too, so slots are only ever accessed through raw
+    // pointers
+    SLOTS.add((key % SLOT_COUNT as u64) as usize)
+}
+
+/// Looks `path` up, or on a miss returns the generation of its slot to pass to `store` with
+/// the result, which is only cached if nothing changed the slot in between.
+unsafe fn lookup(path: &[u8]) -> Result<stat, u64> {
+    let key = key(path);
+    let slot = slot(key);
+    let seq = (*slot).seq.load(Ordering::Acquire);
+    if seq & 1 == 1 {
+        return Err(seq);
+    }
+    let found = ptr::read_volatile(ptr::addr_of!((*slot).key));
+    let expires_ms = ptr::read_volatile(ptr::addr_of!((*slot).expires_ms));
+    let st = ptr::read_volatile(ptr::addr_of!((*slot).st));
+    fence(Ordering::Acquire);
+    if (*slot).seq.load(Ordering::Relaxed) != seq || found != key || expires_ms < now_ms() {
+        return Err(seq);
+    }
+    Ok(st)
+}
+
+/// Updates the slot for `key`. With `st` the result is cached unless the slot changed since
+/// the lookup that returned the generation, which a concurrent invalidation would have done, or
+/// another process is updating it. With `st` None the slot is emptied if it still holds `key`,
+/// waiting for another process updating it so the invalidation cannot be lost.
+///
+/// A slot left locked by a process that died while updating it is taken over and emptied once
+/// it has been locked for `STALE_MS`.
+unsafe fn store(key: u64, st: Option<(&stat, u64)>) {
+    let slot = slot(key);
+    let (locked, seq, recovered) = loop {
+        let current = (*slot).seq.load(Ordering::Relaxed);
+        if st.is_some_and(|(_, generation)| generation != current) {
+            return;
+        }
+        let since = (current >> 32) as u32;
+        let (seq, recovered) = if current & 1 == 0 {
+            ((current as u32).wrapping_add(1), false)
+        } else if (now_ms() as u32).wrapping_sub(since) > STALE_MS {
+            // still odd, as the slot is being updated by us now
+            ((current as u32).wrapping_add(2), true)
+        } else if st.is_some() {
+            return;
+        } else {
+            hint::spin_loop();
+            continue;
+        };
+        let locked = (now_ms() as u32 as u64) << 32 | seq as u64;
+        if (*slot)
+            .seq
+            .compare_exchange(current, locked, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            break (locked, seq, recovered);
+        }
+        hint::spin_loop();
+    };
+    match st {
+        Some((st, _)) => {
+            ptr::write_volatile(ptr::addr_of_mut!((*slot).key), key);
+            ptr::write_volatile(ptr::addr_of_mut!((*slot).expires_ms), now_ms() + TTL_MS);
+            ptr::write_volatile(ptr::addr_of_mut!((*slot).st), *st);
+        }
+        // whatever the dead writer left may be torn
+        None if recovered || ptr::read_volatile(ptr::addr_of!((*slot).key)) == key => {
+            ptr::write_volatile(ptr::addr_of_mut!((*slot).key), 0);
+        }
+        None => (),
+    }
+    // a writer that stalled past `STALE_MS` finds its slot taken over and leaves it be
+    let _ = (*slot).seq.compare_exchange(
+        locked,
+        seq.wrapping_add(1) as u64,
+        Ordering::Release,
+        Ordering::Relaxed,
+    );
+}
+
+unsafe fn invalidate(path: *const c_char) {
+    if !path.is_null() {
+        store(key(CStr::from_ptr(path).to_bytes()), None);
+    }
+}
+
+/// Invalidates `path` and the directory holding it, whose times and link count change along
+/// with its entries.
+unsafe fn invalidate_entry(path: *const c_char) {
+    invalidate(path);
+    if path.is_null() {
+        return;
+    }
+    let path = CStr::from_ptr(path).to_bytes();
+    let parent = match path.iter().rposition(|&c| c == b'/') {
+        Some(0) => &b"/"[..],
+        Some(slash) => &path[..slash],
+        None => return,
+    };
+    store(key(parent), None);
+}
+
+unsafe extern "C" fn getattr(
+    arg1: *const c_char,
+    arg2: *mut stat,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    // requests on an open handle want that file's attributes, whatever its path is now
+    if arg1.is_null() || !fi.is_null() {
+        return NEXT.assume_init_ref().getattr.unwrap()(arg1, arg2, fi);
+    }
+    let path = CStr::from_ptr(arg1).to_bytes();
+    let generation = match lookup(path) {
+        Ok(st) => {
+            arg2.write(st);
+            return 0;
+        }
+        Err(generation) => generation,
+    };
+    let res = NEXT.assume_init_ref().getattr.unwrap()(arg1, arg2, fi);
+    if res == 0 {
+        store(key(path), Some((&*arg2, generation)));
+    }
+    res
+}
+
+unsafe extern "C" fn mknod(arg1: *const c_char, arg2: mode_t, arg3: dev_t) -> c_int {
+    let res = NEXT.assume_init_ref().mknod.unwrap()(arg1, arg2, arg3);
+    invalidate_entry(arg1);
+    res
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    let res = NEXT.assume_init_ref().mkdir.unwrap()(arg1, arg2);
+    invalidate_entry(arg1);
+    res
+}
+
+unsafe extern "C" fn unlink(arg1: *const c_char) -> c_int {
+    let res = NEXT.assume_init_ref().unlink.unwrap()(arg1);
+    invalidate_entry(arg1);
+    res
+}
+
+unsafe extern "C" fn rmdir(arg1: *const c_char) -> c_int {
+    let res = NEXT.assume_init_ref().rmdir.unwrap()(arg1);
+    invalidate_entry(arg1);
+    res
+}
+
+unsafe extern "C" fn symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let res = NEXT.assume_init_ref().symlink.unwrap()(arg1, arg2);
+    invalidate_entry(arg2);
+    res
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    let res = NEXT.assume_init_ref().rename.unwrap()(arg1, arg2, flags);
+    invalidate_entry(arg1);
+    invalidate_entry(arg2);
+    res
+}
+
+unsafe extern "C" fn link(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let res = NEXT.assume_init_ref().link.unwrap()(arg1, arg2);
+    invalidate(arg1);
+    invalidate_entry(arg2);
+    res
+}
+
+unsafe extern "C" fn chmod(arg1: *const c_char, arg2: mode_t, fi: *mut fuse_file_info) -> c_int {
+    let res = NEXT.assume_init_ref().chmod.unwrap()(arg1, arg2, fi);
+    invalidate(arg1);
+    res
+}
+
+unsafe extern "C" fn chown(
+    arg1: *const c_char,
+    arg2: uid_t,
+    arg3: gid_t,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let res = NEXT.assume_init_ref().chown.unwrap()(arg1, arg2, arg3, fi);
+    invalidate(arg1);
+    res
+}
+
+unsafe extern "C" fn truncate(arg1: *const c_char, arg2: off_t, fi: *mut fuse_file_info) -> c_int {
+    let res = NEXT.assume_init_ref().truncate.unwrap()(arg1, arg2, fi);
+    invalidate(arg1);
+    res
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let res = NEXT.assume_init_ref().write.unwrap()(arg1, arg2, arg3, arg4, arg5);
+    invalidate(arg1);
+    res
+}
+
+unsafe extern "C" fn setxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *const c_char,
+    arg4: usize,
+    arg5: c_int,
+) -> c_int {
+    let res = NEXT.assume_init_ref().setxattr.unwrap()(arg1, arg2, arg3, arg4, arg5);
+    invalidate(arg1);
+    res
+}
+
+unsafe extern "C" fn removexattr(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let res = NEXT.assume_init_ref().removexattr.unwrap()(arg1, arg2);
+    invalidate(arg1);
+    res
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    let res = NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3);
+    invalidate_entry(arg1);
+    res
+}
+
+unsafe extern "C" fn utimens(
+    arg1: *const c_char,
+    tv: *const timespec,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let res = NEXT.assume_init_ref().utimens.unwrap()(arg1, tv, fi);
+    invalidate(arg1);
+    res
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let res = NEXT.assume_init_ref().write_buf.unwrap()(arg1, buf, off, arg2);
+    invalidate(arg1);
+    res
+}
+
+unsafe extern "C" fn fallocate(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: off_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let res = NEXT.assume_init_ref().fallocate.unwrap()(arg1, arg2, arg3, arg4, arg5);
+    invalidate(arg1);
+    res
+}
+
+unsafe extern "C" fn copy_file_range(
+    path_in: *const c_char,
+    fi_in: *mut fuse_file_info,
+    offset_in: off_t,
+    path_out: *const c_char,
+    fi_out: *mut fuse_file_info,
+    offset_out: off_t,
+    size: usize,
+    flags: c_int,
+) -> isize {
+    let res = NEXT.assume_init_ref().copy_file_range.unwrap()(
+        path_in, fi_in, offset_in, path_out, fi_out, offset_out, size, flags,
+    );
+    invalidate(path_out);
+    res
+}
+
+/// Maps the table at `shm_path`, creating it with room for `entries` slots if it is new.
+/// Returns the mapping and its length.
+unsafe fn map_table(shm_path: &OsStr, entries: c_uint) -> io::Result<(*mut c_void, usize)> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(shm_path)?;
+    // a new file is all zeroes, which are empty slots
+    let mut len = file.metadata()?.len() as usize;
+    if len < mem::size_of::<Slot>() {
+        len = entries.max(1) as usize * mem::size_of::<Slot>();
+        file.set_len(len as u64)?;
+    }
+    let map = mmap(
+        ptr::null_mut(),
+        len,
+        (PROT_READ | PROT_WRITE) as c_int,
+        MAP_SHARED as c_int,
+        file.as_raw_fd(),
+        0,
+    );
+    if map as isize == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((map, len))
+}
+
+/// Caches `getattr` results for `ttl_ms` milliseconds in a table of `entries` slots mapped from
+/// `shm_path`, typically a file in `/dev/shm`, so every instance mapping the same file shares
+/// it. With many pods mounting one volume, repeated stats of the same hot files are then served
+/// from memory instead of each instance asking the backing filesystem. Changes made through any
+/// instance evict what they affect from the table, changes made behind their back, and the old
+/// paths of files below a renamed directory, show up once the entries expire. All instances
+/// must agree on the path they see the volume under and run the same version of the layer, and
+/// an existing table keeps its size. Returns null if the table cannot be mapped.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer and a valid C string
+#[no_mangle]
+pub unsafe extern "C" fn new_shmcache_layer(
+    next: *const fuse_operations,
+    shm_path: *const c_char,
+    entries: c_uint,
+    ttl_ms: c_uint,
+) -> *const fuse_operations {
+    let shm_path = OsStr::from_bytes(CStr::from_ptr(shm_path).to_bytes());
+    let (map, len) = match map_table(shm_path, entries) {
+        Ok(table) => table,
+        Err(err) => {
+            eprintln!("failed to map attribute cache {shm_path:?}: {err}");
+            return ptr::null();
+        }
+    };
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    TTL_MS = ttl_ms as u64;
+    SLOTS = map.cast();
+    SLOT_COUNT = len / mem::size_of::<Slot>();
+    Box::into_raw(Box::new(fuse_operations {
+        getattr: next.getattr.and(Some(getattr)),
+        mknod: next.mknod.and(Some(mknod)),
+        mkdir: next.mkdir.and(Some(mkdir)),
+        unlink: next.unlink.and(Some(unlink)),
+        rmdir: next.rmdir.and(Some(rmdir)),
+        symlink: next.symlink.and(Some(symlink)),
+        rename: next.rename.and(Some(rename)),
+        link: next.link.and(Some(link)),
+        chmod: next.chmod.and(Some(chmod)),
+        chown: next.chown.and(Some(chown)),
+        truncate: next.truncate.and(Some(truncate)),
+        write: next.write.and(Some(write)),
+        setxattr: next.setxattr.and(Some(setxattr)),
+        removexattr: next.removexattr.and(Some(removexattr)),
+        create: next.create.and(Some(create)),
+        utimens: next.utimens.and(Some(utimens)),
+        write_buf: next.write_buf.and(Some(write_buf)),
+        fallocate: next.fallocate.and(Some(fallocate)),
+        copy_file_range: next.copy_file_range.and(Some(copy_file_range)),
+        ..next
+    }))
+}
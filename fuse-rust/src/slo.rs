@@ -0,0 +1,234 @@
+use crate::fuse::{
+    fuse_bufvec, fuse_config, fuse_conn_info, fuse_file_info, fuse_fill_dir_flags,
+    fuse_fill_dir_flags_FUSE_FILL_DIR_PLUS, fuse_fill_dir_t, fuse_operations, fuse_readdir_flags,
+    fuse_readdir_flags_FUSE_READDIR_PLUS, off_t, stat,
+};
+use std::{
+    ffi::{c_char, c_int, c_uint, c_void},
+    mem::MaybeUninit,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Length of the periods the read latency percentile is computed over.
+const PERIOD: Duration = Duration::from_secs(1);
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut TARGET: Duration = Duration::ZERO;
+static mut SUSTAIN: Duration = Duration::ZERO;
+static mut DEGRADED_TIMEOUT: f64 = 0.0;
+/// Configuration libfuse consults when replying, kept from `init` to change timeouts later.
+static mut CONFIG: *mut fuse_config = ptr::null_mut();
+/// Attribute and entry timeouts the mount was configured with.
+static mut TIMEOUTS: (f64, f64) = (0.0, 0.0);
+static DEGRADED: AtomicBool = AtomicBool::new(false);
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker {
+    samples: Vec::new(),
+    period_start: None,
+    since: None,
+});
+
+struct Tracker {
+    /// Latencies of the reads finished in the current period.
+    samples: Vec<Duration>,
+    period_start: Option<Instant>,
+    /// Since when the objective has been met while degraded, or missed while not.
+    since: Option<Instant>,
+}
+
+unsafe fn switch(degraded: bool, p99: Duration) {
+    DEGRADED.store(degraded, Ordering::Relaxed);
+    if !CONFIG.is_null() {
+        let (attr, entry) = TIMEOUTS;
+        (*CONFIG).attr_timeout = if degraded {
+            attr.max(DEGRADED_TIMEOUT)
+        } else {
+            attr
+        };
+        (*CONFIG).entry_timeout = if degraded {
+            entry.max(DEGRADED_TIMEOUT)
+        } else {
+            entry
+        };
+    }
+    if degraded {
+        eprintln!(
+            "read p99 {p99:?} above {:?} for {:?}, entering degraded mode",
+            TARGET, SUSTAIN
+        );
+    } else {
+        eprintln!(
+            "read p99 {p99:?} within {:?} for {:?}, leaving degraded mode",
+            TARGET, SUSTAIN
+        );
+    }
+}
+
+/// Records the latency of a read, and at the end of each period compares the 99th percentile
+/// of the period's reads with the objective.
+unsafe fn observe(elapsed: Duration) {
+    let mut tracker = TRACKER.lock().unwrap();
+    tracker.samples.push(elapsed);
+    if tracker
+        .period_start
+        .get_or_insert_with(Instant::now)
+        .elapsed()
+        < PERIOD
+    {
+        return;
+    }
+    tracker.samples.sort_unstable();
+    let p99 = tracker.samples[(tracker.samples.len() * 99).div_ceil(100) - 1];
+    tracker.samples.clear();
+    tracker.period_start = None;
+
+    let violated = p99 > TARGET;
+    if violated == DEGRADED.load(Ordering::Relaxed) {
+        tracker.since = None;
+        return;
+    }
+    if tracker.since.get_or_insert_with(Instant::now).elapsed() >= SUSTAIN {
+        tracker.since = None;
+        switch(violated, p99);
+    }
+}
+
+unsafe fn timed<T>(op: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let res = op();
+    observe(start.elapsed());
+    res
+}
+
+unsafe extern "C" fn init(conn: *mut fuse_conn_info, cfg: *mut fuse_config) -> *mut c_void {
+    let res = match NEXT.assume_init_ref().init {
+        Some(init) => init(conn, cfg),
+        None => ptr::null_mut(),
+    };
+    CONFIG = cfg;
+    TIMEOUTS = ((*cfg).attr_timeout, (*cfg).entry_timeout);
+    res
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let res = NEXT.assume_init_ref().open.unwrap()(arg1, arg2);
+    if res == 0 && DEGRADED.load(Ordering::Relaxed) {
+        // let the guest serve rereads from its page cache instead of the backend
+        (*arg2).set_keep_cache(1);
+    }
+    res
+}
+
+unsafe extern "C" fn read(
+    arg1: *const c_char,
+    arg2: *mut c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    timed(|| NEXT.assume_init_ref().read.unwrap()(arg1, arg2, arg3, arg4, arg5))
+}
+
+unsafe extern "C" fn read_buf(
+    arg1: *const c_char,
+    bufp: *mut *mut fuse_bufvec,
+    size: usize,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    timed(|| NEXT.assume_init_ref().read_buf.unwrap()(arg1, bufp, size, off, arg2))
+}
+
+struct Filler {
+    buf: *mut c_void,
+    filler: fuse_fill_dir_t,
+}
+
+/// Passes an entry on without its attributes, whether or not the next layer filled them in.
+unsafe extern "C" fn fill_plain(
+    buf: *mut c_void,
+    name: *const c_char,
+    stbuf: *const stat,
+    off: off_t,
+    flags: fuse_fill_dir_flags,
+) -> c_int {
+    let filler = &*buf.cast::<Filler>();
+    filler.filler.unwrap()(
+        filler.buf,
+        name,
+        stbuf,
+        off,
+        flags & !fuse_fill_dir_flags_FUSE_FILL_DIR_PLUS,
+    )
+}
+
+unsafe extern "C" fn readdir(
+    arg1: *const c_char,
+    arg2: *mut c_void,
+    arg3: fuse_fill_dir_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+    arg6: fuse_readdir_flags,
+) -> c_int {
+    let readdir = NEXT.assume_init_ref().readdir.unwrap();
+    if !DEGRADED.load(Ordering::Relaxed) || arg6 & fuse_readdir_flags_FUSE_READDIR_PLUS == 0 {
+        return readdir(arg1, arg2, arg3, arg4, arg5, arg6);
+    }
+    // listing without attributes spares the kernel caching them, and backends that look at
+    // the request flags a stat per entry
+    let mut filler = Filler {
+        buf: arg2,
+        filler: arg3,
+    };
+    readdir(
+        arg1,
+        (&mut filler as *mut Filler).cast(),
+        Some(fill_plain),
+        arg4,
+        arg5,
+        arg6 & !fuse_readdir_flags_FUSE_READDIR_PLUS,
+    )
+}
+
+/// Returns whether the mount is currently in degraded mode.
+#[no_mangle]
+pub extern "C" fn slo_degraded() -> bool {
+    DEGRADED.load(Ordering::Relaxed)
+}
+
+/// Tracks the 99th percentile latency of reads against the next layer over one second periods,
+/// and once it has stayed above `read_p99_us` microseconds for `sustain_secs` seconds, switches
+/// the mount to a degraded mode that keeps guest workloads usable over slow or flaky storage:
+/// directory listings no longer fetch the attributes of every entry, attribute and entry
+/// timeouts are raised to at least `degraded_timeout_secs` seconds, and files opened from then
+/// on keep their page cache. The mount switches back once the objective has been met again
+/// for `sustain_secs` seconds. Both switches are logged, and `slo_degraded` reports the mode.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer
+#[no_mangle]
+pub unsafe extern "C" fn new_slo_layer(
+    next: *const fuse_operations,
+    read_p99_us: c_uint,
+    sustain_secs: c_uint,
+    degraded_timeout_secs: c_uint,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    TARGET = Duration::from_micros(read_p99_us as u64);
+    SUSTAIN = Duration::from_secs(sustain_secs as u64);
+    DEGRADED_TIMEOUT = degraded_timeout_secs as f64;
+    Box::into_raw(Box::new(fuse_operations {
+        open: next.open.and(Some(open)),
+        read: next.read.and(Some(read)),
+        readdir: next.readdir.and(Some(readdir)),
+        init: Some(init),
+        read_buf: next.read_buf.and(Some(read_buf)),
+        ..next
+    }))
+}
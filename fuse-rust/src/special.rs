@@ -0,0 +1,248 @@
+use crate::fuse::{
+    fcntl, fstat, fuse_bufvec, fuse_file_info, fuse_interrupted, fuse_operations, mode_t, off_t,
+    poll, pollfd, stat, EACCES, EAGAIN, EINTR, ENXIO, F_GETFL, F_SETFL, O_NONBLOCK, POLLERR,
+    POLLHUP, POLLIN, POLLOUT, S_IFBLK, S_IFCHR, S_IFIFO, S_IFMT, S_IFSOCK,
+};
+use std::{
+    collections::BTreeSet,
+    ffi::{c_char, c_int},
+    mem::MaybeUninit,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut ALLOW_SPECIAL: bool = false;
+/// Handles of special files, which are opened with `O_NONBLOCK` on the host whatever the guest
+/// asked for.
+static SPECIAL: Mutex<BTreeSet<u64>> = Mutex::new(BTreeSet::new());
+/// Number of handles in `SPECIAL`, so reads and writes of ordinary files skip the lock.
+static SPECIAL_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Longest pause between two attempts when emulating a blocking open.
+const MAX_BACKOFF: Duration = Duration::from_millis(50);
+/// How long a wait for a special file to become ready goes before checking for interrupts.
+const POLL_SLICE_MS: c_int = 100;
+
+/// Whether `mode` is that of a FIFO, socket or device node, any of which could block or
+/// otherwise wedge the daemon when opened on the host.
+fn is_special(mode: mode_t) -> bool {
+    matches!(mode & S_IFMT, S_IFIFO | S_IFCHR | S_IFBLK | S_IFSOCK)
+}
+
+/// Retries `op` while it fails with `errno`, backing off between attempts, until it succeeds,
+/// fails differently or the request is interrupted. There is nothing to wait on before a special
+/// file is open.
+fn retry(errno: u32, mut op: impl FnMut() -> c_int) -> c_int {
+    let mut backoff = Duration::from_millis(1);
+    loop {
+        match op() {
+            res if res == -(errno as c_int) => {
+                if unsafe { fuse_interrupted() } != 0 {
+                    return -(EINTR as c_int);
+                }
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Opens a file with `O_NONBLOCK` so that it cannot block the calling thread in the kernel
+/// whatever it turns out to be, then tells special files from others by the handle, which
+/// unlike the path cannot be swapped for something else in between.
+///
+/// Blocking opens of special files are emulated for guests that did not ask for `O_NONBLOCK`.
+/// Opening a FIFO for writing blocks until a reader shows up, just like on the host. Opening one
+/// for reading returns right away instead of waiting for a writer, and the first blocking read
+/// waits for one instead. Other files get their flags back once open.
+unsafe fn open_nonblock(
+    path: *const c_char,
+    fi: *mut fuse_file_info,
+    open: impl Fn() -> c_int,
+) -> c_int {
+    let flags = (*fi).flags;
+    let blocking = flags & O_NONBLOCK as c_int == 0;
+    (*fi).flags |= O_NONBLOCK as c_int;
+    let mut res = open();
+    // only FIFOs without a reader and device nodes without a device fail like this
+    if res == -(ENXIO as c_int) && ALLOW_SPECIAL && blocking {
+        res = retry(ENXIO, &open);
+    }
+    (*fi).flags = flags;
+    if res == -(ENXIO as c_int) && !ALLOW_SPECIAL {
+        return -(EACCES as c_int);
+    }
+    if res != 0 {
+        return res;
+    }
+    let fd = (*fi).fh as c_int;
+    let mut st = MaybeUninit::<stat>::zeroed();
+    if fstat(fd, st.as_mut_ptr()) != 0 || !is_special(st.assume_init().st_mode) {
+        if blocking {
+            fcntl(
+                fd,
+                F_SETFL as c_int,
+                fcntl(fd, F_GETFL as c_int) & !(O_NONBLOCK as c_int),
+            );
+        }
+        return 0;
+    }
+    if !ALLOW_SPECIAL {
+        if let Some(release) = NEXT.assume_init_ref().release {
+            release(path, fi);
+        }
+        return -(EACCES as c_int);
+    }
+    if SPECIAL.lock().unwrap().insert((*fi).fh) {
+        SPECIAL_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+    0
+}
+
+unsafe fn is_special_handle(fi: *mut fuse_file_info) -> bool {
+    !fi.is_null()
+        && SPECIAL_COUNT.load(Ordering::Relaxed) > 0
+        && SPECIAL.lock().unwrap().contains(&(*fi).fh)
+}
+
+/// Waits for the host file descriptor `fd` to be ready for `events`, or to hang up, for as long
+/// as it takes if `block` is set and not at all otherwise. Returns whether it is ready.
+unsafe fn ready(fd: u64, events: u32, block: bool) -> Result<bool, c_int> {
+    let mut pfd = pollfd {
+        fd: fd as c_int,
+        events: events as _,
+        revents: 0,
+    };
+    loop {
+        match poll(&mut pfd, 1, if block { POLL_SLICE_MS } else { 0 }) {
+            0 if block => {
+                if fuse_interrupted() != 0 {
+                    return Err(-(EINTR as c_int));
+                }
+            }
+            0 => return Ok(false),
+            res if res > 0 => {
+                return Ok(pfd.revents as u32 & (events | POLLHUP | POLLERR) != 0);
+            }
+            // not a descriptor that can be polled, leave it to the operation
+            _ => return Ok(true),
+        }
+    }
+}
+
+/// Runs a read or write of a special file once the file is ready for `events`. Guests that
+/// asked for blocking I/O on the handle, as the current flags tell, wait for that in `poll`,
+/// others fail with `EAGAIN` right away when the file is not ready.
+unsafe fn when_ready(fi: *mut fuse_file_info, events: u32, op: impl Fn() -> c_int) -> c_int {
+    if !is_special_handle(fi) {
+        return op();
+    }
+    let block = (*fi).flags & O_NONBLOCK as c_int == 0;
+    loop {
+        match ready((*fi).fh, events, block) {
+            Ok(true) => (),
+            Ok(false) => return -(EAGAIN as c_int),
+            Err(res) => return res,
+        }
+        match op() {
+            // someone else got there first
+            res if res == -(EAGAIN as c_int) && block => continue,
+            res => return res,
+        }
+    }
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    open_nonblock(arg1, arg2, || {
+        NEXT.assume_init_ref().open.unwrap()(arg1, arg2)
+    })
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    open_nonblock(arg1, arg3, || {
+        NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3)
+    })
+}
+
+unsafe extern "C" fn read(
+    arg1: *const c_char,
+    arg2: *mut c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    when_ready(arg5, POLLIN, || {
+        NEXT.assume_init_ref().read.unwrap()(arg1, arg2, arg3, arg4, arg5)
+    })
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    when_ready(arg5, POLLOUT, || {
+        NEXT.assume_init_ref().write.unwrap()(arg1, arg2, arg3, arg4, arg5)
+    })
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    when_ready(arg2, POLLOUT, || {
+        NEXT.assume_init_ref().write_buf.unwrap()(arg1, buf, off, arg2)
+    })
+}
+
+unsafe extern "C" fn release(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    if SPECIAL_COUNT.load(Ordering::Relaxed) > 0 && SPECIAL.lock().unwrap().remove(&(*arg2).fh) {
+        SPECIAL_COUNT.fetch_sub(1, Ordering::Relaxed);
+    }
+    match NEXT.assume_init_ref().release {
+        Some(release) => release(arg1, arg2),
+        None => 0,
+    }
+}
+
+/// Fails opens of FIFOs, sockets and device nodes with `EACCES`, including `O_CREAT` opens of
+/// existing ones, unless `allow_special` is set.
+///
+/// Every file is opened with `O_NONBLOCK` on the host so that special ones cannot stall a
+/// dispatch thread in the kernel, and what was opened is checked on the handle, which closes it
+/// again if it turns out to be a special file that is not allowed. Reads and writes follow the `O_NONBLOCK` flag the guest has on
+/// the handle at the time: blocking ones wait in `poll` for the file to become ready, the others
+/// fail with `EAGAIN` when it is not, including reads of a FIFO no writer has opened yet. This
+/// needs a next layer whose handles are host file descriptors, like the passthrough's.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer
+#[no_mangle]
+pub unsafe extern "C" fn new_special_layer(
+    next: *const fuse_operations,
+    allow_special: bool,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    ALLOW_SPECIAL = allow_special;
+    Box::into_raw(Box::new(fuse_operations {
+        open: next.open.and(Some(open)),
+        create: next.create.and(Some(create)),
+        read: next.read.and(Some(read)),
+        write: next.write.and(Some(write)),
+        write_buf: next.write_buf.and(Some(write_buf)),
+        release: Some(release),
+        ..next
+    }))
+}
@@ -0,0 +1,353 @@
+use crate::fuse::{
+    dev_t, fuse_file_info, fuse_fill_dir_flags, fuse_fill_dir_t, fuse_operations,
+    fuse_readdir_flags, gid_t, mode_t, off_t, stat, timespec, uid_t, EEXIST, EINVAL, ENOENT, EPERM,
+    S_IFLNK, S_IFMT,
+};
+use std::{
+    ffi::{c_char, c_int, c_uint, c_void, CStr, CString},
+    mem::MaybeUninit,
+    ptr,
+};
+
+/// Longest symlink target read from the next layer.
+const MAX_TARGET: usize = 4096;
+/// Longest chain of symlinks followed, like the kernel's `MAXSYMLINKS`.
+const MAX_LINKS: usize = 40;
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut POLICY: SymlinkPolicy = SymlinkPolicy::Present;
+/// Host directory being shared, without trailing slashes.
+static mut ROOT: Option<Vec<u8>> = None;
+
+/// How symlinks on the host are presented to the guest.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Symlinks are shown as symlinks and resolved by the guest.
+    Present,
+    /// Symlinks pointing within the share are shown as what they point to, others as symlinks.
+    Resolve,
+    /// Symlinks do not exist as far as the guest is concerned and cannot be created.
+    Hide,
+}
+
+/// Maps the target of the symlink at `link` to a path within the mount, or `None` if it points
+/// outside the share. `..` is applied lexically, which matches what the host does as long as
+/// no directory on the way is itself a symlink.
+unsafe fn resolve_target(link: &[u8], target: &[u8]) -> Option<Vec<u8>> {
+    let (base, rest) = if target.starts_with(b"/") {
+        let rest = target.strip_prefix(&ROOT.as_ref()?[..])?;
+        if !rest.is_empty() && !rest.starts_with(b"/") {
+            return None;
+        }
+        (&b""[..], rest)
+    } else {
+        (&link[..link.iter().rposition(|&c| c == b'/')?], target)
+    };
+    let mut components: Vec<&[u8]> = base
+        .split(|&c| c == b'/')
+        .filter(|c| !c.is_empty())
+        .collect();
+    for component in rest.split(|&c| c == b'/') {
+        match component {
+            b"" | b"." => (),
+            b".." => {
+                components.pop()?;
+            }
+            name => components.push(name),
+        }
+    }
+    let mut path = Vec::new();
+    for component in components {
+        path.push(b'/');
+        path.extend_from_slice(component);
+    }
+    if path.is_empty() {
+        path.push(b'/');
+    }
+    Some(path)
+}
+
+/// Follows the symlink at `path` and any it leads to while they stay within the share, filling
+/// `st` with the attributes of what they end at. Returns false if the chain leaves the share,
+/// is too long or dangles, in which case `st` is left alone.
+unsafe fn follow(path: &CStr, st: *mut stat) -> bool {
+    let next = NEXT.assume_init_ref();
+    let mut path = path.to_owned();
+    for _ in 0..MAX_LINKS {
+        let mut target = [0u8; MAX_TARGET];
+        if next.readlink.unwrap()(path.as_ptr(), target.as_mut_ptr().cast(), target.len()) != 0 {
+            return false;
+        }
+        let target = CStr::from_bytes_until_nul(&target).unwrap().to_bytes();
+        let Some(resolved) = resolve_target(path.to_bytes(), target) else {
+            return false;
+        };
+        path = CString::new(resolved).unwrap();
+        let mut target_st = MaybeUninit::<stat>::zeroed();
+        if next.getattr.unwrap()(path.as_ptr(), target_st.as_mut_ptr(), ptr::null_mut()) != 0 {
+            return false;
+        }
+        if target_st.assume_init_ref().st_mode & S_IFMT != S_IFLNK {
+            st.write(target_st.assume_init());
+            return true;
+        }
+    }
+    false
+}
+
+unsafe extern "C" fn getattr(
+    arg1: *const c_char,
+    arg2: *mut stat,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let res = NEXT.assume_init_ref().getattr.unwrap()(arg1, arg2, fi);
+    if res != 0 || arg1.is_null() || (*arg2).st_mode & S_IFMT != S_IFLNK {
+        return res;
+    }
+    match POLICY {
+        SymlinkPolicy::Resolve => {
+            follow(CStr::from_ptr(arg1), arg2);
+            0
+        }
+        SymlinkPolicy::Hide => -(ENOENT as c_int),
+        SymlinkPolicy::Present => 0,
+    }
+}
+
+unsafe extern "C" fn readlink(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    match POLICY {
+        SymlinkPolicy::Resolve => {
+            // a followed symlink looks like what it points to, which is not a symlink
+            let mut st = MaybeUninit::<stat>::zeroed();
+            if follow(CStr::from_ptr(arg1), st.as_mut_ptr()) {
+                return -(EINVAL as c_int);
+            }
+        }
+        SymlinkPolicy::Hide => return -(ENOENT as c_int),
+        SymlinkPolicy::Present => (),
+    }
+    NEXT.assume_init_ref().readlink.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    if POLICY == SymlinkPolicy::Hide {
+        return -(EPERM as c_int);
+    }
+    NEXT.assume_init_ref().symlink.unwrap()(arg1, arg2)
+}
+
+/// Whether `path` is a symlink hidden from the guest. The host follows symlinks when creating,
+/// opening or changing files by path, which would let the guest reach through a hidden one to
+/// wherever it points, so such operations are refused.
+unsafe fn is_hidden(path: *const c_char) -> bool {
+    if POLICY != SymlinkPolicy::Hide || path.is_null() {
+        return false;
+    }
+    let mut st = MaybeUninit::<stat>::zeroed();
+    NEXT.assume_init_ref().getattr.unwrap()(path, st.as_mut_ptr(), ptr::null_mut()) == 0
+        && st.assume_init().st_mode & S_IFMT == S_IFLNK
+}
+
+unsafe extern "C" fn mknod(arg1: *const c_char, arg2: mode_t, arg3: dev_t) -> c_int {
+    if is_hidden(arg1) {
+        return -(EEXIST as c_int);
+    }
+    NEXT.assume_init_ref().mknod.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    if is_hidden(arg1) {
+        return -(EEXIST as c_int);
+    }
+    NEXT.assume_init_ref().mkdir.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    if is_hidden(arg1) {
+        return -(EEXIST as c_int);
+    }
+    NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().open.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    if is_hidden(arg2) {
+        return -(EPERM as c_int);
+    }
+    NEXT.assume_init_ref().rename.unwrap()(arg1, arg2, flags)
+}
+
+unsafe extern "C" fn link(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    if is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    if is_hidden(arg2) {
+        return -(EEXIST as c_int);
+    }
+    NEXT.assume_init_ref().link.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn chmod(arg1: *const c_char, arg2: mode_t, fi: *mut fuse_file_info) -> c_int {
+    if fi.is_null() && is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().chmod.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn chown(
+    arg1: *const c_char,
+    arg2: uid_t,
+    arg3: gid_t,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    if fi.is_null() && is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().chown.unwrap()(arg1, arg2, arg3, fi)
+}
+
+unsafe extern "C" fn truncate(arg1: *const c_char, arg2: off_t, fi: *mut fuse_file_info) -> c_int {
+    if fi.is_null() && is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().truncate.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn utimens(
+    arg1: *const c_char,
+    tv: *const timespec,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    if fi.is_null() && is_hidden(arg1) {
+        return -(ENOENT as c_int);
+    }
+    NEXT.assume_init_ref().utimens.unwrap()(arg1, tv, fi)
+}
+
+struct Filler {
+    buf: *mut c_void,
+    filler: fuse_fill_dir_t,
+    dir: Vec<u8>,
+}
+
+unsafe extern "C" fn fill(
+    buf: *mut c_void,
+    name: *const c_char,
+    stbuf: *const stat,
+    off: off_t,
+    flags: fuse_fill_dir_flags,
+) -> c_int {
+    let filler = &*buf.cast::<Filler>();
+    if stbuf.is_null() || (*stbuf).st_mode & S_IFMT != S_IFLNK {
+        return filler.filler.unwrap()(filler.buf, name, stbuf, off, flags);
+    }
+    if POLICY == SymlinkPolicy::Hide {
+        return 0;
+    }
+    let mut path = filler.dir.clone();
+    if path != b"/" {
+        path.push(b'/');
+    }
+    path.extend_from_slice(CStr::from_ptr(name).to_bytes());
+    // keep the inode number the next layer reported, only the type changes
+    let mut st = *stbuf;
+    let mut target = MaybeUninit::<stat>::zeroed();
+    if follow(&CString::new(path).unwrap(), target.as_mut_ptr()) {
+        st.st_mode = st.st_mode & !S_IFMT | target.assume_init().st_mode & S_IFMT;
+    }
+    filler.filler.unwrap()(filler.buf, name, &st, off, flags)
+}
+
+unsafe extern "C" fn readdir(
+    arg1: *const c_char,
+    arg2: *mut c_void,
+    arg3: fuse_fill_dir_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+    arg6: fuse_readdir_flags,
+) -> c_int {
+    if arg1.is_null() {
+        return NEXT.assume_init_ref().readdir.unwrap()(arg1, arg2, arg3, arg4, arg5, arg6);
+    }
+    let mut filler = Filler {
+        buf: arg2,
+        filler: arg3,
+        dir: CStr::from_ptr(arg1).to_bytes().to_vec(),
+    };
+    NEXT.assume_init_ref().readdir.unwrap()(
+        arg1,
+        ptr::addr_of_mut!(filler).cast(),
+        Some(fill),
+        arg4,
+        arg5,
+        arg6,
+    )
+}
+
+/// Controls how symlinks found on the host are presented to the guest. `Present` shows them as
+/// they are, which is what the mount does without this layer. `Resolve` shows symlinks whose
+/// target stays within the share as the file or directory they point to, while those pointing
+/// outside of it, dangling ones and loops are still shown as symlinks. Absolute targets are
+/// considered within the share when they start with `root`, the host directory being shared,
+/// which may be null to only resolve relative targets. `Hide` makes symlinks disappear from
+/// lookups and listings and refuses to create new ones, for mounts where the guest must never
+/// be handed a symlink. Creating, opening or changing a file by the name of a hidden symlink is
+/// refused as well, as the host would follow it.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer and a valid C string or null root
+#[no_mangle]
+pub unsafe extern "C" fn new_symlink_layer(
+    next: *const fuse_operations,
+    policy: SymlinkPolicy,
+    root: *const c_char,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    POLICY = policy;
+    if !root.is_null() {
+        let root = CStr::from_ptr(root).to_bytes();
+        let len = root
+            .iter()
+            .rposition(|&c| c != b'/')
+            .map_or(0, |last| last + 1);
+        ROOT = Some(root[..len].to_vec());
+    }
+    if policy == SymlinkPolicy::Present || next.getattr.is_none() || next.readlink.is_none() {
+        return Box::into_raw(Box::new(next));
+    }
+    let mut ops = fuse_operations {
+        getattr: Some(getattr),
+        readlink: Some(readlink),
+        symlink: next.symlink.and(Some(symlink)),
+        readdir: next.readdir.and(Some(readdir)),
+        ..next
+    };
+    if policy == SymlinkPolicy::Hide {
+        ops = fuse_operations {
+            mknod: next.mknod.and(Some(mknod)),
+            mkdir: next.mkdir.and(Some(mkdir)),
+            create: next.create.and(Some(create)),
+            open: next.open.and(Some(open)),
+            rename: next.rename.and(Some(rename)),
+            link: next.link.and(Some(link)),
+            chmod: next.chmod.and(Some(chmod)),
+            chown: next.chown.and(Some(chown)),
+            truncate: next.truncate.and(Some(truncate)),
+            utimens: next.utimens.and(Some(utimens)),
+            ..ops
+        };
+    }
+    Box::into_raw(Box::new(ops))
+}
@@ -0,0 +1,109 @@
+use crate::fuse::{
+    fuse_config, fuse_conn_info, fuse_file_info, fuse_operations, stat, time_t, timespec,
+    UTIME_NOW, UTIME_OMIT,
+};
+use std::{
+    ffi::{c_char, c_int, c_uint, c_void},
+    mem::MaybeUninit,
+    ptr,
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut GRANULARITY_NS: i64 = 1;
+static mut MIN_TIME: time_t = time_t::MIN;
+static mut MAX_TIME: time_t = time_t::MAX;
+
+/// Rounds `ts` down to the granularity of the backing filesystem.
+unsafe fn truncate_time(ts: &mut timespec) {
+    ts.tv_nsec -= ts.tv_nsec % GRANULARITY_NS;
+}
+
+/// Maps a timestamp set by the guest to one the backing filesystem can store exactly, so reading
+/// it back gives the same value.
+unsafe fn map_time(mut ts: timespec) -> timespec {
+    if ts.tv_nsec == UTIME_NOW as _ || ts.tv_nsec == UTIME_OMIT as _ {
+        return ts;
+    }
+    if ts.tv_sec < MIN_TIME {
+        ts.tv_sec = MIN_TIME;
+        ts.tv_nsec = 0;
+    } else if ts.tv_sec > MAX_TIME {
+        ts.tv_sec = MAX_TIME;
+        ts.tv_nsec = 999_999_999;
+    }
+    truncate_time(&mut ts);
+    ts
+}
+
+unsafe extern "C" fn init(conn: *mut fuse_conn_info, cfg: *mut fuse_config) -> *mut c_void {
+    let res = match NEXT.assume_init_ref().init {
+        Some(init) => init(conn, cfg),
+        None => ptr::null_mut(),
+    };
+    // the kernel rounds the times it caches and sets on its own to this
+    (*conn).time_gran = GRANULARITY_NS as c_uint;
+    res
+}
+
+unsafe extern "C" fn getattr(
+    arg1: *const c_char,
+    arg2: *mut stat,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let res = NEXT.assume_init_ref().getattr.unwrap()(arg1, arg2, fi);
+    if res == 0 {
+        let st = &mut *arg2;
+        truncate_time(&mut st.st_atim);
+        truncate_time(&mut st.st_mtim);
+        truncate_time(&mut st.st_ctim);
+    }
+    res
+}
+
+unsafe extern "C" fn utimens(
+    arg1: *const c_char,
+    arg2: *const timespec,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    if arg2.is_null() {
+        return NEXT.assume_init_ref().utimens.unwrap()(arg1, arg2, fi);
+    }
+    let times = [map_time(*arg2), map_time(*arg2.add(1))];
+    NEXT.assume_init_ref().utimens.unwrap()(arg1, times.as_ptr(), fi)
+}
+
+/// Advertises a timestamp granularity of `granularity_ns` nanoseconds to the kernel instead of
+/// the default of 1ns, for backing filesystems with coarser timestamps like ext3 or HFS+
+/// (1s). Times reported to the guest are rounded down to it, and times set by the guest are
+/// rounded the same way and clamped to `min_time..=max_time` seconds, so tools comparing what
+/// they set with what they read back do not see files as modified.
+///
+/// The granularity must be between 1ns and 1s, the layer is not created otherwise and this
+/// returns null.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer
+#[no_mangle]
+pub unsafe extern "C" fn new_timegran_layer(
+    next: *const fuse_operations,
+    granularity_ns: c_uint,
+    min_time: time_t,
+    max_time: time_t,
+) -> *const fuse_operations {
+    if !(1..=1_000_000_000).contains(&granularity_ns) {
+        eprintln!("invalid timestamp granularity {granularity_ns}ns");
+        return ptr::null();
+    }
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    GRANULARITY_NS = granularity_ns as i64;
+    MIN_TIME = min_time;
+    MAX_TIME = max_time;
+    Box::into_raw(Box::new(fuse_operations {
+        getattr: next.getattr.and(Some(getattr)),
+        init: Some(init),
+        utimens: next.utimens.and(Some(utimens)),
+        ..next
+    }))
+}
@@ -0,0 +1,570 @@
+use crate::fuse::{
+    dev_t, flock, fuse_bufvec, fuse_config, fuse_conn_info, fuse_file_info, fuse_fill_dir_t,
+    fuse_operations, fuse_pollhandle, fuse_readdir_flags, gid_t, mode_t, off_t, stat, statvfs,
+    timespec, uid_t,
+};
+use std::{
+    ffi::{c_char, c_int, c_uint, c_void},
+    mem::MaybeUninit,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut IDLE_AFTER: Duration = Duration::ZERO;
+static mut SHRINK_PERCENT: usize = 0;
+static mut START: Option<Instant> = None;
+/// Milliseconds since `START` at which the last request finished.
+static LAST_ACTIVE: AtomicU64 = AtomicU64::new(0);
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+/// Whether requests were made since memory was last trimmed.
+static DIRTY: AtomicBool = AtomicBool::new(false);
+
+/// What the allocator holds, in bytes.
+pub struct HeapStats {
+    /// Memory handed out to live allocations.
+    pub allocated: usize,
+    /// Memory the allocator keeps mapped from the host.
+    pub resident: usize,
+}
+
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+const ALLOCATOR: &str = "glibc malloc";
+#[cfg(feature = "jemalloc")]
+const ALLOCATOR: &str = "jemalloc";
+#[cfg(feature = "mimalloc")]
+const ALLOCATOR: &str = "mimalloc";
+
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+pub fn heap_stats() -> HeapStats {
+    let info = unsafe { crate::fuse::mallinfo2() };
+    HeapStats {
+        allocated: info.uordblks + info.hblkhd,
+        resident: info.arena + info.hblkhd,
+    }
+}
+
+/// Hands the free memory of the allocator back to the host.
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+pub fn release_heap() {
+    unsafe { crate::fuse::malloc_trim(0) };
+}
+
+#[cfg(feature = "jemalloc")]
+unsafe fn jemalloc_stat(name: &std::ffi::CStr) -> usize {
+    let mut value = 0usize;
+    let mut len = std::mem::size_of::<usize>();
+    tikv_jemalloc_sys::mallctl(
+        name.as_ptr(),
+        (&mut value as *mut usize).cast(),
+        &mut len,
+        ptr::null_mut(),
+        0,
+    );
+    value
+}
+
+#[cfg(feature = "jemalloc")]
+pub fn heap_stats() -> HeapStats {
+    unsafe {
+        // the statistics are a snapshot taken when the epoch advances
+        let mut epoch = 1u64;
+        tikv_jemalloc_sys::mallctl(
+            c"epoch".as_ptr(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            (&mut epoch as *mut u64).cast(),
+            std::mem::size_of::<u64>(),
+        );
+        HeapStats {
+            allocated: jemalloc_stat(c"stats.allocated"),
+            resident: jemalloc_stat(c"stats.resident"),
+        }
+    }
+}
+
+/// Hands the free memory of the allocator back to the host.
+#[cfg(feature = "jemalloc")]
+pub fn release_heap() {
+    // 4096 is MALLCTL_ARENAS_ALL
+    unsafe {
+        tikv_jemalloc_sys::mallctl(
+            c"arena.4096.purge".as_ptr(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            0,
+        )
+    };
+}
+
+#[cfg(feature = "mimalloc")]
+pub fn heap_stats() -> HeapStats {
+    // elapsed, user and system time, current and peak rss, current and peak commit, page faults
+    let mut info = [0usize; 8];
+    let [elapsed, user, system, rss, peak_rss, commit, peak_commit, faults] = &mut info;
+    unsafe {
+        libmimalloc_sys::mi_process_info(
+            elapsed,
+            user,
+            system,
+            rss,
+            peak_rss,
+            commit,
+            peak_commit,
+            faults,
+        )
+    };
+    HeapStats {
+        allocated: info[5],
+        resident: info[3],
+    }
+}
+
+/// Hands the free memory of the allocator back to the host.
+#[cfg(feature = "mimalloc")]
+pub fn release_heap() {
+    unsafe { libmimalloc_sys::mi_collect(true) };
+}
+
+/// Drops `shrink_percent` of what the caches of all layers hold and hands the memory freed
+/// back to the host.
+pub fn trim(shrink_percent: usize) {
+    let before = heap_stats();
+    let dropped = crate::budget::shrink(shrink_percent);
+    release_heap();
+    let after = heap_stats();
+    eprintln!(
+        "trimmed memory: {dropped} cached bytes dropped, {} resident bytes before, {} after",
+        before.resident, after.resident
+    );
+}
+
+/// Marks a request as in flight for as long as it lives.
+struct Active;
+
+impl Active {
+    fn start() -> Active {
+        IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+        DIRTY.store(true, Ordering::Relaxed);
+        Active
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        let now = unsafe { START.unwrap().elapsed() };
+        LAST_ACTIVE.store(now.as_millis() as u64, Ordering::Relaxed);
+        IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Trims memory once per stretch of idleness, as caches do not grow without requests.
+unsafe fn watch() {
+    loop {
+        thread::sleep(IDLE_AFTER.min(Duration::from_secs(1)));
+        if IN_FLIGHT.load(Ordering::Relaxed) > 0 || !DIRTY.load(Ordering::Relaxed) {
+            continue;
+        }
+        let last = Duration::from_millis(LAST_ACTIVE.load(Ordering::Relaxed));
+        if START.unwrap().elapsed().saturating_sub(last) >= IDLE_AFTER {
+            DIRTY.store(false, Ordering::Relaxed);
+            trim(SHRINK_PERCENT);
+        }
+    }
+}
+
+unsafe extern "C" fn init(conn: *mut fuse_conn_info, cfg: *mut fuse_config) -> *mut c_void {
+    LAST_ACTIVE.store(
+        START.unwrap().elapsed().as_millis() as u64,
+        Ordering::Relaxed,
+    );
+    thread::spawn(|| unsafe { watch() });
+    match NEXT.assume_init_ref().init {
+        Some(init) => init(conn, cfg),
+        None => ptr::null_mut(),
+    }
+}
+
+unsafe extern "C" fn getattr(
+    arg1: *const c_char,
+    arg2: *mut stat,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().getattr.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn readlink(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().readlink.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn mknod(arg1: *const c_char, arg2: mode_t, arg3: dev_t) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().mknod.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().mkdir.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn unlink(arg1: *const c_char) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().unlink.unwrap()(arg1)
+}
+
+unsafe extern "C" fn rmdir(arg1: *const c_char) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().rmdir.unwrap()(arg1)
+}
+
+unsafe extern "C" fn symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().symlink.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().rename.unwrap()(arg1, arg2, flags)
+}
+
+unsafe extern "C" fn link(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().link.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn chmod(arg1: *const c_char, arg2: mode_t, fi: *mut fuse_file_info) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().chmod.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn chown(
+    arg1: *const c_char,
+    arg2: uid_t,
+    arg3: gid_t,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().chown.unwrap()(arg1, arg2, arg3, fi)
+}
+
+unsafe extern "C" fn truncate(arg1: *const c_char, arg2: off_t, fi: *mut fuse_file_info) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().truncate.unwrap()(arg1, arg2, fi)
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().open.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn read(
+    arg1: *const c_char,
+    arg2: *mut c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().read.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().write.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn statfs(arg1: *const c_char, arg2: *mut statvfs) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().statfs.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn flush(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().flush.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn release(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().release.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn fsync(arg1: *const c_char, arg2: c_int, arg3: *mut fuse_file_info) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().fsync.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn setxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *const c_char,
+    arg4: usize,
+    arg5: c_int,
+) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().setxattr.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn getxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *mut c_char,
+    arg4: usize,
+) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().getxattr.unwrap()(arg1, arg2, arg3, arg4)
+}
+
+unsafe extern "C" fn listxattr(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().listxattr.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn removexattr(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().removexattr.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn opendir(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().opendir.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn readdir(
+    arg1: *const c_char,
+    arg2: *mut c_void,
+    arg3: fuse_fill_dir_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+    arg6: fuse_readdir_flags,
+) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().readdir.unwrap()(arg1, arg2, arg3, arg4, arg5, arg6)
+}
+
+unsafe extern "C" fn releasedir(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().releasedir.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn fsyncdir(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: *mut fuse_file_info,
+) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().fsyncdir.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn access(arg1: *const c_char, arg2: c_int) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().access.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn lock(
+    arg1: *const c_char,
+    arg2: *mut fuse_file_info,
+    cmd: c_int,
+    arg3: *mut flock,
+) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().lock.unwrap()(arg1, arg2, cmd, arg3)
+}
+
+unsafe extern "C" fn utimens(
+    arg1: *const c_char,
+    tv: *const timespec,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().utimens.unwrap()(arg1, tv, fi)
+}
+
+unsafe extern "C" fn bmap(arg1: *const c_char, blocksize: usize, idx: *mut u64) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().bmap.unwrap()(arg1, blocksize, idx)
+}
+
+unsafe extern "C" fn ioctl(
+    arg1: *const c_char,
+    cmd: c_int,
+    arg: *mut c_void,
+    arg2: *mut fuse_file_info,
+    flags: c_uint,
+    data: *mut c_void,
+) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().ioctl.unwrap()(arg1, cmd, arg, arg2, flags, data)
+}
+
+unsafe extern "C" fn poll(
+    arg1: *const c_char,
+    arg2: *mut fuse_file_info,
+    ph: *mut fuse_pollhandle,
+    reventsp: *mut c_uint,
+) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().poll.unwrap()(arg1, arg2, ph, reventsp)
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().write_buf.unwrap()(arg1, buf, off, arg2)
+}
+
+unsafe extern "C" fn read_buf(
+    arg1: *const c_char,
+    bufp: *mut *mut fuse_bufvec,
+    size: usize,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().read_buf.unwrap()(arg1, bufp, size, off, arg2)
+}
+
+unsafe extern "C" fn flock(arg1: *const c_char, arg2: *mut fuse_file_info, op: c_int) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().flock.unwrap()(arg1, arg2, op)
+}
+
+unsafe extern "C" fn fallocate(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: off_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let _active = Active::start();
+    NEXT.assume_init_ref().fallocate.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn copy_file_range(
+    path_in: *const c_char,
+    fi_in: *mut fuse_file_info,
+    offset_in: off_t,
+    path_out: *const c_char,
+    fi_out: *mut fuse_file_info,
+    offset_out: off_t,
+    size: usize,
+    flags: c_int,
+) -> isize {
+    let _active = Active::start();
+    NEXT.assume_init_ref().copy_file_range.unwrap()(
+        path_in, fi_in, offset_in, path_out, fi_out, offset_out, size, flags,
+    )
+}
+
+unsafe extern "C" fn lseek(
+    arg1: *const c_char,
+    off: off_t,
+    whence: c_int,
+    arg2: *mut fuse_file_info,
+) -> off_t {
+    let _active = Active::start();
+    NEXT.assume_init_ref().lseek.unwrap()(arg1, off, whence, arg2)
+}
+
+/// Trims memory right away, see [`new_trim_layer`], whether or not the layer is in use.
+#[no_mangle]
+pub extern "C" fn trim_memory(shrink_percent: c_uint) {
+    trim(shrink_percent as usize);
+}
+
+/// Prints which allocator the process uses and what it holds.
+#[no_mangle]
+pub extern "C" fn heap_report() {
+    let stats = heap_stats();
+    eprintln!(
+        "{ALLOCATOR}: {} bytes allocated, {} bytes resident",
+        stats.allocated, stats.resident
+    );
+}
+
+/// Returns memory to the host between bursts, so long-lived daemons serving one pod each do
+/// not sit on their peak usage: once no request was made for `idle_secs`, the caches of all
+/// layers drop `shrink_percent` of what they hold, see [`crate::budget`], and the allocator
+/// hands its free memory back, with `malloc_trim` or the equivalent of the allocator picked by
+/// the `jemalloc` or `mimalloc` feature. This happens once per idle stretch, and
+/// [`trim_memory`] does it on demand. The allocator features only replace the allocator of the
+/// Rust layers, C code in the process keeps using malloc.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer
+#[no_mangle]
+pub unsafe extern "C" fn new_trim_layer(
+    next: *const fuse_operations,
+    idle_secs: c_uint,
+    shrink_percent: c_uint,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    IDLE_AFTER = Duration::from_secs(idle_secs.max(1) as u64);
+    SHRINK_PERCENT = shrink_percent as usize;
+    START = Some(Instant::now());
+    Box::into_raw(Box::new(fuse_operations {
+        init: Some(init),
+        getattr: next.getattr.and(Some(getattr)),
+        readlink: next.readlink.and(Some(readlink)),
+        mknod: next.mknod.and(Some(mknod)),
+        mkdir: next.mkdir.and(Some(mkdir)),
+        unlink: next.unlink.and(Some(unlink)),
+        rmdir: next.rmdir.and(Some(rmdir)),
+        symlink: next.symlink.and(Some(symlink)),
+        rename: next.rename.and(Some(rename)),
+        link: next.link.and(Some(link)),
+        chmod: next.chmod.and(Some(chmod)),
+        chown: next.chown.and(Some(chown)),
+        truncate: next.truncate.and(Some(truncate)),
+        open: next.open.and(Some(open)),
+        read: next.read.and(Some(read)),
+        write: next.write.and(Some(write)),
+        statfs: next.statfs.and(Some(statfs)),
+        flush: next.flush.and(Some(flush)),
+        release: next.release.and(Some(release)),
+        fsync: next.fsync.and(Some(fsync)),
+        setxattr: next.setxattr.and(Some(setxattr)),
+        getxattr: next.getxattr.and(Some(getxattr)),
+        listxattr: next.listxattr.and(Some(listxattr)),
+        removexattr: next.removexattr.and(Some(removexattr)),
+        opendir: next.opendir.and(Some(opendir)),
+        readdir: next.readdir.and(Some(readdir)),
+        releasedir: next.releasedir.and(Some(releasedir)),
+        fsyncdir: next.fsyncdir.and(Some(fsyncdir)),
+        access: next.access.and(Some(access)),
+        create: next.create.and(Some(create)),
+        lock: next.lock.and(Some(lock)),
+        utimens: next.utimens.and(Some(utimens)),
+        bmap: next.bmap.and(Some(bmap)),
+        ioctl: next.ioctl.and(Some(ioctl)),
+        poll: next.poll.and(Some(poll)),
+        write_buf: next.write_buf.and(Some(write_buf)),
+        read_buf: next.read_buf.and(Some(read_buf)),
+        flock: next.flock.and(Some(flock)),
+        fallocate: next.fallocate.and(Some(fallocate)),
+        copy_file_range: next.copy_file_range.and(Some(copy_file_range)),
+        lseek: next.lseek.and(Some(lseek)),
+        ..next
+    }))
+}
@@ -0,0 +1,231 @@
+use crate::fuse::{
+    fuse_buf, fuse_buf_copy, fuse_buf_size, fuse_bufvec, fuse_file_info, fuse_operations, off_t,
+    stat, EPERM, EXDEV, O_ACCMODE, O_APPEND, O_RDONLY, O_WRONLY,
+};
+use std::{
+    ffi::{c_char, c_int, CStr},
+    mem::MaybeUninit,
+    slice,
+};
+
+/// Number of leading bytes the file types are recognized by.
+const HEAD: usize = 8;
+
+/// Leading bytes of the file types that can be blocked.
+const TYPES: [(&str, &[&[u8]]); 6] = [
+    ("elf", &[b"\x7fELF"]),
+    ("script", &[b"#!"]),
+    ("pe", &[b"MZ"]),
+    (
+        "macho",
+        &[
+            b"\xfe\xed\xfa\xce",
+            b"\xfe\xed\xfa\xcf",
+            b"\xce\xfa\xed\xfe",
+            b"\xcf\xfa\xed\xfe",
+            b"\xca\xfe\xba\xbe",
+        ],
+    ),
+    ("wasm", &[b"\0asm"]),
+    ("java", &[b"\xca\xfe\xba\xbe"]),
+];
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut BLOCKED: Vec<&[u8]> = Vec::new();
+
+/// Reads up to `len` leading bytes of the file, through `fi` if it is open for reading and by
+/// opening `path` for reading otherwise.
+unsafe fn read_head(
+    path: *const c_char,
+    fi: *mut fuse_file_info,
+    len: usize,
+) -> Result<Vec<u8>, c_int> {
+    let next = NEXT.assume_init_ref();
+    let Some(read) = next.read else {
+        return Err(-(EPERM as c_int));
+    };
+    let mut head = vec![0u8; len];
+    if (*fi).flags & O_ACCMODE as c_int != O_WRONLY as c_int {
+        let res = read(path, head.as_mut_ptr().cast(), len, 0, fi);
+        if res < 0 {
+            return Err(res);
+        }
+        head.truncate(res as usize);
+        return Ok(head);
+    }
+    if path.is_null() {
+        return Err(-(EPERM as c_int));
+    }
+    let mut reader = MaybeUninit::<fuse_file_info>::zeroed().assume_init();
+    reader.flags = O_RDONLY as c_int;
+    if let Some(open) = next.open {
+        let res = open(path, &mut reader);
+        if res != 0 {
+            return Err(res);
+        }
+    }
+    let res = read(path, head.as_mut_ptr().cast(), len, 0, &mut reader);
+    if let Some(release) = next.release {
+        release(path, &mut reader);
+    }
+    if res < 0 {
+        return Err(res);
+    }
+    head.truncate(res as usize);
+    Ok(head)
+}
+
+/// The leading bytes the file has before a write at `off` through `fi`, with the gap up to the
+/// write zero filled, and where the write lands, or `None` if it lands past them. Appends land
+/// at the end of the file whatever the offset.
+unsafe fn head_before(
+    path: *const c_char,
+    fi: *mut fuse_file_info,
+    off: off_t,
+) -> Result<Option<(Vec<u8>, usize)>, c_int> {
+    let append = !fi.is_null() && (*fi).flags & O_APPEND as c_int != 0;
+    if fi.is_null() || (!append && off >= HEAD as off_t) {
+        return Ok(None);
+    }
+    let mut st = MaybeUninit::<stat>::zeroed();
+    let res = NEXT.assume_init_ref().getattr.unwrap()(path, st.as_mut_ptr(), fi);
+    if res != 0 {
+        return Err(res);
+    }
+    let size = st.assume_init().st_size.max(0) as usize;
+    let off = if append { size } else { off as usize };
+    if off >= HEAD {
+        return Ok(None);
+    }
+    let mut head = read_head(path, fi, size.min(HEAD))?;
+    if head.len() < off {
+        head.resize(off, 0);
+    }
+    Ok(Some((head, off)))
+}
+
+/// Refuses a write of `data` that would make the file start like a blocked type.
+unsafe fn check(
+    path: *const c_char,
+    fi: *mut fuse_file_info,
+    data: &[u8],
+    off: off_t,
+) -> Result<(), c_int> {
+    let Some((mut head, off)) = head_before(path, fi, off)? else {
+        return Ok(());
+    };
+    let written = data.len().min(HEAD - off);
+    if head.len() < off + written {
+        head.resize(off + written, 0);
+    }
+    head[off..off + written].copy_from_slice(&data[..written]);
+    if BLOCKED.iter().any(|&magic| head.starts_with(magic)) {
+        return Err(-(EPERM as c_int));
+    }
+    Ok(())
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let data = slice::from_raw_parts(arg2.cast::<u8>(), arg3);
+    if let Err(res) = check(arg1, arg5, data, arg4) {
+        return res;
+    }
+    NEXT.assume_init_ref().write.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    match head_before(arg1, arg2, off) {
+        Ok(Some(_)) => (),
+        Ok(None) => return NEXT.assume_init_ref().write_buf.unwrap()(arg1, buf, off, arg2),
+        Err(res) => return res,
+    }
+    // the data may sit in a pipe, which can only be read once, so it is copied into memory to
+    // look at and passed on from there
+    let mut data = vec![0u8; fuse_buf_size(buf)];
+    let mut mem = fuse_bufvec {
+        count: 1,
+        idx: 0,
+        off: 0,
+        buf: [fuse_buf {
+            size: data.len(),
+            flags: 0,
+            mem: data.as_mut_ptr().cast(),
+            fd: -1,
+            pos: 0,
+        }],
+    };
+    let copied = fuse_buf_copy(&mut mem, buf, 0);
+    if copied < 0 {
+        return copied as c_int;
+    }
+    data.truncate(copied as usize);
+    mem.buf[0].size = data.len();
+    if let Err(res) = check(arg1, arg2, &data, off) {
+        return res;
+    }
+    NEXT.assume_init_ref().write_buf.unwrap()(arg1, &mut mem, off, arg2)
+}
+
+unsafe extern "C" fn copy_file_range(
+    path_in: *const c_char,
+    fi_in: *mut fuse_file_info,
+    offset_in: off_t,
+    path_out: *const c_char,
+    fi_out: *mut fuse_file_info,
+    offset_out: off_t,
+    size: usize,
+    flags: c_int,
+) -> isize {
+    // the data is not seen on the way, so the caller has to fall back to reading and writing it
+    match head_before(path_out, fi_out, offset_out) {
+        Ok(Some(_)) => return -(EXDEV as isize),
+        Ok(None) => (),
+        Err(res) => return res as isize,
+    }
+    NEXT.assume_init_ref().copy_file_range.unwrap()(
+        path_in, fi_in, offset_in, path_out, fi_out, offset_out, size, flags,
+    )
+}
+
+/// Refuses with `EPERM` writes that would make a file start like one of the comma separated
+/// `types`, enforcing policies such as no binaries on a data-only volume. Known types are
+/// `elf`, `script` (`#!`), `pe`, `macho`, `wasm` and `java` class files. Types are recognized
+/// by their leading bytes, however many writes, appends or truncations it takes to put them
+/// there: writes landing on them are checked against what the file already holds, which is
+/// read back through the next layer, and refused too if that fails. Truncating only ever adds
+/// zeros, which no type ends with.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer and a valid C string
+#[no_mangle]
+pub unsafe extern "C" fn new_typeblock_layer(
+    next: *const fuse_operations,
+    types: *const c_char,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    for name in CStr::from_ptr(types).to_string_lossy().split(',') {
+        match TYPES.iter().find(|(known, _)| *known == name.trim()) {
+            Some((_, magics)) => BLOCKED.extend_from_slice(magics),
+            None => eprintln!("ignoring unknown file type {name:?}"),
+        }
+    }
+    Box::into_raw(Box::new(fuse_operations {
+        write: next.write.and(Some(write)),
+        write_buf: next.write_buf.and(Some(write_buf)),
+        copy_file_range: next.copy_file_range.and(Some(copy_file_range)),
+        ..next
+    }))
+}
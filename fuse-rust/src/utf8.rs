@@ -0,0 +1,639 @@
+use crate::fuse::{
+    dev_t, flock, fuse_bufvec, fuse_file_info, fuse_fill_dir_flags, fuse_fill_dir_t,
+    fuse_operations, fuse_pollhandle, fuse_readdir_flags, gid_t, mode_t, off_t, stat, statvfs,
+    timespec, uid_t, EINVAL,
+};
+use std::{
+    ffi::{c_char, c_int, c_uint, c_void, CStr, CString},
+    mem::MaybeUninit,
+    ptr, str,
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+
+/// What happens to file names that are not valid UTF-8.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FilenamePolicy {
+    /// Names are passed on as the bytes they are.
+    Raw,
+    /// Files, directories and links cannot be created or renamed to such names.
+    Reject,
+    /// Names are percent-encoded below this layer and decoded above it.
+    Escape,
+}
+
+/// Percent-encodes the bytes of `path` that are not part of valid UTF-8, and `%` itself so
+/// decoding gives back the original.
+fn escape(mut path: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(path.len());
+    while !path.is_empty() {
+        let (valid, invalid) = match str::from_utf8(path) {
+            Ok(valid) => (valid.len(), 0),
+            Err(err) => (
+                err.valid_up_to(),
+                err.error_len().unwrap_or(path.len() - err.valid_up_to()),
+            ),
+        };
+        for &c in &path[..valid] {
+            match c {
+                b'%' => escaped.extend_from_slice(b"%25"),
+                c => escaped.push(c),
+            }
+        }
+        for &c in &path[valid..valid + invalid] {
+            escaped.extend_from_slice(format!("%{c:02X}").as_bytes());
+        }
+        path = &path[valid + invalid..];
+    }
+    escaped
+}
+
+/// Decodes `name` if `escape` produced it from a name that needed escaping, which takes it to
+/// only decode `%25` and the escapes of bytes that are never valid UTF-8, `%80` to `%FF`, and
+/// escaping the result to give `name` back.
+fn decode(name: &[u8]) -> Option<Vec<u8>> {
+    if !name.contains(&b'%') {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(name.len());
+    let mut i = 0;
+    while i < name.len() {
+        if name[i] != b'%' {
+            decoded.push(name[i]);
+            i += 1;
+            continue;
+        }
+        let hex = name.get(i + 1..i + 3)?;
+        let c = u8::from_str_radix(str::from_utf8(hex).ok()?, 16).ok()?;
+        if c != b'%' && c < 0x80 {
+            return None;
+        }
+        decoded.push(c);
+        i += 3;
+    }
+    (escape(&decoded) == name).then_some(decoded)
+}
+
+/// The guest's name for the name `name` has below this layer. Names not produced by `escape`,
+/// like those made on the host, are shown as they are.
+fn unescape(name: &[u8]) -> Vec<u8> {
+    decode(name).unwrap_or_else(|| name.to_vec())
+}
+
+/// Decodes each component of the symlink target `target`.
+fn unescape_target(target: &[u8]) -> Vec<u8> {
+    let components: Vec<_> = target.split(|&c| c == b'/').map(unescape).collect();
+    components.join(&b'/')
+}
+
+fn needs_escape(name: &[u8]) -> bool {
+    name.contains(&b'%') || str::from_utf8(name).is_err()
+}
+
+unsafe fn exists(path: &[u8]) -> bool {
+    let Ok(path) = CString::new(path) else {
+        return false;
+    };
+    let mut st = MaybeUninit::<stat>::zeroed();
+    NEXT.assume_init_ref().getattr.unwrap()(path.as_ptr(), st.as_mut_ptr(), ptr::null_mut()) == 0
+}
+
+/// The path below this layer for the guest's `path`. Components that need escaping are
+/// escaped, unless only the component as it is exists below, which is how names shown
+/// unescaped are reached.
+unsafe fn resolve(path: &[u8]) -> Vec<u8> {
+    let mut resolved = Vec::with_capacity(path.len());
+    for (i, component) in path.split(|&c| c == b'/').enumerate() {
+        if i > 0 {
+            resolved.push(b'/');
+        }
+        if !needs_escape(component) {
+            resolved.extend_from_slice(component);
+            continue;
+        }
+        let start = resolved.len();
+        resolved.extend_from_slice(&escape(component));
+        if !exists(&resolved) && exists(&[&resolved[..start], component].concat()) {
+            resolved.truncate(start);
+            resolved.extend_from_slice(component);
+        }
+    }
+    resolved
+}
+
+/// A guest path as stored below this layer.
+struct Escaped(Option<CString>);
+
+impl Escaped {
+    fn ptr(&self) -> *const c_char {
+        self.0.as_ref().map_or(ptr::null(), |path| path.as_ptr())
+    }
+}
+
+unsafe fn escaped(path: *const c_char) -> Escaped {
+    if path.is_null() {
+        return Escaped(None);
+    }
+    // neither escaping nor a name read below adds a NUL, but should one turn up, the empty
+    // path fails the operation instead of the daemon
+    let path = resolve(CStr::from_ptr(path).to_bytes());
+    Escaped(Some(CString::new(path).unwrap_or_default()))
+}
+
+/// Whether the last component of `path` is valid UTF-8.
+unsafe fn valid_name(path: *const c_char) -> bool {
+    if path.is_null() {
+        return true;
+    }
+    let path = CStr::from_ptr(path).to_bytes();
+    let name = match path.iter().rposition(|&c| c == b'/') {
+        Some(slash) => &path[slash + 1..],
+        None => path,
+    };
+    str::from_utf8(name).is_ok()
+}
+
+unsafe extern "C" fn checked_mknod(arg1: *const c_char, arg2: mode_t, arg3: dev_t) -> c_int {
+    if !valid_name(arg1) {
+        return -(EINVAL as c_int);
+    }
+    NEXT.assume_init_ref().mknod.unwrap()(arg1, arg2, arg3)
+}
+
+unsafe extern "C" fn checked_mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    if !valid_name(arg1) {
+        return -(EINVAL as c_int);
+    }
+    NEXT.assume_init_ref().mkdir.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn checked_symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    if !valid_name(arg2) {
+        return -(EINVAL as c_int);
+    }
+    NEXT.assume_init_ref().symlink.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn checked_rename(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    flags: c_uint,
+) -> c_int {
+    if !valid_name(arg2) {
+        return -(EINVAL as c_int);
+    }
+    NEXT.assume_init_ref().rename.unwrap()(arg1, arg2, flags)
+}
+
+unsafe extern "C" fn checked_link(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    if !valid_name(arg2) {
+        return -(EINVAL as c_int);
+    }
+    NEXT.assume_init_ref().link.unwrap()(arg1, arg2)
+}
+
+unsafe extern "C" fn checked_create(
+    arg1: *const c_char,
+    arg2: mode_t,
+    arg3: *mut fuse_file_info,
+) -> c_int {
+    if !valid_name(arg1) {
+        return -(EINVAL as c_int);
+    }
+    NEXT.assume_init_ref().create.unwrap()(arg1, arg2, arg3)
+}
+
+struct Filler {
+    buf: *mut c_void,
+    filler: fuse_fill_dir_t,
+}
+
+unsafe extern "C" fn fill(
+    buf: *mut c_void,
+    name: *const c_char,
+    stbuf: *const stat,
+    off: off_t,
+    flags: fuse_fill_dir_flags,
+) -> c_int {
+    let filler = &*buf.cast::<Filler>();
+    match CString::new(unescape(CStr::from_ptr(name).to_bytes())) {
+        Ok(name) => filler.filler.unwrap()(filler.buf, name.as_ptr(), stbuf, off, flags),
+        Err(_) => filler.filler.unwrap()(filler.buf, name, stbuf, off, flags),
+    }
+}
+
+unsafe extern "C" fn readdir(
+    arg1: *const c_char,
+    arg2: *mut c_void,
+    arg3: fuse_fill_dir_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+    arg6: fuse_readdir_flags,
+) -> c_int {
+    let arg1 = escaped(arg1);
+    let mut filler = Filler {
+        buf: arg2,
+        filler: arg3,
+    };
+    NEXT.assume_init_ref().readdir.unwrap()(
+        arg1.ptr(),
+        (&mut filler as *mut Filler).cast(),
+        Some(fill),
+        arg4,
+        arg5,
+        arg6,
+    )
+}
+
+unsafe extern "C" fn readlink(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    let arg1 = escaped(arg1);
+    if arg3 == 0 {
+        return NEXT.assume_init_ref().readlink.unwrap()(arg1.ptr(), arg2, arg3);
+    }
+    // every byte of the target may have been escaped to three
+    let mut target = vec![0u8; arg3.saturating_mul(3)];
+    let res = NEXT.assume_init_ref().readlink.unwrap()(
+        arg1.ptr(),
+        target.as_mut_ptr().cast(),
+        target.len(),
+    );
+    if res != 0 {
+        return res;
+    }
+    let target = unescape_target(CStr::from_ptr(target.as_ptr().cast()).to_bytes());
+    let len = target.len().min(arg3 - 1);
+    ptr::copy_nonoverlapping(target.as_ptr(), arg2.cast(), len);
+    *arg2.add(len) = 0;
+    0
+}
+
+unsafe extern "C" fn symlink(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    // the target is not resolved, it may point anywhere
+    let arg1 = Escaped(Some(
+        CString::new(escape(CStr::from_ptr(arg1).to_bytes())).unwrap_or_default(),
+    ));
+    let arg2 = escaped(arg2);
+    NEXT.assume_init_ref().symlink.unwrap()(arg1.ptr(), arg2.ptr())
+}
+
+unsafe extern "C" fn getattr(
+    arg1: *const c_char,
+    arg2: *mut stat,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().getattr.unwrap()(arg1.ptr(), arg2, fi)
+}
+
+unsafe extern "C" fn mknod(arg1: *const c_char, arg2: mode_t, arg3: dev_t) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().mknod.unwrap()(arg1.ptr(), arg2, arg3)
+}
+
+unsafe extern "C" fn mkdir(arg1: *const c_char, arg2: mode_t) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().mkdir.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn unlink(arg1: *const c_char) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().unlink.unwrap()(arg1.ptr())
+}
+
+unsafe extern "C" fn rmdir(arg1: *const c_char) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().rmdir.unwrap()(arg1.ptr())
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    let arg1 = escaped(arg1);
+    let arg2 = escaped(arg2);
+    NEXT.assume_init_ref().rename.unwrap()(arg1.ptr(), arg2.ptr(), flags)
+}
+
+unsafe extern "C" fn link(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let arg1 = escaped(arg1);
+    let arg2 = escaped(arg2);
+    NEXT.assume_init_ref().link.unwrap()(arg1.ptr(), arg2.ptr())
+}
+
+unsafe extern "C" fn chmod(arg1: *const c_char, arg2: mode_t, fi: *mut fuse_file_info) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().chmod.unwrap()(arg1.ptr(), arg2, fi)
+}
+
+unsafe extern "C" fn chown(
+    arg1: *const c_char,
+    arg2: uid_t,
+    arg3: gid_t,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().chown.unwrap()(arg1.ptr(), arg2, arg3, fi)
+}
+
+unsafe extern "C" fn truncate(arg1: *const c_char, arg2: off_t, fi: *mut fuse_file_info) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().truncate.unwrap()(arg1.ptr(), arg2, fi)
+}
+
+unsafe extern "C" fn open(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().open.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn read(
+    arg1: *const c_char,
+    arg2: *mut c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().read.unwrap()(arg1.ptr(), arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn write(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: usize,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().write.unwrap()(arg1.ptr(), arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn statfs(arg1: *const c_char, arg2: *mut statvfs) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().statfs.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn flush(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().flush.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn release(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().release.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn fsync(arg1: *const c_char, arg2: c_int, arg3: *mut fuse_file_info) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().fsync.unwrap()(arg1.ptr(), arg2, arg3)
+}
+
+unsafe extern "C" fn setxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *const c_char,
+    arg4: usize,
+    arg5: c_int,
+) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().setxattr.unwrap()(arg1.ptr(), arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn getxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *mut c_char,
+    arg4: usize,
+) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().getxattr.unwrap()(arg1.ptr(), arg2, arg3, arg4)
+}
+
+unsafe extern "C" fn listxattr(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().listxattr.unwrap()(arg1.ptr(), arg2, arg3)
+}
+
+unsafe extern "C" fn removexattr(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().removexattr.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn opendir(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().opendir.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn releasedir(arg1: *const c_char, arg2: *mut fuse_file_info) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().releasedir.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn fsyncdir(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().fsyncdir.unwrap()(arg1.ptr(), arg2, arg3)
+}
+
+unsafe extern "C" fn access(arg1: *const c_char, arg2: c_int) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().access.unwrap()(arg1.ptr(), arg2)
+}
+
+unsafe extern "C" fn create(arg1: *const c_char, arg2: mode_t, arg3: *mut fuse_file_info) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().create.unwrap()(arg1.ptr(), arg2, arg3)
+}
+
+unsafe extern "C" fn lock(
+    arg1: *const c_char,
+    arg2: *mut fuse_file_info,
+    cmd: c_int,
+    arg3: *mut flock,
+) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().lock.unwrap()(arg1.ptr(), arg2, cmd, arg3)
+}
+
+unsafe extern "C" fn utimens(
+    arg1: *const c_char,
+    tv: *const timespec,
+    fi: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().utimens.unwrap()(arg1.ptr(), tv, fi)
+}
+
+unsafe extern "C" fn bmap(arg1: *const c_char, blocksize: usize, idx: *mut u64) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().bmap.unwrap()(arg1.ptr(), blocksize, idx)
+}
+
+unsafe extern "C" fn ioctl(
+    arg1: *const c_char,
+    cmd: c_int,
+    arg: *mut c_void,
+    arg2: *mut fuse_file_info,
+    flags: c_uint,
+    data: *mut c_void,
+) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().ioctl.unwrap()(arg1.ptr(), cmd, arg, arg2, flags, data)
+}
+
+unsafe extern "C" fn poll(
+    arg1: *const c_char,
+    arg2: *mut fuse_file_info,
+    ph: *mut fuse_pollhandle,
+    reventsp: *mut c_uint,
+) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().poll.unwrap()(arg1.ptr(), arg2, ph, reventsp)
+}
+
+unsafe extern "C" fn write_buf(
+    arg1: *const c_char,
+    buf: *mut fuse_bufvec,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().write_buf.unwrap()(arg1.ptr(), buf, off, arg2)
+}
+
+unsafe extern "C" fn read_buf(
+    arg1: *const c_char,
+    bufp: *mut *mut fuse_bufvec,
+    size: usize,
+    off: off_t,
+    arg2: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().read_buf.unwrap()(arg1.ptr(), bufp, size, off, arg2)
+}
+
+unsafe extern "C" fn flock(arg1: *const c_char, arg2: *mut fuse_file_info, op: c_int) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().flock.unwrap()(arg1.ptr(), arg2, op)
+}
+
+unsafe extern "C" fn fallocate(
+    arg1: *const c_char,
+    arg2: c_int,
+    arg3: off_t,
+    arg4: off_t,
+    arg5: *mut fuse_file_info,
+) -> c_int {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().fallocate.unwrap()(arg1.ptr(), arg2, arg3, arg4, arg5)
+}
+
+unsafe extern "C" fn copy_file_range(
+    path_in: *const c_char,
+    fi_in: *mut fuse_file_info,
+    offset_in: off_t,
+    path_out: *const c_char,
+    fi_out: *mut fuse_file_info,
+    offset_out: off_t,
+    size: usize,
+    flags: c_int,
+) -> isize {
+    let path_in = escaped(path_in);
+    let path_out = escaped(path_out);
+    NEXT.assume_init_ref().copy_file_range.unwrap()(
+        path_in.ptr(),
+        fi_in,
+        offset_in,
+        path_out.ptr(),
+        fi_out,
+        offset_out,
+        size,
+        flags,
+    )
+}
+
+unsafe extern "C" fn lseek(
+    arg1: *const c_char,
+    off: off_t,
+    whence: c_int,
+    arg2: *mut fuse_file_info,
+) -> off_t {
+    let arg1 = escaped(arg1);
+    NEXT.assume_init_ref().lseek.unwrap()(arg1.ptr(), off, whence, arg2)
+}
+
+/// Applies `policy` to file names that are not valid UTF-8, which the layers below pass around
+/// as raw bytes but audit logs, metrics and object store backends may not cope with. Escaping
+/// percent-encodes every such byte and every `%` in paths and symlink targets on the way down,
+/// and decodes the names listed in directories and the targets read from symlinks on the way
+/// up. Only names escaping produced are decoded, others, like a host name with a `%` or an
+/// invalid byte of its own, show up as they are and are reached by that name, unless the
+/// escaped form of the name exists too. Looking up a name that needs escaping costs a getattr
+/// per such component.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer
+#[no_mangle]
+pub unsafe extern "C" fn new_utf8_layer(
+    next: *const fuse_operations,
+    policy: FilenamePolicy,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    Box::into_raw(Box::new(match policy {
+        FilenamePolicy::Raw => next,
+        FilenamePolicy::Reject => fuse_operations {
+            mknod: next.mknod.and(Some(checked_mknod)),
+            mkdir: next.mkdir.and(Some(checked_mkdir)),
+            symlink: next.symlink.and(Some(checked_symlink)),
+            rename: next.rename.and(Some(checked_rename)),
+            link: next.link.and(Some(checked_link)),
+            create: next.create.and(Some(checked_create)),
+            ..next
+        },
+        FilenamePolicy::Escape => fuse_operations {
+            getattr: next.getattr.and(Some(getattr)),
+            mknod: next.mknod.and(Some(mknod)),
+            mkdir: next.mkdir.and(Some(mkdir)),
+            unlink: next.unlink.and(Some(unlink)),
+            rmdir: next.rmdir.and(Some(rmdir)),
+            rename: next.rename.and(Some(rename)),
+            link: next.link.and(Some(link)),
+            chmod: next.chmod.and(Some(chmod)),
+            chown: next.chown.and(Some(chown)),
+            truncate: next.truncate.and(Some(truncate)),
+            open: next.open.and(Some(open)),
+            read: next.read.and(Some(read)),
+            write: next.write.and(Some(write)),
+            statfs: next.statfs.and(Some(statfs)),
+            flush: next.flush.and(Some(flush)),
+            release: next.release.and(Some(release)),
+            fsync: next.fsync.and(Some(fsync)),
+            setxattr: next.setxattr.and(Some(setxattr)),
+            getxattr: next.getxattr.and(Some(getxattr)),
+            listxattr: next.listxattr.and(Some(listxattr)),
+            removexattr: next.removexattr.and(Some(removexattr)),
+            opendir: next.opendir.and(Some(opendir)),
+            releasedir: next.releasedir.and(Some(releasedir)),
+            fsyncdir: next.fsyncdir.and(Some(fsyncdir)),
+            access: next.access.and(Some(access)),
+            create: next.create.and(Some(create)),
+            lock: next.lock.and(Some(lock)),
+            utimens: next.utimens.and(Some(utimens)),
+            bmap: next.bmap.and(Some(bmap)),
+            ioctl: next.ioctl.and(Some(ioctl)),
+            poll: next.poll.and(Some(poll)),
+            write_buf: next.write_buf.and(Some(write_buf)),
+            read_buf: next.read_buf.and(Some(read_buf)),
+            flock: next.flock.and(Some(flock)),
+            fallocate: next.fallocate.and(Some(fallocate)),
+            copy_file_range: next.copy_file_range.and(Some(copy_file_range)),
+            lseek: next.lseek.and(Some(lseek)),
+            readdir: next.readdir.and(Some(readdir)),
+            readlink: next.readlink.and(Some(readlink)),
+            symlink: next.symlink.and(Some(symlink)),
+            ..next
+        },
+    }))
+}
@@ -0,0 +1,133 @@
+use crate::fuse::{fuse_operations, E2BIG, ENOSPC, ERANGE};
+use std::{
+    ffi::{c_char, c_int, c_uint, CStr},
+    mem::MaybeUninit,
+    ptr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut MAX_COUNT: usize = 0;
+static mut MAX_SIZE: usize = 0;
+/// Held while checking and setting an attribute, so concurrent sets cannot both squeeze in.
+static SETTING: Mutex<()> = Mutex::new(());
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+
+/// Names of the attributes of `path` with the sizes of their values, as the next layer reports
+/// them.
+unsafe fn attributes(path: *const c_char) -> Result<Vec<(Vec<u8>, usize)>, c_int> {
+    let next = NEXT.assume_init_ref();
+    let Some(listxattr) = next.listxattr else {
+        return Ok(Vec::new());
+    };
+    let names = loop {
+        let size = listxattr(path, ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(size);
+        }
+        let mut names = vec![0u8; size as usize];
+        match listxattr(path, names.as_mut_ptr().cast(), names.len()) {
+            // attributes were added since the size was asked for
+            res if res == -(ERANGE as c_int) => continue,
+            res if res < 0 => return Err(res),
+            res => names.truncate(res as usize),
+        }
+        break names;
+    };
+    Ok(names
+        .split_inclusive(|&c| c == 0)
+        .map(|name| {
+            let size = match next.getxattr {
+                Some(getxattr) => getxattr(path, name.as_ptr().cast(), ptr::null_mut(), 0).max(0),
+                None => 0,
+            };
+            (name[..name.len() - 1].to_vec(), size as usize)
+        })
+        .collect())
+}
+
+/// Fails with `ENOSPC` unless setting `name` to a value of `size` bytes keeps the attributes of
+/// `path` within the limits, or leaves them no further beyond than they already were.
+unsafe fn check(path: *const c_char, name: &[u8], size: usize) -> c_int {
+    let attrs = match attributes(path) {
+        Ok(attrs) => attrs,
+        Err(res) => return res,
+    };
+    let footprint = |(name, size): &(Vec<u8>, usize)| name.len() + size;
+    let before: usize = attrs.iter().map(footprint).sum();
+    let (count, total) = match attrs.iter().find(|(existing, _)| existing == name) {
+        Some(existing) => (
+            attrs.len(),
+            before - footprint(existing) + name.len() + size,
+        ),
+        None => (attrs.len() + 1, before + name.len() + size),
+    };
+    let grows = count > attrs.len() || total > before;
+    if grows && MAX_COUNT > 0 && count > MAX_COUNT {
+        return -(ENOSPC as c_int);
+    }
+    if grows && MAX_SIZE > 0 && total > MAX_SIZE {
+        return -(ENOSPC as c_int);
+    }
+    0
+}
+
+unsafe extern "C" fn setxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *const c_char,
+    arg4: usize,
+    arg5: c_int,
+) -> c_int {
+    let name = CStr::from_ptr(arg2).to_bytes();
+    // an attribute that could never fit is not a matter of how full the file is
+    if MAX_SIZE > 0 && name.len() + arg4 > MAX_SIZE {
+        REJECTED.fetch_add(1, Ordering::Relaxed);
+        return -(E2BIG as c_int);
+    }
+    let _setting = SETTING.lock().unwrap();
+    let res = check(arg1, name, arg4);
+    if res != 0 {
+        REJECTED.fetch_add(1, Ordering::Relaxed);
+        return res;
+    }
+    NEXT.assume_init_ref().setxattr.unwrap()(arg1, arg2, arg3, arg4, arg5)
+}
+
+/// Prints how many attributes were refused for going over the limits.
+#[no_mangle]
+pub extern "C" fn xattrlimit_report() {
+    eprintln!(
+        "{} extended attributes refused",
+        REJECTED.load(Ordering::Relaxed)
+    );
+}
+
+/// Limits the extended attributes each file may have to `max_count` attributes whose names and
+/// values take `max_size` bytes together, so a guest cannot bloat host inodes on filesystems
+/// where attributes are expensive to store. Attributes are counted from what the next layer
+/// lists, including those set on the host. Setting one that would go over a limit fails with
+/// `ENOSPC`, or `E2BIG` for one that alone exceeds `max_size`, while changes that do not grow a
+/// file already over its limits go through. A limit of 0 leaves it unlimited.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer
+#[no_mangle]
+pub unsafe extern "C" fn new_xattrlimit_layer(
+    next: *const fuse_operations,
+    max_count: c_uint,
+    max_size: usize,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    MAX_COUNT = max_count as usize;
+    MAX_SIZE = max_size;
+    Box::into_raw(Box::new(fuse_operations {
+        setxattr: next.setxattr.and(Some(setxattr)),
+        ..next
+    }))
+}
@@ -0,0 +1,459 @@
+use crate::{
+    budget::Consumer,
+    fuse::{
+        fuse_operations, stat, EEXIST, ENODATA, ENOSYS, ERANGE, RENAME_EXCHANGE, XATTR_CREATE,
+        XATTR_REPLACE,
+    },
+};
+use std::{
+    collections::BTreeMap,
+    ffi::{c_char, c_int, c_uint, CStr, OsStr},
+    fs,
+    io::{self, Read, Write},
+    mem::{self, MaybeUninit},
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+    ptr, slice,
+    sync::Mutex,
+};
+
+/// Names in this namespace are kept in the sidecar store and never reach the backing filesystem.
+const PREFIX: &[u8] = b"user.fsinterposer.";
+
+type Attrs = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Records appended to the log before it is compacted, per attribute it holds.
+const RECORDS_PER_ATTR: usize = 2;
+/// Records any log may grow to before it is compacted.
+const MIN_RECORDS: usize = 1024;
+
+static mut NEXT: MaybeUninit<fuse_operations> = MaybeUninit::uninit();
+static mut DB_PATH: Option<PathBuf> = None;
+static STORE: Mutex<BTreeMap<Vec<u8>, Attrs>> = Mutex::new(BTreeMap::new());
+/// The database the changes are appended to, only locked while `STORE` is.
+static LOG: Mutex<Option<Log>> = Mutex::new(None);
+/// The store holds the only copy of attributes kept in memory only, so it is counted against
+/// the budget without ever being evicted.
+static MEMORY: Consumer = Consumer::pinned(c"xattr store");
+const FILE: usize = mem::size_of::<(Vec<u8>, Attrs)>();
+const ATTR: usize = mem::size_of::<(Vec<u8>, Vec<u8>)>();
+
+fn footprint(file: &[u8], attrs: &Attrs) -> usize {
+    let values: usize = attrs
+        .iter()
+        .map(|(name, value)| ATTR + name.len() + value.len())
+        .sum();
+    FILE + file.len() + values
+}
+
+struct Log {
+    file: fs::File,
+    records: usize,
+}
+
+/// A change to the store, as kept in the database.
+enum Record<'a> {
+    Set(&'a [u8], &'a [u8], &'a [u8]),
+    Remove(&'a [u8], &'a [u8]),
+    /// Drops the attributes of a path and everything below it.
+    Drop(&'a [u8]),
+    /// Moves the attributes of a path and everything below it, swapping them if exchanged.
+    Rename(&'a [u8], &'a [u8], bool),
+}
+
+fn read_chunk(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let mut chunk = vec![0; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut chunk)?;
+    Ok(chunk)
+}
+
+fn write_chunk(writer: &mut impl Write, chunk: &[u8]) -> io::Result<()> {
+    writer.write_all(&(chunk.len() as u32).to_le_bytes())?;
+    writer.write_all(chunk)
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+impl Record<'_> {
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let chunks: &[&[u8]] = match *self {
+            Record::Set(file, name, value) => &[b"set", file, name, value],
+            Record::Remove(file, name) => &[b"remove", file, name],
+            Record::Drop(path) => &[b"drop", path],
+            Record::Rename(from, to, false) => &[b"rename", from, to],
+            Record::Rename(from, to, true) => &[b"exchange", from, to],
+        };
+        chunks
+            .iter()
+            .try_for_each(|chunk| write_chunk(writer, chunk))
+    }
+
+    fn apply(&self, store: &mut BTreeMap<Vec<u8>, Attrs>) {
+        match *self {
+            Record::Set(file, name, value) => {
+                if !store.contains_key(file) {
+                    MEMORY.charge(FILE + file.len());
+                }
+                let attrs = store.entry(file.to_vec()).or_default();
+                match attrs.insert(name.to_vec(), value.to_vec()) {
+                    Some(old) => MEMORY.release(old.len()),
+                    None => MEMORY.charge(ATTR + name.len()),
+                }
+                MEMORY.charge(value.len());
+            }
+            Record::Remove(file, name) => {
+                if let Some(attrs) = store.get_mut(file) {
+                    if let Some(old) = attrs.remove(name) {
+                        MEMORY.release(ATTR + name.len() + old.len());
+                    }
+                    if attrs.is_empty() {
+                        store.remove(file);
+                        MEMORY.release(FILE + file.len());
+                    }
+                }
+            }
+            Record::Drop(path) => {
+                take_subtree(store, path);
+            }
+            Record::Rename(from, to, exchange) => {
+                let moved = take_subtree(store, from);
+                let replaced = take_subtree(store, to);
+                for (suffix, attrs) in moved {
+                    insert(store, [to, &suffix].concat(), attrs);
+                }
+                if exchange {
+                    for (suffix, attrs) in replaced {
+                        insert(store, [from, &suffix].concat(), attrs);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Whether `key` is `path` itself or lies below it.
+fn is_under(key: &[u8], path: &[u8]) -> bool {
+    key.strip_prefix(path)
+        .is_some_and(|rest| rest.is_empty() || rest[0] == b'/')
+}
+
+fn insert(store: &mut BTreeMap<Vec<u8>, Attrs>, file: Vec<u8>, attrs: Attrs) {
+    MEMORY.charge(footprint(&file, &attrs));
+    store.insert(file, attrs);
+}
+
+fn take_subtree(store: &mut BTreeMap<Vec<u8>, Attrs>, path: &[u8]) -> Vec<(Vec<u8>, Attrs)> {
+    let keys: Vec<_> = store
+        .keys()
+        .filter(|key| is_under(key, path))
+        .cloned()
+        .collect();
+    keys.into_iter()
+        .map(|key| {
+            let attrs = store.remove(&key).unwrap();
+            MEMORY.release(footprint(&key, &attrs));
+            (key[path.len()..].to_vec(), attrs)
+        })
+        .collect()
+}
+
+/// Replays the records in the database at `path`. A record cut short by a crash ends it.
+fn load(path: &PathBuf) -> io::Result<BTreeMap<Vec<u8>, Attrs>> {
+    let mut store = BTreeMap::new();
+    let mut reader = match fs::File::open(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(store),
+        result => io::BufReader::new(result?),
+    };
+    let replay = |reader: &mut io::BufReader<fs::File>, store: &mut BTreeMap<_, _>| {
+        let kind = read_chunk(reader)?;
+        let mut chunks = Vec::new();
+        let count = match &kind[..] {
+            b"set" => 3,
+            b"remove" | b"rename" | b"exchange" => 2,
+            b"drop" => 1,
+            _ => return Err(invalid("unknown record")),
+        };
+        for _ in 0..count {
+            chunks.push(read_chunk(reader)?);
+        }
+        let record = match (&kind[..], &chunks[..]) {
+            (b"set", [file, name, value]) => Record::Set(file, name, value),
+            (b"remove", [file, name]) => Record::Remove(file, name),
+            (b"drop", [path]) => Record::Drop(path),
+            (b"rename", [from, to]) => Record::Rename(from, to, false),
+            (_, [from, to]) => Record::Rename(from, to, true),
+            _ => return Err(invalid("malformed record")),
+        };
+        record.apply(store);
+        Ok(())
+    };
+    loop {
+        match replay(&mut reader, &mut store) {
+            Ok(()) => (),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(store),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Rewrites the database with one record per attribute, going through a temporary file so a
+/// crash never leaves a truncated store behind, and opens it for appending.
+fn compact(path: &PathBuf, store: &BTreeMap<Vec<u8>, Attrs>) -> io::Result<Log> {
+    let tmp = path.with_extension("tmp");
+    let mut writer = io::BufWriter::new(fs::File::create(&tmp)?);
+    let mut records = 0;
+    for (file, attrs) in store {
+        for (name, value) in attrs {
+            Record::Set(file, name, value).write(&mut writer)?;
+            records += 1;
+        }
+    }
+    writer.into_inner()?.sync_all()?;
+    fs::rename(&tmp, path)?;
+    let file = fs::OpenOptions::new().append(true).open(path)?;
+    Ok(Log { file, records })
+}
+
+/// Applies `record` to the store and appends it to the database, compacting the database once
+/// it has grown well past what the store holds.
+unsafe fn commit(store: &mut BTreeMap<Vec<u8>, Attrs>, record: Record) {
+    record.apply(store);
+    let Some(path) = DB_PATH.as_ref() else {
+        return;
+    };
+    let mut log = LOG.lock().unwrap();
+    let result = (|| {
+        let live: usize = store.values().map(|attrs| attrs.len()).sum();
+        match log.as_mut() {
+            Some(log) if log.records < MIN_RECORDS.max(live * RECORDS_PER_ATTR) => {
+                let mut buf = Vec::new();
+                record.write(&mut buf)?;
+                log.file.write_all(&buf)?;
+                log.file.sync_data()?;
+                log.records += 1;
+                Ok(())
+            }
+            _ => compact(path, store).map(|compacted| *log = Some(compacted)),
+        }
+    })();
+    if let Err(err) = result {
+        eprintln!("failed to persist xattr store {}: {err}", path.display());
+        // start over from what the store holds with the next change
+        *log = None;
+    }
+}
+
+unsafe extern "C" fn unlink(arg1: *const c_char) -> c_int {
+    let res = NEXT.assume_init_ref().unlink.unwrap()(arg1);
+    if res == 0 {
+        let path = CStr::from_ptr(arg1).to_bytes();
+        let mut store = STORE.lock().unwrap();
+        if store.contains_key(path) {
+            commit(&mut store, Record::Drop(path));
+        }
+    }
+    res
+}
+
+unsafe extern "C" fn rmdir(arg1: *const c_char) -> c_int {
+    let res = NEXT.assume_init_ref().rmdir.unwrap()(arg1);
+    if res == 0 {
+        let path = CStr::from_ptr(arg1).to_bytes();
+        let mut store = STORE.lock().unwrap();
+        if store.contains_key(path) {
+            commit(&mut store, Record::Drop(path));
+        }
+    }
+    res
+}
+
+unsafe extern "C" fn rename(arg1: *const c_char, arg2: *const c_char, flags: c_uint) -> c_int {
+    let res = NEXT.assume_init_ref().rename.unwrap()(arg1, arg2, flags);
+    if res != 0 {
+        return res;
+    }
+    let from = CStr::from_ptr(arg1).to_bytes();
+    let to = CStr::from_ptr(arg2).to_bytes();
+    let mut store = STORE.lock().unwrap();
+    if store
+        .keys()
+        .any(|key| is_under(key, from) || is_under(key, to))
+    {
+        let exchange = flags & RENAME_EXCHANGE != 0;
+        commit(&mut store, Record::Rename(from, to, exchange));
+    }
+    res
+}
+
+/// Checks with the next layer that `path` exists, so the store never answers for files that
+/// are gone or never were.
+unsafe fn check_exists(path: *const c_char) -> Result<(), c_int> {
+    let Some(getattr) = NEXT.assume_init_ref().getattr else {
+        return Ok(());
+    };
+    let mut st = MaybeUninit::<stat>::zeroed();
+    match getattr(path, st.as_mut_ptr(), ptr::null_mut()) {
+        0 => Ok(()),
+        res => Err(res),
+    }
+}
+
+unsafe extern "C" fn setxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *const c_char,
+    arg4: usize,
+    arg5: c_int,
+) -> c_int {
+    let name = CStr::from_ptr(arg2).to_bytes();
+    if !name.starts_with(PREFIX) {
+        return match NEXT.assume_init_ref().setxattr {
+            Some(setxattr) => setxattr(arg1, arg2, arg3, arg4, arg5),
+            None => -(ENOSYS as c_int),
+        };
+    }
+    if let Err(res) = check_exists(arg1) {
+        return res;
+    }
+    let path = CStr::from_ptr(arg1).to_bytes();
+    let mut store = STORE.lock().unwrap();
+    let exists = store
+        .get(path)
+        .is_some_and(|attrs| attrs.contains_key(name));
+    if exists && arg5 & XATTR_CREATE as c_int != 0 {
+        return -(EEXIST as c_int);
+    }
+    if !exists && arg5 & XATTR_REPLACE as c_int != 0 {
+        return -(ENODATA as c_int);
+    }
+    let value = slice::from_raw_parts(arg3.cast::<u8>(), arg4);
+    commit(&mut store, Record::Set(path, name, value));
+    drop(store);
+    // the other caches make room for what the store grew by
+    crate::budget::enforce();
+    0
+}
+
+unsafe extern "C" fn getxattr(
+    arg1: *const c_char,
+    arg2: *const c_char,
+    arg3: *mut c_char,
+    arg4: usize,
+) -> c_int {
+    let name = CStr::from_ptr(arg2).to_bytes();
+    if !name.starts_with(PREFIX) {
+        return match NEXT.assume_init_ref().getxattr {
+            Some(getxattr) => getxattr(arg1, arg2, arg3, arg4),
+            None => -(ENOSYS as c_int),
+        };
+    }
+    if let Err(res) = check_exists(arg1) {
+        return res;
+    }
+    let store = STORE.lock().unwrap();
+    let Some(value) = store
+        .get(CStr::from_ptr(arg1).to_bytes())
+        .and_then(|attrs| attrs.get(name))
+    else {
+        return -(ENODATA as c_int);
+    };
+    if arg4 != 0 {
+        if arg4 < value.len() {
+            return -(ERANGE as c_int);
+        }
+        slice::from_raw_parts_mut(arg3.cast::<u8>(), value.len()).copy_from_slice(value);
+    }
+    value.len() as c_int
+}
+
+unsafe extern "C" fn listxattr(arg1: *const c_char, arg2: *mut c_char, arg3: usize) -> c_int {
+    let len = match NEXT.assume_init_ref().listxattr {
+        Some(listxattr) => match listxattr(arg1, arg2, arg3) {
+            // names from the sidecar store are still worth listing when the backing filesystem
+            // has no xattr support at all
+            res if res >= 0 => res as usize,
+            res if res == -(ERANGE as c_int) => return res,
+            _ => 0,
+        },
+        None => 0,
+    };
+    let store = STORE.lock().unwrap();
+    let names: Vec<u8> = store
+        .get(CStr::from_ptr(arg1).to_bytes())
+        .into_iter()
+        .flat_map(|attrs| attrs.keys())
+        .flat_map(|name| name.iter().copied().chain([0]))
+        .collect();
+    if arg3 != 0 {
+        if arg3 < len + names.len() {
+            return -(ERANGE as c_int);
+        }
+        slice::from_raw_parts_mut(arg2.cast::<u8>().add(len), names.len()).copy_from_slice(&names);
+    }
+    (len + names.len()) as c_int
+}
+
+unsafe extern "C" fn removexattr(arg1: *const c_char, arg2: *const c_char) -> c_int {
+    let name = CStr::from_ptr(arg2).to_bytes();
+    if !name.starts_with(PREFIX) {
+        return match NEXT.assume_init_ref().removexattr {
+            Some(removexattr) => removexattr(arg1, arg2),
+            None => -(ENOSYS as c_int),
+        };
+    }
+    let mut store = STORE.lock().unwrap();
+    let path = CStr::from_ptr(arg1).to_bytes();
+    if !store
+        .get(path)
+        .is_some_and(|attrs| attrs.contains_key(name))
+    {
+        return -(ENODATA as c_int);
+    }
+    commit(&mut store, Record::Remove(path, name));
+    0
+}
+
+/// Keeps xattrs in the `user.fsinterposer.` namespace in a sidecar store instead of on the backing
+/// files, so they work even on filesystems without xattr support. The store is loaded from
+/// `db_path`, where every change is appended as it is made and which is compacted from time to
+/// time, or kept in memory only if it is null. Returns null if the store cannot be loaded.
+///
+/// Attributes are tracked by path, so hard links do not share them.
+///
+/// # Safety
+///
+/// This function must be called with a non-null next pointer, and `db_path` must be null or a
+/// valid C string
+#[no_mangle]
+pub unsafe extern "C" fn new_xattrstore_layer(
+    next: *const fuse_operations,
+    db_path: *const c_char,
+) -> *const fuse_operations {
+    let next = unsafe { next.read() };
+    NEXT.write(next);
+    if !db_path.is_null() {
+        let path = PathBuf::from(OsStr::from_bytes(CStr::from_ptr(db_path).to_bytes()));
+        match load(&path) {
+            Ok(store) => *STORE.lock().unwrap() = store,
+            Err(err) => {
+                eprintln!("failed to load xattr store {}: {err}", path.display());
+                return ptr::null();
+            }
+        }
+        DB_PATH = Some(path);
+    }
+    Box::into_raw(Box::new(fuse_operations {
+        unlink: next.unlink.and(Some(unlink)),
+        rmdir: next.rmdir.and(Some(rmdir)),
+        rename: next.rename.and(Some(rename)),
+        setxattr: Some(setxattr),
+        getxattr: Some(getxattr),
+        listxattr: Some(listxattr),
+        removexattr: Some(removexattr),
+        ..next
+    }))
+}
@@ -1,2 +1,12 @@
 #define FUSE_USE_VERSION 31
 #include <fuse3/fuse.h>
+#include <errno.h>
+#include <sys/xattr.h>
+#include <linux/fs.h>
+#include <stdlib.h>
+#include <signal.h>
+#include <sys/mman.h>
+#include <malloc.h>
+#include <poll.h>
+#include <sys/syscall.h>
+#include <unistd.h>
@@ -6,22 +6,18 @@
 #include <fuse.h>
 #include <string.h>
 #include "passthrough/passthrough.h"
+#include "passthrough/options.h"
 
 // Basic passthrough file system
 int main(int argc, char *argv[])
 {
 	enum { MAX_ARGS = 10 };
-	int i,new_argc;
+	int res, new_argc;
 	char *new_argv[MAX_ARGS];
 
 	umask(0);
-			/* Process the "--plus" option apart */
-	for (i=0, new_argc=0; (i<argc) && (new_argc<MAX_ARGS); i++) {
-		if (!strcmp(argv[i], "--plus")) {
-			fill_dir_plus = FUSE_FILL_DIR_PLUS;
-		} else {
-			new_argv[new_argc++] = argv[i];
-		}
-	}
+	res = parse_options(argc, argv, &new_argc, new_argv, MAX_ARGS);
+	if (res != -1)
+		return res;
 	return fuse_main(new_argc, new_argv, &xmp_oper, NULL);
 }
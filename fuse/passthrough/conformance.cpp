@@ -0,0 +1,536 @@
+#define FUSE_USE_VERSION 31
+
+#define _GNU_SOURCE
+
+#include "conformance.h"
+#include <errno.h>
+#include <fcntl.h>
+#include <limits.h>
+#include <fuse.h>
+#include <stdio.h>
+#include <string.h>
+#include <unistd.h>
+#include <linux/fs.h>
+#include <sys/stat.h>
+
+#include <string>
+#include <vector>
+
+struct semantic {
+	const char *name;
+	/* Returns 0 if the semantic holds, 1 if it does not, or a negative
+	   errno if an operation it needs failed */
+	int (*run)(void);
+};
+
+static const struct fuse_operations *ops;
+static std::string scratch;
+
+static std::string at(const char *name)
+{
+	return scratch + "/" + name;
+}
+
+static int get(const char *name, struct stat *st)
+{
+	if (ops->getattr == NULL)
+		return -ENOSYS;
+	return ops->getattr(at(name).c_str(), st, NULL);
+}
+
+static int open_file(const char *name, int flags, struct fuse_file_info *fi)
+{
+	memset(fi, 0, sizeof(*fi));
+	fi->flags = flags;
+	if (ops->open == NULL)
+		return 0;
+	return ops->open(at(name).c_str(), fi);
+}
+
+static void release_file(const char *name, struct fuse_file_info *fi)
+{
+	if (ops->release)
+		ops->release(at(name).c_str(), fi);
+}
+
+static int make_file(const char *name, const char *data)
+{
+	std::string path = at(name);
+	struct fuse_file_info fi;
+	int res;
+
+	memset(&fi, 0, sizeof(fi));
+	fi.flags = O_WRONLY | O_CREAT | O_EXCL;
+	if (ops->create) {
+		res = ops->create(path.c_str(), 0644, &fi);
+	} else if (ops->mknod) {
+		res = ops->mknod(path.c_str(), S_IFREG | 0644, 0);
+		if (res == 0)
+			res = open_file(name, O_WRONLY, &fi);
+	} else {
+		return -ENOSYS;
+	}
+	if (res)
+		return res;
+
+	if (data) {
+		int len = strlen(data);
+		res = ops->write ? ops->write(path.c_str(), data, len, 0, &fi)
+				 : -ENOSYS;
+		if (res >= 0)
+			res = res == len ? 0 : -EIO;
+	}
+	release_file(name, &fi);
+	return res;
+}
+
+/* Returns the number of bytes read into buf */
+static int read_file(const char *name, char *buf, size_t size)
+{
+	struct fuse_file_info fi;
+	int res;
+
+	if (ops->read == NULL)
+		return -ENOSYS;
+	if ((res = open_file(name, O_RDONLY, &fi)))
+		return res;
+	res = ops->read(at(name).c_str(), buf, size, 0, &fi);
+	release_file(name, &fi);
+	return res;
+}
+
+static int make_dir(const char *name, mode_t mode)
+{
+	if (ops->mkdir == NULL)
+		return -ENOSYS;
+	return ops->mkdir(at(name).c_str(), mode);
+}
+
+static int rename_to(const char *from, const char *to, unsigned int flags)
+{
+	if (ops->rename == NULL)
+		return -ENOSYS;
+	return ops->rename(at(from).c_str(), at(to).c_str(), flags);
+}
+
+static int set_old_times(const char *name)
+{
+	const struct timespec ts[2] = { { 1, 0 }, { 1, 0 } };
+
+	if (ops->utimens == NULL)
+		return -ENOSYS;
+	return ops->utimens(at(name).c_str(), ts, NULL);
+}
+
+static void remove_file(const char *name)
+{
+	if (ops->unlink)
+		ops->unlink(at(name).c_str());
+}
+
+static void remove_dir(const char *name)
+{
+	if (ops->rmdir)
+		ops->rmdir(at(name).c_str());
+}
+
+static int check_mtime(void)
+{
+	struct fuse_file_info fi;
+	struct stat st;
+	int res;
+
+	if ((res = make_file("mtime", NULL)) == 0 &&
+	    (res = set_old_times("mtime")) == 0 &&
+	    (res = open_file("mtime", O_WRONLY, &fi)) == 0) {
+		res = ops->write ? ops->write(at("mtime").c_str(), "x", 1, 0, &fi)
+				 : -ENOSYS;
+		release_file("mtime", &fi);
+		if (res >= 0 && (res = get("mtime", &st)) == 0)
+			res = st.st_mtime > 1 ? 0 : 1;
+	}
+	remove_file("mtime");
+	return res;
+}
+
+static int check_atime(void)
+{
+	char buf[16];
+	struct stat st;
+	int res;
+
+	/* an atime not newer than the mtime gets updated under relatime too,
+	   so only noatime-like behaviour fails this */
+	if ((res = make_file("atime", "atime")) == 0 &&
+	    (res = set_old_times("atime")) == 0 &&
+	    (res = read_file("atime", buf, sizeof(buf))) >= 0 &&
+	    (res = get("atime", &st)) == 0)
+		res = st.st_atime > 1 ? 0 : 1;
+	remove_file("atime");
+	return res;
+}
+
+static int check_ctime(void)
+{
+	struct stat before, after;
+	int res;
+
+	if ((res = make_file("ctime", NULL)) == 0 &&
+	    (res = get("ctime", &before)) == 0) {
+		/* let coarse timestamp clocks tick over */
+		usleep(20000);
+		res = ops->chmod ? ops->chmod(at("ctime").c_str(), 0600, NULL)
+				 : -ENOSYS;
+		if (res == 0 && (res = get("ctime", &after)) == 0)
+			res = after.st_ctim.tv_sec > before.st_ctim.tv_sec ||
+			      (after.st_ctim.tv_sec == before.st_ctim.tv_sec &&
+			       after.st_ctim.tv_nsec > before.st_ctim.tv_nsec) ? 0 : 1;
+	}
+	remove_file("ctime");
+	return res;
+}
+
+static int check_sticky(void)
+{
+	struct stat st;
+	int res;
+
+	if ((res = make_dir("sticky", 0777)) == 0) {
+		res = ops->chmod ? ops->chmod(at("sticky").c_str(), 01777, NULL)
+				 : -ENOSYS;
+		if (res == 0 && (res = get("sticky", &st)) == 0)
+			res = st.st_mode & S_ISVTX ? 0 : 1;
+	}
+	remove_dir("sticky");
+	return res;
+}
+
+static int check_setgid(void)
+{
+	struct stat st;
+	int res;
+
+	if ((res = make_dir("setgid", 0777)) == 0) {
+		res = ops->chmod ? ops->chmod(at("setgid").c_str(), 02777, NULL)
+				 : -ENOSYS;
+		if (res == 0 && (res = make_dir("setgid/child", 0777)) == 0 &&
+		    (res = get("setgid/child", &st)) == 0)
+			res = st.st_mode & S_ISGID ? 0 : 1;
+	}
+	remove_dir("setgid/child");
+	remove_dir("setgid");
+	return res;
+}
+
+static int check_rename_nonempty_dir(void)
+{
+	int res;
+
+	if ((res = make_dir("rename-a", 0755)) == 0 &&
+	    (res = make_dir("rename-b", 0755)) == 0 &&
+	    (res = make_file("rename-b/file", NULL)) == 0) {
+		res = rename_to("rename-a", "rename-b", 0);
+		res = res == -ENOTEMPTY || res == -EEXIST ? 0 : res == 0 ? 1 : res;
+	}
+	remove_file("rename-b/file");
+	remove_dir("rename-a");
+	remove_dir("rename-b");
+	return res;
+}
+
+static int check_rename_empty_dir(void)
+{
+	int res;
+
+	if ((res = make_dir("rename-a", 0755)) == 0 &&
+	    (res = make_dir("rename-b", 0755)) == 0)
+		res = rename_to("rename-a", "rename-b", 0);
+	remove_dir("rename-a");
+	remove_dir("rename-b");
+	return res;
+}
+
+static int check_rename_file_over_dir(void)
+{
+	int res;
+
+	if ((res = make_file("rename-file", NULL)) == 0 &&
+	    (res = make_dir("rename-dir", 0755)) == 0) {
+		res = rename_to("rename-file", "rename-dir", 0);
+		res = res == -EISDIR ? 0 : res == 0 ? 1 : res;
+	}
+	remove_file("rename-file");
+	remove_dir("rename-dir");
+	return res;
+}
+
+static int check_rename_noreplace(void)
+{
+	int res;
+
+	if ((res = make_file("noreplace-a", NULL)) == 0 &&
+	    (res = make_file("noreplace-b", NULL)) == 0) {
+		res = rename_to("noreplace-a", "noreplace-b", RENAME_NOREPLACE);
+		res = res == -EEXIST ? 0 : res == 0 ? 1 : res;
+	}
+	remove_file("noreplace-a");
+	remove_file("noreplace-b");
+	return res;
+}
+
+static int check_rename_exchange(void)
+{
+	char buf[16];
+	int res;
+
+	if ((res = make_file("exchange-a", "a")) == 0 &&
+	    (res = make_file("exchange-b", "b")) == 0 &&
+	    (res = rename_to("exchange-a", "exchange-b", RENAME_EXCHANGE)) == 0 &&
+	    (res = read_file("exchange-a", buf, sizeof(buf))) >= 0)
+		res = res == 1 && buf[0] == 'b' ? 0 : 1;
+	remove_file("exchange-a");
+	remove_file("exchange-b");
+	return res;
+}
+
+static int check_rmdir_nonempty(void)
+{
+	int res;
+
+	if ((res = make_dir("rmdir", 0755)) == 0 &&
+	    (res = make_file("rmdir/file", NULL)) == 0) {
+		res = ops->rmdir ? ops->rmdir(at("rmdir").c_str()) : -ENOSYS;
+		res = res == -ENOTEMPTY || res == -EEXIST ? 0 : res == 0 ? 1 : res;
+	}
+	remove_file("rmdir/file");
+	remove_dir("rmdir");
+	return res;
+}
+
+static int check_hard_links(void)
+{
+	struct stat st;
+	int res;
+
+	if ((res = make_file("link-a", NULL)) == 0) {
+		res = ops->link ? ops->link(at("link-a").c_str(),
+					    at("link-b").c_str())
+				: -ENOSYS;
+		if (res == 0 && (res = get("link-a", &st)) == 0)
+			res = st.st_nlink == 2 ? 0 : 1;
+	}
+	remove_file("link-a");
+	remove_file("link-b");
+	return res;
+}
+
+static int check_unlinked_open(void)
+{
+	struct fuse_file_info fi;
+	char buf[16];
+	int res;
+
+	if ((res = make_file("unlinked", "data")) ||
+	    (res = open_file("unlinked", O_RDONLY, &fi)))
+		goto out;
+	res = ops->unlink ? ops->unlink(at("unlinked").c_str()) : -ENOSYS;
+	if (res == 0)
+		res = ops->read ? ops->read(at("unlinked").c_str(), buf,
+					    sizeof(buf), 0, &fi)
+				: -ENOSYS;
+	release_file("unlinked", &fi);
+	if (res >= 0)
+		res = res == 4 && !memcmp(buf, "data", 4) ? 0 : 1;
+out:
+	remove_file("unlinked");
+	return res;
+}
+
+static int check_truncate_extends(void)
+{
+	char buf[16];
+	int res;
+
+	if ((res = make_file("truncate", "x")) == 0) {
+		res = ops->truncate ? ops->truncate(at("truncate").c_str(), 4,
+						    NULL)
+				    : -ENOSYS;
+		if (res == 0 &&
+		    (res = read_file("truncate", buf, sizeof(buf))) >= 0)
+			res = res == 4 && !memcmp(buf, "x\0\0\0", 4) ? 0 : 1;
+	}
+	remove_file("truncate");
+	return res;
+}
+
+static int check_excl_create(void)
+{
+	int res;
+
+	if ((res = make_file("excl", NULL)) == 0) {
+		res = make_file("excl", NULL);
+		res = res == -EEXIST ? 0 : res == 0 ? 1 : res;
+	}
+	remove_file("excl");
+	return res;
+}
+
+static int check_symlinks(void)
+{
+	char buf[16];
+	int res;
+
+	if (ops->symlink == NULL || ops->readlink == NULL)
+		return -ENOSYS;
+	if ((res = ops->symlink("target", at("symlink").c_str())) == 0 &&
+	    (res = ops->readlink(at("symlink").c_str(), buf, sizeof(buf))) == 0)
+		res = strcmp(buf, "target") ? 1 : 0;
+	remove_file("symlink");
+	return res;
+}
+
+static int check_xattrs(void)
+{
+	char buf[16];
+	int res;
+
+	if (ops->setxattr == NULL || ops->getxattr == NULL)
+		return -ENOSYS;
+	if ((res = make_file("xattr", NULL)) == 0 &&
+	    (res = ops->setxattr(at("xattr").c_str(), "user.conformance", "1",
+				 1, 0)) == 0 &&
+	    (res = ops->getxattr(at("xattr").c_str(), "user.conformance", buf,
+				 sizeof(buf))) >= 0)
+		res = res == 1 && buf[0] == '1' ? 0 : 1;
+	remove_file("xattr");
+	return res;
+}
+
+static int check_long_names(void)
+{
+	std::string longest(NAME_MAX, 'n');
+	std::string too_long(NAME_MAX + 1, 'n');
+	int res;
+
+	if ((res = make_file(longest.c_str(), NULL)) == 0) {
+		res = make_file(too_long.c_str(), NULL);
+		res = res == -ENAMETOOLONG ? 0 : res == 0 ? 1 : res;
+	}
+	remove_file(longest.c_str());
+	remove_file(too_long.c_str());
+	return res;
+}
+
+static int check_deep_tree(void)
+{
+	std::string name(NAME_MAX, 'd');
+	std::vector<std::string> dirs;
+	std::string path = "deep";
+	int res;
+
+	/* nest directories up to a path of PATH_MAX - 1 bytes, the longest
+	   the host takes, then expect a longer one to be refused */
+	while ((res = make_dir(path.c_str(), 0755)) == 0) {
+		dirs.push_back(path);
+		size_t len = at(path.c_str()).size();
+		if (len + 2 >= PATH_MAX)
+			break;
+		path += "/" + name.substr(0, PATH_MAX - 2 - len);
+	}
+	if (res == 0) {
+		path += "/d";
+		res = make_dir(path.c_str(), 0755);
+		if (res == 0)
+			dirs.push_back(path);
+		res = res == -ENAMETOOLONG ? 0 : res == 0 ? 1 : res;
+	} else if (res == -ENAMETOOLONG) {
+		/* refused below PATH_MAX, as when layers below lengthen paths */
+		res = 1;
+	}
+	for (auto dir = dirs.rbegin(); dir != dirs.rend(); dir++)
+		remove_dir(dir->c_str());
+	return res;
+}
+
+static const struct semantic semantics[] = {
+	{ "mtime updated on write", check_mtime },
+	{ "atime updated on read", check_atime },
+	{ "ctime updated on chmod", check_ctime },
+	{ "sticky bit kept on directories", check_sticky },
+	{ "setgid inherited by subdirectories", check_setgid },
+	{ "rename over non-empty directory refused", check_rename_nonempty_dir },
+	{ "rename over empty directory", check_rename_empty_dir },
+	{ "rename of file over directory refused", check_rename_file_over_dir },
+	{ "RENAME_NOREPLACE", check_rename_noreplace },
+	{ "RENAME_EXCHANGE", check_rename_exchange },
+	{ "rmdir of non-empty directory refused", check_rmdir_nonempty },
+	{ "hard links counted", check_hard_links },
+	{ "unlinked files readable while open", check_unlinked_open },
+	{ "truncate extends with zeros", check_truncate_extends },
+	{ "O_EXCL refuses existing files", check_excl_create },
+	{ "symlinks", check_symlinks },
+	{ "user extended attributes", check_xattrs },
+	{ "NAME_MAX names, longer refused", check_long_names },
+	{ "PATH_MAX paths in deep trees, longer refused", check_deep_tree },
+};
+
+static void print_string(const char *s)
+{
+	putchar('"');
+	for (; *s; s++) {
+		if (*s == '"' || *s == '\\')
+			printf("\\%c", *s);
+		else if ((unsigned char) *s < 0x20)
+			printf("\\u%04x", *s);
+		else
+			putchar(*s);
+	}
+	putchar('"');
+}
+
+int conformance_report(const struct fuse_operations *ops, const char *dir)
+{
+	struct fuse_conn_info conn;
+	struct fuse_config cfg;
+	void *private_data = NULL;
+	int res;
+
+	::ops = ops;
+	/* set up what init would for a mount, such as the handler for
+	   broken leases, which would otherwise kill the process */
+	memset(&conn, 0, sizeof(conn));
+	memset(&cfg, 0, sizeof(cfg));
+	if (ops->init)
+		private_data = ops->init(&conn, &cfg);
+	scratch = std::string(dir) + "/.conformance-" + std::to_string(getpid());
+	res = ops->mkdir ? ops->mkdir(scratch.c_str(), 0777) : -ENOSYS;
+	if (res) {
+		fprintf(stderr, "cannot create scratch directory %s: %s\n",
+			scratch.c_str(), strerror(-res));
+		if (ops->destroy)
+			ops->destroy(private_data);
+		return 1;
+	}
+
+	printf("{\"dir\":");
+	print_string(dir);
+	printf(",\"semantics\":[");
+	for (size_t i = 0; i < sizeof(semantics) / sizeof(semantics[0]); i++) {
+		res = semantics[i].run();
+		printf("%s{\"name\":", i ? "," : "");
+		print_string(semantics[i].name);
+		printf(",\"satisfied\":%s", res == 0 ? "true" : "false");
+		if (res < 0) {
+			printf(",\"error\":");
+			print_string(strerror(-res));
+		}
+		printf("}");
+	}
+	printf("]}\n");
+
+	if (ops->rmdir == NULL || ops->rmdir(scratch.c_str()))
+		fprintf(stderr, "could not remove %s\n", scratch.c_str());
+	if (ops->destroy)
+		ops->destroy(private_data);
+	return 0;
+}
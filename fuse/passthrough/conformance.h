@@ -0,0 +1,27 @@
+#ifndef CONFORMANCE_H
+#define CONFORMANCE_H
+
+#define FUSE_USE_VERSION 31
+
+#include <fuse.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Run a POSIX semantics test suite through ops in a scratch directory
+   below dir, without mounting anything, and print a JSON report of which
+   semantics hold with the current configuration. Paths are handed to ops
+   as dir followed by the path within it, so dir is a host path for the
+   passthrough and a path within the file system for stacked layers.
+   Operations run without a FUSE session: init and destroy are called
+   around the suite, and fuse_get_context() returns NULL, so permission
+   checks that depend on the caller are not enforced.
+   Returns 0 once the report is printed, 1 if the suite could not run. */
+int conformance_report(const struct fuse_operations *ops, const char *dir);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif // CONFORMANCE_H
@@ -0,0 +1,293 @@
+#define FUSE_USE_VERSION 31
+
+#define _GNU_SOURCE
+
+#include "conformance.h"
+#include "options.h"
+#include "passthrough.h"
+#include "self_check.h"
+#include <ctype.h>
+#include <errno.h>
+#include <fuse.h>
+#include <limits.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+struct config_option {
+	const char *section;
+	const char *key;
+	const char *env;
+	const char *flag;
+	int *value;
+	/* Value stored when the option is enabled */
+	int enabled;
+	/* Takes a non-negative number instead of a boolean */
+	bool numeric;
+};
+
+static const struct config_option options[] = {
+	{ "passthrough", "plus", "FSINTERPOSER_PLUS", "--plus",
+	  &fill_dir_plus, FUSE_FILL_DIR_PLUS },
+	{ "passthrough", "drop_cache", "FSINTERPOSER_DROP_CACHE",
+	  "--drop-cache", &drop_cache_on_release, 1 },
+	{ "passthrough", "leases", "FSINTERPOSER_LEASES", "--leases",
+	  &use_leases, 1 },
+	{ "passthrough", "prealloc_mib", "FSINTERPOSER_PREALLOC_MIB",
+	  "--prealloc-mib", &prealloc_mib, 0, true },
+	{ "passthrough", "noatime", "FSINTERPOSER_NOATIME", "--noatime",
+	  &atime_policy, ATIME_NOATIME },
+	{ "passthrough", "relatime", "FSINTERPOSER_RELATIME", "--relatime",
+	  &atime_policy, ATIME_RELATIME },
+	{ "passthrough", "atime_flush_secs", "FSINTERPOSER_ATIME_FLUSH_SECS",
+	  "--atime-flush-secs", &atime_flush_secs, 0, true },
+	{ "passthrough", "enforce_permissions",
+	  "FSINTERPOSER_ENFORCE_PERMISSIONS", "--enforce-permissions",
+	  &enforce_permissions, 1 },
+};
+
+static int parse_bool(const char *s, int *value)
+{
+	if (!strcmp(s, "true") || !strcmp(s, "1")) {
+		*value = 1;
+		return 0;
+	}
+	if (!strcmp(s, "false") || !strcmp(s, "0")) {
+		*value = 0;
+		return 0;
+	}
+	return -1;
+}
+
+static int parse_number(const char *s, int *value)
+{
+	char *end;
+	long n;
+
+	errno = 0;
+	n = strtol(s, &end, 10);
+	if (end == s || *end != '\0' || errno || n < 0 || n > INT_MAX)
+		return -1;
+	*value = n;
+	return 0;
+}
+
+/* Stores the value of opt given as a string */
+static int set_option(const struct config_option *opt, const char *s)
+{
+	int value;
+
+	if (opt->numeric) {
+		if (parse_number(s, &value) == -1)
+			return -1;
+		*opt->value = value;
+		return 0;
+	}
+	if (parse_bool(s, &value) == -1)
+		return -1;
+	/* Options sharing a variable only clear their own value */
+	if (value)
+		*opt->value = opt->enabled;
+	else if (*opt->value == opt->enabled)
+		*opt->value = 0;
+	return 0;
+}
+
+static char *trim(char *s)
+{
+	char *end;
+
+	while (isspace((unsigned char) *s))
+		s++;
+	end = s + strlen(s);
+	while (end > s && isspace((unsigned char) end[-1]))
+		end--;
+	*end = '\0';
+	return s;
+}
+
+static bool known_section(const char *section)
+{
+	for (const struct config_option &o : options)
+		if (!strcmp(o.section, section))
+			return true;
+	return false;
+}
+
+/* Bare keys only, as dotted and quoted ones could name other sections */
+static bool bare_key(const char *key)
+{
+	if (*key == '\0')
+		return false;
+	for (; *key; key++)
+		if (!isalnum((unsigned char) *key) && *key != '_' && *key != '-')
+			return false;
+	return true;
+}
+
+/* Loads the subset of TOML needed here: [section] headers, comments and
+   bare key = boolean or key = integer pairs. Everything else TOML allows,
+   such as strings, arrays, tables of other layers or dotted keys, is
+   rejected with the reason rather than misread, and so are unknown
+   sections, keys and values, so typos don't go unnoticed. */
+static int load_config(const char *path)
+{
+	char buf[512], section[64] = "";
+	unsigned lineno = 0;
+	const char *error = "invalid line";
+	FILE *file = fopen(path, "r");
+
+	if (file == NULL) {
+		fprintf(stderr, "cannot open config %s: %s\n", path,
+			strerror(errno));
+		return -1;
+	}
+
+	while (fgets(buf, sizeof(buf), file) != NULL) {
+		char *line, *eq, *key, *value;
+		const struct config_option *opt = NULL;
+
+		lineno++;
+		if (strchr(buf, '\n') == NULL && !feof(file)) {
+			error = "line too long";
+			goto invalid;
+		}
+		if ((line = strchr(buf, '#')) != NULL)
+			*line = '\0';
+		line = trim(buf);
+		if (*line == '\0')
+			continue;
+
+		if (*line == '[') {
+			size_t len = strlen(line);
+			char *name;
+
+			if (line[1] == '[') {
+				error = "arrays of tables are not supported";
+				goto invalid;
+			}
+			if (line[len - 1] != ']' || len - 2 >= sizeof(section))
+				goto invalid;
+			memcpy(section, line + 1, len - 2);
+			section[len - 2] = '\0';
+			name = trim(section);
+			memmove(section, name, strlen(name) + 1);
+			if (!known_section(section)) {
+				fprintf(stderr, "%s:%u: unknown section [%s], "
+					"only [passthrough] is supported\n",
+					path, lineno, section);
+				fclose(file);
+				return -1;
+			}
+			continue;
+		}
+
+		if ((eq = strchr(line, '=')) == NULL)
+			goto invalid;
+		*eq = '\0';
+		key = trim(line);
+		value = trim(eq + 1);
+		if (!bare_key(key)) {
+			error = "only bare keys are supported";
+			goto invalid;
+		}
+		if (*value != '\0' && strchr("\"'[{", *value) != NULL) {
+			error = "only boolean and integer values are supported";
+			goto invalid;
+		}
+
+		for (const struct config_option &o : options)
+			if (!strcmp(o.section, section) && !strcmp(o.key, key))
+				opt = &o;
+		if (opt == NULL) {
+			fprintf(stderr, "%s:%u: unknown option %s.%s\n", path,
+				lineno, section, key);
+			fclose(file);
+			return -1;
+		}
+		if (set_option(opt, value) == -1) {
+			error = opt->numeric ? "expected a non-negative integer" :
+					       "expected true or false";
+			goto invalid;
+		}
+	}
+
+	fclose(file);
+	return 0;
+
+invalid:
+	fprintf(stderr, "%s:%u: %s\n", path, lineno, error);
+	fclose(file);
+	return -1;
+}
+
+static int load_env(void)
+{
+	for (const struct config_option &opt : options) {
+		const char *value = getenv(opt.env);
+
+		if (value == NULL)
+			continue;
+		if (set_option(&opt, value) == -1) {
+			fprintf(stderr, "invalid value for %s: %s\n", opt.env,
+				value);
+			return -1;
+		}
+	}
+	return 0;
+}
+
+int parse_options(int argc, char *argv[], int *new_argc, char *new_argv[],
+		  int max_args)
+{
+	const char *conformance_dir = NULL, *self_check_dir = NULL;
+	int i;
+
+	for (i = 1; i + 1 < argc; i++)
+		if (!strcmp(argv[i], "--config") && load_config(argv[i + 1]))
+			return 1;
+	if (load_env())
+		return 1;
+
+	for (i = 0, *new_argc = 0; (i < argc) && (*new_argc < max_args); i++) {
+		const struct config_option *opt = NULL, *negated = NULL;
+
+		/* "--no-<flag>" turns a boolean off again, such as one
+		   enabled in the configuration file */
+		for (const struct config_option &o : options) {
+			if (!strcmp(argv[i], o.flag))
+				opt = &o;
+			else if (!o.numeric && !strncmp(argv[i], "--no-", 5) &&
+				 !strcmp(argv[i] + 5, o.flag + 2))
+				negated = &o;
+		}
+
+		if (negated != NULL) {
+			set_option(negated, "false");
+		} else if (opt != NULL && opt->numeric) {
+			if (i + 1 >= argc || set_option(opt, argv[i + 1]) == -1) {
+				fprintf(stderr, "%s needs a non-negative number\n",
+					opt->flag);
+				return 1;
+			}
+			i++;
+		} else if (opt != NULL) {
+			*opt->value = opt->enabled;
+		} else if (!strcmp(argv[i], "--config") && i + 1 < argc) {
+			i++;
+		} else if (!strcmp(argv[i], "--self-check") && i + 1 < argc) {
+			/* run once every option has been applied */
+			self_check_dir = argv[++i];
+		} else if (!strcmp(argv[i], "--conformance-report") &&
+			   i + 1 < argc) {
+			conformance_dir = argv[++i];
+		} else {
+			new_argv[(*new_argc)++] = argv[i];
+		}
+	}
+	if (self_check_dir != NULL)
+		return self_check(self_check_dir);
+	if (conformance_dir != NULL)
+		return conformance_report(&xmp_oper, conformance_dir);
+	return -1;
+}
@@ -0,0 +1,28 @@
+#ifndef OPTIONS_H
+#define OPTIONS_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Process the interposer options apart from the FUSE ones, which are copied
+   to new_argv. Options are taken from, in increasing order of precedence,
+   the defaults, the file given with "--config", FSINTERPOSER_* environment
+   variables and the command line, where "--no-<flag>" turns a boolean
+   option off. Returns -1 if the file system should be mounted, or the
+   status to exit with otherwise.
+
+   The file is a small subset of TOML that only configures the passthrough
+   itself: a [passthrough] section of bare keys set to booleans or
+   non-negative integers, named like the flags with underscores. The layers
+   of the Rust library take their settings from whoever stacks them, so
+   sections for them, and any other TOML such as strings or arrays, are
+   rejected with an error rather than ignored. */
+int parse_options(int argc, char *argv[], int *new_argc, char *new_argv[],
+		  int max_args);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif // OPTIONS_H
@@ -46,20 +46,540 @@
 #include <sys/un.h>
 #endif
 #include <sys/time.h>
+#include <sys/file.h>
 #ifdef HAVE_SETXATTR
 #include <sys/xattr.h>
 #endif
+#ifdef HAVE_COPY_FILE_RANGE
+#include <sys/ioctl.h>
+#include <linux/fs.h>
+#endif
 
 #include "passthrough_helpers.h"
+#include "rmtree.h"
+
+#include <atomic>
+#include <map>
+#include <mutex>
+#include <signal.h>
+#include <string>
+#include <thread>
+#include <unordered_map>
+#include <vector>
 
 int fill_dir_plus = 0;
 
+/* Evict the pages of files that were only read once from the host page
+   cache when they are released, so large one-time reads such as image
+   extraction don't push out the working set of other workloads. */
+int drop_cache_on_release = 0;
+
+/* A file opened again within this many seconds is not a one-shot file */
+#define REOPEN_WINDOW 30
+
+struct open_state {
+	unsigned handles;
+	time_t last_open;
+	bool reused;
+};
+
+/* Keyed by device and inode, as inode numbers repeat across the file
+   systems mounted below the shared directory */
+static std::mutex open_states_lock;
+static std::map<std::pair<dev_t, ino_t>, open_state> open_states;
+
+static void track_open(int fd)
+{
+	struct stat st;
+	time_t now = time(NULL);
+
+	if (!drop_cache_on_release || fstat(fd, &st) == -1)
+		return;
+
+	std::lock_guard<std::mutex> guard(open_states_lock);
+	open_state &state = open_states[{ st.st_dev, st.st_ino }];
+	if (state.handles > 0 || now - state.last_open < REOPEN_WINDOW)
+		state.reused = true;
+	state.handles++;
+	state.last_open = now;
+}
+
+static void track_release(int fd)
+{
+	struct stat st;
+	time_t now = time(NULL);
+
+	if (!drop_cache_on_release || fstat(fd, &st) == -1)
+		return;
+
+	std::lock_guard<std::mutex> guard(open_states_lock);
+	auto it = open_states.find({ st.st_dev, st.st_ino });
+	if (it == open_states.end() || --it->second.handles > 0)
+		return;
+	if (!it->second.reused)
+		posix_fadvise(fd, 0, 0, POSIX_FADV_DONTNEED);
+
+	/* Forget files that have been closed for longer than the window */
+	for (auto state = open_states.begin(); state != open_states.end();) {
+		if (state->second.handles == 0 &&
+		    now - state->second.last_open >= REOPEN_WINDOW)
+			state = open_states.erase(state);
+		else
+			++state;
+	}
+}
+
+/* Let the guest cache file contents while this process holds a read lease
+   on the backing file. When another party (another interposer or a host
+   process) opens the file for writing, the kernel breaks the lease, the
+   cached pages are invalidated and the lease is given up so the writer can
+   proceed, similar to NFS delegations. Until every handle whose lease was
+   broken is released, new handles of the file neither take a lease nor
+   keep the cache. */
+int use_leases = 0;
+
+#define LEASE_SIGNAL (SIGRTMIN + 1)
+
+static int lease_pipe[2];
+
+struct lease {
+	std::string path;
+	std::pair<dev_t, ino_t> file;
+	bool broken;
+};
+
+/* By handle, until it is released, as the lease of a broken handle is
+   gone but reads through it still fill the guest's cache */
+static std::mutex leases_lock;
+static std::unordered_map<int, lease> leases;
+/* Files with such handles open, whose cached contents the writer may have
+   changed since, so no other handle may keep them */
+static std::map<std::pair<dev_t, ino_t>, unsigned> broken_leases;
+
+static void lease_broken(int sig, siginfo_t *info, void *ucontext)
+{
+	(void) sig;
+	(void) ucontext;
+	int saved_errno = errno;
+	if (write(lease_pipe[1], &info->si_fd, sizeof(info->si_fd)) == -1)
+		perror("lease pipe");
+	errno = saved_errno;
+}
+
+static void revoke_leases(struct fuse *fuse)
+{
+	int fd;
+
+	while (read(lease_pipe[0], &fd, sizeof(fd)) == sizeof(fd)) {
+		std::string path;
+		{
+			std::lock_guard<std::mutex> guard(leases_lock);
+			auto it = leases.find(fd);
+			if (it == leases.end() || it->second.broken)
+				continue;
+			/* the signal may be for a handle released since whose
+			   descriptor now belongs to another, whose lease is not
+			   being broken */
+			if (fcntl(fd, F_GETLEASE) == F_RDLCK)
+				continue;
+			fcntl(fd, F_SETLEASE, F_UNLCK);
+			it->second.broken = true;
+			broken_leases[it->second.file]++;
+			path = it->second.path;
+		}
+		if (fuse)
+			fuse_invalidate_path(fuse, path.c_str());
+	}
+}
+
+static void start_leases(void)
+{
+	struct fuse_context *ctx = fuse_get_context();
+	struct sigaction sa;
+
+	if (pipe(lease_pipe) == -1) {
+		perror("lease pipe");
+		use_leases = 0;
+		return;
+	}
+	memset(&sa, 0, sizeof(sa));
+	sa.sa_sigaction = lease_broken;
+	sa.sa_flags = SA_SIGINFO | SA_RESTART;
+	sigaction(LEASE_SIGNAL, &sa, NULL);
+	/* outside a session, as in the conformance report, there is no
+	   kernel cache to invalidate */
+	std::thread(revoke_leases, ctx ? ctx->fuse : NULL).detach();
+}
+
+/* Only read-only handles can hold a lease, and the kernel refuses it when
+   the file is open for writing elsewhere or we don't own the file. */
+static void try_lease(const char *path, struct fuse_file_info *fi)
+{
+	struct stat st;
+
+	if (!use_leases || (fi->flags & O_ACCMODE) != O_RDONLY ||
+	    fstat(fi->fh, &st) == -1)
+		return;
+
+	std::lock_guard<std::mutex> guard(leases_lock);
+	if (broken_leases.count({ st.st_dev, st.st_ino }))
+		return;
+	if (fcntl(fi->fh, F_SETSIG, LEASE_SIGNAL) == -1 ||
+	    fcntl(fi->fh, F_SETLEASE, F_RDLCK) == -1)
+		return;
+	leases[fi->fh] = { path, { st.st_dev, st.st_ino }, false };
+	fi->keep_cache = 1;
+}
+
+static void drop_lease(struct fuse_file_info *fi)
+{
+	if (!use_leases)
+		return;
+
+	std::lock_guard<std::mutex> guard(leases_lock);
+	auto it = leases.find(fi->fh);
+	if (it == leases.end())
+		return;
+	if (it->second.broken && --broken_leases[it->second.file] == 0)
+		broken_leases.erase(it->second.file);
+	leases.erase(it);
+}
+
+/* Reserve this many MiB past the end of files opened for writing, so
+   writes into the reservation cannot fail with ENOSPC and large files end
+   up less fragmented. Whatever is left unused is given back on release.
+   Files that cannot get a reservation are opened anyway, and only writes
+   that find no space fail. */
+int prealloc_mib = 0;
+
+static std::mutex reservations_lock;
+static std::unordered_map<int, off_t> reservations;
+
+static void reserve_space(const char *path, int fd, int flags)
+{
+	struct stat st;
+	off_t len = (off_t) prealloc_mib << 20;
+
+	if (!prealloc_mib || (flags & O_ACCMODE) == O_RDONLY ||
+	    fstat(fd, &st) == -1 || !S_ISREG(st.st_mode))
+		return;
+	/* Filesystems without fallocate just don't get a reservation */
+	if (fallocate(fd, FALLOC_FL_KEEP_SIZE, st.st_size, len) == -1) {
+		if (errno == ENOSPC)
+			fprintf(stderr, "no space to reserve for %s\n", path);
+		return;
+	}
+
+	std::lock_guard<std::mutex> guard(reservations_lock);
+	reservations[fd] = st.st_size + len;
+}
+
+static void release_space(int fd)
+{
+	struct stat st;
+	off_t end;
+
+	{
+		std::lock_guard<std::mutex> guard(reservations_lock);
+		auto it = reservations.find(fd);
+		if (it == reservations.end())
+			return;
+		end = it->second;
+		reservations.erase(it);
+	}
+	if (fstat(fd, &st) == 0 && st.st_size < end)
+		fallocate(fd, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE,
+			  st.st_size, end - st.st_size);
+}
+
+/* With strict access times, open files with O_NOATIME where the process
+   may and record reads instead, setting the access time of the last read
+   every this many seconds and on fsync and release, so read-heavy
+   workloads cause one metadata write per interval instead of one per
+   read. 0 leaves updates to the backing file system. */
+int atime_flush_secs = 0;
+
+struct batched_atime {
+	struct timespec atime;
+	bool dirty;
+};
+
+static std::mutex batched_atimes_lock;
+static std::unordered_map<int, batched_atime> batched_atimes;
+
+static void batch_atime(int fd)
+{
+	std::lock_guard<std::mutex> guard(batched_atimes_lock);
+	batched_atimes[fd] = { { 0, 0 }, false };
+}
+
+static void record_read(int fd)
+{
+	struct timespec now;
+
+	if (!atime_flush_secs)
+		return;
+	clock_gettime(CLOCK_REALTIME, &now);
+	std::lock_guard<std::mutex> guard(batched_atimes_lock);
+	auto it = batched_atimes.find(fd);
+	if (it != batched_atimes.end())
+		it->second = { now, true };
+}
+
+/* Called with batched_atimes_lock held, so the descriptor cannot be closed
+   and reused meanwhile */
+static void flush_atime(int fd, batched_atime &batched)
+{
+	if (!batched.dirty)
+		return;
+	const struct timespec times[2] = { batched.atime, { 0, UTIME_OMIT } };
+	futimens(fd, times);
+	batched.dirty = false;
+}
+
+static void flush_atimes(void)
+{
+	for (;;) {
+		sleep(atime_flush_secs);
+		std::lock_guard<std::mutex> guard(batched_atimes_lock);
+		for (auto &batched : batched_atimes)
+			flush_atime(batched.first, batched.second);
+	}
+}
+
+static void sync_atime(int fd, bool forget)
+{
+	std::lock_guard<std::mutex> guard(batched_atimes_lock);
+	auto it = batched_atimes.find(fd);
+	if (it == batched_atimes.end())
+		return;
+	flush_atime(fd, it->second);
+	if (forget)
+		batched_atimes.erase(it);
+}
+
+/* How reads update access times. ATIME_HOST leaves it to the backing file
+   system. Under ATIME_NOATIME files are opened with O_NOATIME, like they
+   are when the guest asks for it. ATIME_RELATIME puts the access time a
+   file had at open back on release where relatime would have left it
+   alone, which changes the ctime. Both need the process to own the file
+   or have CAP_FOWNER, on other files reads update access times as the
+   backing file system does. */
+int atime_policy = ATIME_HOST;
+
+struct saved_atime {
+	struct timespec atime;
+};
+
+static std::mutex saved_atimes_lock;
+static std::unordered_map<int, saved_atime> saved_atimes;
+
+static int open_atime(const char *path, int flags)
+{
+	bool noatime = (flags & O_NOATIME) || atime_policy == ATIME_NOATIME;
+	struct stat st;
+	int fd;
+
+	if (!noatime && atime_policy != ATIME_RELATIME) {
+		if (!atime_flush_secs)
+			return open(path, flags);
+		fd = open(path, flags | O_NOATIME);
+		if (fd != -1)
+			batch_atime(fd);
+		else if (errno == EPERM)
+			fd = open(path, flags);
+		return fd;
+	}
+	if (noatime) {
+		/* setting the access time back would need the same privilege
+		   as O_NOATIME, so there is nothing to fall back to */
+		fd = open(path, flags | O_NOATIME);
+		if (fd == -1 && errno == EPERM)
+			fd = open(path, flags & ~O_NOATIME);
+		return fd;
+	}
+	fd = open(path, flags);
+	if (fd != -1 && fstat(fd, &st) == 0) {
+		std::lock_guard<std::mutex> guard(saved_atimes_lock);
+		saved_atimes[fd] = { st.st_atim };
+	}
+	return fd;
+}
+
+static bool before(const struct timespec &a, const struct timespec &b)
+{
+	return a.tv_sec < b.tv_sec ||
+	       (a.tv_sec == b.tv_sec && a.tv_nsec <= b.tv_nsec);
+}
+
+static void restore_atime(int fd)
+{
+	struct saved_atime saved;
+	struct stat st;
+
+	{
+		std::lock_guard<std::mutex> guard(saved_atimes_lock);
+		auto it = saved_atimes.find(fd);
+		if (it == saved_atimes.end())
+			return;
+		saved = it->second;
+		saved_atimes.erase(it);
+	}
+	if (fstat(fd, &st) == -1 ||
+	    (st.st_atim.tv_sec == saved.atime.tv_sec &&
+	     st.st_atim.tv_nsec == saved.atime.tv_nsec))
+		return;
+	/* relatime updates access times not newer than the last change or
+	   older than a day */
+	if (before(saved.atime, st.st_mtim) || before(saved.atime, st.st_ctim) ||
+	    st.st_atim.tv_sec - saved.atime.tv_sec >= 24 * 60 * 60)
+		return;
+
+	const struct timespec times[2] = { saved.atime, { 0, UTIME_OMIT } };
+	futimens(fd, times);
+}
+
+/* Check the permissions of the caller on the server too, for mounts
+   without default_permissions where the guest kernel only checks what the
+   file system tells it to: write and search permission on the parent
+   directory for creating and removing entries, the sticky bit for
+   removals, the mode bits for access(), and ownership for chmod and
+   chown, whose outcome under the host credentials is different in a user
+   namespace. */
+int enforce_permissions = 0;
+
+/* Operations run outside a session, such as by the conformance report,
+   have no caller and act with the server's own credentials */
+static bool enforcing(void)
+{
+	return enforce_permissions && fuse_get_context() != NULL;
+}
+
+static bool caller_in_group(gid_t gid)
+{
+	int n;
+
+	if (fuse_get_context()->gid == gid)
+		return true;
+	if ((n = fuse_getgroups(0, NULL)) <= 0)
+		return false;
+	std::vector<gid_t> groups(n);
+	n = fuse_getgroups(n, groups.data());
+	for (int i = 0; i < n && i < (int) groups.size(); i++)
+		if (groups[i] == gid)
+			return true;
+	return false;
+}
+
+/* Whether the caller has the permissions in mask (R_OK, W_OK, X_OK) on a
+   file with the attributes in st */
+static bool caller_may(const struct stat *st, int mask)
+{
+	uid_t uid = fuse_get_context()->uid;
+	mode_t bits;
+
+	if (uid == 0)
+		/* root may execute only if anyone may */
+		return !(mask & X_OK) || S_ISDIR(st->st_mode) ||
+		       (st->st_mode & (S_IXUSR | S_IXGRP | S_IXOTH));
+	if (uid == st->st_uid)
+		bits = st->st_mode >> 6;
+	else if (caller_in_group(st->st_gid))
+		bits = st->st_mode >> 3;
+	else
+		bits = st->st_mode;
+	return (bits & mask & 7) == (mask & 7);
+}
+
+static std::string parent_of(const char *path)
+{
+	const char *slash = strrchr(path, '/');
+
+	if (slash == NULL)
+		return ".";
+	if (slash == path)
+		return "/";
+	return std::string(path, slash - path);
+}
+
+/* The caller may add or remove entries of the parent of path */
+static int check_parent(const char *path)
+{
+	struct stat st;
+
+	if (lstat(parent_of(path).c_str(), &st) == -1)
+		return -errno;
+	return caller_may(&st, W_OK | X_OK) ? 0 : -EACCES;
+}
+
+/* The caller may remove or replace path in its parent directory, which
+   in a sticky directory takes owning either of them */
+static int check_removal(const char *path)
+{
+	struct stat dir, st;
+	uid_t uid = fuse_get_context()->uid;
+	int res;
+
+	if ((res = check_parent(path)))
+		return res;
+	if (lstat(parent_of(path).c_str(), &dir) == -1)
+		return -errno;
+	if (!(dir.st_mode & S_ISVTX) || uid == 0 || uid == dir.st_uid)
+		return 0;
+	/* A missing entry is left to the operation to report */
+	if (lstat(path, &st) == -1)
+		return errno == ENOENT ? 0 : -errno;
+	return uid == st.st_uid ? 0 : -EPERM;
+}
+
+/* Like the kernel, only root and the owner may chmod, and the setgid bit
+   of a file whose group the caller is not in is dropped */
+static int check_chmod(const char *path, mode_t *mode)
+{
+	struct stat st;
+	uid_t uid = fuse_get_context()->uid;
+
+	if (lstat(path, &st) == -1)
+		return -errno;
+	if (uid == 0)
+		return 0;
+	if (uid != st.st_uid)
+		return -EPERM;
+	if (!S_ISDIR(st.st_mode) && !caller_in_group(st.st_gid))
+		*mode &= ~S_ISGID;
+	return 0;
+}
+
+/* Only root may give files away, and owners may only change the group to
+   one they are in */
+static int check_chown(const char *path, uid_t owner, gid_t group)
+{
+	struct stat st;
+	uid_t uid = fuse_get_context()->uid;
+
+	if (lstat(path, &st) == -1)
+		return -errno;
+	if (uid == 0)
+		return 0;
+	if (owner != (uid_t) -1 && owner != st.st_uid)
+		return -EPERM;
+	if (group != (gid_t) -1 && group != st.st_gid &&
+	    (uid != st.st_uid || !caller_in_group(group)))
+		return -EPERM;
+	return 0;
+}
+
 void *xmp_init(struct fuse_conn_info *conn,
 		      struct fuse_config *cfg)
 {
 	(void) conn;
 	cfg->use_ino = 1;
 
+	if (use_leases)
+		start_leases();
+	if (atime_flush_secs)
+		std::thread(flush_atimes).detach();
+
 	/* Pick up changes from lower filesystem right away. This is
 	   also necessary for better hardlink support. When the kernel
 	   calls the unlink() handler, it does not know the inode of
@@ -89,8 +609,15 @@ int xmp_getattr(const char *path, struct stat *stbuf,
 
 int xmp_access(const char *path, int mask)
 {
+	struct stat st;
 	int res;
 
+	if (enforcing()) {
+		if (lstat(path, &st) == -1)
+			return -errno;
+		return caller_may(&st, mask) ? 0 : -EACCES;
+	}
+
 	res = access(path, mask);
 	if (res == -1)
 		return -errno;
@@ -143,6 +670,9 @@ int xmp_mknod(const char *path, mode_t mode, dev_t rdev)
 {
 	int res;
 
+	if (enforcing() && (res = check_parent(path)))
+		return res;
+
 	res = mknod_wrapper(AT_FDCWD, path, NULL, mode, rdev);
 	if (res == -1)
 		return -errno;
@@ -154,6 +684,9 @@ int xmp_mkdir(const char *path, mode_t mode)
 {
 	int res;
 
+	if (enforcing() && (res = check_parent(path)))
+		return res;
+
 	res = mkdir(path, mode);
 	if (res == -1)
 		return -errno;
@@ -165,6 +698,9 @@ int xmp_unlink(const char *path)
 {
 	int res;
 
+	if (enforcing() && (res = check_removal(path)))
+		return res;
+
 	res = unlink(path);
 	if (res == -1)
 		return -errno;
@@ -176,6 +712,9 @@ int xmp_rmdir(const char *path)
 {
 	int res;
 
+	if (enforcing() && (res = check_removal(path)))
+		return res;
+
 	res = rmdir(path);
 	if (res == -1)
 		return -errno;
@@ -187,6 +726,9 @@ int xmp_symlink(const char *from, const char *to)
 {
 	int res;
 
+	if (enforcing() && (res = check_parent(to)))
+		return res;
+
 	res = symlink(from, to);
 	if (res == -1)
 		return -errno;
@@ -201,6 +743,10 @@ int xmp_rename(const char *from, const char *to, unsigned int flags)
 	if (flags)
 		return -EINVAL;
 
+	if (enforcing() &&
+	    ((res = check_removal(from)) || (res = check_removal(to))))
+		return res;
+
 	res = rename(from, to);
 	if (res == -1)
 		return -errno;
@@ -212,6 +758,9 @@ int xmp_link(const char *from, const char *to)
 {
 	int res;
 
+	if (enforcing() && (res = check_parent(to)))
+		return res;
+
 	res = link(from, to);
 	if (res == -1)
 		return -errno;
@@ -225,6 +774,9 @@ int xmp_chmod(const char *path, mode_t mode,
 	(void) fi;
 	int res;
 
+	if (enforcing() && (res = check_chmod(path, &mode)))
+		return res;
+
 	res = chmod(path, mode);
 	if (res == -1)
 		return -errno;
@@ -238,6 +790,9 @@ int xmp_chown(const char *path, uid_t uid, gid_t gid,
 	(void) fi;
 	int res;
 
+	if (enforcing() && (res = check_chown(path, uid, gid)))
+		return res;
+
 	res = lchown(path, uid, gid);
 	if (res == -1)
 		return -errno;
@@ -279,12 +834,19 @@ int xmp_utimens(const char *path, const struct timespec ts[2],
 int xmp_create(const char *path, mode_t mode,
 		      struct fuse_file_info *fi)
 {
+	struct stat st;
 	int res;
 
+	if (enforcing() && lstat(path, &st) == -1 &&
+	    (res = check_parent(path)))
+		return res;
+
 	res = open(path, fi->flags, mode);
 	if (res == -1)
 		return -errno;
 
+	reserve_space(path, res, fi->flags);
+	track_open(res);
 	fi->fh = res;
 	return 0;
 }
@@ -293,11 +855,14 @@ int xmp_open(const char *path, struct fuse_file_info *fi)
 {
 	int res;
 
-	res = open(path, fi->flags);
+	res = open_atime(path, fi->flags);
 	if (res == -1)
 		return -errno;
 
+	reserve_space(path, res, fi->flags);
+	track_open(res);
 	fi->fh = res;
+	try_lease(path, fi);
 	return 0;
 }
 
@@ -321,6 +886,8 @@ int xmp_read(const char *path, char *buf, size_t size, off_t offset,
 
 	if(fi == NULL)
 		close(fd);
+	else
+		record_read(fd);
 	return res;
 }
 
@@ -362,6 +929,11 @@ int xmp_statfs(const char *path, struct statvfs *stbuf)
 int xmp_release(const char *path, struct fuse_file_info *fi)
 {
 	(void) path;
+	track_release(fi->fh);
+	release_space(fi->fh);
+	restore_atime(fi->fh);
+	sync_atime(fi->fh, true);
+	drop_lease(fi);
 	close(fi->fh);
 	return 0;
 }
@@ -374,7 +946,8 @@ int xmp_fsync(const char *path, int isdatasync,
 
 	(void) path;
 	(void) isdatasync;
-	(void) fi;
+	if (fi != NULL)
+		sync_atime(fi->fh, false);
 	return 0;
 }
 
@@ -444,6 +1017,70 @@ int xmp_removexattr(const char *path, const char *name)
 #endif /* HAVE_SETXATTR */
 
 #ifdef HAVE_COPY_FILE_RANGE
+/* Copies the data extents of the source range one at a time and skips its
+   holes, so copying a sparse file doesn't fill them with zeros. This is
+   only done when the destination range lies past the end of the
+   destination file; skipping a hole would otherwise leave old data behind.
+   Returns 0 if that doesn't apply, so the caller copies everything. */
+static ssize_t copy_sparse(int fd_in, off_t offset_in, int fd_out,
+			   off_t offset_out, size_t len)
+{
+	struct stat st;
+	off_t pos = offset_in, end = offset_in + len;
+
+	if (len == 0 || fstat(fd_out, &st) == -1 || offset_out < st.st_size)
+		return 0;
+	/* Like copy_file_range, stop at the end of the source */
+	if (fstat(fd_in, &st) == -1 || offset_in >= st.st_size)
+		return 0;
+	if (end > st.st_size)
+		end = st.st_size;
+
+	while (pos < end) {
+		off_t data, hole, in, out;
+
+		data = lseek(fd_in, pos, SEEK_DATA);
+		if (data == -1 && errno != ENXIO) {
+			/* No SEEK_DATA support */
+			if (pos == offset_in)
+				return 0;
+			break;
+		}
+		/* ENXIO means only a hole is left */
+		if (data == -1 || data >= end) {
+			pos = end;
+			break;
+		}
+		hole = lseek(fd_in, data, SEEK_HOLE);
+		if (hole == -1)
+			break;
+		if (hole > end)
+			hole = end;
+
+		in = data;
+		out = offset_out + (data - offset_in);
+		while (in < hole) {
+			ssize_t res = copy_file_range(fd_in, &in, fd_out, &out,
+						      hole - in, 0);
+			if (res <= 0) {
+				if (pos == offset_in && in == data)
+					return res;
+				hole = in;
+				end = in;
+				break;
+			}
+		}
+		pos = hole;
+	}
+
+	/* A trailing hole doesn't extend the destination by itself */
+	end = offset_out + (pos - offset_in);
+	if (fstat(fd_out, &st) == 0 && st.st_size < end &&
+	    ftruncate(fd_out, end) == -1)
+		return -1;
+	return pos - offset_in;
+}
+
 ssize_t xmp_copy_file_range(const char *path_in,
 				   struct fuse_file_info *fi_in,
 				   off_t offset_in, const char *path_out,
@@ -471,8 +1108,30 @@ ssize_t xmp_copy_file_range(const char *path_in,
 		return -errno;
 	}
 
-	res = copy_file_range(fd_in, &offset_in, fd_out, &offset_out, len,
-			      flags);
+	res = -1;
+#ifdef FICLONERANGE
+	/* Try to share the extents first, which is instantaneous on
+	   reflink capable file systems such as XFS and Btrfs. This fails
+	   for unaligned ranges or unsupported file systems, in which case
+	   we fall back to a regular copy. */
+	if (len > 0 && flags == 0) {
+		struct file_clone_range range = {
+			.src_fd = fd_in,
+			.src_offset = (__u64) offset_in,
+			.src_length = len,
+			.dest_offset = (__u64) offset_out,
+		};
+		if (ioctl(fd_out, FICLONERANGE, &range) != -1)
+			res = len;
+	}
+#endif
+	if (res == -1 && flags == 0)
+		res = copy_sparse(fd_in, offset_in, fd_out, offset_out, len);
+	else if (res == -1)
+		res = 0;
+	if (res == 0)
+		res = copy_file_range(fd_in, &offset_in, fd_out, &offset_out,
+				      len, flags);
 	if (res == -1)
 		res = -errno;
 
@@ -507,6 +1166,182 @@ off_t xmp_lseek(const char *path, off_t off, int whence, struct fuse_file_info *
 	return res;
 }
 
+int xmp_flock(const char *path, struct fuse_file_info *fi, int op)
+{
+	int res;
+	(void) path;
+
+	/* BSD locks belong to the open file description, so closing the
+	   handle in release drops any lock still held through it */
+	res = flock(fi->fh, op);
+	if (res == -1)
+		return -errno;
+
+	return 0;
+}
+
+/* Removing a tree on the host in one request, see rmtree.h */
+
+#define RMTREE_DEFAULT_DEPTH 128
+
+/* Removals log their progress every this many entries */
+#define RMTREE_PROGRESS 10000
+
+/* Bumped by FSINTERPOSER_IOC_RMTREE_CANCEL, which stops every removal
+   that started before */
+static std::atomic<unsigned> rmtree_generation;
+
+struct rmtree_walk {
+	const char *root;
+	dev_t dev;
+	unsigned generation;
+	uint32_t max_depth;
+	uint64_t removed;
+};
+
+/* The caller may remove the entry name of the directory open as dirfd,
+   with the attributes in dir, which in a sticky directory takes owning
+   either of them */
+static int check_removal_at(const struct stat *dir, int dirfd,
+			    const char *name)
+{
+	struct stat st;
+	uid_t uid = fuse_get_context()->uid;
+
+	if (!(dir->st_mode & S_ISVTX) || uid == 0 || uid == dir->st_uid)
+		return 0;
+	if (fstatat(dirfd, name, &st, AT_SYMLINK_NOFOLLOW) == -1)
+		return -errno;
+	return uid == st.st_uid ? 0 : -EPERM;
+}
+
+/* Remove everything in the directory open as dirfd, which is closed.
+   Entries are removed while the directory is read, which only leaves
+   out entries that are gone anyway. */
+static int rmtree_at(struct rmtree_walk *walk, int dirfd, uint32_t depth)
+{
+	struct stat dir, st;
+	struct dirent *de;
+	DIR *dp;
+	int res = 0;
+
+	if (fstat(dirfd, &dir) == -1)
+		res = -errno;
+	else if (dir.st_dev != walk->dev)
+		res = -EXDEV;
+	else if (enforcing() && !caller_may(&dir, W_OK | X_OK))
+		res = -EACCES;
+	if (res || (dp = fdopendir(dirfd)) == NULL) {
+		res = res ? res : -errno;
+		close(dirfd);
+		return res;
+	}
+
+	for (;;) {
+		bool is_dir;
+		int fd;
+
+		errno = 0;
+		if ((de = readdir(dp)) == NULL) {
+			res = -errno;
+			break;
+		}
+		if (!strcmp(de->d_name, ".") || !strcmp(de->d_name, ".."))
+			continue;
+		if (fuse_interrupted() ||
+		    rmtree_generation.load() != walk->generation) {
+			res = -EINTR;
+			break;
+		}
+
+		is_dir = de->d_type == DT_DIR;
+		if (de->d_type == DT_UNKNOWN) {
+			if (fstatat(dirfd, de->d_name, &st,
+				    AT_SYMLINK_NOFOLLOW) == -1) {
+				res = -errno;
+				break;
+			}
+			is_dir = S_ISDIR(st.st_mode);
+		}
+		if (enforcing() &&
+		    (res = check_removal_at(&dir, dirfd, de->d_name)))
+			break;
+		if (is_dir) {
+			if (depth >= walk->max_depth) {
+				res = -ELOOP;
+				break;
+			}
+			fd = openat(dirfd, de->d_name,
+				    O_RDONLY | O_DIRECTORY | O_NOFOLLOW);
+			if (fd == -1) {
+				res = -errno;
+				break;
+			}
+			if ((res = rmtree_at(walk, fd, depth + 1)))
+				break;
+		}
+		if (unlinkat(dirfd, de->d_name, is_dir ? AT_REMOVEDIR : 0) == -1) {
+			res = -errno;
+			break;
+		}
+		if (++walk->removed % RMTREE_PROGRESS == 0)
+			fprintf(stderr, "rmtree %s: %llu entries removed\n",
+				walk->root, (unsigned long long) walk->removed);
+	}
+
+	closedir(dp);
+	return res;
+}
+
+/* Removing a tree through the guest takes a lookup, and an unlink or
+   rmdir, per entry. The ioctl does it on the host in one request, so
+   container cleanup is bound by the host file system instead. The guest
+   may keep cached entries of the removed files until their timeouts run
+   out. */
+int xmp_ioctl(const char *path, int cmd, void *arg,
+	      struct fuse_file_info *fi, unsigned int flags, void *data)
+{
+	struct fsinterposer_rmtree *req = (struct fsinterposer_rmtree *) data;
+	struct rmtree_walk walk;
+	struct stat st;
+	int fd;
+
+	(void) arg;
+	(void) fi;
+
+	switch ((unsigned int) cmd) {
+	case FSINTERPOSER_IOC_RMTREE_CANCEL:
+		rmtree_generation++;
+		return 0;
+	case FSINTERPOSER_IOC_RMTREE:
+		break;
+	default:
+		return -ENOTTY;
+	}
+	if (!(flags & FUSE_IOCTL_DIR))
+		return -ENOTDIR;
+
+	fd = open(path, O_RDONLY | O_DIRECTORY | O_NOFOLLOW);
+	if (fd == -1)
+		return -errno;
+	if (fstat(fd, &st) == -1) {
+		close(fd);
+		return -errno;
+	}
+	walk.root = path;
+	walk.dev = st.st_dev;
+	walk.generation = rmtree_generation.load();
+	walk.max_depth = req->max_depth ? req->max_depth : RMTREE_DEFAULT_DEPTH;
+	walk.removed = 0;
+
+	req->error = -rmtree_at(&walk, fd, 0);
+	req->removed = walk.removed;
+	fprintf(stderr, "rmtree %s: %llu entries removed, %s\n", path,
+		(unsigned long long) walk.removed,
+		req->error ? strerror(req->error) : "done");
+	return 0;
+}
+
 const struct fuse_operations xmp_oper = {
 	.getattr	= xmp_getattr,
 	.readlink	= xmp_readlink,
@@ -539,6 +1374,8 @@ const struct fuse_operations xmp_oper = {
 #ifdef HAVE_UTIMENSAT
 	.utimens	= xmp_utimens,
 #endif
+	.ioctl		= xmp_ioctl,
+	.flock		= xmp_flock,
 #ifdef HAVE_POSIX_FALLOCATE
 	.fallocate	= xmp_fallocate,
 #endif
@@ -11,6 +11,22 @@ extern "C" {
 
 extern int fill_dir_plus;
 
+extern int drop_cache_on_release;
+
+extern int use_leases;
+
+extern int prealloc_mib;
+
+#define ATIME_HOST 0
+#define ATIME_NOATIME 1
+#define ATIME_RELATIME 2
+
+extern int atime_policy;
+
+extern int atime_flush_secs;
+
+extern int enforce_permissions;
+
 void *xmp_init(struct fuse_conn_info *conn,
 		        struct fuse_config *cfg);
 
@@ -98,6 +114,11 @@ ssize_t xmp_copy_file_range(const char *path_in,
 
 off_t xmp_lseek(const char *path, off_t off, int whence, struct fuse_file_info *fi);
 
+int xmp_flock(const char *path, struct fuse_file_info *fi, int op);
+
+int xmp_ioctl(const char *path, int cmd, void *arg,
+              struct fuse_file_info *fi, unsigned int flags, void *data);
+
 extern const struct fuse_operations xmp_oper;
 
 #ifdef __cplusplus
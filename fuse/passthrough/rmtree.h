@@ -0,0 +1,33 @@
+#ifndef RMTREE_H
+#define RMTREE_H
+
+/* ioctls for removing directory trees on the host in one request instead
+   of one unlink or rmdir per entry. Guest tools include this header on
+   its own, it does not depend on libfuse. */
+
+#include <stdint.h>
+#include <sys/ioctl.h>
+
+struct fsinterposer_rmtree {
+	/* in: how many directory levels below the one the ioctl is made on
+	   may be descended into, 0 for the default of 128 */
+	uint32_t max_depth;
+	/* out: 0 if everything below the directory was removed, otherwise
+	   the errno of the entry the removal stopped at, EINTR if it was
+	   cancelled, ELOOP if the tree is deeper than max_depth and EXDEV
+	   at a directory on another file system */
+	int32_t error;
+	/* out: entries removed */
+	uint64_t removed;
+};
+
+/* Remove everything below the directory the ioctl is made on, leaving
+   the directory itself to rmdir. The removal stops at a directory on
+   another file system, such as a mount point, without descending into
+   it. */
+#define FSINTERPOSER_IOC_RMTREE _IOWR('f', 1, struct fsinterposer_rmtree)
+
+/* Cancel every removal in progress, which stop at the next entry */
+#define FSINTERPOSER_IOC_RMTREE_CANCEL _IO('f', 2)
+
+#endif // RMTREE_H
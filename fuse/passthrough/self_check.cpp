@@ -0,0 +1,249 @@
+#define _GNU_SOURCE
+
+#include "passthrough.h"
+#include "self_check.h"
+#include <errno.h>
+#include <fcntl.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+#include <limits.h>
+#include <sys/stat.h>
+#include <sys/statvfs.h>
+#include <sys/xattr.h>
+
+struct check {
+	const char *name;
+	/* Needed by an operation compiled into the passthrough */
+	bool required;
+	/* Hint printed when the check fails */
+	const char *advice;
+	int (*run)(int dirfd);
+};
+
+static int create_file(int dirfd, const char *name)
+{
+	int fd = openat(dirfd, name, O_CREAT | O_EXCL | O_RDWR | O_CLOEXEC, 0600);
+	if (fd == -1)
+		return -1;
+	if (write(fd, "self-check", 10) != 10) {
+		close(fd);
+		return -1;
+	}
+	return fd;
+}
+
+static int check_tmpfile(int dirfd)
+{
+	int fd = openat(dirfd, ".", O_TMPFILE | O_RDWR | O_CLOEXEC, 0600);
+	if (fd == -1)
+		return -1;
+	close(fd);
+	return 0;
+}
+
+static int check_xattr(int dirfd)
+{
+	char buf[16];
+	int res = -1;
+	int fd = create_file(dirfd, "xattr");
+	if (fd == -1)
+		return -1;
+	if (fsetxattr(fd, "user.self-check", "1", 1, 0) == 0 &&
+	    fgetxattr(fd, "user.self-check", buf, sizeof(buf)) == 1)
+		res = 0;
+	close(fd);
+	unlinkat(dirfd, "xattr", 0);
+	return res;
+}
+
+static int check_file_handles(int dirfd)
+{
+	struct {
+		struct file_handle fh;
+		unsigned char data[MAX_HANDLE_SZ];
+	} handle;
+	int mount_id;
+
+	handle.fh.handle_bytes = MAX_HANDLE_SZ;
+	return name_to_handle_at(dirfd, "", &handle.fh, &mount_id,
+				 AT_EMPTY_PATH);
+}
+
+static int check_renameat2(int dirfd)
+{
+	int fd, res;
+
+	fd = create_file(dirfd, "rename-a");
+	if (fd == -1)
+		return -1;
+	close(fd);
+	fd = create_file(dirfd, "rename-b");
+	if (fd == -1) {
+		unlinkat(dirfd, "rename-a", 0);
+		return -1;
+	}
+	close(fd);
+
+	res = renameat2(dirfd, "rename-a", dirfd, "rename-b", RENAME_EXCHANGE);
+	if (res == 0 &&
+	    renameat2(dirfd, "rename-a", dirfd, "rename-b",
+		      RENAME_NOREPLACE) != -1)
+		res = -1;
+
+	unlinkat(dirfd, "rename-a", 0);
+	unlinkat(dirfd, "rename-b", 0);
+	return res;
+}
+
+static int check_fallocate(int dirfd)
+{
+	int res = -1;
+	int fd = create_file(dirfd, "fallocate");
+	if (fd == -1)
+		return -1;
+	if (posix_fallocate(fd, 0, 4096) == 0 &&
+	    fallocate(fd, FALLOC_FL_KEEP_SIZE, 4096, 4096) == 0 &&
+	    fallocate(fd, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, 0,
+		      4096) == 0)
+		res = 0;
+	close(fd);
+	unlinkat(dirfd, "fallocate", 0);
+	return res;
+}
+
+static int check_copy_file_range(int dirfd)
+{
+	int res = -1;
+	int in = create_file(dirfd, "copy-in");
+	int out = create_file(dirfd, "copy-out");
+	off_t off_in = 0, off_out = 0;
+
+	if (in != -1 && out != -1 &&
+	    copy_file_range(in, &off_in, out, &off_out, 10, 0) == 10)
+		res = 0;
+	if (in != -1)
+		close(in);
+	if (out != -1)
+		close(out);
+	unlinkat(dirfd, "copy-in", 0);
+	unlinkat(dirfd, "copy-out", 0);
+	return res;
+}
+
+static const struct check checks[] = {
+	{ "O_TMPFILE", false, NULL, check_tmpfile },
+#ifdef HAVE_SETXATTR
+	{ "extended attributes", true,
+	  "rebuild without HAVE_SETXATTR", check_xattr },
+#else
+	{ "extended attributes", false, NULL, check_xattr },
+#endif
+	{ "file handles", false, NULL, check_file_handles },
+	{ "renameat2 flags", false, NULL, check_renameat2 },
+#ifdef HAVE_POSIX_FALLOCATE
+	{ "fallocate modes", true,
+	  "rebuild without HAVE_POSIX_FALLOCATE", check_fallocate },
+#else
+	{ "fallocate modes", false, NULL, check_fallocate },
+#endif
+#ifdef HAVE_COPY_FILE_RANGE
+	{ "copy_file_range", true,
+	  "rebuild without HAVE_COPY_FILE_RANGE", check_copy_file_range },
+#else
+	{ "copy_file_range", false, NULL, check_copy_file_range },
+#endif
+};
+
+/* Whether the process has CAP_FOWNER, which O_NOATIME on files of other
+   users takes */
+static bool has_fowner(void)
+{
+	char line[256];
+	unsigned long long caps = 0;
+	FILE *status = fopen("/proc/self/status", "r");
+
+	if (status == NULL)
+		return false;
+	while (fgets(line, sizeof(line), status))
+		if (sscanf(line, "CapEff: %llx", &caps) == 1)
+			break;
+	fclose(status);
+	return caps & (1ULL << 3);
+}
+
+/* How the file system of dir updates access times by itself */
+static const char *host_atime(const char *dir)
+{
+	struct statvfs st;
+
+	if (statvfs(dir, &st) == -1)
+		return "unknown";
+	if (st.f_flag & ST_NOATIME)
+		return "noatime";
+	if (st.f_flag & ST_RELATIME)
+		return "relatime";
+	return "strictatime";
+}
+
+static void print_atime_policy(const char *dir)
+{
+	/* batching, O_NOATIME and setting access times back all only work
+	   on files of this user without CAP_FOWNER */
+	const char *others = has_fowner() ? "" :
+		", on files of other users as the backing file system updates them";
+
+	if (atime_policy == ATIME_HOST && atime_flush_secs)
+		printf("atime policy: host (%s), set in batches every %d seconds%s\n",
+		       host_atime(dir), atime_flush_secs, others);
+	else if (atime_policy == ATIME_HOST)
+		printf("atime policy: host (%s), as the backing file system updates them\n",
+		       host_atime(dir));
+	else if (atime_policy == ATIME_RELATIME)
+		printf("atime policy: relatime, emulated by restoring access times on release%s\n",
+		       others);
+	else if (has_fowner())
+		printf("atime policy: noatime, with O_NOATIME\n");
+	else
+		printf("atime policy: noatime, with O_NOATIME on files of this user, not supported on others\n");
+}
+
+int self_check(const char *dir)
+{
+	char scratch[PATH_MAX];
+	int dirfd, failed = 0;
+
+	snprintf(scratch, sizeof(scratch), "%s/.self-check-XXXXXX", dir);
+	if (mkdtemp(scratch) == NULL) {
+		fprintf(stderr, "cannot create scratch directory in %s: %s\n",
+			dir, strerror(errno));
+		return 1;
+	}
+	dirfd = open(scratch, O_RDONLY | O_DIRECTORY | O_CLOEXEC);
+	if (dirfd == -1) {
+		fprintf(stderr, "cannot open %s: %s\n", scratch,
+			strerror(errno));
+		rmdir(scratch);
+		return 1;
+	}
+
+	printf("capabilities of %s:\n", dir);
+	for (const struct check &check : checks) {
+		errno = 0;
+		bool ok = check.run(dirfd) == 0;
+		printf("  %-20s %s", check.name, ok ? "yes" : "no");
+		if (!ok && errno)
+			printf(" (%s)", strerror(errno));
+		if (!ok && check.required) {
+			printf(" - required, %s", check.advice);
+			failed = 1;
+		}
+		printf("\n");
+	}
+	print_atime_policy(dir);
+
+	close(dirfd);
+	rmdir(scratch);
+	return failed;
+}
@@ -0,0 +1,17 @@
+#ifndef SELF_CHECK_H
+#define SELF_CHECK_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Exercise the backing directory dir with the operations the passthrough
+   relies on and print a capability report. Returns 0 if everything the
+   build needs is supported, 1 otherwise. */
+int self_check(const char *dir);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif // SELF_CHECK_H
@@ -7,6 +7,7 @@
 #include <string.h>
 #include <stdio.h>
 #include "passthrough/passthrough.h"
+#include "passthrough/options.h"
 
 #include "opentelemetry/trace/provider.h"
 #include "opentelemetry/trace/tracer_provider.h"
@@ -63,17 +64,12 @@ int main(int argc, char *argv[])
 	tracing_file_op.read = tracing_read;
 
 	enum { MAX_ARGS = 10 };
-	int i,new_argc;
+	int res, new_argc;
 	char *new_argv[MAX_ARGS];
 
 	umask(0);
-			/* Process the "--plus" option apart */
-	for (i=0, new_argc=0; (i<argc) && (new_argc<MAX_ARGS); i++) {
-		if (!strcmp(argv[i], "--plus")) {
-			fill_dir_plus = FUSE_FILL_DIR_PLUS;
-		} else {
-			new_argv[new_argc++] = argv[i];
-		}
-	}
+	res = parse_options(argc, argv, &new_argc, new_argv, MAX_ARGS);
+	if (res != -1)
+		return res;
 	return fuse_main(new_argc, new_argv, &tracing_file_op, NULL);
 }